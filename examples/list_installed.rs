@@ -1,5 +1,9 @@
+#[cfg(windows)]
 fn main() {
-    for p in quickcfg::ffi::win::msi_enum_products().unwrap() {
+    for p in quickcfg::ffi::win::msi::msi_enum_products().unwrap() {
         println!("{:?}", p);
     }
 }
+
+#[cfg(not(windows))]
+fn main() {}
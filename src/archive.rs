@@ -0,0 +1,368 @@
+//! Fetching the configuration as an archive over HTTP, as an alternative to git.
+
+use crate::{cache, net, state_dir::StateFile, verify::Verify, Load, Save};
+use anyhow::{anyhow, Context as _, Error};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io::{Read, Seek};
+use std::path::{Path, PathBuf};
+
+/// Paths in the configuration root which belong to quickcfg, and are kept when replacing it.
+const KEEP: &[&str] = &[".state.yml", ".state"];
+
+/// Supported archive formats.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Format {
+    TarGz,
+    Zip,
+}
+
+impl Format {
+    /// Detect the archive format from the extension in the given URL.
+    fn from_url(url: &str) -> Option<Format> {
+        let path = url.split(['?', '#']).next().unwrap_or(url);
+
+        if path.ends_with(".tar.gz") || path.ends_with(".tgz") {
+            return Some(Format::TarGz);
+        }
+
+        if path.ends_with(".zip") {
+            return Some(Format::Zip);
+        }
+
+        None
+    }
+}
+
+/// Test if the given URL refers to an archive that we know how to unpack.
+pub fn is_archive_url(url: &str) -> bool {
+    Format::from_url(url).is_some()
+}
+
+/// Where the configuration archive is fetched from.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Source {
+    /// URL of the archive.
+    pub url: String,
+    /// URL of the file with the SHA-256 checksum of the archive.
+    pub checksum_url: String,
+    /// Checksum of the archive which is currently unpacked.
+    #[serde(default)]
+    pub sha256: Option<String>,
+    /// ETag of the checksum file of the archive which is currently unpacked.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub checksum_etag: Option<String>,
+}
+
+impl Source {
+    /// Construct a new source for the given URL, with the checksum in a `.sha256` file next to
+    /// it.
+    pub fn new(url: &str) -> Self {
+        Source {
+            url: url.to_string(),
+            checksum_url: format!("{}.sha256", url),
+            sha256: None,
+            checksum_etag: None,
+        }
+    }
+
+    /// Open the source recorded in the given state directory, if the configuration came from an
+    /// archive.
+    pub fn open(state_dir: &Path) -> Result<Option<Source>, Error> {
+        <Source as Load>::load(&StateFile::Archive.path(state_dir))
+    }
+
+    /// Record the source in the given state directory.
+    pub fn save(&self, state_dir: &Path) -> Result<(), Error> {
+        <Source as Save>::save(self, &StateFile::Archive.path(state_dir))
+    }
+
+    /// Check if the archive has changed since it was last unpacked.
+    pub fn needs_update(&self) -> Result<bool, Error> {
+        match self.remote_checksum()? {
+            Some((checksum, _)) => Ok(self.sha256.as_deref() != Some(checksum.as_str())),
+            None => Ok(false),
+        }
+    }
+
+    /// Download the checksum of the current remote archive, together with its ETag.
+    ///
+    /// Returns `None` if the checksum hasn't changed since the current archive was unpacked.
+    fn remote_checksum(&self) -> Result<Option<(String, Option<String>)>, Error> {
+        let etag = self
+            .checksum_etag
+            .as_deref()
+            .filter(|_| self.sha256.is_some());
+
+        let (response, etag) = match net::client()?.get_conditional(&self.checksum_url, etag)? {
+            net::Conditional::NotModified => return Ok(None),
+            net::Conditional::Modified { response, etag } => (response, etag),
+        };
+
+        let checksum = parse_checksum(&response.text()?)
+            .ok_or_else(|| anyhow!("bad checksum in {}", self.checksum_url))?;
+
+        Ok(Some((checksum, etag)))
+    }
+
+    /// Check if the archive has changed, and if so download, verify, and unpack it into root.
+    ///
+    /// Returns `true` if the configuration was updated.
+    pub fn update(&mut self, root: &Path) -> Result<bool, Error> {
+        let format = Format::from_url(&self.url)
+            .ok_or_else(|| anyhow!("unsupported archive: {}", self.url))?;
+
+        let (expected, etag) = match self.remote_checksum()? {
+            Some(checksum) => checksum,
+            None => return Ok(false),
+        };
+
+        if self.sha256.as_deref() == Some(expected.as_str()) {
+            self.checksum_etag = etag;
+            return Ok(false);
+        }
+
+        let archive = sibling(root, "download")?;
+
+        let result =
+            download(&self.url, &expected, &archive).and_then(|()| unpack(format, &archive, root));
+
+        let _ = fs::remove_file(&archive);
+        result?;
+
+        self.sha256 = Some(expected);
+        self.checksum_etag = etag;
+        Ok(true)
+    }
+}
+
+/// Parse a checksum file, which is either just the checksum or in the format used by
+/// `sha256sum`.
+pub(crate) fn parse_checksum(content: &str) -> Option<String> {
+    let checksum = content.split_whitespace().next()?.to_lowercase();
+
+    if checksum.len() != 64 || !checksum.chars().all(|c| c.is_ascii_hexdigit()) {
+        return None;
+    }
+
+    Some(checksum)
+}
+
+/// Download the archive at the given URL to `path`, verifying it against the given SHA-256.
+///
+/// The archive is verified as it's written, so that it's never held in memory. If verification
+/// fails, the file must be discarded.
+pub fn download(url: &str, sha256: &str, path: &Path) -> Result<(), Error> {
+    let verify = Verify {
+        sha256: Some(sha256.to_string()),
+        ..Verify::default()
+    };
+
+    let mut file =
+        fs::File::create(path).with_context(|| anyhow!("failed to create: {}", path.display()))?;
+
+    verify.check_stream(url, &mut file, |out| {
+        cache::download(url, Some(sha256), out)
+    })
+}
+
+/// A temporary path next to the given directory, which is on the same file system so that it can
+/// be renamed into place.
+pub fn sibling(dir: &Path, extension: &str) -> Result<PathBuf, Error> {
+    let name = dir
+        .file_name()
+        .ok_or_else(|| anyhow!("not a directory: {}", dir.display()))?
+        .to_string_lossy();
+
+    Ok(dir.with_file_name(format!(".{}.{}.{}", name, std::process::id(), extension)))
+}
+
+/// Extract the archive at `archive`, downloaded from the given URL, into `dir`, replacing anything
+/// in it.
+///
+/// The directory is only replaced once the archive has been fully extracted.
+pub fn extract(url: &str, archive: &Path, dir: &Path) -> Result<(), Error> {
+    let format = Format::from_url(url).ok_or_else(|| anyhow!("unsupported archive: {}", url))?;
+    let unpack_dir = sibling(dir, "tmp")?;
+
+    let result = (|| -> Result<(), Error> {
+        let content = unpack_into(format, open(archive)?, &unpack_dir)?;
+
+        if dir.exists() {
+            fs::remove_dir_all(dir)?;
+        }
+
+        fs::rename(content, dir)?;
+        Ok(())
+    })();
+
+    if unpack_dir.exists() {
+        let _ = fs::remove_dir_all(&unpack_dir);
+    }
+
+    result.with_context(|| anyhow!("failed to extract {} to {}", url, dir.display()))
+}
+
+/// Unpack the archive into the given directory, replacing anything in it.
+///
+/// Returns the directory with the content of the archive.
+fn unpack_into<R>(format: Format, archive: R, unpack_dir: &Path) -> Result<PathBuf, Error>
+where
+    R: Read + Seek,
+{
+    if unpack_dir.exists() {
+        fs::remove_dir_all(unpack_dir)?;
+    }
+
+    fs::create_dir_all(unpack_dir)?;
+
+    match format {
+        Format::TarGz => {
+            let decoder = flate2::read::GzDecoder::new(archive);
+            tar::Archive::new(decoder).unpack(unpack_dir)?;
+        }
+        Format::Zip => {
+            zip::ZipArchive::new(archive)?.extract(unpack_dir)?;
+        }
+    }
+
+    // NB: archives commonly contain a single top-level directory, like the ones from GitHub.
+    let entries = fs::read_dir(unpack_dir)?.collect::<Result<Vec<_>, _>>()?;
+
+    match entries.as_slice() {
+        [entry] if entry.file_type()?.is_dir() => Ok(entry.path()),
+        _ => Ok(unpack_dir.to_owned()),
+    }
+}
+
+/// Unpack the archive and replace the content of root with it.
+///
+/// The archive is unpacked next to root so that everything can be moved with renames on the same
+/// file system, and the old content is only removed once the new content is in place.
+fn unpack(format: Format, archive: &Path, root: &Path) -> Result<(), Error> {
+    let unpack_dir = sibling(root, "tmp")?;
+    let old_dir = sibling(root, "old")?;
+
+    let result = (|| -> Result<(), Error> {
+        let content = unpack_into(format, open(archive)?, &unpack_dir)?;
+
+        if old_dir.exists() {
+            fs::remove_dir_all(&old_dir)?;
+        }
+
+        fs::create_dir_all(&old_dir)?;
+        fs::create_dir_all(root)?;
+        move_entries(root, &old_dir)?;
+
+        if let Err(e) = move_entries(&content, root) {
+            // NB: put the old content back, so that root isn't left half replaced.
+            move_entries(root, &content)?;
+            move_entries(&old_dir, root)?;
+            return Err(e);
+        }
+
+        Ok(())
+    })();
+
+    for dir in [&unpack_dir, &old_dir] {
+        if dir.exists() {
+            let _ = fs::remove_dir_all(dir);
+        }
+    }
+
+    result.with_context(|| anyhow!("failed to unpack archive into {}", root.display()))
+}
+
+/// Open a downloaded archive.
+fn open(archive: &Path) -> Result<fs::File, Error> {
+    fs::File::open(archive).with_context(|| anyhow!("failed to open: {}", archive.display()))
+}
+
+/// Move everything except the paths which belong to quickcfg from one directory to another.
+fn move_entries(from: &Path, to: &Path) -> Result<(), Error> {
+    for entry in fs::read_dir(from)? {
+        let entry = entry?;
+
+        if KEEP.iter().any(|keep| entry.file_name() == *keep) {
+            continue;
+        }
+
+        fs::rename(entry.path(), to.join(entry.file_name()))?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{extract, is_archive_url, parse_checksum, unpack, Format};
+    use std::fs;
+
+    #[test]
+    fn test_format() {
+        assert_eq!(
+            Format::from_url("https://example.com/config.tar.gz?token=1"),
+            Some(Format::TarGz)
+        );
+        assert_eq!(
+            Format::from_url("https://example.com/config.zip"),
+            Some(Format::Zip)
+        );
+        assert!(!is_archive_url("https://github.com/example/config.git"));
+    }
+
+    #[test]
+    fn test_parse_checksum() {
+        let checksum = "E3B0C44298FC1C149AFBF4C8996FB92427AE41E4649B934CA495991B7852B855";
+
+        assert_eq!(
+            parse_checksum(&format!("{}  config.tar.gz\n", checksum)),
+            Some(checksum.to_lowercase())
+        );
+        assert_eq!(parse_checksum("nope"), None);
+        assert_eq!(parse_checksum(""), None);
+    }
+
+    #[test]
+    fn test_unpack() -> anyhow::Result<()> {
+        let dir = std::env::temp_dir().join(format!("quickcfg-archive-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let root = dir.join("root");
+        fs::create_dir_all(root.join(".state"))?;
+        fs::write(root.join("old.yml"), b"old")?;
+
+        let mut archive = Vec::new();
+
+        {
+            let encoder = flate2::write::GzEncoder::new(&mut archive, Default::default());
+            let mut builder = tar::Builder::new(encoder);
+            let mut header = tar::Header::new_gnu();
+            header.set_size(3);
+            header.set_mode(0o644);
+            builder.append_data(&mut header, "config/new.yml", &b"new"[..])?;
+            builder.into_inner()?.finish()?;
+        }
+
+        let path = dir.with_extension("tar.gz");
+        fs::write(&path, &archive)?;
+        unpack(Format::TarGz, &path, &root)?;
+
+        assert!(root.join(".state").is_dir());
+        assert!(!root.join("old.yml").exists());
+        assert_eq!(fs::read(root.join("new.yml"))?, b"new");
+        assert_eq!(fs::read_dir(&dir)?.count(), 1);
+
+        let tool = dir.join("tool");
+        fs::create_dir_all(&tool)?;
+        fs::write(tool.join("old"), b"old")?;
+        extract("https://example.com/tool.tar.gz", &path, &tool)?;
+
+        assert!(!tool.join("old").exists());
+        assert_eq!(fs::read(tool.join("new.yml"))?, b"new");
+        assert_eq!(fs::read_dir(&dir)?.count(), 2);
+
+        fs::remove_file(&path)?;
+        fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,346 @@
+//! Content-addressed cache of downloads, so that the same file isn't downloaded again by other
+//! systems or later runs.
+//!
+//! Downloads are stored under `cache/downloads` in the state directory, by the SHA-256 of their
+//! contents in `blobs`, with an index from the URL they were downloaded from in `urls`. When the
+//! cache grows larger than its maximum size, the downloads which were least recently used are
+//! evicted.
+
+use crate::{net, state_dir::StateFile};
+use anyhow::{anyhow, Context as _, Error};
+use filetime::FileTime;
+use sha2::{Digest, Sha256};
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::SystemTime;
+
+/// The configured cache.
+static CACHE: RwLock<Option<Arc<Cache>>> = RwLock::new(None);
+
+/// Counter to give temporary files unique names, since units download in parallel.
+static TEMP: AtomicUsize = AtomicUsize::new(0);
+
+/// Configure the shared cache in the given state directory, replacing any previous configuration.
+pub fn configure(state_dir: &Path, max_size: u64) {
+    let cache = Cache::new(&dir(state_dir), max_size);
+    *CACHE.write().unwrap_or_else(|e| e.into_inner()) = Some(Arc::new(cache));
+}
+
+/// Directory of the download cache in the given state directory.
+pub fn dir(state_dir: &Path) -> PathBuf {
+    StateFile::Cache.path(state_dir).join("downloads")
+}
+
+/// Download the given URL into the given writer, through the cache if it has been configured.
+///
+/// If the SHA-256 of the contents is known, a download with the same contents is used regardless
+/// of which URL it was downloaded from.
+pub fn download(url: &str, sha256: Option<&str>, out: &mut dyn Write) -> Result<(), Error> {
+    let cache = CACHE.read().unwrap_or_else(|e| e.into_inner()).clone();
+
+    let cache = match cache {
+        Some(cache) => cache,
+        None => {
+            net::client()?.get(url)?.copy_to(out)?;
+            return Ok(());
+        }
+    };
+
+    let blob = cache.fetch(url, sha256)?;
+    let mut file = fs::File::open(&blob)
+        .with_context(|| anyhow!("failed to open cached download: {}", blob.display()))?;
+    io::copy(&mut file, out)?;
+
+    if let Err(e) = cache.evict() {
+        log::warn!("Failed to evict downloads from cache: {}", e);
+    }
+
+    Ok(())
+}
+
+/// A content-addressed cache of downloads.
+pub struct Cache {
+    /// Directory of the cache.
+    dir: PathBuf,
+    /// How large the cache may grow, in bytes.
+    max_size: u64,
+    /// Held while evicting, so that concurrent downloads don't evict the same files.
+    evicting: Mutex<()>,
+}
+
+impl Cache {
+    /// Construct a cache in the given directory.
+    pub fn new(dir: &Path, max_size: u64) -> Self {
+        Cache {
+            dir: dir.to_owned(),
+            max_size,
+            evicting: Mutex::new(()),
+        }
+    }
+
+    /// Get the path to the cached contents of the given URL, downloading them if they aren't
+    /// cached.
+    ///
+    /// If the SHA-256 of the contents is known, the cached contents are looked up by it instead of
+    /// by the URL, since what the URL points to might have changed.
+    pub fn fetch(&self, url: &str, sha256: Option<&str>) -> Result<PathBuf, Error> {
+        let index = self.dir.join("urls").join(hex_digest(url.as_bytes()));
+
+        let cached = match sha256 {
+            Some(sha256) => Some(sha256.to_string()),
+            None => fs::read_to_string(&index)
+                .ok()
+                .map(|hash| hash.trim().to_string()),
+        };
+
+        // NB: the hash is used as a path, so it must not be able to point outside of the cache.
+        let cached = cached.filter(|hash| is_sha256(hash));
+
+        if let Some(blob) = cached.map(|hash| self.blob(&hash)).filter(|b| b.is_file()) {
+            log::trace!("Using cached download of {}: {}", url, blob.display());
+            // NB: the modified time is used to evict the least recently used downloads.
+            filetime::set_file_mtime(&blob, FileTime::from_system_time(SystemTime::now()))?;
+            return Ok(blob);
+        }
+
+        let blobs = self.dir.join("blobs");
+        fs::create_dir_all(&blobs)
+            .with_context(|| anyhow!("failed to create cache: {}", blobs.display()))?;
+
+        let temp = temp_path(&blobs);
+
+        let result = (|| -> Result<PathBuf, Error> {
+            let mut response = net::client()?.get(url)?;
+            let mut out = HashingWriter::new(fs::File::create(&temp)?);
+            response.copy_to(&mut out)?;
+            let hash = out.finish()?;
+
+            let blob = self.blob(&hash);
+            fs::rename(&temp, &blob)?;
+            Ok(blob)
+        })();
+
+        let blob = match result {
+            Ok(blob) => blob,
+            Err(e) => {
+                let _ = fs::remove_file(&temp);
+                return Err(e);
+            }
+        };
+
+        if let Some(parent) = index.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        let hash = blob.file_name().unwrap_or_default().to_string_lossy();
+        write_unique(&index, hash.as_bytes())?;
+        Ok(blob)
+    }
+
+    /// Evict the least recently used downloads until the cache fits in its maximum size.
+    pub fn evict(&self) -> Result<(), Error> {
+        let _guard = self.evicting.lock().unwrap_or_else(|e| e.into_inner());
+
+        let mut blobs = Vec::new();
+        let mut size = 0;
+
+        for entry in read_dir(&self.dir.join("blobs"))? {
+            let meta = entry.metadata()?;
+
+            // NB: skip downloads which are in progress.
+            if !meta.is_file() || entry.file_name().to_string_lossy().starts_with('.') {
+                continue;
+            }
+
+            size += meta.len();
+            blobs.push((meta.modified()?, meta.len(), entry.path()));
+        }
+
+        if size <= self.max_size {
+            return Ok(());
+        }
+
+        blobs.sort();
+
+        for (_, len, path) in blobs {
+            if size <= self.max_size {
+                break;
+            }
+
+            log::trace!("Evicting cached download: {}", path.display());
+            fs::remove_file(&path)?;
+            size -= len;
+        }
+
+        // NB: remove urls which point to evicted downloads.
+        for entry in read_dir(&self.dir.join("urls"))? {
+            let hash = fs::read_to_string(entry.path()).unwrap_or_default();
+
+            if !self.blob(hash.trim()).is_file() {
+                let _ = fs::remove_file(entry.path());
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Remove everything from the cache.
+    ///
+    /// Returns the number of bytes which were freed.
+    pub fn clean(&self) -> Result<u64, Error> {
+        let _guard = self.evicting.lock().unwrap_or_else(|e| e.into_inner());
+
+        let mut size = 0;
+
+        for entry in read_dir(&self.dir.join("blobs"))? {
+            size += entry.metadata()?.len();
+        }
+
+        if self.dir.is_dir() {
+            fs::remove_dir_all(&self.dir)
+                .with_context(|| anyhow!("failed to remove cache: {}", self.dir.display()))?;
+        }
+
+        Ok(size)
+    }
+
+    /// Path to the download with the given SHA-256.
+    fn blob(&self, sha256: &str) -> PathBuf {
+        self.dir.join("blobs").join(sha256.to_lowercase())
+    }
+}
+
+/// Writer which hashes everything written through it.
+struct HashingWriter<W> {
+    inner: W,
+    hasher: Sha256,
+}
+
+impl<W> HashingWriter<W>
+where
+    W: Write,
+{
+    fn new(inner: W) -> Self {
+        HashingWriter {
+            inner,
+            hasher: Sha256::new(),
+        }
+    }
+
+    /// Flush the writer, and return the hex-encoded SHA-256 of everything written.
+    fn finish(mut self) -> Result<String, Error> {
+        self.inner.flush()?;
+        Ok(format!("{:x}", self.hasher.finalize()))
+    }
+}
+
+impl<W> Write for HashingWriter<W>
+where
+    W: Write,
+{
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.hasher.update(&buf[..n]);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Hex-encoded SHA-256 of the given bytes.
+fn hex_digest(bytes: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(bytes))
+}
+
+/// Test if the given string is a hex-encoded SHA-256.
+fn is_sha256(s: &str) -> bool {
+    s.len() == 64 && s.bytes().all(|b| b.is_ascii_hexdigit())
+}
+
+/// A unique temporary path in the given directory.
+fn temp_path(dir: &Path) -> PathBuf {
+    let n = TEMP.fetch_add(1, Ordering::SeqCst);
+    dir.join(format!(".{}.{}.tmp", std::process::id(), n))
+}
+
+/// Write the given file through a uniquely named temporary file, so that concurrent writers don't
+/// clobber each other.
+fn write_unique(path: &Path, contents: &[u8]) -> Result<(), Error> {
+    let temp = temp_path(path.parent().unwrap_or_else(|| Path::new(".")));
+
+    let result = fs::write(&temp, contents).and_then(|()| fs::rename(&temp, path));
+
+    if let Err(e) = result {
+        let _ = fs::remove_file(&temp);
+        return Err(Error::from(e).context(anyhow!("failed to write: {}", path.display())));
+    }
+
+    Ok(())
+}
+
+/// Read the entries of the given directory, which are none if it doesn't exist.
+fn read_dir(dir: &Path) -> Result<Vec<fs::DirEntry>, Error> {
+    match fs::read_dir(dir) {
+        Ok(entries) => Ok(entries.collect::<Result<_, _>>()?),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Vec::new()),
+        Err(e) => Err(Error::from(e).context(anyhow!("failed to read: {}", dir.display()))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{hex_digest, is_sha256, Cache, HashingWriter};
+    use std::fs;
+    use std::io::Write as _;
+    use std::time::{Duration, SystemTime};
+
+    #[test]
+    fn test_hashing_writer() -> anyhow::Result<()> {
+        let mut out = HashingWriter::new(Vec::new());
+        out.write_all(b"hello world")?;
+        assert_eq!(hex_digest(b"hello world"), out.finish()?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_is_sha256() {
+        assert!(is_sha256(&hex_digest(b"hello world")));
+        assert!(!is_sha256("../../etc/passwd"));
+        assert!(!is_sha256("abc"));
+    }
+
+    #[test]
+    fn test_evict_least_recently_used() -> anyhow::Result<()> {
+        let dir = std::env::temp_dir().join(format!("quickcfg-cache-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("blobs"))?;
+        fs::create_dir_all(dir.join("urls"))?;
+
+        let cache = Cache::new(&dir, 10);
+        let now = SystemTime::now();
+
+        for (name, age) in &[("old", 20), ("new", 10), ("newest", 0)] {
+            let blob = cache.blob(name);
+            fs::write(&blob, b"12345")?;
+            let modified = now - Duration::from_secs(*age);
+            filetime::set_file_mtime(&blob, filetime::FileTime::from_system_time(modified))?;
+            fs::write(dir.join("urls").join(name), name)?;
+        }
+
+        cache.evict()?;
+
+        assert!(!cache.blob("old").is_file());
+        assert!(!dir.join("urls").join("old").is_file());
+        assert!(cache.blob("new").is_file());
+        assert!(cache.blob("newest").is_file());
+
+        assert_eq!(10, cache.clean()?);
+        assert!(!dir.is_dir());
+        Ok(())
+    }
+}
@@ -1,13 +1,113 @@
 //! Helper to run external commands.
 
 use anyhow::{bail, Error};
+use std::cell::RefCell;
 use std::ffi::{OsStr, OsString};
 use std::fmt;
 use std::io;
 use std::path::{Path, PathBuf};
 use std::process;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
 use thiserror::Error;
 
+/// How often to check if a command has exited, when it has a deadline.
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// If commands are run without anyone to answer prompts.
+static NON_INTERACTIVE: AtomicBool = AtomicBool::new(false);
+
+/// Commands which have been run, if commands are traced.
+static TRACED: Mutex<Option<Vec<Traced>>> = Mutex::new(None);
+
+thread_local! {
+    /// Environment variables for every command spawned by the current thread.
+    static ENV: RefCell<Vec<(String, String)>> = const { RefCell::new(Vec::new()) };
+}
+
+/// Run the given function with environment variables which are set for every command it spawns.
+///
+/// This is used for the environment of a system, since its units call into package managers and
+/// other helpers which don't know which system they are running for.
+pub(crate) fn with_env<T>(env: &[(String, String)], f: impl FnOnce() -> T) -> T {
+    let previous = ENV.with(|e| e.replace(env.to_vec()));
+    let output = f();
+    ENV.with(|e| e.replace(previous));
+    output
+}
+
+/// Run every command spawned from now on without anyone to answer prompts.
+///
+/// This makes `sudo` fail instead of asking for a password, since nobody would answer it.
+pub(crate) fn set_non_interactive(non_interactive: bool) {
+    NON_INTERACTIVE.store(non_interactive, Ordering::Relaxed);
+}
+
+/// Test if commands are run without anyone to answer prompts.
+pub(crate) fn is_non_interactive() -> bool {
+    NON_INTERACTIVE.load(Ordering::Relaxed)
+}
+
+/// Start or stop tracing every command which is run.
+///
+/// Traced commands are logged as they exit, and collected until they're taken with [take_traced].
+pub fn set_tracing(tracing: bool) {
+    let mut traced = TRACED.lock().unwrap_or_else(|e| e.into_inner());
+    *traced = if tracing { Some(Vec::new()) } else { None };
+}
+
+/// Take the commands which have been traced so far.
+pub fn take_traced() -> Vec<Traced> {
+    let mut traced = TRACED.lock().unwrap_or_else(|e| e.into_inner());
+    traced.as_mut().map(std::mem::take).unwrap_or_default()
+}
+
+/// A command which has been run while tracing.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Traced {
+    /// The program which was run.
+    pub program: PathBuf,
+    /// Arguments passed to the program.
+    pub args: Vec<String>,
+    /// The working directory, if it wasn't inherited.
+    pub working_directory: Option<PathBuf>,
+    /// Names of environment variables which were set for the command.
+    ///
+    /// Values are left out, since they might be secrets like tokens.
+    pub env: Vec<String>,
+    /// The exit status, or `None` if the command couldn't be run or was killed by a signal.
+    pub status: Option<i32>,
+    /// How long the command ran for.
+    pub duration: Duration,
+}
+
+impl fmt::Display for Traced {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        self.program.display().fmt(fmt)?;
+
+        for arg in &self.args {
+            write!(fmt, " {}", arg)?;
+        }
+
+        if let Some(working_directory) = &self.working_directory {
+            write!(fmt, " (in {})", working_directory.display())?;
+        }
+
+        if !self.env.is_empty() {
+            write!(fmt, " (env: {})", self.env.join(", "))?;
+        }
+
+        match self.status {
+            Some(status) => write!(fmt, ": status={}", status)?,
+            None => write!(fmt, ": no status")?,
+        }
+
+        write!(fmt, " in {:.2?}", self.duration)
+    }
+}
+
 /// The decoded output after running a command.
 pub struct Output {
     pub status: process::ExitStatus,
@@ -57,6 +157,20 @@ pub struct Command {
     pub(crate) name: PathBuf,
     pub(crate) working_directory: Option<PathBuf>,
     pub(crate) args: Vec<OsString>,
+    pub(crate) env: Vec<(OsString, OsString)>,
+    pub(crate) stdin: Option<Vec<u8>>,
+}
+
+impl fmt::Display for Command {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        self.name.display().fmt(fmt)?;
+
+        for arg in &self.args {
+            write!(fmt, " {}", arg.to_string_lossy())?;
+        }
+
+        Ok(())
+    }
 }
 
 impl Command {
@@ -66,6 +180,8 @@ impl Command {
             name: name.into(),
             working_directory: None,
             args: Vec::new(),
+            env: Vec::new(),
+            stdin: None,
         }
     }
 
@@ -87,9 +203,44 @@ impl Command {
             .extend(args.into_iter().map(|arg| arg.as_ref().to_owned()));
     }
 
+    /// Set an environment variable for the command.
+    pub fn env<K, V>(&mut self, key: K, value: V)
+    where
+        K: AsRef<OsStr>,
+        V: AsRef<OsStr>,
+    {
+        self.env
+            .push((key.as_ref().to_owned(), value.as_ref().to_owned()));
+    }
+
+    /// Write the given input to the stdin of the command.
+    pub fn stdin(&mut self, input: impl Into<Vec<u8>>) {
+        self.stdin = Some(input.into());
+    }
+
     fn command(&self) -> process::Command {
         let mut cmd = process::Command::new(self.name.as_os_str());
+
+        let is_sudo = self.name.file_stem() == Some(OsStr::new("sudo"));
+
+        if is_sudo && is_non_interactive() {
+            cmd.arg("--non-interactive");
+        }
+
+        ENV.with(|env| {
+            let env = env.borrow();
+
+            // NB: sudo resets the environment unless told otherwise.
+            if !env.is_empty() && is_sudo {
+                let keys = env.iter().map(|(k, _)| k.as_str()).collect::<Vec<_>>();
+                cmd.arg(format!("--preserve-env={}", keys.join(",")));
+            }
+
+            cmd.envs(env.iter().map(|(k, v)| (k, v)));
+        });
+
         cmd.args(&self.args);
+        cmd.envs(self.env.iter().map(|(k, v)| (k, v)));
 
         if let Some(working_directory) = self.working_directory.as_ref() {
             cmd.current_dir(working_directory);
@@ -98,6 +249,33 @@ impl Command {
         cmd
     }
 
+    /// Wait for the given child to exit, killing it if the deadline of the current thread passes.
+    fn wait(&self, mut child: process::Child) -> io::Result<process::ExitStatus> {
+        if crate::deadline::remaining().is_none() {
+            return child.wait();
+        }
+
+        loop {
+            if let Some(status) = child.try_wait()? {
+                return Ok(status);
+            }
+
+            if crate::deadline::has_passed() {
+                // NB: only reap the child if we managed to kill it, otherwise we'd block on it.
+                if child.kill().is_ok() {
+                    let _ = child.wait();
+                }
+
+                return Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    format!("command timed out: {}", self.name.display()),
+                ));
+            }
+
+            thread::sleep(POLL_INTERVAL);
+        }
+    }
+
     /// Configure the working directory of this command.
     pub fn working_directory(&mut self, path: impl AsRef<Path>) {
         self.working_directory = Some(path.as_ref().to_owned());
@@ -141,8 +319,18 @@ impl Command {
     /// This is discouraged, since it basically requires the command to be running on the main
     /// thread.
     pub fn run_inherited(&self) -> Result<(), Error> {
+        if let Some(fake) = crate::environment::simulation() {
+            let output = fake.run(self);
+
+            if !output.status.success() {
+                return Err(Error::from(output.into_error()));
+            }
+
+            return Ok(());
+        }
+
         let mut cmd = self.command();
-        let status = cmd.status()?;
+        let status = self.trace(|| self.wait(cmd.spawn()?), |s| s.code())?;
 
         if !status.success() {
             bail!(
@@ -157,16 +345,85 @@ impl Command {
 
     /// Run the given command, return a string of all output.
     pub fn run(self) -> io::Result<Output> {
-        let output = self.command().output()?;
+        if let Some(fake) = crate::environment::simulation() {
+            return Ok(fake.run(&self));
+        }
+
+        let output = self.trace(|| self.output(), |o| o.status.code())?;
 
         let output = Output {
             status: output.status,
-            stdout: String::from_utf8(output.stdout).map_err(|_| {
-                io::Error::new(io::ErrorKind::Other, "Cannot decode stdout as utf-8")
-            })?,
-            stderr: String::from_utf8(output.stderr).map_err(|_| {
-                io::Error::new(io::ErrorKind::Other, "Cannot decode stderr as utf-8")
-            })?,
+            stdout: String::from_utf8(output.stdout)
+                .map_err(|_| io::Error::other("Cannot decode stdout as utf-8"))?,
+            stderr: String::from_utf8(output.stderr)
+                .map_err(|_| io::Error::other("Cannot decode stderr as utf-8"))?,
+        };
+
+        Ok(output)
+    }
+
+    /// Run the command, collecting its output.
+    fn output(&self) -> io::Result<process::Output> {
+        use std::io::Read as _;
+
+        use std::io::Write as _;
+
+        let output = if crate::deadline::remaining().is_none() && self.stdin.is_none() {
+            self.command().output()?
+        } else {
+            let stdin = match self.stdin {
+                Some(_) => process::Stdio::piped(),
+                None => process::Stdio::null(),
+            };
+
+            let mut child = self
+                .command()
+                .stdin(stdin)
+                .stdout(process::Stdio::piped())
+                .stderr(process::Stdio::piped())
+                .spawn()?;
+
+            // NB: write input on a separate thread for the same reason we read output on one.
+            let write = match (child.stdin.take(), self.stdin.clone()) {
+                (Some(mut pipe), Some(input)) => {
+                    Some(thread::spawn(move || pipe.write_all(&input)))
+                }
+                _ => None,
+            };
+
+            // NB: read output on separate threads, so that the child doesn't block on full pipes.
+            let read = |pipe: Option<Box<dyn io::Read + Send>>| {
+                thread::spawn(move || -> io::Result<Vec<u8>> {
+                    let mut buf = Vec::new();
+
+                    if let Some(mut pipe) = pipe {
+                        pipe.read_to_end(&mut buf)?;
+                    }
+
+                    Ok(buf)
+                })
+            };
+
+            let stdout = read(child.stdout.take().map(|p| Box::new(p) as Box<_>));
+            let stderr = read(child.stderr.take().map(|p| Box::new(p) as Box<_>));
+            let status = self.wait(child)?;
+
+            // NB: the command is allowed to exit without reading all of its input.
+            if let Some(write) = write {
+                let _ = write.join();
+            }
+
+            let join = |handle: thread::JoinHandle<io::Result<Vec<u8>>>| {
+                handle
+                    .join()
+                    .map_err(|_| io::Error::other("Reading output panicked"))?
+            };
+
+            process::Output {
+                status,
+                stdout: join(stdout)?,
+                stderr: join(stderr)?,
+            }
         };
 
         Ok(output)
@@ -174,12 +431,115 @@ impl Command {
 
     /// Run the command and wait for exit status.
     pub fn status(self) -> io::Result<process::ExitStatus> {
-        Ok(self.command().status()?)
+        if let Some(fake) = crate::environment::simulation() {
+            return Ok(fake.run(&self).status);
+        }
+
+        self.trace(|| self.wait(self.command().spawn()?), |s| s.code())
     }
 
     /// Run as administrator.
     #[cfg(windows)]
     pub fn runas(self) -> io::Result<i32> {
-        Ok(crate::ffi::win::shellapi::runas(self)?)
+        if let Some(fake) = crate::environment::simulation() {
+            return Ok(fake.run(&self).status.code().unwrap_or(1));
+        }
+
+        self.trace(
+            || crate::ffi::win::shellapi::runas(self.clone()),
+            |code| Some(*code),
+        )
+    }
+
+    /// Run the given function which runs the command, and trace it if commands are traced.
+    fn trace<T>(
+        &self,
+        run: impl FnOnce() -> io::Result<T>,
+        status: impl FnOnce(&T) -> Option<i32>,
+    ) -> io::Result<T> {
+        if TRACED.lock().unwrap_or_else(|e| e.into_inner()).is_none() {
+            return run();
+        }
+
+        let start = Instant::now();
+        let result = run();
+
+        let mut env = ENV.with(|env| {
+            env.borrow()
+                .iter()
+                .map(|(k, _)| k.clone())
+                .collect::<Vec<_>>()
+        });
+
+        env.extend(
+            self.env
+                .iter()
+                .map(|(k, _)| k.to_string_lossy().into_owned()),
+        );
+
+        let traced = Traced {
+            program: self.name.clone(),
+            args: self
+                .args
+                .iter()
+                .map(|a| a.to_string_lossy().into_owned())
+                .collect(),
+            working_directory: self.working_directory.clone(),
+            env,
+            status: result.as_ref().ok().and_then(status),
+            duration: start.elapsed(),
+        };
+
+        log::info!("command: {}", traced);
+
+        if let Some(traced_commands) = TRACED.lock().unwrap_or_else(|e| e.into_inner()).as_mut() {
+            traced_commands.push(traced);
+        }
+
+        result
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::Command;
+    use std::io;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn test_timeout() {
+        let start = Instant::now();
+
+        let result = crate::deadline::with_timeout(Some(Duration::from_millis(100)), || {
+            let mut command = Command::new("sleep");
+            command.arg("10");
+            command.run()
+        });
+
+        assert_eq!(
+            result.err().map(|e| e.kind()),
+            Some(io::ErrorKind::TimedOut)
+        );
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn test_with_env() {
+        let env = vec![(String::from("QC_TEST_ENV"), String::from("hello"))];
+
+        let output = crate::command::with_env(&env, || {
+            let mut command = Command::new("sh");
+            command.args(&["-c", "echo $QC_TEST_ENV"]);
+            command.run_stdout()
+        });
+
+        assert_eq!(output.unwrap(), "hello\n");
+    }
+
+    #[test]
+    fn test_stdin() {
+        let mut command = Command::new("cat");
+        command.stdin("hello");
+        assert_eq!(command.run_stdout().unwrap(), "hello");
     }
 }
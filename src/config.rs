@@ -1,12 +1,42 @@
 //! Model for configuration file.
-use crate::{system::System, template::Template};
+use crate::{
+    environment as e,
+    facts::{self, Facts},
+    git,
+    system::System,
+    template::Template,
+};
+use anyhow::Error;
+use relative_path::RelativePathBuf;
 use serde::{Deserialize, Deserializer};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
 use std::time::Duration;
 
+/// Name of the configuration file in YAML.
+const YAML_FILE: &str = "quickcfg.yml";
+/// Name of the configuration file in TOML.
+const TOML_FILE: &str = "quickcfg.toml";
+
 /// Default git refresh in seconds.
 const DEFAULT_GIT_REFRESH_SECONDS: u64 = 3600 * 24 * 3;
 /// Refresh package state every hour, unless changed.
 const DEFAULT_PACKAGE_REFRESH_SECONDS: u64 = 3600;
+const DEFAULT_PACKAGE_LISTING_TTL_SECONDS: u64 = 300;
+
+/// Errors raised when loading the configuration.
+#[derive(Debug, thiserror::Error)]
+pub enum ConfigError {
+    /// Both a YAML and a TOML configuration file exist.
+    #[error("both {} and {} exist, remove one of them", .yaml.display(), .toml.display())]
+    Ambiguous { yaml: PathBuf, toml: PathBuf },
+    /// A var uses a variable which doesn't exist.
+    #[error("var `{key}` uses a missing variable: {template}")]
+    MissingVariable { key: String, template: String },
+    /// The selected profile doesn't exist.
+    #[error("profile `{name}` doesn't exist{}", expected_profiles(.profiles))]
+    MissingProfile { name: String, profiles: Vec<String> },
+}
 
 /// Configuration model.
 #[derive(Deserialize, Default, Debug, PartialEq, Eq)]
@@ -16,6 +46,10 @@ pub struct Config {
     #[serde(default = "default_git_refresh", deserialize_with = "human_duration")]
     pub git_refresh: Duration,
 
+    /// Maximum random delay added to refresh intervals, to spread out checks from many machines.
+    #[serde(default, deserialize_with = "human_duration")]
+    pub git_refresh_jitter: Duration,
+
     /// The interval at which we check for packages.
     #[serde(
         default = "default_package_refresh",
@@ -23,10 +57,587 @@ pub struct Config {
     )]
     pub package_refresh: Duration,
 
+    /// How long the packages listed as installed by a package manager are reused between runs.
+    #[serde(
+        default = "default_package_listing_ttl",
+        deserialize_with = "human_duration"
+    )]
+    pub package_listing_ttl: Duration,
+
+    /// How long each unit may run before it fails, unless the system specifies a timeout.
+    #[serde(default, deserialize_with = "human_duration_opt")]
+    pub unit_timeout: Option<Duration>,
+
+    /// Stop running units after the first one which fails.
+    #[serde(default)]
+    pub fail_fast: bool,
+
+    /// Which git backend to use.
+    #[serde(default)]
+    pub git_backend: git::Backend,
+
+    /// What to do with uncommitted changes in repositories when updating them.
+    #[serde(default)]
+    pub git_local_changes: git::LocalChanges,
+
+    /// Commit and push local changes in the configuration repository when checking for updates.
+    #[serde(default)]
+    pub git_auto_push: bool,
+
+    /// How to authenticate when cloning and updating repositories.
+    #[serde(default)]
+    pub git_auth: GitAuth,
+
+    /// Initialize and update submodules when the configuration repository is updated.
+    #[serde(default = "default_true")]
+    pub git_submodules: bool,
+
+    /// How much of repositories to fetch when cloning and updating them.
+    #[serde(default)]
+    pub git_fetch: git::Fetch,
+
+    /// Branch of the configuration repository to check out and track.
+    #[serde(default)]
+    pub git_branch: Option<String>,
+
+    /// Tag or commit of the configuration repository to pin to.
+    #[serde(default)]
+    pub git_ref: Option<String>,
+
+    /// Directory with executable scripts that provide additional facts.
+    #[serde(default)]
+    pub facts_dir: Option<RelativePathBuf>,
+
+    /// How long to cache the output of each fact script, by script name.
+    #[serde(default, deserialize_with = "human_durations")]
+    pub facts_ttl: BTreeMap<String, Duration>,
+
+    /// User-defined vars available to all templates, which can themselves use facts.
+    #[serde(default)]
+    pub vars: BTreeMap<String, Template>,
+
+    /// Where to store state.
+    #[serde(default)]
+    pub state_location: StateLocation,
+
+    /// What to do when symlinks can't be created, like on Windows without developer mode.
+    #[serde(default)]
+    pub symlink_fallback: SymlinkFallback,
+
+    /// When to show a desktop notification about the result of a run.
+    #[serde(default)]
+    pub notify: Notify,
+
+    /// Which actions to ask for confirmation before.
+    #[serde(default)]
+    pub prompts: Prompts,
+
+    /// Where to log to, in addition to stderr.
+    #[serde(default)]
+    pub log: Vec<LogSink>,
+
+    /// How to fetch things over HTTP.
+    #[serde(default)]
+    pub http: Http,
+
+    /// Where to update the quickcfg binary from.
+    #[serde(default)]
+    pub self_update: SelfUpdate,
+
+    /// Webhook to report the result of non-interactive runs to.
+    #[serde(default)]
+    pub report: Option<Report>,
+
+    /// Additional configuration repositories, whose hierarchies and systems are merged with this one.
+    #[serde(default)]
+    pub repos: Vec<Repo>,
+
     /// The hierarchy at which we load `Data` from.
     pub hierarchy: Vec<Template>,
     /// The systems to apply.
     pub systems: Vec<System>,
+
+    /// Systems which are added or overridden on specific hosts, by hostname.
+    #[serde(default)]
+    pub hosts: BTreeMap<String, Host>,
+
+    /// Named groups of systems and hierarchy, of which one can be selected for each machine.
+    #[serde(default)]
+    pub profiles: BTreeMap<String, Profile>,
+}
+
+/// A named group of systems and hierarchy, selected for a machine with `--profile`.
+#[derive(Deserialize, Default, Debug, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct Profile {
+    /// Hierarchy to load, which takes precedence over the hierarchy of the configuration.
+    #[serde(default)]
+    pub hierarchy: Vec<Template>,
+    /// Systems to apply, which replace the systems with the same `id`.
+    #[serde(default)]
+    pub systems: Vec<System>,
+}
+
+/// Systems which are added or overridden on a specific host.
+#[derive(Deserialize, Default, Debug, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct Host {
+    /// Systems to apply on the host, which replace the systems with the same `id`.
+    #[serde(default)]
+    pub systems: Vec<System>,
+}
+
+/// What to do when symlinks can't be created.
+#[derive(Deserialize, Default, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum SymlinkFallback {
+    /// Link directories with junctions, and fail for files.
+    #[default]
+    Junction,
+    /// Link directories with junctions, and copy files, which are updated when the file they link
+    /// to changes.
+    Copy,
+    /// Fail.
+    None,
+}
+
+/// Where state is stored.
+#[derive(Deserialize, Default, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum StateLocation {
+    /// In `.state.yml` and `.state` in the configuration root.
+    #[default]
+    Root,
+    /// In the state directory of the user, outside of the configuration root.
+    User,
+}
+
+/// When to show a desktop notification about the result of a run.
+#[derive(Deserialize, Default, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Notify {
+    /// Never show a notification.
+    #[default]
+    Never,
+    /// Only when the run failed.
+    Failure,
+    /// When the run failed, or changed something.
+    Always,
+}
+
+/// When to ask for confirmation before an action.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum PromptPolicy {
+    /// Always ask, and don't do it in non-interactive mode unless `--yes` is used.
+    Always,
+    /// Never ask, and always do it.
+    Never,
+    /// Ask when running interactively, and do it without asking otherwise.
+    InteractiveOnly,
+}
+
+/// An action which can be confirmed before it's taken.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Concern {
+    /// Checking for updates to the configuration.
+    Update,
+    /// Installing packages.
+    Install,
+    /// Overwriting a file which isn't managed by quickcfg.
+    Overwrite,
+}
+
+impl Concern {
+    /// The name of the concern, which is what answers are remembered by.
+    pub fn name(self) -> &'static str {
+        match self {
+            Concern::Update => "update",
+            Concern::Install => "install",
+            Concern::Overwrite => "overwrite",
+        }
+    }
+}
+
+/// Which actions to ask for confirmation before.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct Prompts {
+    /// Before checking for updates to the configuration.
+    #[serde(default = "default_prompt_update")]
+    pub update: PromptPolicy,
+    /// Before installing packages.
+    #[serde(default = "default_prompt_never")]
+    pub install: PromptPolicy,
+    /// Before overwriting a file which isn't managed by quickcfg.
+    #[serde(default = "default_prompt_never")]
+    pub overwrite: PromptPolicy,
+}
+
+impl Prompts {
+    /// The policy for the given concern.
+    pub fn policy(&self, concern: Concern) -> PromptPolicy {
+        match concern {
+            Concern::Update => self.update,
+            Concern::Install => self.install,
+            Concern::Overwrite => self.overwrite,
+        }
+    }
+}
+
+impl Default for Prompts {
+    fn default() -> Self {
+        Prompts {
+            update: default_prompt_update(),
+            install: default_prompt_never(),
+            overwrite: default_prompt_never(),
+        }
+    }
+}
+
+/// Checking for updates is confirmed when running interactively by default.
+fn default_prompt_update() -> PromptPolicy {
+    PromptPolicy::InteractiveOnly
+}
+
+fn default_prompt_never() -> PromptPolicy {
+    PromptPolicy::Never
+}
+
+/// Where to log to, in addition to stderr.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(tag = "type", rename_all = "kebab-case", deny_unknown_fields)]
+pub enum LogSink {
+    /// A log file in the state directory, which is rotated when it grows too large.
+    File {
+        /// Size in bytes at which the log file is rotated.
+        #[serde(default = "default_log_max_size")]
+        max_size: u64,
+        /// Number of rotated log files to keep.
+        #[serde(default = "default_log_keep")]
+        keep: usize,
+    },
+    /// The systemd journal, with the system and unit being applied as structured fields.
+    Journald,
+    /// The local syslog daemon.
+    Syslog,
+}
+
+/// How to fetch things over HTTP.
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct Http {
+    /// Proxy to use, instead of the `HTTPS_PROXY` and `HTTP_PROXY` environment variables.
+    #[serde(default)]
+    pub proxy: Option<String>,
+    /// Additional CA certificate to trust, in PEM format.
+    #[serde(default)]
+    pub ca_certificate: Option<PathBuf>,
+    /// How long connecting may take.
+    #[serde(
+        default = "default_connect_timeout",
+        deserialize_with = "human_duration"
+    )]
+    pub connect_timeout: Duration,
+    /// How long each request may take, unless the unit making it has a shorter timeout.
+    #[serde(default, deserialize_with = "human_duration_opt")]
+    pub timeout: Option<Duration>,
+    /// How many times to retry requests which fail in a way which might be temporary.
+    #[serde(default = "default_http_retries")]
+    pub retries: u32,
+    /// How many bytes of downloads to keep cached.
+    #[serde(default = "default_cache_size")]
+    pub cache_size: u64,
+}
+
+impl Default for Http {
+    fn default() -> Self {
+        Http {
+            proxy: None,
+            ca_certificate: None,
+            connect_timeout: default_connect_timeout(),
+            timeout: None,
+            retries: default_http_retries(),
+            cache_size: default_cache_size(),
+        }
+    }
+}
+
+/// Default timeout when connecting.
+fn default_connect_timeout() -> Duration {
+    Duration::from_secs(30)
+}
+
+/// Default size of the download cache.
+fn default_cache_size() -> u64 {
+    256 * 1024 * 1024
+}
+
+/// Default number of retries for requests.
+fn default_http_retries() -> u32 {
+    2
+}
+
+/// Where to update the quickcfg binary from.
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct SelfUpdate {
+    /// GitHub repository to take releases from.
+    #[serde(default = "default_self_update_repo")]
+    pub repo: String,
+    /// The base64-encoded minisign public key which releases are signed with.
+    #[serde(default)]
+    pub public_key: Option<String>,
+}
+
+impl Default for SelfUpdate {
+    fn default() -> Self {
+        SelfUpdate {
+            repo: default_self_update_repo(),
+            public_key: None,
+        }
+    }
+}
+
+/// Default repository to take releases from.
+pub(crate) fn default_self_update_repo() -> String {
+    String::from("udoprog/quickcfg")
+}
+
+/// A webhook to report the result of runs to.
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct Report {
+    /// Url to POST the report to.
+    pub url: String,
+    /// How long each attempt to send the report may take.
+    #[serde(
+        default = "default_report_timeout",
+        deserialize_with = "human_duration"
+    )]
+    pub timeout: Duration,
+    /// How many times to retry sending the report if it fails.
+    #[serde(default = "default_report_retries")]
+    pub retries: u32,
+}
+
+/// Default timeout when sending reports.
+fn default_report_timeout() -> Duration {
+    Duration::from_secs(10)
+}
+
+/// Default number of retries when sending reports.
+fn default_report_retries() -> u32 {
+    3
+}
+
+/// Authentication options for git.
+#[derive(Deserialize, Default, Debug, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct GitAuth {
+    /// SSH private key to use.
+    #[serde(default)]
+    pub ssh_key: Option<Template>,
+    /// Credential helper to use for HTTPS remotes, like `store` or `manager`.
+    #[serde(default)]
+    pub credential_helper: Option<String>,
+    /// Hierarchy key which contains a token to use for HTTPS remotes.
+    #[serde(default)]
+    pub token_key: Option<String>,
+    /// Username to use together with the token.
+    #[serde(default = "default_token_user")]
+    pub token_user: String,
+}
+
+/// Default username when authenticating with a token, which is what GitHub expects.
+fn default_token_user() -> String {
+    String::from("x-access-token")
+}
+
+/// An additional configuration repository.
+#[derive(Deserialize, Debug, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct Repo {
+    /// Name of the repository, which is also the directory it's cloned into.
+    pub name: String,
+    /// Remote url to clone the repository from.
+    pub url: String,
+    /// Branch of the repository to check out and track.
+    #[serde(default)]
+    pub branch: Option<String>,
+    /// The interval at which we check for updates, instead of `git_refresh`.
+    #[serde(default, deserialize_with = "human_duration_opt")]
+    pub refresh: Option<Duration>,
+    /// Maximum random delay added to the refresh interval, instead of `git_refresh_jitter`.
+    #[serde(default, deserialize_with = "human_duration_opt")]
+    pub refresh_jitter: Option<Duration>,
+}
+
+impl Repo {
+    /// The revision of the repository to track.
+    pub fn revision(&self) -> git::Revision<'_> {
+        match self.branch.as_deref() {
+            Some(branch) => git::Revision::Branch(branch),
+            None => git::Revision::Head,
+        }
+    }
+
+    /// The interval at which to check this repository for updates, including jitter.
+    pub fn refresh_interval(&self, config: &Config) -> Duration {
+        jitter(
+            self.refresh.unwrap_or(config.git_refresh),
+            self.refresh_jitter.unwrap_or(config.git_refresh_jitter),
+        )
+    }
+}
+
+/// List the profiles which could have been meant, if there are any.
+fn expected_profiles(profiles: &[String]) -> String {
+    if profiles.is_empty() {
+        return String::from(", since no profiles are defined");
+    }
+
+    format!(", expected one of: {}", profiles.join(", "))
+}
+
+impl Config {
+    /// Find the configuration file in the given directory.
+    ///
+    /// This is `quickcfg.yml`, or `quickcfg.toml` if only that exists. It is an error for both to
+    /// exist, since it would be ambiguous which one to use.
+    pub fn path(root: &Path) -> Result<PathBuf, Error> {
+        let yaml = root.join(YAML_FILE);
+        let toml = root.join(TOML_FILE);
+
+        match (yaml.is_file(), toml.is_file()) {
+            (true, true) => Err(ConfigError::Ambiguous { yaml, toml }.into()),
+            (false, true) => Ok(toml),
+            _ => Ok(yaml),
+        }
+    }
+
+    /// Render all user-defined vars using the given facts and environment.
+    pub fn render_vars(
+        &self,
+        facts: &Facts,
+        environment: impl e::Environment,
+    ) -> Result<BTreeMap<String, String>, Error> {
+        let mut out = BTreeMap::new();
+
+        for (key, template) in &self.vars {
+            let value = template.as_string(facts, environment)?.ok_or_else(|| {
+                ConfigError::MissingVariable {
+                    key: key.to_string(),
+                    template: template.to_string(),
+                }
+            })?;
+
+            out.insert(key.to_string(), value);
+        }
+
+        Ok(out)
+    }
+
+    /// The interval at which to check the configuration for updates, including jitter.
+    pub fn refresh_interval(&self) -> Duration {
+        jitter(self.git_refresh, self.git_refresh_jitter)
+    }
+
+    /// Check that the profile with the given name exists.
+    pub fn check_profile(&self, name: &str) -> Result<(), ConfigError> {
+        if self.profiles.contains_key(name) {
+            return Ok(());
+        }
+
+        Err(ConfigError::MissingProfile {
+            name: name.to_string(),
+            profiles: self.profiles.keys().cloned().collect(),
+        })
+    }
+
+    /// The hierarchy to load with the given profile, where the hierarchy of the profile comes
+    /// first so that it takes precedence.
+    pub fn hierarchy(&self, profile: Option<&str>) -> Vec<&Template> {
+        let profile = profile.and_then(|name| self.profiles.get(name));
+
+        profile
+            .into_iter()
+            .flat_map(|profile| &profile.hierarchy)
+            .chain(&self.hierarchy)
+            .collect()
+    }
+
+    /// The systems to apply with the given profile, on the host described by the given facts.
+    ///
+    /// Hosts are matched by either their full or short hostname. Systems of the profile and of a
+    /// matching host replace the systems with the same `id`, and are otherwise added after them.
+    pub fn systems(&self, facts: &Facts, profile: Option<&str>) -> Vec<&System> {
+        let mut systems = self.systems.iter().collect::<Vec<_>>();
+
+        // NB: profiles which don't exist are ignored, since additional repositories might not
+        // define every profile.
+        if let Some(profile) = profile.and_then(|name| self.profiles.get(name)) {
+            merge_systems(&mut systems, &profile.systems);
+        }
+
+        let hostnames = [facts.get(facts::HOSTNAME), facts.get(facts::SHORT_HOSTNAME)];
+
+        // NB: the full hostname might be the same as the short one.
+        let hosts = self
+            .hosts
+            .iter()
+            .filter(|(name, _)| hostnames.contains(&Some(name.as_str())));
+
+        for (_, host) in hosts {
+            merge_systems(&mut systems, &host.systems);
+        }
+
+        systems
+    }
+}
+
+/// Merge systems into the given systems, replacing the ones with the same `id`.
+fn merge_systems<'a>(systems: &mut Vec<&'a System>, overrides: &'a [System]) {
+    for system in overrides {
+        let existing = system
+            .id()
+            .and_then(|id| systems.iter().position(|s| s.id() == Some(id)));
+
+        match existing {
+            Some(index) => systems[index] = system,
+            None => systems.push(system),
+        }
+    }
+}
+
+/// Add a random delay of up to `jitter` to the given interval.
+pub(crate) fn jitter(interval: Duration, jitter: Duration) -> Duration {
+    use std::collections::hash_map::RandomState;
+    use std::hash::{BuildHasher as _, Hasher as _};
+
+    if jitter == Duration::default() {
+        return interval;
+    }
+
+    // NB: the keys of `RandomState` are randomly seeded, which is good enough for spreading out
+    // requests.
+    let random = RandomState::new().build_hasher().finish();
+    interval + jitter.mul_f64(random as f64 / u64::MAX as f64)
+}
+
+/// Helper for fields which default to `true`.
+fn default_true() -> bool {
+    true
+}
+
+/// Default size at which log files are rotated.
+fn default_log_max_size() -> u64 {
+    crate::logging::DEFAULT_MAX_SIZE
+}
+
+/// Default number of rotated log files to keep.
+fn default_log_keep() -> usize {
+    crate::logging::DEFAULT_KEEP
 }
 
 /// Return default git refresh in seconds.
@@ -39,6 +650,11 @@ fn default_package_refresh() -> Duration {
     Duration::from_secs(DEFAULT_PACKAGE_REFRESH_SECONDS)
 }
 
+/// Return default package listing ttl in seconds.
+fn default_package_listing_ttl() -> Duration {
+    Duration::from_secs(DEFAULT_PACKAGE_LISTING_TTL_SECONDS)
+}
+
 /// Parse a human duration.
 pub fn human_duration<'de, D>(deserializer: D) -> Result<Duration, D::Error>
 where
@@ -47,3 +663,203 @@ where
     let string = String::deserialize(deserializer)?;
     humantime::parse_duration(&string).map_err(serde::de::Error::custom)
 }
+
+/// Parse an optional human duration.
+pub fn human_duration_opt<'de, D>(deserializer: D) -> Result<Option<Duration>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    human_duration(deserializer).map(Some)
+}
+
+/// Parse a map of human durations.
+pub fn human_durations<'de, D>(deserializer: D) -> Result<BTreeMap<String, Duration>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let strings = BTreeMap::<String, String>::deserialize(deserializer)?;
+    let mut out = BTreeMap::new();
+
+    for (key, string) in strings {
+        let duration = humantime::parse_duration(&string).map_err(serde::de::Error::custom)?;
+        out.insert(key, duration);
+    }
+
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{jitter, Config, LogSink};
+    use crate::facts::{self, Facts};
+    use std::time::Duration;
+
+    #[test]
+    fn test_toml() {
+        let yaml: Config = serde_yaml::from_str(
+            "git_refresh: 1d\nhierarchy:\n  - common.yml\nsystems:\n  - type: copy-dir\n    from: home\n    to: home://\n",
+        )
+        .unwrap();
+
+        let toml: Config = toml::from_str(
+            "git_refresh = \"1d\"\nhierarchy = [\"common.yml\"]\n\n[[systems]]\ntype = \"copy-dir\"\nfrom = \"home\"\nto = \"home://\"\n",
+        )
+        .unwrap();
+
+        assert_eq!(yaml, toml);
+    }
+
+    #[test]
+    fn test_path() {
+        let root = std::env::temp_dir().join(format!("quickcfg-config-{}", std::process::id()));
+        std::fs::create_dir_all(&root).unwrap();
+
+        assert_eq!(Config::path(&root).unwrap(), root.join("quickcfg.yml"));
+        std::fs::write(root.join("quickcfg.toml"), "").unwrap();
+        assert_eq!(Config::path(&root).unwrap(), root.join("quickcfg.toml"));
+        std::fs::write(root.join("quickcfg.yml"), "").unwrap();
+        assert!(Config::path(&root).is_err());
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_log_sinks() {
+        let config: Config = serde_yaml::from_str(
+            "log:\n  - type: file\n    keep: 2\n  - type: journald\nhierarchy: []\nsystems: []\n",
+        )
+        .unwrap();
+
+        assert_eq!(
+            config.log,
+            vec![
+                LogSink::File {
+                    max_size: crate::logging::DEFAULT_MAX_SIZE,
+                    keep: 2,
+                },
+                LogSink::Journald,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_jitter() {
+        let interval = Duration::from_secs(3600);
+        let max = Duration::from_secs(600);
+
+        assert_eq!(jitter(interval, Duration::default()), interval);
+
+        for _ in 0..100 {
+            let value = jitter(interval, max);
+            assert!(value >= interval && value <= interval + max);
+        }
+    }
+
+    #[test]
+    fn test_host_systems() {
+        let config: Config = serde_yaml::from_str(
+            r#"
+hierarchy: []
+systems:
+  - type: link
+    id: vimrc
+    path: home://.vimrc
+    link: vimrc
+  - type: link
+    path: home://.bashrc
+    link: bashrc
+hosts:
+  work-laptop:
+    systems:
+      - type: link
+        id: vimrc
+        path: home://.vimrc
+        link: vimrc-work
+      - type: link
+        path: home://.ssh/config
+        link: ssh-work
+"#,
+        )
+        .unwrap();
+
+        let links = |hostname: &str| {
+            let facts = Facts::new(vec![
+                (facts::HOSTNAME.to_string(), hostname.to_string()),
+                (
+                    facts::SHORT_HOSTNAME.to_string(),
+                    hostname.split('.').next().unwrap().to_string(),
+                ),
+            ]);
+
+            config
+                .systems(&facts, None)
+                .into_iter()
+                .map(|system| match system {
+                    crate::system::System::Link(link) => link.link.to_string(),
+                    _ => unreachable!(),
+                })
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(links("home-desktop"), vec!["vimrc", "bashrc"]);
+        assert_eq!(
+            links("work-laptop.example.com"),
+            vec!["vimrc-work", "bashrc", "ssh-work"]
+        );
+    }
+
+    #[test]
+    fn test_profiles() {
+        let config: Config = serde_yaml::from_str(
+            r#"
+hierarchy: [common.yml]
+systems:
+  - type: link
+    id: gitconfig
+    path: home://.gitconfig
+    link: gitconfig
+profiles:
+  work:
+    hierarchy: [work.yml]
+    systems:
+      - type: link
+        id: gitconfig
+        path: home://.gitconfig
+        link: gitconfig-work
+"#,
+        )
+        .unwrap();
+
+        let facts = Facts::new(Vec::new());
+
+        let hierarchy = |profile| {
+            config
+                .hierarchy(profile)
+                .into_iter()
+                .map(|template| template.to_string())
+                .collect::<Vec<_>>()
+        };
+
+        let links = |profile| {
+            config
+                .systems(&facts, profile)
+                .into_iter()
+                .map(|system| match system {
+                    crate::system::System::Link(link) => link.link.to_string(),
+                    _ => unreachable!(),
+                })
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(hierarchy(None), vec!["common.yml"]);
+        assert_eq!(hierarchy(Some("work")), vec!["work.yml", "common.yml"]);
+        assert_eq!(links(None), vec!["gitconfig"]);
+        assert_eq!(links(Some("work")), vec!["gitconfig-work"]);
+
+        assert!(config.check_profile("work").is_ok());
+        assert_eq!(
+            config.check_profile("server").unwrap_err().to_string(),
+            "profile `server` doesn't exist, expected one of: work"
+        );
+    }
+}
@@ -0,0 +1,56 @@
+//! Deadline for the unit running on the current thread.
+//!
+//! Units call into package managers and other helpers which don't know which unit they are
+//! running for, so the deadline is tracked per thread and checked by anything which can block,
+//! like external commands and downloads.
+
+use std::cell::Cell;
+use std::time::{Duration, Instant};
+
+thread_local! {
+    static DEADLINE: Cell<Option<Instant>> = const { Cell::new(None) };
+}
+
+/// Run the given function with a deadline `timeout` from now, if one is specified.
+pub(crate) fn with_timeout<T>(timeout: Option<Duration>, f: impl FnOnce() -> T) -> T {
+    let deadline = timeout.map(|timeout| Instant::now() + timeout);
+    let previous = DEADLINE.with(|d| d.replace(deadline));
+    let output = f();
+    DEADLINE.with(|d| d.set(previous));
+    output
+}
+
+/// Time remaining until the deadline of the current thread, if there is one.
+///
+/// Returns a zero duration if the deadline has passed.
+pub(crate) fn remaining() -> Option<Duration> {
+    let deadline = DEADLINE.with(|d| d.get())?;
+    Some(deadline.saturating_duration_since(Instant::now()))
+}
+
+/// Test if the deadline of the current thread has passed.
+pub(crate) fn has_passed() -> bool {
+    remaining() == Some(Duration::default())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{has_passed, remaining, with_timeout};
+    use std::time::Duration;
+
+    #[test]
+    fn test_deadline() {
+        assert_eq!(remaining(), None);
+
+        with_timeout(Some(Duration::from_secs(3600)), || {
+            assert!(remaining().unwrap() > Duration::from_secs(3500));
+            assert!(!has_passed());
+
+            with_timeout(Some(Duration::default()), || assert!(has_passed()));
+
+            assert!(!has_passed());
+        });
+
+        assert_eq!(remaining(), None);
+    }
+}
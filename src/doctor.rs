@@ -0,0 +1,264 @@
+//! Diagnostics for common problems with the environment that quickcfg runs in, each with a
+//! suggested fix.
+
+use crate::{
+    command::Command,
+    environment as e,
+    facts::{self, Facts},
+    git, hierarchy, lock, os, packages,
+    state_dir::StateFile,
+    Config, DiskState, Timestamp,
+};
+use std::fmt;
+use std::path::Path;
+use std::time::Duration;
+
+/// How far in the future a recorded update may be before it's considered clock skew, since clocks
+/// are adjusted by small amounts all the time.
+const CLOCK_SKEW_TOLERANCE: Duration = Duration::from_secs(60);
+
+/// A problem found by a check.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Problem {
+    /// What the problem is.
+    pub message: String,
+    /// How to fix it.
+    pub fix: String,
+}
+
+impl Problem {
+    fn new(message: impl Into<String>, fix: impl Into<String>) -> Self {
+        Problem {
+            message: message.into(),
+            fix: fix.into(),
+        }
+    }
+}
+
+/// The result of one check.
+#[derive(Debug)]
+pub struct Finding {
+    /// What was checked.
+    pub check: &'static str,
+    /// Problems found by the check.
+    pub problems: Vec<Problem>,
+}
+
+impl fmt::Display for Finding {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        if self.problems.is_empty() {
+            return write!(fmt, "ok: {}", self.check);
+        }
+
+        for (i, problem) in self.problems.iter().enumerate() {
+            if i > 0 {
+                writeln!(fmt)?;
+            }
+
+            writeln!(fmt, "problem: {}: {}", self.check, problem.message)?;
+            write!(fmt, "  fix: {}", problem.fix)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Run every check.
+pub fn run(
+    config: &Config,
+    root: &Path,
+    state_path: &Path,
+    state_dir: &Path,
+    facts: &Facts,
+    now: Timestamp,
+) -> Vec<Finding> {
+    let mut findings = vec![
+        finding("git", check_git(config)),
+        finding("package manager", check_package_manager(facts)),
+    ];
+
+    if cfg!(windows) {
+        findings.push(finding("symlinks", check_symlinks(state_dir)));
+    }
+
+    findings.push(finding("hierarchy", check_hierarchy(config, root, facts)));
+    findings.push(finding("lock", check_lock(state_dir)));
+    findings.push(finding("clock", check_clock(state_path, now)));
+    findings
+}
+
+fn finding(check: &'static str, problems: Vec<Problem>) -> Finding {
+    Finding { check, problems }
+}
+
+/// Check that the `git` command is available.
+fn check_git(config: &Config) -> Vec<Problem> {
+    let mut git = Command::new(os::command("git"));
+    git.arg("--version");
+
+    if matches!(git.run(), Ok(output) if output.status.success()) {
+        return Vec::new();
+    }
+
+    let message = match config.git_backend {
+        git::Backend::Cli => "`git` is not installed, but `git_backend` is `cli`",
+        _ if cfg!(feature = "git2") => {
+            "`git` is not installed, so the built-in backend is used which doesn't support ssh config or credential helpers"
+        }
+        _ => "`git` is not installed, and there is no built-in backend",
+    };
+
+    vec![Problem::new(
+        message,
+        "Install git with the package manager of the system",
+    )]
+}
+
+/// Check that a package manager was detected for the default `install` system.
+fn check_package_manager(facts: &Facts) -> Vec<Problem> {
+    let what = facts
+        .get(facts::DISTRO_FAMILY)
+        .or_else(|| facts.get(facts::DISTRO))
+        .or_else(|| facts.get(facts::OS))
+        .unwrap_or("unknown");
+
+    match packages::detect(facts) {
+        Ok(provider) if provider.default().is_some() => Vec::new(),
+        Ok(..) => vec![Problem::new(
+            format!("No package manager was detected for `{}`", what),
+            "Set `provider` on `install` systems, or make sure the package manager is in PATH",
+        )],
+        Err(e) => vec![Problem::new(
+            format!("Failed to detect package manager: {}", e),
+            "Make sure the package manager is installed and runs",
+        )],
+    }
+}
+
+/// Check that symlinks can be created, which on Windows requires a privilege.
+fn check_symlinks(state_dir: &Path) -> Vec<Problem> {
+    let target = state_dir.join("doctor-target");
+    let link = state_dir.join("doctor-link");
+
+    let result = std::fs::write(&target, "").and_then(|()| symlink_file(&target, &link));
+    let _ = std::fs::remove_file(&link);
+    let _ = std::fs::remove_file(&target);
+
+    match result {
+        Ok(()) => Vec::new(),
+        Err(e) => vec![Problem::new(
+            format!("Can't create symlinks: {}", e),
+            "Enable Developer Mode in Settings, or run quickcfg as administrator",
+        )],
+    }
+}
+
+#[cfg(windows)]
+fn symlink_file(target: &Path, link: &Path) -> std::io::Result<()> {
+    std::os::windows::fs::symlink_file(target, link)
+}
+
+#[cfg(not(windows))]
+fn symlink_file(target: &Path, link: &Path) -> std::io::Result<()> {
+    std::os::unix::fs::symlink(target, link)
+}
+
+/// Check that every hierarchy file which exists can be read.
+fn check_hierarchy(config: &Config, root: &Path, facts: &Facts) -> Vec<Problem> {
+    let mut problems = Vec::new();
+
+    for template in &config.hierarchy {
+        if let Err(e) = hierarchy::load(std::iter::once(template), root, facts, e::Real) {
+            problems.push(Problem::new(
+                format!("Can't load `{}`: {:#}", template, e),
+                "Fix the permissions or syntax of the file, or remove it from `hierarchy`",
+            ));
+        }
+    }
+
+    problems
+}
+
+/// Check for a lock left behind by an instance which is no longer running.
+fn check_lock(state_dir: &Path) -> Vec<Problem> {
+    let path = StateFile::Lock.path(state_dir);
+
+    match lock::is_stale(&path) {
+        Ok(false) => Vec::new(),
+        Ok(true) => vec![Problem::new(
+            format!("Stale lock: {}", path.display()),
+            "Nothing, it's taken over the next time quickcfg runs",
+        )],
+        Err(e) => vec![Problem::new(
+            format!("Can't read lock: {}", e),
+            format!("Fix the permissions of {}", path.display()),
+        )],
+    }
+}
+
+/// Check that no update was recorded in the future, which keeps `git_refresh` from updating until
+/// the clock has caught up.
+fn check_clock(state_path: &Path, now: Timestamp) -> Vec<Problem> {
+    let state = match DiskState::open(state_path) {
+        Ok(state) => state.unwrap_or_default(),
+        Err(e) => {
+            return vec![Problem::new(
+                format!("Invalid disk state `{}`: {}", state_path.display(), e),
+                "Remove it, to start over with no state",
+            )]
+        }
+    };
+
+    let mut problems = Vec::new();
+
+    for (name, last_update) in &state.last_update {
+        if last_update.duration_since(now).unwrap_or_default() <= CLOCK_SKEW_TOLERANCE {
+            continue;
+        }
+
+        problems.push(Problem::new(
+            format!(
+                "`{}` was last updated at {}, which is in the future, so it isn't refreshed until then",
+                name, last_update
+            ),
+            "Fix the system clock, and run with --force to update now",
+        ));
+    }
+
+    problems
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{check_clock, Finding, Problem};
+    use crate::{DiskState, Save, Timestamp};
+    use std::time::Duration;
+
+    #[test]
+    fn test_check_clock() -> anyhow::Result<()> {
+        let path = std::env::temp_dir().join(format!("quickcfg-doctor-{}.yml", std::process::id()));
+
+        let now = Timestamp::now();
+        let mut state = DiskState::default();
+        state.last_update.insert(String::from("git"), now);
+        state.save(&path)?;
+        assert!(check_clock(&path, now - Duration::from_secs(10)).is_empty());
+
+        assert_eq!(1, check_clock(&path, now - Duration::from_secs(3600)).len());
+        std::fs::remove_file(&path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_display() {
+        let finding = Finding {
+            check: "git",
+            problems: vec![Problem::new("missing", "install it")],
+        };
+
+        assert_eq!(
+            "problem: git: missing\n  fix: install it",
+            finding.to_string()
+        );
+    }
+}
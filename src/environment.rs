@@ -1,11 +1,23 @@
 //! Helpers for accessing environment variables.
+use crate::command::{Command, Output};
 use anyhow::{bail, Error};
+use serde::Deserialize;
 use std::collections::HashMap;
 use std::env;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, OnceLock};
+
+/// The fake environment which commands are run against, if we are simulating.
+static SIMULATION: OnceLock<Fake> = OnceLock::new();
 
 pub trait Environment: Copy {
     /// Access the given environment variables.
     fn var(&self, key: &str) -> Result<Option<String>, Error>;
+
+    /// The home directory to use instead of the one of the current user, if any.
+    fn home_dir(&self) -> Option<&Path> {
+        None
+    }
 }
 
 /// The real environment.
@@ -30,3 +42,208 @@ impl<S: std::hash::BuildHasher> Environment for &HashMap<String, String, S> {
         Ok(self.get(key).map(|s| s.to_string()))
     }
 }
+
+/// The scripted result of a command in a [Fake] environment.
+#[derive(Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct Script {
+    /// The command line to match, which matches every command that it is a prefix of, like
+    /// `apt list` matching `apt list --installed`.
+    pub command: String,
+    /// The exit status of the command.
+    #[serde(default)]
+    pub status: i32,
+    /// What the command prints to stdout.
+    #[serde(default)]
+    pub stdout: String,
+    /// What the command prints to stderr.
+    #[serde(default)]
+    pub stderr: String,
+}
+
+/// A fake environment, which applies a configuration without touching the host.
+///
+/// Everything that would be deployed to the home directory is deployed to the `home` directory of
+/// an overlay instead, and commands aren't run. Commands exit successfully without any output,
+/// unless they match a [Script], and every command is recorded so it can be inspected afterwards.
+#[derive(Debug)]
+pub struct Fake {
+    home: PathBuf,
+    vars: HashMap<String, String>,
+    scripts: Vec<Script>,
+    ran: Mutex<Vec<String>>,
+}
+
+impl Fake {
+    /// Construct a fake environment with the given overlay directory.
+    pub fn new(overlay: &Path) -> Self {
+        let home = overlay.join("home");
+
+        let mut vars = HashMap::new();
+        vars.insert(String::from("HOME"), home.display().to_string());
+
+        Fake {
+            home,
+            vars,
+            scripts: Vec::new(),
+            ran: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Set an environment variable, which otherwise comes from the real environment.
+    pub fn var(&mut self, key: &str, value: &str) {
+        self.vars.insert(key.to_string(), value.to_string());
+    }
+
+    /// Add a scripted result for commands.
+    ///
+    /// Scripts added later take precedence over earlier ones.
+    pub fn script(&mut self, script: Script) {
+        self.scripts.push(script);
+    }
+
+    /// The home directory in the overlay.
+    pub fn home(&self) -> &Path {
+        &self.home
+    }
+
+    /// The command lines of every command which has been run, in order.
+    pub fn ran(&self) -> Vec<String> {
+        self.ran.lock().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+
+    /// Run the given command, returning its scripted output.
+    pub(crate) fn run(&self, command: &Command) -> Output {
+        let line = command.to_string();
+        log::info!("Simulating: {}", line);
+
+        let script = self
+            .scripts
+            .iter()
+            .rev()
+            .find(|s| is_prefix(&s.command, &line));
+
+        self.ran
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .push(line);
+
+        match script {
+            Some(script) => Output {
+                status: exit_status(script.status),
+                stdout: script.stdout.clone(),
+                stderr: script.stderr.clone(),
+            },
+            None => Output {
+                status: exit_status(0),
+                stdout: String::new(),
+                stderr: String::new(),
+            },
+        }
+    }
+}
+
+impl Environment for &Fake {
+    fn var(&self, key: &str) -> Result<Option<String>, Error> {
+        match self.vars.get(key) {
+            Some(value) => Ok(Some(value.clone())),
+            None => Real.var(key),
+        }
+    }
+
+    fn home_dir(&self) -> Option<&Path> {
+        Some(&self.home)
+    }
+}
+
+/// Simulate every command run by this process in the given fake environment.
+///
+/// This can only be done once, and fails if we are already simulating.
+pub fn simulate(fake: Fake) -> Result<&'static Fake, Error> {
+    if SIMULATION.set(fake).is_err() {
+        bail!("Already simulating");
+    }
+
+    Ok(simulation().expect("simulation was just set"))
+}
+
+/// The fake environment that we are simulating in, if any.
+pub fn simulation() -> Option<&'static Fake> {
+    SIMULATION.get()
+}
+
+/// Test if the command line `prefix` is a prefix of `line`, by whole words.
+fn is_prefix(prefix: &str, line: &str) -> bool {
+    let mut line = line.split_whitespace();
+    prefix
+        .split_whitespace()
+        .all(|word| line.next() == Some(word))
+}
+
+#[cfg(unix)]
+fn exit_status(code: i32) -> std::process::ExitStatus {
+    use std::os::unix::process::ExitStatusExt as _;
+    std::process::ExitStatus::from_raw((code & 0xff) << 8)
+}
+
+#[cfg(windows)]
+fn exit_status(code: i32) -> std::process::ExitStatus {
+    use std::os::windows::process::ExitStatusExt as _;
+    std::process::ExitStatus::from_raw(code as u32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Environment as _, Fake, Script};
+    use crate::command::Command;
+    use std::path::Path;
+
+    #[test]
+    fn test_fake() {
+        let mut fake = Fake::new(Path::new("/tmp/overlay"));
+        fake.var("EDITOR", "vim");
+
+        fake.script(Script {
+            command: String::from("apt list"),
+            stdout: String::from("vim/stable,now 2:9.0 amd64 [installed]\n"),
+            ..Script::default()
+        });
+
+        fake.script(Script {
+            command: String::from("apt list --upgradable"),
+            status: 1,
+            ..Script::default()
+        });
+
+        let environment = &fake;
+        assert_eq!(environment.var("EDITOR").unwrap().as_deref(), Some("vim"));
+        assert_eq!(
+            environment.home_dir(),
+            Some(Path::new("/tmp/overlay").join("home").as_path())
+        );
+
+        let mut command = Command::new("apt");
+        command.args(&["list", "--installed"]);
+        let output = fake.run(&command);
+        assert!(output.status.success());
+        assert!(output.stdout.starts_with("vim/stable"));
+
+        let mut command = Command::new("apt");
+        command.args(&["list", "--upgradable"]);
+        let output = fake.run(&command);
+        assert_eq!(output.status.code(), Some(1));
+
+        let mut command = Command::new("aptitude");
+        command.arg("list");
+        assert!(fake.run(&command).stdout.is_empty());
+
+        assert_eq!(
+            fake.ran(),
+            vec![
+                "apt list --installed",
+                "apt list --upgradable",
+                "aptitude list"
+            ]
+        );
+    }
+}
@@ -1,20 +1,104 @@
 //! Loading facts about the system that we are currently running on.
 
+use crate::command::Command;
 use crate::template::Vars;
-use anyhow::{bail, Error};
+use crate::Timestamp;
+use anyhow::{anyhow, bail, Context as _, Error};
+use handlebars::JsonValue;
+use serde::{Deserialize, Serialize};
 use std::borrow::Borrow;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::fs;
 use std::hash::Hash;
 use std::io;
 use std::path::Path;
+use std::time::Duration;
 
 /// The `distro` fact key.
 pub const DISTRO: &str = "distro";
 
+/// The `distro_id` fact key, as reported by `ID` in `os-release`.
+pub const DISTRO_ID: &str = "distro_id";
+
+/// The `distro_version` fact key, as reported by `VERSION_ID` in `os-release`.
+pub const DISTRO_VERSION: &str = "distro_version";
+
+/// The `distro_family` fact key, like `debian` for Ubuntu or `rhel` for Fedora.
+pub const DISTRO_FAMILY: &str = "distro_family";
+
 /// The `os` fact key.
 pub const OS: &str = "os";
 
+/// The `arch` fact key.
+pub const ARCH: &str = "arch";
+
+/// The `kernel_version` fact key.
+pub const KERNEL_VERSION: &str = "kernel_version";
+
+/// The `hostname` fact key.
+pub const HOSTNAME: &str = "hostname";
+
+/// The `short_hostname` fact key, which is the hostname up until the first dot.
+pub const SHORT_HOSTNAME: &str = "short_hostname";
+
+/// The `wsl` fact key, which is `1` or `2` when running under the Windows Subsystem for Linux.
+pub const WSL: &str = "wsl";
+
+/// The `container` fact key, like `docker` or `podman` when running inside a container.
+pub const CONTAINER: &str = "container";
+
+/// The `ci` fact key, like `github-actions` when running in a CI environment.
+pub const CI: &str = "ci";
+
+/// The `desktop` fact key, like `gnome`, `kde`, or `sway`. Is `none` without a desktop.
+pub const DESKTOP: &str = "desktop";
+
+/// The `display_server` fact key, one of `x11`, `wayland`, `native`, or `headless`.
+pub const DISPLAY_SERVER: &str = "display_server";
+
+/// The `selinux` fact key on Linux, which is `enforcing` or `permissive` if SELinux is enabled.
+pub const SELINUX: &str = "selinux";
+
+/// The `os_version` fact key, like `14.5` on macOS or `10.0.22631.4037` on Windows.
+pub const OS_VERSION: &str = "os_version";
+
+/// The `os_build` fact key, like `23F79` on macOS or `22631` on Windows.
+pub const OS_BUILD: &str = "os_build";
+
+/// The `chip` fact key on macOS, which is `apple` or `intel`.
+pub const CHIP: &str = "chip";
+
+/// The `homebrew_prefix` fact key on macOS, like `/opt/homebrew`.
+pub const HOMEBREW_PREFIX: &str = "homebrew_prefix";
+
+/// The `developer_mode` fact key on Windows, which is `true` if developer mode is enabled.
+pub const DEVELOPER_MODE: &str = "developer_mode";
+
+/// The `symlinks` fact key on Windows, which is `true` if we can create symlinks.
+pub const SYMLINKS: &str = "symlinks";
+
+/// Facts cached from a single fact script.
+#[derive(Deserialize, Serialize, Debug, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct CachedFacts {
+    /// When the script was last run.
+    pub updated: Timestamp,
+    /// The facts emitted by the script.
+    pub facts: BTreeMap<String, String>,
+}
+
+/// Cached output of fact scripts, stored in the state directory.
+#[derive(Deserialize, Serialize, Default, Debug, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct FactsCache {
+    /// If the cache has been modified and should be saved.
+    #[serde(skip)]
+    pub dirty: bool,
+    /// Cached facts by script name.
+    #[serde(default)]
+    pub scripts: BTreeMap<String, CachedFacts>,
+}
+
 /// The holder of all the facts detected in the system.
 pub struct Facts(HashMap<String, String>);
 
@@ -28,13 +112,140 @@ impl Facts {
     pub fn load() -> Result<Facts, Error> {
         let mut facts = HashMap::new();
 
-        if let Some(distro) = detect_distro()? {
+        let distro = detect_distro()?;
+        let os_release = load_os_release()?;
+
+        if let Some(id) = os_release.get("ID") {
+            facts.insert(DISTRO_ID.to_string(), id.to_string());
+        }
+
+        if let Some(version) = os_release.get("VERSION_ID") {
+            facts.insert(DISTRO_VERSION.to_string(), version.to_string());
+        }
+
+        if let Some(family) = distro_family(distro.as_deref(), &os_release) {
+            facts.insert(DISTRO_FAMILY.to_string(), family.to_string());
+        }
+
+        if let Some(distro) = distro {
             facts.insert(DISTRO.to_string(), distro);
         }
 
+        if let Some(kernel_version) = read_trimmed("/proc/sys/kernel/osrelease")? {
+            if let Some(wsl) = wsl_version(&kernel_version) {
+                facts.insert(WSL.to_string(), wsl.to_string());
+            }
+
+            facts.insert(KERNEL_VERSION.to_string(), kernel_version);
+        }
+
+        if let Some(container) = detect_container()? {
+            facts.insert(CONTAINER.to_string(), container);
+        }
+
+        // NB: only exists if SELinux is enabled, and confined users might not be allowed to read it,
+        // in which case the mode is unknown.
+        match fs::read_to_string("/sys/fs/selinux/enforce") {
+            Ok(enforce) => {
+                facts.insert(
+                    SELINUX.to_string(),
+                    selinux_mode(enforce.trim()).to_string(),
+                );
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => (),
+            Err(e) if e.kind() == io::ErrorKind::PermissionDenied => {
+                log::debug!("Cannot tell if SELinux is enforcing: {}", e);
+            }
+            Err(e) => bail!("failed to read file: /sys/fs/selinux/enforce: {}", e),
+        }
+
+        if let Some(ci) = detect_ci(|var| std::env::var_os(var).is_some()) {
+            facts.insert(CI.to_string(), ci.to_string());
+        }
+
+        if let Some(hostname) = detect_hostname()? {
+            let short = hostname.split('.').next().unwrap_or_default().to_string();
+            facts.insert(HOSTNAME.to_string(), hostname);
+            facts.insert(SHORT_HOSTNAME.to_string(), short);
+        }
+
+        let (desktop, display_server) = detect_desktop(std::env::consts::OS, |var| {
+            std::env::var(var).ok().filter(|value| !value.is_empty())
+        });
+
+        for (key, value) in crate::os::detect_facts()? {
+            facts.insert(key.to_string(), value);
+        }
+
+        facts.insert(DESKTOP.to_string(), desktop);
+        facts.insert(DISPLAY_SERVER.to_string(), display_server.to_string());
         facts.insert(OS.to_string(), std::env::consts::OS.to_string());
+        facts.insert(ARCH.to_string(), std::env::consts::ARCH.to_string());
         return Ok(Facts(facts));
 
+        /// Load `os-release`, if available.
+        fn load_os_release() -> Result<HashMap<String, String>, Error> {
+            for path in &["/etc/os-release", "/usr/lib/os-release"] {
+                if let Some(content) = read_trimmed(path)? {
+                    return Ok(parse_os_release(&content));
+                }
+            }
+
+            Ok(HashMap::new())
+        }
+
+        /// Detect if we are running in a container.
+        fn detect_container() -> Result<Option<String>, Error> {
+            if metadata("/.dockerenv")?.is_some() {
+                return Ok(Some("docker".to_string()));
+            }
+
+            if metadata("/run/.containerenv")?.is_some() {
+                return Ok(Some("podman".to_string()));
+            }
+
+            // NB: set by systemd-nspawn, lxc, and others.
+            if let Ok(container) = std::env::var("container") {
+                if !container.is_empty() {
+                    return Ok(Some(container));
+                }
+            }
+
+            Ok(None)
+        }
+
+        /// Detect the hostname of the system.
+        fn detect_hostname() -> Result<Option<String>, Error> {
+            if let Some(hostname) = read_trimmed("/proc/sys/kernel/hostname")? {
+                return Ok(Some(hostname));
+            }
+
+            for var in &["HOSTNAME", "COMPUTERNAME"] {
+                if let Ok(hostname) = std::env::var(var) {
+                    return Ok(Some(hostname));
+                }
+            }
+
+            match Command::new(crate::os::command("hostname")).run_stdout() {
+                Ok(hostname) => Ok(Some(hostname.trim().to_string())),
+                Err(e) => {
+                    log::trace!("failed to run `hostname`: {}", e);
+                    Ok(None)
+                }
+            }
+        }
+
+        /// Read the trimmed content of a file, if it exists.
+        fn read_trimmed(path: &str) -> Result<Option<String>, Error> {
+            match fs::read_to_string(path) {
+                Ok(content) => Ok(Some(content.trim().to_string())),
+                Err(e) => match e.kind() {
+                    io::ErrorKind::NotFound => Ok(None),
+                    _ => bail!("failed to read file: {}: {}", path, e),
+                },
+            }
+        }
+
         /// Detect which distro we appear to be running.
         #[allow(unreachable_code)]
         fn detect_distro() -> Result<Option<String>, Error> {
@@ -52,6 +263,13 @@ impl Facts {
                 return Ok(Some("gentoo".to_string()));
             }
 
+            if metadata("/etc/arch-release")?
+                .map(|m| m.is_file())
+                .unwrap_or(false)
+            {
+                return Ok(Some("arch".to_string()));
+            }
+
             if metadata("/etc/debian_version")?
                 .map(|m| m.is_file())
                 .unwrap_or(false)
@@ -77,18 +295,452 @@ impl Facts {
         }
     }
 
+    /// Run all executable scripts in the given directory and add the facts they emit.
+    ///
+    /// Scripts are run in alphabetical order, so later scripts override facts from earlier ones.
+    ///
+    /// Output from scripts which have a TTL configured is stored in the given cache, and re-used
+    /// until the TTL has expired.
+    pub fn load_scripts(
+        &mut self,
+        dir: &Path,
+        cache: &mut FactsCache,
+        ttl: &BTreeMap<String, Duration>,
+        now: Timestamp,
+    ) -> Result<(), Error> {
+        let entries = match fs::read_dir(dir) {
+            Ok(entries) => entries,
+            Err(e) => match e.kind() {
+                io::ErrorKind::NotFound => {
+                    log::trace!("skipping missing facts directory: {}", dir.display());
+                    return Ok(());
+                }
+                _ => bail!("failed to read facts directory: {}: {}", dir.display(), e),
+            },
+        };
+
+        let mut scripts = Vec::new();
+
+        for entry in entries {
+            let path = entry
+                .with_context(|| anyhow!("failed to read facts directory: {}", dir.display()))?
+                .path();
+
+            // NB: symlinks are followed, but a broken one shouldn't stop all facts from loading.
+            let m = match fs::metadata(&path) {
+                Ok(m) => m,
+                Err(e) => {
+                    log::warn!("Skipping fact script: {}: {}", path.display(), e);
+                    continue;
+                }
+            };
+
+            if is_executable(&m) {
+                scripts.push(path);
+            } else {
+                log::trace!("skipping non-executable fact script: {}", path.display());
+            }
+        }
+
+        scripts.sort();
+
+        for script in scripts {
+            let name = match script.file_name().and_then(|n| n.to_str()) {
+                Some(name) => name.to_string(),
+                None => bail!("bad fact script name: {}", script.display()),
+            };
+
+            let ttl = ttl.get(&name).copied();
+
+            if let (Some(ttl), Some(cached)) = (ttl, cache.scripts.get(&name)) {
+                if now.duration_since(cached.updated).unwrap_or_default() < ttl {
+                    log::trace!("using cached facts from: {}", script.display());
+                    self.0.extend(cached.facts.clone());
+                    continue;
+                }
+            }
+
+            let output = Command::new(&script)
+                .run_stdout()
+                .with_context(|| anyhow!("failed to run fact script: {}", script.display()))?;
+
+            let facts = parse_script_output(&output)
+                .with_context(|| anyhow!("bad output from fact script: {}", script.display()))?;
+
+            if ttl.is_some() {
+                cache.dirty = true;
+                cache.scripts.insert(
+                    name,
+                    CachedFacts {
+                        updated: now,
+                        facts: facts.iter().cloned().collect(),
+                    },
+                );
+            }
+
+            self.0.extend(facts);
+        }
+
+        return Ok(());
+
+        #[cfg(unix)]
+        fn is_executable(m: &fs::Metadata) -> bool {
+            use std::os::unix::fs::PermissionsExt;
+            m.is_file() && m.permissions().mode() & 0o111 != 0
+        }
+
+        #[cfg(not(unix))]
+        fn is_executable(m: &fs::Metadata) -> bool {
+            m.is_file()
+        }
+    }
+
     /// Get the specified fact, if present.
-    pub fn get<Q: ?Sized>(&self, k: &Q) -> Option<&str>
+    pub fn get<Q>(&self, k: &Q) -> Option<&str>
     where
         String: Borrow<Q>,
-        Q: Hash + Eq,
+        Q: ?Sized + Hash + Eq,
     {
         self.0.get(k).map(|s| s.as_str())
     }
 }
 
+/// Convert the content of `/sys/fs/selinux/enforce` into the mode of SELinux.
+fn selinux_mode(enforce: &str) -> &'static str {
+    if enforce == "1" {
+        "enforcing"
+    } else {
+        "permissive"
+    }
+}
+
+/// Detect the version of WSL from the kernel release.
+///
+/// WSL 1 reports releases like `4.4.0-19041-Microsoft`, while WSL 2 reports releases like
+/// `5.15.90.1-microsoft-standard-WSL2`.
+fn wsl_version(kernel_release: &str) -> Option<&'static str> {
+    let lower = kernel_release.to_lowercase();
+
+    if !lower.contains("microsoft") {
+        return None;
+    }
+
+    if lower.contains("wsl2") || lower.contains("microsoft-standard") {
+        Some("2")
+    } else {
+        Some("1")
+    }
+}
+
+/// Detect which CI environment we are running in from the presence of environment variables.
+fn detect_ci(is_set: impl Fn(&str) -> bool) -> Option<&'static str> {
+    let known = [
+        ("GITHUB_ACTIONS", "github-actions"),
+        ("GITLAB_CI", "gitlab"),
+        ("CIRCLECI", "circleci"),
+        ("TRAVIS", "travis"),
+        ("BUILDKITE", "buildkite"),
+        ("JENKINS_URL", "jenkins"),
+        ("TF_BUILD", "azure-pipelines"),
+        ("CI", "unknown"),
+    ];
+
+    known
+        .iter()
+        .find(|(var, _)| is_set(var))
+        .map(|(_, name)| *name)
+}
+
+/// Detect the running desktop environment and display server.
+///
+/// Windows and macOS always have a desktop, which is reported as the name of the OS.
+fn detect_desktop(os: &str, var: impl Fn(&str) -> Option<String>) -> (String, &'static str) {
+    if os == "windows" || os == "macos" {
+        return (os.to_string(), "native");
+    }
+
+    let display_server = match var("XDG_SESSION_TYPE").as_deref() {
+        Some("x11") => "x11",
+        Some("wayland") => "wayland",
+        _ if var("WAYLAND_DISPLAY").is_some() => "wayland",
+        _ if var("DISPLAY").is_some() => "x11",
+        _ => "headless",
+    };
+
+    let known = [
+        "gnome", "kde", "xfce", "sway", "hyprland", "i3", "cinnamon", "mate", "lxqt", "budgie",
+    ];
+
+    let desktop = if let Some(current) = var("XDG_CURRENT_DESKTOP") {
+        let current = current.to_lowercase();
+        let mut parts = current.split(':');
+
+        match parts.clone().find(|part| known.contains(part)) {
+            Some(desktop) => desktop.to_string(),
+            None => parts.next().unwrap_or_default().to_string(),
+        }
+    } else if var("SWAYSOCK").is_some() {
+        "sway".to_string()
+    } else if let Some(session) = var("DESKTOP_SESSION") {
+        session.to_lowercase()
+    } else {
+        "none".to_string()
+    };
+
+    (desktop, display_server)
+}
+
+/// Parse the content of an `os-release` file.
+fn parse_os_release(content: &str) -> HashMap<String, String> {
+    let mut out = HashMap::new();
+
+    for line in content.lines() {
+        let line = line.trim();
+
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let mut it = line.splitn(2, '=');
+
+        if let (Some(key), Some(value)) = (it.next(), it.next()) {
+            let value = value.trim_matches(|c| c == '"' || c == '\'');
+            out.insert(key.to_string(), value.to_string());
+        }
+    }
+
+    out
+}
+
+/// Determine the family of the distro from its `os-release` information.
+///
+/// Falls back to the distro detected from release files.
+fn distro_family(
+    distro: Option<&str>,
+    os_release: &HashMap<String, String>,
+) -> Option<&'static str> {
+    let ids = os_release
+        .get("ID")
+        .into_iter()
+        .chain(os_release.get("ID_LIKE"))
+        .flat_map(|ids| ids.split_whitespace())
+        .chain(distro);
+
+    for id in ids {
+        let family = match id {
+            "debian" | "ubuntu" => "debian",
+            "rhel" | "fedora" | "centos" => "rhel",
+            "arch" | "archlinux" => "arch",
+            "suse" | "opensuse" => "suse",
+            "gentoo" => "gentoo",
+            "nixos" => "nixos",
+            _ => continue,
+        };
+
+        return Some(family);
+    }
+
+    None
+}
+
+/// Parse the output of a fact script.
+///
+/// This is either a JSON object, or one `key=value` pair per line.
+fn parse_script_output(output: &str) -> Result<Vec<(String, String)>, Error> {
+    let output = output.trim();
+    let mut facts = Vec::new();
+
+    if output.starts_with('{') {
+        let object = match serde_json::from_str(output)? {
+            JsonValue::Object(object) => object,
+            _ => bail!("expected JSON object"),
+        };
+
+        for (key, value) in object {
+            let value = match value {
+                JsonValue::String(value) => value,
+                JsonValue::Null => continue,
+                other => other.to_string(),
+            };
+
+            facts.push((key, value));
+        }
+
+        return Ok(facts);
+    }
+
+    for line in output.lines() {
+        let line = line.trim();
+
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let mut it = line.splitn(2, '=');
+
+        match (it.next(), it.next()) {
+            (Some(key), Some(value)) => {
+                facts.push((key.trim().to_string(), value.trim().to_string()));
+            }
+            _ => bail!("expected `key=value`, but got `{}`", line),
+        }
+    }
+
+    Ok(facts)
+}
+
 impl Vars for &Facts {
     fn get(&self, k: &str) -> Option<&str> {
         Facts::get(self, k)
     }
+
+    fn to_json(&self) -> JsonValue {
+        JsonValue::Object(
+            self.0
+                .iter()
+                .map(|(k, v)| (k.to_string(), JsonValue::String(v.to_string())))
+                .collect(),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        detect_ci, detect_desktop, distro_family, parse_os_release, parse_script_output,
+        selinux_mode, wsl_version, Facts, FactsCache,
+    };
+    use crate::Timestamp;
+    use std::collections::{BTreeMap, HashMap};
+
+    #[test]
+    fn test_detect_desktop() {
+        let env = |vars: &[(&str, &str)]| {
+            vars.iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect::<HashMap<_, _>>()
+        };
+
+        let gnome = env(&[
+            ("XDG_CURRENT_DESKTOP", "ubuntu:GNOME"),
+            ("XDG_SESSION_TYPE", "wayland"),
+        ]);
+        assert_eq!(
+            detect_desktop("linux", |v| gnome.get(v).cloned()),
+            ("gnome".to_string(), "wayland")
+        );
+
+        let sway = env(&[
+            ("SWAYSOCK", "/run/sway.sock"),
+            ("WAYLAND_DISPLAY", "wayland-1"),
+        ]);
+        assert_eq!(
+            detect_desktop("linux", |v| sway.get(v).cloned()),
+            ("sway".to_string(), "wayland")
+        );
+
+        assert_eq!(
+            detect_desktop("linux", |_| None),
+            ("none".to_string(), "headless")
+        );
+
+        assert_eq!(
+            detect_desktop("macos", |_| None),
+            ("macos".to_string(), "native")
+        );
+    }
+
+    #[test]
+    fn test_environment_detection() {
+        assert_eq!(wsl_version("4.4.0-19041-Microsoft"), Some("1"));
+        assert_eq!(wsl_version("5.15.90.1-microsoft-standard-WSL2"), Some("2"));
+        assert_eq!(wsl_version("6.8.0-45-generic"), None);
+
+        assert_eq!(
+            detect_ci(|var| var == "CI" || var == "GITLAB_CI"),
+            Some("gitlab")
+        );
+        assert_eq!(detect_ci(|var| var == "CI"), Some("unknown"));
+        assert_eq!(detect_ci(|_| false), None);
+
+        assert_eq!(selinux_mode("1"), "enforcing");
+        assert_eq!(selinux_mode("0"), "permissive");
+    }
+
+    #[test]
+    fn test_os_release() {
+        let os_release = parse_os_release(
+            "NAME=\"Pop!_OS\"\nVERSION_ID=\"22.04\"\nID=pop\nID_LIKE=\"ubuntu debian\"\n",
+        );
+
+        assert_eq!(os_release.get("ID").map(String::as_str), Some("pop"));
+        assert_eq!(
+            os_release.get("VERSION_ID").map(String::as_str),
+            Some("22.04")
+        );
+        assert_eq!(distro_family(None, &os_release), Some("debian"));
+        assert_eq!(
+            distro_family(Some("fedora"), &Default::default()),
+            Some("rhel")
+        );
+        assert_eq!(distro_family(None, &Default::default()), None);
+
+        let os_release = parse_os_release("ID=manjaro\nID_LIKE=arch\n");
+        assert_eq!(distro_family(None, &os_release), Some("arch"));
+
+        let os_release = parse_os_release("ID=nixos\n");
+        assert_eq!(distro_family(None, &os_release), Some("nixos"));
+    }
+
+    #[test]
+    fn test_parse_script_output() {
+        assert_eq!(
+            parse_script_output("# comment\nvpn = connected\n\nmodel=XPS 13\n").unwrap(),
+            vec![
+                ("vpn".to_string(), "connected".to_string()),
+                ("model".to_string(), "XPS 13".to_string()),
+            ]
+        );
+
+        let mut facts =
+            parse_script_output(r#"{"vpn": "connected", "cores": 8, "missing": null}"#).unwrap();
+        facts.sort();
+
+        assert_eq!(
+            facts,
+            vec![
+                ("cores".to_string(), "8".to_string()),
+                ("vpn".to_string(), "connected".to_string()),
+            ]
+        );
+
+        assert!(parse_script_output("not a fact").is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_load_scripts_skips_broken_symlinks() -> anyhow::Result<()> {
+        use std::fs;
+        use std::os::unix::fs::{symlink, PermissionsExt as _};
+
+        let dir = std::env::temp_dir().join(format!("quickcfg-facts-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir)?;
+
+        let script = dir.join("vpn");
+        fs::write(&script, "#!/bin/sh\necho vpn=connected\n")?;
+        fs::set_permissions(&script, fs::Permissions::from_mode(0o755))?;
+        symlink(dir.join("missing"), dir.join("broken"))?;
+        symlink(&script, dir.join("linked"))?;
+
+        let mut facts = Facts::new(Vec::new());
+        let mut cache = FactsCache::default();
+        facts.load_scripts(&dir, &mut cache, &BTreeMap::new(), Timestamp::now())?;
+
+        assert_eq!(Some("connected"), facts.get("vpn"));
+        assert!(cache.scripts.is_empty());
+
+        fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
 }
@@ -1,2 +1,4 @@
 pub mod msi;
 pub mod shellapi;
+pub mod wincred;
+pub mod winuser;
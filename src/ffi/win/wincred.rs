@@ -0,0 +1,84 @@
+use std::ffi::OsStr;
+use std::io;
+use std::os::windows::ffi::OsStrExt as _;
+use std::ptr;
+use winapi::shared::minwindef::{DWORD, TRUE};
+use winapi::shared::winerror::ERROR_NOT_FOUND;
+use winapi::um::wincred;
+
+fn wide_string(s: impl AsRef<OsStr>) -> Vec<u16> {
+    s.as_ref().encode_wide().chain(Some(0)).collect::<Vec<_>>()
+}
+
+/// Read the user name of the generic credential with the given target, if there is one.
+pub fn read_user_name(target: &str) -> io::Result<Option<String>> {
+    let target = wide_string(target);
+    let mut credential = ptr::null_mut();
+
+    unsafe {
+        let result = wincred::CredReadW(
+            target.as_ptr(),
+            wincred::CRED_TYPE_GENERIC,
+            0,
+            &mut credential,
+        );
+
+        if result != TRUE {
+            let e = io::Error::last_os_error();
+
+            if e.raw_os_error() == Some(ERROR_NOT_FOUND as i32) {
+                return Ok(None);
+            }
+
+            return Err(e);
+        }
+
+        let user_name = (*credential).UserName;
+
+        let user_name = if user_name.is_null() {
+            String::new()
+        } else {
+            let len = (0..).take_while(|&i| *user_name.offset(i) != 0).count();
+            String::from_utf16_lossy(std::slice::from_raw_parts(user_name, len))
+        };
+
+        wincred::CredFree(credential as *mut _);
+        Ok(Some(user_name))
+    }
+}
+
+/// Write a generic credential with the given target, user name, and secret, replacing any existing
+/// one.
+///
+/// The secret is stored as UTF-16, like `cmdkey` does, so that other tools can read it.
+pub fn write(target: &str, user_name: &str, secret: &str) -> io::Result<()> {
+    let mut target = wide_string(target);
+    let mut user_name = wide_string(user_name);
+    let mut blob = secret
+        .encode_utf16()
+        .flat_map(u16::to_le_bytes)
+        .collect::<Vec<u8>>();
+
+    unsafe {
+        let mut credential: wincred::CREDENTIALW = std::mem::zeroed();
+        credential.Type = wincred::CRED_TYPE_GENERIC;
+        credential.TargetName = target.as_mut_ptr();
+        credential.UserName = user_name.as_mut_ptr();
+        credential.CredentialBlobSize = blob.len() as DWORD;
+        credential.CredentialBlob = blob.as_mut_ptr();
+        credential.Persist = wincred::CRED_PERSIST_LOCAL_MACHINE;
+
+        let result = wincred::CredWriteW(&mut credential, 0);
+
+        // NB: don't leave the secret behind in memory which is freed.
+        for b in blob.iter_mut() {
+            ptr::write_volatile(b, 0);
+        }
+
+        if result != TRUE {
+            return Err(io::Error::last_os_error());
+        }
+    }
+
+    Ok(())
+}
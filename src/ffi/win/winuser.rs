@@ -0,0 +1,32 @@
+use std::ffi::OsStr;
+use std::os::windows::ffi::OsStrExt as _;
+use winapi::shared::minwindef::LPARAM;
+use winapi::um::winuser;
+
+/// How long to wait for each window to handle the broadcast, in milliseconds.
+const BROADCAST_TIMEOUT: u32 = 1000;
+
+/// Tell running programs, like Explorer, that environment variables have changed, so that programs
+/// they start get the new values.
+pub fn broadcast_environment_change() {
+    let environment = OsStr::new("Environment")
+        .encode_wide()
+        .chain(Some(0))
+        .collect::<Vec<u16>>();
+
+    let mut result = 0;
+
+    // NB: failing to broadcast only means that the change is picked up later, like after signing
+    // in again.
+    unsafe {
+        winuser::SendMessageTimeoutW(
+            winuser::HWND_BROADCAST,
+            winuser::WM_SETTINGCHANGE,
+            0,
+            environment.as_ptr() as LPARAM,
+            winuser::SMTO_ABORTIFHUNG,
+            BROADCAST_TIMEOUT,
+            &mut result,
+        );
+    }
+}
@@ -1,13 +1,37 @@
 //! Utilities for reading and writing serde types to and from the filesystem.
 
-use anyhow::{anyhow, bail, Context as _, Error};
+use crate::FileSystem;
+use anyhow::{anyhow, Error};
 use serde::de::DeserializeOwned;
 use serde::ser::Serialize;
-use serde_yaml;
 use std::fs::File;
 use std::io;
 use std::path::Path;
 
+/// Errors raised when loading a file.
+#[derive(Debug, thiserror::Error)]
+pub enum LoadError {
+    /// The file exists, but couldn't be opened.
+    #[error("Could not open file")]
+    Open(#[source] io::Error),
+    /// The file couldn't be read.
+    #[error("Could not read file")]
+    Read(#[source] io::Error),
+    /// The file isn't valid YAML, or doesn't have the expected structure.
+    #[error("Failed to parse as YAML")]
+    Yaml(#[source] serde_yaml::Error),
+    /// The file isn't valid TOML, or doesn't have the expected structure.
+    #[error("Failed to parse as TOML")]
+    Toml(#[source] toml::de::Error),
+    /// The file has a field or variant which doesn't exist, most likely because of a typo.
+    #[error("{location}: {message}, did you mean `{suggestion}`?")]
+    Typo {
+        location: String,
+        message: String,
+        suggestion: String,
+    },
+}
+
 pub trait Load: Sized {
     /// Load the file from the given path.
     fn load(path: &Path) -> Result<Option<Self>, Error>;
@@ -27,23 +51,164 @@ where
             Ok(f) => f,
             Err(e) => match e.kind() {
                 io::ErrorKind::NotFound => return Ok(None),
-                _ => bail!("Could not open file: {}", e),
+                _ => return Err(LoadError::Open(e).into()),
             },
         };
 
-        let out: T =
-            serde_yaml::from_reader(f).with_context(|| anyhow!("Failed to parse as YAML"))?;
+        // NB: files are YAML unless they have a `.toml` extension.
+        if path.extension().is_some_and(|e| e == "toml") {
+            let content = io::read_to_string(f).map_err(LoadError::Read)?;
+            let out: T = toml::from_str(&content).map_err(|e| toml_error(path, &content, e))?;
+            return Ok(Some(out));
+        }
+
+        let out: T = serde_yaml::from_reader(f).map_err(|e| yaml_error(path, e))?;
         Ok(Some(out))
     }
 }
 
+/// Convert an error from parsing YAML, suggesting what was meant if it's about a typo.
+fn yaml_error(path: &Path, e: serde_yaml::Error) -> LoadError {
+    let location = e.location().map(|l| (l.line(), l.column()));
+    let message = e.to_string();
+
+    // NB: the location is added back in front, together with the path.
+    let message = match message.rfind(" at line ") {
+        Some(index) => &message[..index],
+        None => &message,
+    };
+
+    typo(path, location, message).unwrap_or(LoadError::Yaml(e))
+}
+
+/// Convert an error from parsing TOML, suggesting what was meant if it's about a typo.
+fn toml_error(path: &Path, content: &str, e: toml::de::Error) -> LoadError {
+    let location = e.span().map(|span| {
+        let before = &content[..span.start];
+        let line = before.matches('\n').count() + 1;
+        let column = before
+            .rsplit('\n')
+            .next()
+            .unwrap_or_default()
+            .chars()
+            .count()
+            + 1;
+        (line, column)
+    });
+
+    typo(path, location, e.message()).unwrap_or(LoadError::Toml(e))
+}
+
+/// Construct an error which suggests the closest valid name, if the given message is about an
+/// unknown field or variant, like `unknown field `templatse`, expected one of `from`, `templates``.
+fn typo(path: &Path, location: Option<(usize, usize)>, message: &str) -> Option<LoadError> {
+    let (message, expected) = message.split_once(", expected ")?;
+
+    let unknown = ["unknown field `", "unknown variant `"]
+        .iter()
+        .find_map(|prefix| message.split_once(prefix))?
+        .1
+        .strip_suffix('`')?;
+
+    let candidates = expected.split('`').skip(1).step_by(2);
+    let suggestion = closest(unknown, candidates)?;
+
+    let location = match location {
+        Some((line, column)) => format!("{}:{}:{}", path.display(), line, column),
+        None => path.display().to_string(),
+    };
+
+    Some(LoadError::Typo {
+        location,
+        message: message.to_string(),
+        suggestion: suggestion.to_string(),
+    })
+}
+
+/// Find the candidate closest to the given name, unless none of them are close enough to be a typo
+/// of it.
+fn closest<'a>(name: &str, candidates: impl IntoIterator<Item = &'a str>) -> Option<&'a str> {
+    let max = usize::max(1, name.chars().count() / 3);
+
+    candidates
+        .into_iter()
+        .map(|candidate| (edit_distance(name, candidate), candidate))
+        .filter(|&(distance, _)| distance <= max)
+        .min_by_key(|&(distance, _)| distance)
+        .map(|(_, candidate)| candidate)
+}
+
+/// The number of single character insertions, deletions, and substitutions needed to turn one
+/// string into another.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b = b.chars().collect::<Vec<_>>();
+    let mut row = (0..=b.len()).collect::<Vec<_>>();
+
+    for (i, ca) in a.chars().enumerate() {
+        let mut previous = row[0];
+        row[0] = i + 1;
+
+        for (j, &cb) in b.iter().enumerate() {
+            let substitution = previous + usize::from(ca != cb);
+            previous = row[j + 1];
+            row[j + 1] = substitution.min(row[j] + 1).min(previous + 1);
+        }
+    }
+
+    row[b.len()]
+}
+
 impl<T> Save for T
 where
     T: Serialize,
 {
     fn save(&self, path: &Path) -> Result<(), Error> {
-        let f = File::create(path).map_err(|e| anyhow!("could not open file: {}", e))?;
-        serde_yaml::to_writer(f, self).map_err(|e| anyhow!("failed to write: {}", e))?;
-        Ok(())
+        // NB: state is synced to disk, since losing it means redoing work.
+        FileSystem::write_atomic(path, true, |f| {
+            serde_yaml::to_writer(f, self).map_err(|e| anyhow!("failed to write: {}", e))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{closest, edit_distance, toml_error, yaml_error};
+    use crate::Config;
+    use std::path::Path;
+
+    #[test]
+    fn test_edit_distance() {
+        assert_eq!(edit_distance("templatse", "templates"), 2);
+        assert_eq!(edit_distance("", "os"), 2);
+        assert_eq!(edit_distance("requires", "requires"), 0);
+        assert_eq!(closest("typ", vec!["type", "to"]), Some("type"));
+        assert_eq!(closest("foo", vec!["from", "to"]), None);
+    }
+
+    #[test]
+    fn test_typo() {
+        let path = Path::new("quickcfg.yml");
+        let yaml =
+            "systems:\n  - type: copy-dir\n    from: home\n    to: home://\n    templatse: true\n";
+        let e = serde_yaml::from_str::<Config>(yaml).unwrap_err();
+
+        assert_eq!(
+            yaml_error(path, e).to_string(),
+            "quickcfg.yml:2:3: systems: unknown field `templatse`, did you mean `templates`?"
+        );
+
+        let e = serde_yaml::from_str::<Config>("systems:\n  - type: copydir\n").unwrap_err();
+        assert!(yaml_error(path, e)
+            .to_string()
+            .ends_with("unknown variant `copydir`, did you mean `copy-dir`?"));
+
+        let path = Path::new("quickcfg.toml");
+        let toml = "fail_fast = true\ngit_refesh = \"1d\"\n";
+        let e = toml::from_str::<Config>(toml).unwrap_err();
+
+        assert_eq!(
+            toml_error(path, toml, e).to_string(),
+            "quickcfg.toml:2:1: unknown field `git_refesh`, did you mean `git_refresh`?"
+        );
     }
 }
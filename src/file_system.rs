@@ -4,10 +4,13 @@
 use crate::{
     hierarchy::Data,
     opts::Opts,
+    state::{DeployedKind, State},
     unit::{CopyFile, CopyTemplate, CreateDir, Dependency, Symlink, SystemUnit, UnitAllocator},
+    Timestamp,
 };
 use anyhow::{anyhow, bail, Context as _, Error};
 use fxhash::FxHashMap;
+use std::collections::BTreeSet;
 use std::fs;
 use std::io;
 use std::path::{Path, PathBuf};
@@ -19,6 +22,8 @@ use std::time::SystemTime;
 pub struct FileSystemInner {
     // TODO: include the system that modified the paths for better diagnostics.
     paths: FxHashMap<PathBuf, Dependency>,
+    /// Ids of the paths in the state directory which have been used.
+    state_paths: BTreeSet<String>,
     invalid: bool,
 }
 
@@ -96,31 +101,36 @@ impl<'a> FileSystem<'a> {
         path: &Path,
         link: PathBuf,
         meta: Option<&fs::Metadata>,
+        state: &State,
     ) -> Result<Option<SystemUnit>, Error> {
         let remove = match meta {
             Some(meta) => {
                 let ty = meta.file_type();
 
                 if !ty.is_symlink() {
-                    bail!("File exists but is not a symlink: {}", path.display());
-                }
+                    if !self.is_stale_copy(path, &link, meta, state)? {
+                        return Ok(None);
+                    }
 
-                let actual_link = fs::read_link(path)?;
+                    true
+                } else {
+                    let actual_link = fs::read_link(path)?;
 
-                if actual_link == link {
-                    return Ok(None);
-                }
+                    if actual_link == link {
+                        return Ok(None);
+                    }
 
-                if !self.opts.force {
-                    bail!(
-                        "Symlink exists `{}`, but contains the wrong link `{}`, expected: {} (use `--force` to override)",
-                        path.display(),
-                        actual_link.display(),
-                        link.display(),
-                    );
-                }
+                    if !self.opts.force {
+                        bail!(
+                            "Symlink exists `{}`, but contains the wrong link `{}`, expected: {} (use `--force` to override)",
+                            path.display(),
+                            actual_link.display(),
+                            link.display(),
+                        );
+                    }
 
-                true
+                    true
+                }
             }
             None => false,
         };
@@ -141,12 +151,49 @@ impl<'a> FileSystem<'a> {
         Ok(Some(unit))
     }
 
+    /// Test if a file which exists where a symlink should be is a copy made in place of the
+    /// symlink, which needs to be updated since the file it links to has changed.
+    ///
+    /// Fails if the file isn't such a copy, or if it has been modified since it was copied.
+    fn is_stale_copy(
+        &self,
+        path: &Path,
+        link: &Path,
+        meta: &fs::Metadata,
+        state: &State,
+    ) -> Result<bool, Error> {
+        let deployed = match state.deployed.get(path) {
+            Some(deployed)
+                if deployed.kind == DeployedKind::File
+                    && deployed.link.as_deref() == Some(link) =>
+            {
+                deployed
+            }
+            _ => bail!("File exists but is not a symlink: {}", path.display()),
+        };
+
+        let modified = Timestamp::from(meta.modified()?);
+
+        if !self.opts.force && !deployed.modified.is_some_and(|m| m.same_millis(modified)) {
+            bail!(
+                "Copy of link `{}` has been modified since it was copied (use `--force` to override)",
+                path.display()
+            );
+        }
+
+        let original = Self::resolve_link(path, link);
+        Ok(!Self::same_contents(path, &original)?)
+    }
+
     /// Optionally set up if we should copy a file.
     ///
     /// This is true if:
     ///
     /// * The destination file does not exist.
-    /// * The destination file has a modified timestamp less than the source file.
+    /// * The destination file has a different modified timestamp or size than when it was copied.
+    /// * Contents are compared with `--full-hash`.
+    ///
+    /// Files which exist are only overwritten if their contents differ.
     pub fn copy_file(
         &self,
         from: &Path,
@@ -154,24 +201,31 @@ impl<'a> FileSystem<'a> {
         to: &Path,
         to_meta: Option<&fs::Metadata>,
         template: bool,
+        state: &State,
     ) -> Result<Option<SystemUnit>, Error> {
-        let from_modified = match self.should_copy_file(&from_meta, &to, to_meta, template)? {
+        let from_modified = match self.should_copy_file(&from_meta, to, to_meta, template, state)? {
             Some(modified) => modified,
             None => return Ok(None),
         };
 
         let mut unit = if template {
-            self.allocator.unit(CopyTemplate {
+            let unit = CopyTemplate {
                 from: from.to_owned(),
                 from_modified,
                 to: to.to_owned(),
                 to_exists: to_meta.is_some(),
-            })
+            };
+
+            // NB: the hash is only checked once the unit is applied, so it's marked here to keep it
+            // when garbage collecting without applying anything.
+            state.mark_used(&unit.id());
+            self.allocator.unit(unit)
         } else {
             self.allocator.unit(CopyFile {
                 from: from.to_owned(),
                 from_modified,
                 to: to.to_owned(),
+                to_exists: to_meta.is_some(),
             })
         };
 
@@ -258,9 +312,65 @@ impl<'a> FileSystem<'a> {
 
     /// Get the state path for the given ID.
     pub fn state_path(&self, id: &str) -> PathBuf {
+        // NB: a poisoned lock is reported by other operations.
+        if let Ok(mut inner) = self.inner.lock() {
+            inner.state_paths.insert(id.to_string());
+        }
+
         self.state_dir.join(id)
     }
 
+    /// Get the ids of all state paths which have been used.
+    pub fn used_state_paths(&self) -> Result<BTreeSet<String>, Error> {
+        let inner = self.inner.lock().map_err(|_| anyhow!("Lock poisoned"))?;
+        Ok(inner.state_paths.clone())
+    }
+
+    /// Walk the given directory with a thread per core, calling `visit` for every entry to set up
+    /// the units it needs.
+    ///
+    /// A directory is always visited before anything in it, so units for files can depend on the
+    /// units which create their directory. The walk stops at the first error.
+    pub fn walk_dir<F>(dir: &Path, visit: F) -> Result<Vec<SystemUnit>, Error>
+    where
+        F: Fn(&ignore::DirEntry, &mut Vec<SystemUnit>) -> Result<(), Error> + Sync,
+    {
+        use ignore::WalkState;
+
+        let units = Mutex::new(Vec::new());
+        let error = Mutex::new(None);
+
+        ignore::WalkBuilder::new(dir)
+            .hidden(false)
+            .build_parallel()
+            .run(|| {
+                Box::new(|entry| {
+                    let mut out = Vec::new();
+
+                    let result = entry
+                        .map_err(Error::from)
+                        .and_then(|entry| visit(&entry, &mut out));
+
+                    if let Err(e) = result {
+                        error
+                            .lock()
+                            .unwrap_or_else(|e| e.into_inner())
+                            .get_or_insert(e);
+                        return WalkState::Quit;
+                    }
+
+                    units.lock().unwrap_or_else(|e| e.into_inner()).extend(out);
+                    WalkState::Continue
+                })
+            });
+
+        if let Some(e) = error.into_inner().unwrap_or_else(|e| e.into_inner()) {
+            return Err(e);
+        }
+
+        Ok(units.into_inner().unwrap_or_else(|e| e.into_inner()))
+    }
+
     /// Try to open metadata, unless the file does not exist.
     ///
     /// If the file does not exist, returns `None`.
@@ -290,6 +400,24 @@ impl<'a> FileSystem<'a> {
         Ok(false)
     }
 
+    /// Resolve what the symlink at `path` containing `link` points to, where a relative link is
+    /// relative to the directory that the symlink is in.
+    ///
+    /// ```rust
+    /// use quickcfg::FileSystem;
+    /// use std::path::{Path, PathBuf};
+    ///
+    /// let path = Path::new("/home/me/.vimrc");
+    /// assert_eq!(FileSystem::resolve_link(path, Path::new(".vim/vimrc")), PathBuf::from("/home/me/.vim/vimrc"));
+    /// assert_eq!(FileSystem::resolve_link(path, Path::new("/etc/vimrc")), PathBuf::from("/etc/vimrc"));
+    /// ```
+    pub fn resolve_link(path: &Path, link: &Path) -> PathBuf {
+        match path.parent() {
+            Some(parent) if link.is_relative() => parent.join(link),
+            _ => link.to_owned(),
+        }
+    }
+
     /// Construct a relative path from a provided base directory path to the provided path
     ///
     /// ```rust
@@ -334,8 +462,8 @@ impl<'a> FileSystem<'a> {
                 }
                 (None, _) => comps.push(Component::ParentDir),
                 (Some(a), Some(b)) if comps.is_empty() && a == b => (),
-                (Some(a), Some(b)) if b == Component::CurDir => comps.push(a),
-                (Some(_), Some(b)) if b == Component::ParentDir => return None,
+                (Some(a), Some(Component::CurDir)) => comps.push(a),
+                (Some(_), Some(Component::ParentDir)) => return None,
                 (Some(a), Some(_)) => {
                     comps.push(Component::ParentDir);
                     for _ in itb {
@@ -351,6 +479,55 @@ impl<'a> FileSystem<'a> {
         Some(comps.iter().map(|c| c.as_os_str()).collect())
     }
 
+    /// Write a file by writing to a temporary file next to it which is then renamed into place,
+    /// so that the file is never left partially written if we are interrupted.
+    ///
+    /// If `sync` is set, the file is flushed to disk before it's renamed into place.
+    pub fn write_atomic<F>(path: &Path, sync: bool, write: F) -> Result<(), Error>
+    where
+        F: FnOnce(&mut fs::File) -> Result<(), Error>,
+    {
+        let name = match path.file_name() {
+            Some(name) => name.to_string_lossy(),
+            None => bail!("Not a file: {}", path.display()),
+        };
+
+        let temp = path.with_file_name(format!(".{}.{}.tmp", name, std::process::id()));
+
+        let result = (|| -> Result<(), Error> {
+            let mut file = fs::File::create(&temp)?;
+            write(&mut file)?;
+
+            // NB: keep the permissions of the file we are replacing.
+            if let Some(meta) = Self::try_open_meta(path)?.filter(|m| m.is_file()) {
+                file.set_permissions(meta.permissions())?;
+            }
+
+            if sync {
+                file.sync_all()?;
+            }
+
+            fs::rename(&temp, path)?;
+            Ok(())
+        })();
+
+        if let Err(e) = result {
+            let _ = fs::remove_file(&temp);
+            return Err(e.context(anyhow!("Failed to write: {}", path.display())));
+        }
+
+        Ok(())
+    }
+
+    /// Test if two files have the same contents.
+    pub fn same_contents(a: &Path, b: &Path) -> Result<bool, Error> {
+        if a.metadata()?.len() != b.metadata()?.len() {
+            return Ok(false);
+        }
+
+        Ok(hash_file(a)? == hash_file(b)?)
+    }
+
     /// Update timestamps for the given path.
     pub fn touch(path: &Path, timestamp: &SystemTime) -> Result<(), Error> {
         use filetime::FileTime;
@@ -368,13 +545,17 @@ impl<'a> FileSystem<'a> {
     /// This is true if:
     ///
     /// * The destination file does not exist.
-    /// * The destination file has a modified timestamp less than the source file.
+    /// * The destination file has a different modified timestamp than the source file.
+    /// * The destination file has a different size than when it was copied, or than the source
+    ///   file if it isn't known.
+    /// * Contents are compared with `--full-hash`.
     fn should_copy_file(
         &self,
         from: &fs::Metadata,
         to: &Path,
         to_meta: Option<&fs::Metadata>,
         template: bool,
+        state: &State,
     ) -> Result<Option<SystemTime>, Error> {
         let from_modified = from.modified()?;
 
@@ -399,10 +580,268 @@ impl<'a> FileSystem<'a> {
             &from_modified
         };
 
-        if *modified != to_modified {
+        if self.opts.full_hash || *modified != to_modified {
+            return Ok(Some(*modified));
+        }
+
+        // NB: the size of a rendered template can only be known from when it was copied.
+        let size = match state.deployed.get(to).and_then(|d| d.size) {
+            Some(size) => Some(size),
+            None if !template => Some(from.len()),
+            None => None,
+        };
+
+        if size.is_some_and(|size| size != to_meta.len()) {
             return Ok(Some(*modified));
         }
 
         Ok(None)
     }
 }
+
+/// Detects paths which only differ by case, since they are the same file on case-insensitive file
+/// systems like the defaults on macOS and Windows.
+pub struct CaseCollisions {
+    /// If collisions are detected at all.
+    enabled: bool,
+    /// Paths seen so far, by their lowercase key.
+    seen: FxHashMap<String, PathBuf>,
+}
+
+impl CaseCollisions {
+    /// Construct a new detector, which only detects collisions if the file system of the current
+    /// platform is case-insensitive by default.
+    pub fn new() -> Self {
+        Self::with_enabled(cfg!(any(windows, target_os = "macos")))
+    }
+
+    fn with_enabled(enabled: bool) -> Self {
+        CaseCollisions {
+            enabled,
+            seen: FxHashMap::default(),
+        }
+    }
+
+    /// Record the given path, failing if another path with the same key only differing by case
+    /// has been recorded before.
+    ///
+    /// The key is what would collide, like the path relative to the directory it's copied to.
+    pub fn check(&mut self, key: &Path, path: &Path) -> Result<(), Error> {
+        if !self.enabled {
+            return Ok(());
+        }
+
+        let key = key.to_string_lossy().to_lowercase();
+
+        if let Some(other) = self.seen.get(&key) {
+            bail!(
+                "`{}` and `{}` only differ by case, and would overwrite each other on a case-insensitive file system",
+                other.display(),
+                path.display()
+            );
+        }
+
+        self.seen.insert(key, path.to_owned());
+        Ok(())
+    }
+}
+
+/// Hash the contents of the given file.
+fn hash_file(path: &Path) -> Result<blake3::Hash, Error> {
+    let mut file =
+        fs::File::open(path).with_context(|| anyhow!("failed to open: {}", path.display()))?;
+    let mut hasher = blake3::Hasher::new();
+    io::copy(&mut file, &mut hasher)?;
+    Ok(hasher.finalize())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{CaseCollisions, FileSystem};
+    use crate::{
+        hierarchy::Data,
+        opts::Opts,
+        state::{Deployed, DeployedKind, State},
+        unit::UnitAllocator,
+        Config, Timestamp,
+    };
+    use std::fs;
+    use std::io::Write as _;
+    use std::path::{Path, PathBuf};
+
+    #[test]
+    fn test_write_atomic() {
+        let dir = std::env::temp_dir().join(format!("quickcfg-write-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("file");
+
+        FileSystem::write_atomic(&path, true, |f| Ok(f.write_all(b"hello")?)).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "hello");
+
+        // NB: a failed write leaves the original file untouched.
+        let result = FileSystem::write_atomic(&path, false, |f| {
+            f.write_all(b"partial")?;
+            anyhow::bail!("interrupted")
+        });
+
+        assert!(result.is_err());
+        assert_eq!(fs::read_to_string(&path).unwrap(), "hello");
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_should_copy_file() -> anyhow::Result<()> {
+        let dir = std::env::temp_dir().join(format!("quickcfg-should-{}", std::process::id()));
+        fs::create_dir_all(&dir)?;
+        let from = dir.join("from");
+        let to = dir.join("to");
+        fs::write(&from, "hello")?;
+        fs::write(&to, "hello")?;
+        let modified = from.metadata()?.modified()?;
+        FileSystem::touch(&to, &modified)?;
+
+        let mut opts = Opts::default();
+        let allocator = UnitAllocator::default();
+        let data = Data::new(None, Vec::new());
+        let config = Config::default();
+        let mut state = State::new(&config, Timestamp::now());
+
+        let should_copy = |opts: &Opts, state: &State| -> anyhow::Result<bool> {
+            let file_system = FileSystem::new(opts, &dir, &allocator, &data);
+            let (from_meta, to_meta) = (from.metadata()?, to.metadata()?);
+            let copy =
+                file_system.should_copy_file(&from_meta, &to, Some(&to_meta), false, state)?;
+            Ok(copy.is_some())
+        };
+
+        assert!(!should_copy(&opts, &state)?);
+
+        // NB: the recorded size wins over the size of the source.
+        state.deploy(
+            &to,
+            Deployed {
+                kind: DeployedKind::File,
+                system: None,
+                modified: Some(Timestamp::from(modified)),
+                size: Some(4),
+                link: None,
+                backup: None,
+            },
+        );
+
+        assert!(should_copy(&opts, &state)?);
+
+        opts.full_hash = true;
+        assert!(should_copy(&opts, &State::new(&config, Timestamp::now()))?);
+        assert!(FileSystem::same_contents(&from, &to)?);
+
+        fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_walk_dir() -> anyhow::Result<()> {
+        let dir = std::env::temp_dir().join(format!("quickcfg-walk-{}", std::process::id()));
+
+        for n in 0..4 {
+            let sub = dir.join(format!("a{}", n)).join("b");
+            fs::create_dir_all(&sub)?;
+            fs::write(sub.join("file"), "")?;
+        }
+
+        let visited = std::sync::Mutex::new(Vec::new());
+
+        let units = FileSystem::walk_dir(&dir, |e, _| {
+            visited.lock().unwrap().push(e.path().to_owned());
+            Ok(())
+        })?;
+
+        assert!(units.is_empty());
+
+        let visited = visited.into_inner().unwrap();
+        assert_eq!(13, visited.len());
+
+        // NB: every directory is visited before anything in it.
+        for (i, path) in visited.iter().enumerate().skip(1) {
+            let parent = path.parent().expect("parent");
+            assert!(visited[..i].iter().any(|p| p == parent));
+        }
+
+        let result = FileSystem::walk_dir(&dir, |e, _| match e.file_name().to_str() {
+            Some("file") => anyhow::bail!("bad file"),
+            _ => Ok(()),
+        });
+
+        assert_eq!("bad file", result.unwrap_err().to_string());
+        fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_is_stale_copy() -> anyhow::Result<()> {
+        let dir = std::env::temp_dir().join(format!("quickcfg-copy-{}", std::process::id()));
+        fs::create_dir_all(&dir)?;
+        let original = dir.join("original");
+        let copy = dir.join("copy");
+        fs::write(&original, "hello")?;
+        fs::copy(&original, &copy)?;
+
+        let opts = Opts::default();
+        let allocator = UnitAllocator::default();
+        let data = Data::new(None, Vec::new());
+        let file_system = FileSystem::new(&opts, &dir, &allocator, &data);
+
+        let config = Config::default();
+        let mut state = State::new(&config, Timestamp::now());
+        let meta = copy.metadata()?;
+        let link = PathBuf::from("original");
+
+        // NB: a file which wasn't deployed as a copy of the link is left alone.
+        assert!(file_system
+            .is_stale_copy(&copy, &link, &meta, &state)
+            .is_err());
+
+        state.deploy(
+            &copy,
+            Deployed {
+                kind: DeployedKind::File,
+                system: None,
+                modified: Some(Timestamp::from(meta.modified()?)),
+                size: None,
+                link: Some(link.clone()),
+                backup: None,
+            },
+        );
+
+        assert!(!file_system.is_stale_copy(&copy, &link, &meta, &state)?);
+        fs::write(&original, "world")?;
+        assert!(file_system.is_stale_copy(&copy, &link, &meta, &state)?);
+
+        fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_case_collisions() {
+        let mut collisions = CaseCollisions::with_enabled(true);
+        assert!(collisions
+            .check(Path::new("Makefile"), Path::new("repo/Makefile"))
+            .is_ok());
+        assert!(collisions
+            .check(Path::new("README"), Path::new("repo/README"))
+            .is_ok());
+
+        let e = collisions
+            .check(Path::new("makefile"), Path::new("repo/makefile"))
+            .unwrap_err();
+        assert!(e
+            .to_string()
+            .contains("`repo/Makefile` and `repo/makefile`"));
+
+        let mut collisions = CaseCollisions::with_enabled(false);
+        assert!(collisions.check(Path::new("a"), Path::new("a")).is_ok());
+        assert!(collisions.check(Path::new("A"), Path::new("A")).is_ok());
+    }
+}
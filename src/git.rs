@@ -1,31 +1,149 @@
 //! Git abstraction.
 
 use anyhow::Error;
+use serde::Deserialize;
 use std::fmt;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
-#[cfg(not(feature = "git2"))]
-#[path = "git/external.rs"]
-mod system;
+mod external;
 #[cfg(feature = "git2")]
 #[path = "git/git2.rs"]
-mod system;
+mod libgit2;
+
+/// Paths used by quickcfg to store state, which are never committed.
+const STATE_PATHS: &[&str] = &[".state.yml", ".state"];
+
+/// Errors raised by git systems.
+#[derive(Debug, thiserror::Error)]
+pub enum GitError {
+    /// There's no branch to update, since HEAD is detached.
+    #[error("HEAD is detached, no branch to update")]
+    Detached,
+    /// The ref to update isn't a branch.
+    #[error("ref `{0}` is not a branch")]
+    NotBranch(String),
+    /// The branch has diverged from the remote.
+    #[error("cannot fast-forward to {0}")]
+    NotFastForward(String),
+    /// The selected backend isn't available in this build.
+    #[error("quickcfg was built without support for libgit2")]
+    Unsupported,
+}
+
+/// Which git backend to use.
+#[derive(Deserialize, Default, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Backend {
+    /// Use the `git` command if it's available, otherwise libgit2.
+    #[default]
+    Auto,
+    /// Always use the `git` command.
+    Cli,
+    /// Always use libgit2.
+    Git2,
+}
+
+/// What to do with uncommitted local changes when updating a repository.
+#[derive(Deserialize, Default, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum LocalChanges {
+    /// Don't update the repository.
+    #[default]
+    Abort,
+    /// Stash the changes, update, and reapply them.
+    Stash,
+    /// Discard the changes.
+    Discard,
+}
+
+/// How much of remote repositories to fetch when cloning and updating them.
+#[derive(Deserialize, Default, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Fetch {
+    /// Fetch the full history and all content.
+    #[default]
+    Full,
+    /// Only fetch the latest commit, like `--depth 1`.
+    Shallow,
+    /// Fetch the full history, but only download file content when it's checked out.
+    Blobless,
+}
+
+/// How to authenticate against remote repositories.
+#[derive(Debug, Default, Clone)]
+pub struct Auth {
+    /// SSH private key to use.
+    pub ssh_key: Option<PathBuf>,
+    /// Credential helper to use for HTTPS remotes.
+    pub credential_helper: Option<String>,
+    /// Username and token to use for HTTPS remotes.
+    pub token: Option<(String, String)>,
+    /// Fail instead of prompting for credentials.
+    pub non_interactive: bool,
+}
+
+/// The revision of the remote repository to track.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Revision<'a> {
+    /// Track whatever branch is currently checked out.
+    Head,
+    /// Check out and fast-forward the given branch.
+    Branch(&'a str),
+    /// Pin to the given tag or commit, which is checked out as a detached HEAD.
+    Ref(&'a str),
+}
+
+impl fmt::Display for Revision<'_> {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            Revision::Head => write!(fmt, "current branch"),
+            Revision::Branch(branch) => write!(fmt, "branch `{}`", branch),
+            Revision::Ref(git_ref) => write!(fmt, "ref `{}`", git_ref),
+        }
+    }
+}
 
 pub trait Git: Send + fmt::Debug {
     /// The path this git instance is associated with.
     fn path(&self) -> &Path;
 
-    /// Check if repo needs to be updated.
-    fn needs_update(&self) -> Result<bool, Error>;
+    /// Fetch the given revision and check if repo needs to be updated to it.
+    ///
+    /// Tracking the current branch while HEAD is detached skips the update with a warning.
+    fn needs_update(&self, revision: Revision<'_>) -> Result<bool, Error>;
+
+    /// Count the commits in the revision fetched by `needs_update` which are not in HEAD.
+    fn commits_behind(&self, revision: Revision<'_>) -> Result<usize, Error>;
 
     /// Check if the local repository has not been modified without comitting.
     fn is_fresh(&self) -> Result<bool, Error>;
 
-    /// Force update repo.
-    fn force_update(&self) -> Result<(), Error>;
+    /// List files with uncommitted changes, ignoring untracked files.
+    fn dirty_files(&self) -> Result<Vec<String>, Error>;
+
+    /// Stash all uncommitted changes.
+    fn stash(&self) -> Result<(), Error>;
+
+    /// Reapply the most recently stashed changes.
+    fn stash_pop(&self) -> Result<(), Error>;
+
+    /// Force update repo to the revision fetched by `needs_update`, discarding local changes.
+    fn force_update(&self, revision: Revision<'_>) -> Result<(), Error>;
+
+    /// Update repo to the revision fetched by `needs_update`.
+    fn update(&self, revision: Revision<'_>) -> Result<(), Error>;
+
+    /// Stage all changes, except for quickcfg state, and return the paths which are staged.
+    fn stage_all(&self) -> Result<Vec<String>, Error>;
+
+    /// Commit everything which is staged.
+    fn commit(&self, message: &str) -> Result<(), Error>;
 
-    /// Update repo.
-    fn update(&self) -> Result<(), Error>;
+    /// Push the current branch to its remote.
+    fn push(&self) -> Result<(), Error>;
+
+    /// Initialize and update all submodules recursively, if there are any.
+    fn update_submodules(&self) -> Result<(), Error>;
 }
 
 pub trait GitSystem: Send + Sync {
@@ -40,7 +158,197 @@ pub trait GitSystem: Send + Sync {
     fn open(&self, path: &Path) -> Result<Box<dyn Git>, Error>;
 }
 
-/// Open the given path.
-pub fn setup() -> Result<Box<dyn GitSystem>, Error> {
-    Ok(Box::new(system::GitSystem::new()))
+/// Set up the git system to use for the given backend.
+pub fn setup(backend: Backend, auth: Auth, fetch: Fetch) -> Result<Box<dyn GitSystem>, Error> {
+    match backend {
+        Backend::Cli => Ok(Box::new(external::GitSystem::new(&auth, fetch))),
+        Backend::Git2 => libgit2(auth, fetch),
+        Backend::Auto => {
+            let external = external::GitSystem::new(&auth, fetch);
+
+            if external.test()? || !cfg!(feature = "git2") {
+                return Ok(Box::new(external));
+            }
+
+            log::trace!("no working git command found, using libgit2");
+            libgit2(auth, fetch)
+        }
+    }
+}
+
+#[cfg(feature = "git2")]
+fn libgit2(auth: Auth, fetch: Fetch) -> Result<Box<dyn GitSystem>, Error> {
+    // NB: the version of libgit2 we use supports neither shallow nor partial clones.
+    if fetch != Fetch::Full {
+        log::warn!(
+            "libgit2 doesn't support {:?} fetching, fetching everything",
+            fetch
+        );
+    }
+
+    Ok(Box::new(libgit2::GitSystem::new(auth)))
+}
+
+#[cfg(not(feature = "git2"))]
+fn libgit2(_: Auth, _: Fetch) -> Result<Box<dyn GitSystem>, Error> {
+    Err(GitError::Unsupported.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{setup, Auth, Backend, Fetch, Revision};
+    use anyhow::Error;
+    use std::fs;
+    use std::path::Path;
+    use std::process::Command;
+
+    /// Run git in the given directory, and return its trimmed output.
+    fn git(dir: &Path, args: &[&str]) -> String {
+        let output = Command::new("git")
+            .args([
+                "-c",
+                "user.name=quickcfg",
+                "-c",
+                "user.email=quickcfg@localhost",
+            ])
+            .args(["-c", "commit.gpgsign=false"])
+            .args(args)
+            .current_dir(dir)
+            .output()
+            .unwrap();
+
+        assert!(
+            output.status.success(),
+            "git {:?}: {}",
+            args,
+            String::from_utf8_lossy(&output.stderr)
+        );
+
+        String::from_utf8(output.stdout).unwrap().trim().to_string()
+    }
+
+    /// Commit a change to the given file and push it to the given branch of origin.
+    fn push(seed: &Path, branch: &str, file: &str, content: &str) -> String {
+        fs::write(seed.join(file), content).unwrap();
+        git(seed, &["add", file]);
+        git(seed, &["commit", "-q", "-m", content]);
+        git(
+            seed,
+            &[
+                "push",
+                "-q",
+                "--force",
+                "origin",
+                &format!("HEAD:{}", branch),
+            ],
+        );
+        git(seed, &["rev-parse", "HEAD"])
+    }
+
+    fn head_branch(work: &Path) -> Option<String> {
+        let output = Command::new("git")
+            .args(["symbolic-ref", "--short", "-q", "HEAD"])
+            .current_dir(work)
+            .output()
+            .unwrap();
+
+        if output.status.success() {
+            Some(String::from_utf8(output.stdout).unwrap().trim().to_string())
+        } else {
+            None
+        }
+    }
+
+    fn test_backend(backend: Backend, name: &str) -> Result<(), Error> {
+        let dir =
+            std::env::temp_dir().join(format!("quickcfg-git-{}-{}", name, std::process::id()));
+        let origin = dir.join("origin.git");
+        let seed = dir.join("seed");
+        let work = dir.join("work");
+        fs::create_dir_all(&origin)?;
+        fs::create_dir_all(&seed)?;
+
+        git(&origin, &["init", "-q", "--bare"]);
+        git(&origin, &["symbolic-ref", "HEAD", "refs/heads/main"]);
+        git(&seed, &["init", "-q"]);
+        git(
+            &seed,
+            &["remote", "add", "origin", origin.to_str().unwrap()],
+        );
+
+        let first = push(&seed, "main", "file", "first");
+        git(&seed, &["tag", "v1"]);
+        git(&seed, &["push", "-q", "origin", "v1"]);
+        let other = push(&seed, "other", "other", "other");
+        git(&seed, &["reset", "-q", "--hard", &first]);
+
+        let system = setup(backend, Auth::default(), Fetch::Full)?;
+        let repo = system.clone(origin.to_str().unwrap(), &work)?;
+        assert!(!repo.needs_update(Revision::Head)?);
+
+        // Tracking the current branch.
+        let second = push(&seed, "main", "file", "second");
+        assert!(repo.needs_update(Revision::Head)?);
+        assert_eq!(repo.commits_behind(Revision::Head)?, 1);
+        repo.update(Revision::Head)?;
+        assert_eq!(git(&work, &["rev-parse", "HEAD"]), second);
+        assert!(!repo.needs_update(Revision::Head)?);
+
+        // Switching to another branch.
+        assert!(repo.needs_update(Revision::Branch("other"))?);
+        repo.update(Revision::Branch("other"))?;
+        assert_eq!(git(&work, &["rev-parse", "HEAD"]), other);
+        assert_eq!(head_branch(&work).as_deref(), Some("other"));
+        assert!(!repo.needs_update(Revision::Branch("other"))?);
+
+        // Pinning to a tag.
+        assert!(repo.needs_update(Revision::Ref("v1"))?);
+        repo.update(Revision::Ref("v1"))?;
+        assert_eq!(git(&work, &["rev-parse", "HEAD"]), first);
+        assert_eq!(head_branch(&work), None);
+        assert!(!repo.needs_update(Revision::Ref("v1"))?);
+
+        // NB: there's no branch to track while HEAD is detached.
+        push(&seed, "main", "file", "third");
+        assert!(!repo.needs_update(Revision::Head)?);
+        assert_eq!(git(&work, &["rev-parse", "HEAD"]), first);
+
+        // Force updating discards local changes.
+        fs::write(work.join("file"), "local")?;
+        assert!(repo.needs_update(Revision::Ref(&second))?);
+        repo.force_update(Revision::Ref(&second))?;
+        assert_eq!(git(&work, &["rev-parse", "HEAD"]), second);
+        assert_eq!(fs::read_to_string(work.join("file"))?, "second");
+
+        fs::write(work.join("file"), "local")?;
+        assert!(repo.needs_update(Revision::Branch("main"))?);
+        repo.force_update(Revision::Branch("main"))?;
+        let third = git(&seed, &["rev-parse", "HEAD"]);
+        assert_eq!(git(&work, &["rev-parse", "HEAD"]), third);
+        assert_eq!(head_branch(&work).as_deref(), Some("main"));
+        assert_eq!(fs::read_to_string(work.join("file"))?, "third");
+
+        // NB: rewritten history can only be force updated to.
+        git(&seed, &["reset", "-q", "--hard", &first]);
+        let rewritten = push(&seed, "main", "file", "rewritten");
+        fs::write(work.join("file"), "local")?;
+        assert!(repo.needs_update(Revision::Head)?);
+        repo.force_update(Revision::Head)?;
+        assert_eq!(git(&work, &["rev-parse", "HEAD"]), rewritten);
+        assert_eq!(fs::read_to_string(work.join("file"))?, "rewritten");
+
+        fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_cli() -> Result<(), Error> {
+        test_backend(Backend::Cli, "cli")
+    }
+
+    #[cfg(feature = "git2")]
+    #[test]
+    fn test_git2() -> Result<(), Error> {
+        test_backend(Backend::Git2, "git2")
+    }
 }
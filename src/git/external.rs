@@ -1,3 +1,6 @@
+//! Git integration using the `git` command.
+
+use super::{Auth, Fetch, GitError, Revision, STATE_PATHS};
 use crate::{command, os};
 use anyhow::Error;
 use std::io;
@@ -5,17 +8,69 @@ use std::path::{Path, PathBuf};
 
 pub struct GitSystem {
     command: command::Command,
+    fetch: Fetch,
 }
 
 impl GitSystem {
-    pub fn new() -> Self {
-        Self {
-            command: command::Command::new(os::command("git")),
+    pub fn new(auth: &Auth, fetch: Fetch) -> Self {
+        let mut command = command::Command::new(os::command("git"));
+
+        if auth.non_interactive {
+            command.env("GIT_TERMINAL_PROMPT", "0");
+        }
+
+        let mut ssh = Vec::new();
+
+        if let Some(ssh_key) = auth.ssh_key.as_ref() {
+            ssh.push(format!("-i {}", quote(&ssh_key.to_string_lossy())));
+            ssh.push(String::from("-o IdentitiesOnly=yes"));
+        }
+
+        if auth.non_interactive {
+            ssh.push(String::from("-o BatchMode=yes"));
         }
+
+        if !ssh.is_empty() {
+            command.env("GIT_SSH_COMMAND", format!("ssh {}", ssh.join(" ")));
+        }
+
+        if let Some((username, token)) = auth.token.as_ref() {
+            // NB: pass the token through the environment, so that it doesn't show up in the
+            // process list.
+            command.env("QUICKCFG_GIT_USERNAME", username);
+            command.env("QUICKCFG_GIT_TOKEN", token);
+            command.args(&["-c", "credential.helper="]);
+            command.args(&[
+                "-c",
+                "credential.helper=!f() { echo \"username=$QUICKCFG_GIT_USERNAME\"; echo \"password=$QUICKCFG_GIT_TOKEN\"; }; f",
+            ]);
+        }
+
+        if let Some(helper) = auth.credential_helper.as_ref() {
+            command.arg("-c");
+            command.arg(format!("credential.helper={}", helper));
+        }
+
+        Self { command, fetch }
     }
 }
 
+/// Parse the paths out of the output of `git status --porcelain`.
+fn parse_status(output: &str) -> Vec<String> {
+    output
+        .lines()
+        .filter(|line| line.len() > 3)
+        .map(|line| line[3..].to_string())
+        .collect()
+}
+
+/// Quote the given argument for use in `GIT_SSH_COMMAND`, which is interpreted by a shell.
+fn quote(arg: &str) -> String {
+    format!("'{}'", arg.replace('\'', "'\\''"))
+}
+
 impl super::GitSystem for GitSystem {
+    /// Test if the `git` command is available.
     fn test(&self) -> Result<bool, Error> {
         let mut command = self.command.clone();
         command.arg("--version");
@@ -32,6 +87,13 @@ impl super::GitSystem for GitSystem {
     fn clone(&self, url: &str, path: &Path) -> Result<Box<dyn super::Git>, Error> {
         let mut command = self.command.clone();
         command.arg("clone");
+
+        match self.fetch {
+            Fetch::Full => (),
+            Fetch::Shallow => command.args(&["--depth", "1"]),
+            Fetch::Blobless => command.arg("--filter=blob:none"),
+        }
+
         command.arg(url);
         command.arg(path);
         command.run_checked()?;
@@ -39,6 +101,7 @@ impl super::GitSystem for GitSystem {
         Ok(Box::new(External {
             path: path.to_owned(),
             command: self.command.clone(),
+            fetch: self.fetch,
         }))
     }
 
@@ -46,6 +109,7 @@ impl super::GitSystem for GitSystem {
         Ok(Box::new(External {
             path: path.to_owned(),
             command: self.command.clone(),
+            fetch: self.fetch,
         }))
     }
 }
@@ -55,23 +119,61 @@ impl super::GitSystem for GitSystem {
 struct External {
     pub path: PathBuf,
     command: command::Command,
+    fetch: Fetch,
 }
 
 impl External {
-    fn rev_parse(&self, git_ref: &str) -> Result<String, Error> {
+    /// Construct a new git command running in the repository.
+    fn git(&self) -> command::Command {
         let mut command = self.command.clone();
         command.working_directory(&self.path);
+        command
+    }
+
+    fn rev_parse(&self, git_ref: &str) -> Result<String, Error> {
+        let mut command = self.git();
         command.args(&["rev-parse", git_ref]);
         Ok(command.run_stdout()?.trim().to_string())
     }
 
     /// Find the merge base between two commits.
     fn merge_base(&self, a: &str, b: &str) -> Result<String, Error> {
-        let mut command = self.command.clone();
-        command.working_directory(&self.path);
+        let mut command = self.git();
         command.args(&["merge-base", a, b]);
         Ok(command.run_stdout()?.trim().to_string())
     }
+
+    /// Get the currently checked out branch, if HEAD is not detached.
+    fn head_branch(&self) -> Result<Option<String>, Error> {
+        let mut command = self.git();
+        command.args(&["symbolic-ref", "--short", "-q", "HEAD"]);
+
+        let output = command.run()?;
+
+        if !output.status.success() {
+            return Ok(None);
+        }
+
+        Ok(Some(output.stdout.trim().to_string()))
+    }
+
+    /// Test if the given local branch exists.
+    fn has_branch(&self, branch: &str) -> Result<bool, Error> {
+        let mut command = self.git();
+        command.args(&["show-ref", "--verify", "--quiet"]);
+        command.arg(format!("refs/heads/{}", branch));
+        Ok(command.status()?.success())
+    }
+
+    /// Test if we need to switch to the given branch.
+    fn needs_switch(&self, revision: Revision<'_>) -> Result<Option<String>, Error> {
+        match revision {
+            Revision::Branch(branch) if self.head_branch()?.as_deref() != Some(branch) => {
+                Ok(Some(branch.to_string()))
+            }
+            _ => Ok(None),
+        }
+    }
 }
 
 impl super::Git for External {
@@ -79,15 +181,49 @@ impl super::Git for External {
         &self.path
     }
 
-    fn needs_update(&self) -> Result<bool, Error> {
+    fn needs_update(&self, revision: Revision<'_>) -> Result<bool, Error> {
         let head = self.rev_parse("HEAD")?;
 
-        let mut command = self.command.clone();
-        command.working_directory(self.path());
-        command.args(&["fetch", "origin", head.as_str()]);
+        let remote_ref = match revision {
+            Revision::Head => match self.head_branch()? {
+                Some(branch) => branch,
+                None => {
+                    log::warn!(
+                        "Not updating `{}`: {}",
+                        self.path.display(),
+                        GitError::Detached
+                    );
+                    return Ok(false);
+                }
+            },
+            Revision::Branch(branch) => branch.to_string(),
+            Revision::Ref(git_ref) => git_ref.to_string(),
+        };
+
+        let mut command = self.git();
+        command.arg("fetch");
+
+        if let Fetch::Shallow = self.fetch {
+            command.args(&["--depth", "1"]);
+        }
+
+        command.args(&["origin", remote_ref.as_str()]);
         command.run_checked()?;
 
-        let remote_head = self.rev_parse("FETCH_HEAD")?;
+        let remote_head = self.rev_parse("FETCH_HEAD^{commit}")?;
+
+        if let Revision::Ref(..) = revision {
+            return Ok(remote_head != head);
+        }
+
+        if self.needs_switch(revision)?.is_some() {
+            return Ok(true);
+        }
+
+        // NB: shallow history doesn't let us tell if the remote is behind us.
+        if let Fetch::Shallow = self.fetch {
+            return Ok(remote_head != head);
+        }
 
         if remote_head != head {
             // check if remote is a base
@@ -97,24 +233,157 @@ impl super::Git for External {
         Ok(false)
     }
 
+    fn commits_behind(&self, _: Revision<'_>) -> Result<usize, Error> {
+        let mut command = self.git();
+        command.args(&["rev-list", "--count", "HEAD..FETCH_HEAD"]);
+        Ok(command.run_stdout()?.trim().parse()?)
+    }
+
     fn is_fresh(&self) -> Result<bool, Error> {
-        let mut command = self.command.clone();
-        command.working_directory(&self.path);
+        let mut command = self.git();
         command.args(&["diff-index", "--quiet", "HEAD"]);
         Ok(command.status()?.success())
     }
 
-    fn force_update(&self) -> Result<(), Error> {
-        let mut command = self.command.clone();
-        command.working_directory(&self.path);
-        command.args(&["reset", "--hard", "FETCH_HEAD"]);
+    fn dirty_files(&self) -> Result<Vec<String>, Error> {
+        let mut command = self.git();
+        command.args(&["status", "--porcelain", "--untracked-files=no"]);
+        Ok(parse_status(&command.run_stdout()?))
+    }
+
+    fn stash(&self) -> Result<(), Error> {
+        let mut command = self.git();
+        command.args(&["stash", "push", "-m", "quickcfg: local changes"]);
         command.run_checked()
     }
 
-    fn update(&self) -> Result<(), Error> {
-        let mut command = self.command.clone();
-        command.working_directory(&self.path);
-        command.args(&["merge", "--ff-only", "FETCH_HEAD"]);
+    fn stash_pop(&self) -> Result<(), Error> {
+        let mut command = self.git();
+        command.args(&["stash", "pop"]);
+        command.run_checked()
+    }
+
+    fn stage_all(&self) -> Result<Vec<String>, Error> {
+        let mut command = self.git();
+        command.args(&["add", "-A", "--", "."]);
+        command.args(STATE_PATHS.iter().map(|path| format!(":(exclude){}", path)));
+        command.run_checked()?;
+
+        let mut command = self.git();
+        command.args(&["diff", "--cached", "--name-only"]);
+
+        Ok(command
+            .run_lines()?
+            .into_iter()
+            .filter(|line| !line.is_empty())
+            .collect())
+    }
+
+    fn commit(&self, message: &str) -> Result<(), Error> {
+        let mut config = self.git();
+        config.args(&["config", "user.email"]);
+
+        let mut command = self.git();
+
+        // NB: fall back to a generic identity on machines where git isn't configured.
+        if !config.run()?.status.success() {
+            command.args(&[
+                "-c",
+                "user.name=quickcfg",
+                "-c",
+                "user.email=quickcfg@localhost",
+            ]);
+        }
+
+        command.args(&["commit", "-q", "-m", message]);
+        command.run_checked()
+    }
+
+    fn push(&self) -> Result<(), Error> {
+        let mut command = self.git();
+        command.args(&["push", "origin", "HEAD"]);
+        command.run_checked()
+    }
+
+    fn force_update(&self, revision: Revision<'_>) -> Result<(), Error> {
+        let mut command = self.git();
+
+        if let Revision::Ref(..) = revision {
+            command.args(&["checkout", "--force", "--detach", "FETCH_HEAD"]);
+        } else if let Some(branch) = self.needs_switch(revision)? {
+            command.args(&["checkout", "--force", "-B", branch.as_str(), "FETCH_HEAD"]);
+        } else {
+            command.args(&["reset", "--hard", "FETCH_HEAD"]);
+        }
+
+        command.run_checked()
+    }
+
+    fn update(&self, revision: Revision<'_>) -> Result<(), Error> {
+        if let Revision::Ref(..) = revision {
+            let mut command = self.git();
+            command.args(&["checkout", "--detach", "FETCH_HEAD"]);
+            return command.run_checked();
+        }
+
+        if let Some(branch) = self.needs_switch(revision)? {
+            let mut command = self.git();
+
+            if !self.has_branch(&branch)? {
+                command.args(&["checkout", "-b", branch.as_str(), "FETCH_HEAD"]);
+                return command.run_checked();
+            }
+
+            command.args(&["checkout", branch.as_str()]);
+            command.run_checked()?;
+        }
+
+        let mut command = self.git();
+
+        // NB: a shallow commit can't be fast-forwarded to, since its parents are unknown.
+        if let Fetch::Shallow = self.fetch {
+            command.args(&["reset", "--keep", "FETCH_HEAD"]);
+        } else {
+            command.args(&["merge", "--ff-only", "FETCH_HEAD"]);
+        }
+
         command.run_checked()
     }
+
+    fn update_submodules(&self) -> Result<(), Error> {
+        if !self.path.join(".gitmodules").is_file() {
+            return Ok(());
+        }
+
+        let mut command = self.git();
+        command.args(&["submodule", "update", "--init", "--recursive"]);
+
+        match self.fetch {
+            Fetch::Full => (),
+            Fetch::Shallow => command.args(&["--depth", "1"]),
+            Fetch::Blobless => command.arg("--filter=blob:none"),
+        }
+
+        command.run_checked()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_status, quote};
+
+    #[test]
+    fn test_quote() {
+        assert_eq!(quote("/home/me/.ssh/id"), "'/home/me/.ssh/id'");
+        assert_eq!(quote("it's"), "'it'\\''s'");
+    }
+
+    #[test]
+    fn test_parse_status() {
+        assert_eq!(
+            parse_status(" M quickcfg.yml\nM  db/common.yml\n"),
+            vec!["quickcfg.yml", "db/common.yml"]
+        );
+        assert!(parse_status("").is_empty());
+    }
 }
@@ -1,23 +1,43 @@
 //! Git integration using libgit2
 
-use anyhow::{anyhow, bail, Result};
-use git2::{ObjectType, Oid, Repository, ResetType};
+use super::{Auth, GitError, Revision, STATE_PATHS};
+use anyhow::Result;
+use git2::build::{CheckoutBuilder, RepoBuilder};
+use git2::{
+    AutotagOption, BranchType, Cred, CredentialType, FetchOptions, IndexAddOption, ObjectType, Oid,
+    PushOptions, RemoteCallbacks, Repository, ResetType, Signature, Status, StatusOptions,
+    SubmoduleUpdateOptions,
+};
 use std::fmt;
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
 
-pub struct GitSystem(());
+/// Number of times we try to authenticate before giving up, since libgit2 keeps asking for
+/// credentials as long as authentication fails.
+const MAX_AUTH_ATTEMPTS: usize = 3;
+
+pub struct GitSystem {
+    auth: Arc<Auth>,
+}
 
 impl GitSystem {
-    pub fn new() -> Self {
-        GitSystem(())
+    pub fn new(auth: Auth) -> Self {
+        GitSystem {
+            auth: Arc::new(auth),
+        }
     }
 }
 
 impl super::GitSystem for GitSystem {
     fn clone(&self, url: &str, path: &Path) -> Result<Box<dyn super::Git>> {
+        let repo = RepoBuilder::new()
+            .fetch_options(fetch_options(&self.auth))
+            .clone(url, path)?;
+
         Ok(Box::new(Git2 {
             path: path.to_owned(),
-            repo: Repository::clone(url, path)?,
+            repo,
+            auth: Arc::clone(&self.auth),
         }))
     }
 
@@ -25,14 +45,71 @@ impl super::GitSystem for GitSystem {
         Ok(Box::new(Git2 {
             path: path.to_owned(),
             repo: Repository::open(path)?,
+            auth: Arc::clone(&self.auth),
         }))
     }
 }
 
+/// Construct fetch options which authenticate using the given configuration.
+fn fetch_options(auth: &Auth) -> FetchOptions<'_> {
+    let mut options = FetchOptions::new();
+    options.remote_callbacks(remote_callbacks(auth));
+    options
+}
+
+/// Construct remote callbacks which authenticate using the given configuration.
+fn remote_callbacks(auth: &Auth) -> RemoteCallbacks<'_> {
+    let mut attempts = 0;
+    let mut callbacks = RemoteCallbacks::new();
+
+    callbacks.credentials(move |url, username, allowed| {
+        attempts += 1;
+
+        if attempts > MAX_AUTH_ATTEMPTS {
+            return Err(git2::Error::from_str("authentication failed"));
+        }
+
+        if allowed.contains(CredentialType::SSH_KEY) {
+            let username = username.unwrap_or("git");
+
+            return match auth.ssh_key.as_ref() {
+                Some(ssh_key) => Cred::ssh_key(username, None, ssh_key, None),
+                None => Cred::ssh_key_from_agent(username),
+            };
+        }
+
+        if allowed.contains(CredentialType::USER_PASS_PLAINTEXT) {
+            if let Some((username, token)) = auth.token.as_ref() {
+                return Cred::userpass_plaintext(username, token);
+            }
+
+            let config = match auth.credential_helper.as_ref() {
+                Some(helper) => {
+                    let mut config = git2::Config::new()?;
+                    config.set_str("credential.helper", helper)?;
+                    config
+                }
+                None => git2::Config::open_default()?,
+            };
+
+            return Cred::credential_helper(&config, url, username);
+        }
+
+        if allowed.contains(CredentialType::DEFAULT) {
+            return Cred::default();
+        }
+
+        Err(git2::Error::from_str("no supported authentication method"))
+    });
+
+    callbacks
+}
+
 /// Helper to interact with a git repository.
 pub struct Git2 {
     pub path: PathBuf,
     pub repo: Repository,
+    auth: Arc<Auth>,
 }
 
 impl fmt::Debug for Git2 {
@@ -43,14 +120,7 @@ impl fmt::Debug for Git2 {
 
 impl Git2 {
     fn rev_parse(&self, git_ref: &str) -> Result<Oid> {
-        let spec = self.repo.revparse(git_ref)?;
-
-        if !spec.mode().contains(git2::RevparseMode::SINGLE) {
-            bail!("bad rev spec");
-        }
-
-        let from = spec.from().ok_or_else(|| anyhow!("missing `from`"))?;
-        Ok(from.id())
+        Ok(self.repo.revparse_single(git_ref)?.peel_to_commit()?.id())
     }
 
     /// Find the merge base between two commits.
@@ -58,15 +128,87 @@ impl Git2 {
         Ok(self.repo.merge_base(a, b)?)
     }
 
-    /// Get the current head branch.
-    fn head_branch(&self) -> Result<String> {
-        Ok(self
-            .repo
-            .head()?
-            .name()
-            .ok_or_else(|| anyhow!("could not find HEAD"))?
-            .to_owned())
+    /// Get the current head branch, if HEAD is not detached.
+    fn head_branch(&self) -> Result<Option<String>> {
+        if self.repo.head_detached()? {
+            return Ok(None);
+        }
+
+        let head = self.repo.head()?;
+        Ok(head.shorthand().map(String::from))
+    }
+
+    /// Test if we need to switch to the given branch.
+    fn needs_switch(&self, revision: Revision<'_>) -> Result<Option<String>> {
+        match revision {
+            Revision::Branch(branch) if self.head_branch()?.as_deref() != Some(branch) => {
+                Ok(Some(branch.to_string()))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// The commit that we fetched for the given revision.
+    fn fetched(&self, revision: Revision<'_>) -> Result<Oid> {
+        match revision {
+            // NB: prefer remote branches, since local ones might be out of date.
+            Revision::Ref(git_ref) => self
+                .rev_parse(&format!("origin/{}", git_ref))
+                .or_else(|_| self.rev_parse(git_ref)),
+            _ => self.rev_parse(&format!("refs/remotes/origin/{}", self.branch(revision)?)),
+        }
+    }
+
+    /// The branch to track for the given revision.
+    fn branch(&self, revision: Revision<'_>) -> Result<String> {
+        match revision {
+            Revision::Head => match self.head_branch()? {
+                Some(branch) => Ok(branch),
+                None => Err(GitError::Detached.into()),
+            },
+            Revision::Branch(branch) => Ok(branch.to_string()),
+            Revision::Ref(git_ref) => Err(GitError::NotBranch(git_ref.to_string()).into()),
+        }
     }
+
+    /// Check out the given commit as a detached HEAD.
+    fn checkout_detached(&self, oid: Oid, force: bool) -> Result<()> {
+        let commit = self.repo.find_object(oid, Some(ObjectType::Commit))?;
+        self.repo
+            .checkout_tree(&commit, Some(&mut checkout(force)))?;
+        self.repo.set_head_detached(oid)?;
+        Ok(())
+    }
+
+    /// Switch to the given branch, creating it at the given commit if it doesn't exist.
+    fn switch(&self, branch: &str, oid: Oid, force: bool) -> Result<()> {
+        let exists = self.repo.find_branch(branch, BranchType::Local).is_ok();
+
+        if force || !exists {
+            let commit = self.repo.find_commit(oid)?;
+            self.repo.branch(branch, &commit, force)?;
+        }
+
+        let name = format!("refs/heads/{}", branch);
+        let target = self.repo.revparse_single(&name)?;
+        self.repo
+            .checkout_tree(&target, Some(&mut checkout(force)))?;
+        self.repo.set_head(&name)?;
+        Ok(())
+    }
+}
+
+/// Construct checkout options, optionally discarding local modifications.
+fn checkout(force: bool) -> CheckoutBuilder<'static> {
+    let mut builder = CheckoutBuilder::new();
+
+    if force {
+        builder.force();
+    } else {
+        builder.safe();
+    }
+
+    builder
 }
 
 impl super::Git for Git2 {
@@ -74,43 +216,235 @@ impl super::Git for Git2 {
         &self.path
     }
 
-    fn needs_update(&self) -> Result<bool> {
-        let head_branch = self.head_branch()?;
+    fn needs_update(&self, revision: Revision<'_>) -> Result<bool> {
+        if revision == Revision::Head && self.repo.head_detached()? {
+            log::warn!(
+                "Not updating `{}`: {}",
+                self.path.display(),
+                GitError::Detached
+            );
+            return Ok(false);
+        }
 
         let mut remote = self.repo.find_remote("origin")?;
-        remote.fetch(&[head_branch.as_str()], None, None)?;
+
+        let refspec = match revision {
+            // NB: fetch everything, since we can't tell if the ref is a tag or a commit.
+            Revision::Ref(..) => String::new(),
+            revision => {
+                let branch = self.branch(revision)?;
+                format!("+refs/heads/{0}:refs/remotes/origin/{0}", branch)
+            }
+        };
+
+        let mut options = fetch_options(&self.auth);
+        options.download_tags(AutotagOption::All);
+
+        if refspec.is_empty() {
+            remote.fetch::<&str>(&[], Some(&mut options), None)?;
+        } else {
+            remote.fetch(&[refspec.as_str()], Some(&mut options), None)?;
+        }
 
         let head = self.rev_parse("HEAD")?;
-        let fetch_head = self.rev_parse("FETCH_HEAD")?;
+        let fetched = self.fetched(revision)?;
+
+        if let Revision::Ref(..) = revision {
+            return Ok(fetched != head);
+        }
 
-        if fetch_head != head {
+        if self.needs_switch(revision)?.is_some() {
+            return Ok(true);
+        }
+
+        if fetched != head {
             // check if remote is a base
-            return Ok(self.merge_base(fetch_head, head)? != fetch_head);
+            return Ok(self.merge_base(fetched, head)? != fetched);
         }
 
         Ok(false)
     }
 
+    fn commits_behind(&self, revision: Revision<'_>) -> Result<usize> {
+        let mut walk = self.repo.revwalk()?;
+        walk.push(self.fetched(revision)?)?;
+        walk.hide(self.rev_parse("HEAD")?)?;
+        Ok(walk.count())
+    }
+
     fn is_fresh(&self) -> Result<bool> {
         let diff = self.repo.diff_index_to_workdir(None, None)?;
         Ok(diff.deltas().next().is_none())
     }
 
-    fn force_update(&self) -> Result<()> {
-        let fetch_head = self.rev_parse("FETCH_HEAD")?;
-        let fetch_head = self
+    fn dirty_files(&self) -> Result<Vec<String>> {
+        let mut options = StatusOptions::new();
+        options.include_untracked(false).include_ignored(false);
+
+        let statuses = self.repo.statuses(Some(&mut options))?;
+
+        Ok(statuses
+            .iter()
+            .filter(|entry| entry.status() != Status::CURRENT)
+            .filter_map(|entry| entry.path().map(String::from))
+            .collect())
+    }
+
+    fn stash(&self) -> Result<()> {
+        // NB: stashing requires a mutable repository.
+        let mut repo = Repository::open(&self.path)?;
+
+        let signature = repo
+            .signature()
+            .or_else(|_| Signature::now("quickcfg", "quickcfg@localhost"))?;
+
+        repo.stash_save(&signature, "quickcfg: local changes", None)?;
+        Ok(())
+    }
+
+    fn stash_pop(&self) -> Result<()> {
+        let mut repo = Repository::open(&self.path)?;
+        repo.stash_pop(0, None)?;
+        Ok(())
+    }
+
+    fn stage_all(&self) -> Result<Vec<String>> {
+        let mut index = self.repo.index()?;
+
+        let mut skip_state = |path: &Path, _: &[u8]| {
+            if STATE_PATHS.iter().any(|state| path.starts_with(state)) {
+                1
+            } else {
+                0
+            }
+        };
+
+        index.add_all(["*"].iter(), IndexAddOption::DEFAULT, Some(&mut skip_state))?;
+        index.update_all(["*"].iter(), Some(&mut skip_state))?;
+        index.write()?;
+
+        let head = self.repo.head()?.peel_to_tree()?;
+        let diff = self
+            .repo
+            .diff_tree_to_index(Some(&head), Some(&index), None)?;
+
+        Ok(diff
+            .deltas()
+            .filter_map(|delta| {
+                delta
+                    .new_file()
+                    .path()
+                    .or_else(|| delta.old_file().path())
+                    .map(|path| path.to_string_lossy().into_owned())
+            })
+            .collect())
+    }
+
+    fn commit(&self, message: &str) -> Result<()> {
+        let signature = self
             .repo
-            .find_object(fetch_head, Some(ObjectType::Commit))?;
-        self.repo.reset(&fetch_head, ResetType::Hard, None)?;
+            .signature()
+            .or_else(|_| Signature::now("quickcfg", "quickcfg@localhost"))?;
+
+        let tree = self.repo.find_tree(self.repo.index()?.write_tree()?)?;
+        let parent = self.repo.head()?.peel_to_commit()?;
+
+        self.repo.commit(
+            Some("HEAD"),
+            &signature,
+            &signature,
+            message,
+            &tree,
+            &[&parent],
+        )?;
+
+        Ok(())
+    }
+
+    fn push(&self) -> Result<()> {
+        let branch = self.branch(Revision::Head)?;
+        let refspec = format!("refs/heads/{0}:refs/heads/{0}", branch);
+
+        let mut callbacks = remote_callbacks(&self.auth);
+
+        callbacks.push_update_reference(|name, status| match status {
+            Some(status) => Err(git2::Error::from_str(&format!(
+                "failed to push {}: {}",
+                name, status
+            ))),
+            None => Ok(()),
+        });
+
+        let mut options = PushOptions::new();
+        options.remote_callbacks(callbacks);
+
+        let mut remote = self.repo.find_remote("origin")?;
+        remote.push(&[refspec.as_str()], Some(&mut options))?;
         Ok(())
     }
 
-    fn update(&self) -> Result<()> {
-        // NB: needs --ff-only
-        let fetch_head = self
+    fn force_update(&self, revision: Revision<'_>) -> Result<()> {
+        let fetched = self.fetched(revision)?;
+
+        if let Revision::Ref(..) = revision {
+            return self.checkout_detached(fetched, true);
+        }
+
+        if let Some(branch) = self.needs_switch(revision)? {
+            return self.switch(&branch, fetched, true);
+        }
+
+        let fetched = self.repo.find_object(fetched, Some(ObjectType::Commit))?;
+        self.repo.reset(&fetched, ResetType::Hard, None)?;
+        Ok(())
+    }
+
+    fn update(&self, revision: Revision<'_>) -> Result<()> {
+        let fetched = self.fetched(revision)?;
+
+        if let Revision::Ref(..) = revision {
+            return self.checkout_detached(fetched, false);
+        }
+
+        if let Some(branch) = self.needs_switch(revision)? {
+            self.switch(&branch, fetched, false)?;
+        }
+
+        let fetched = self.repo.find_annotated_commit(fetched)?;
+        let (analysis, _) = self.repo.merge_analysis(&[&fetched])?;
+
+        if analysis.is_up_to_date() {
+            return Ok(());
+        }
+
+        // NB: same as `git merge --ff-only`.
+        if !analysis.is_fast_forward() {
+            return Err(GitError::NotFastForward(fetched.id().to_string()).into());
+        }
+
+        let commit = self
             .repo
-            .find_annotated_commit(self.rev_parse("FETCH_HEAD")?)?;
-        self.repo.merge(&[&fetch_head], None, None)?;
+            .find_object(fetched.id(), Some(ObjectType::Commit))?;
+        self.repo
+            .checkout_tree(&commit, Some(&mut checkout(false)))?;
+
+        let mut head = self.repo.head()?;
+        head.set_target(fetched.id(), "quickcfg: fast-forward")?;
         Ok(())
     }
+
+    fn update_submodules(&self) -> Result<()> {
+        return update_submodules(&self.repo, &self.auth);
+
+        fn update_submodules(repo: &Repository, auth: &Auth) -> Result<()> {
+            for mut submodule in repo.submodules()? {
+                let mut options = SubmoduleUpdateOptions::new();
+                options.fetch(fetch_options(auth));
+                submodule.update(true, Some(&mut options))?;
+                update_submodules(&submodule.open()?, auth)?;
+            }
+
+            Ok(())
+        }
+    }
 }
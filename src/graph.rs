@@ -0,0 +1,165 @@
+//! Export of the unit dependency graph, for debugging how units are scheduled.
+
+use crate::unit::{Dependency, SystemUnit, UnitId};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fmt::Write as _;
+
+/// Format to export the graph in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    /// Graphviz DOT.
+    Dot,
+    /// Mermaid flowchart.
+    Mermaid,
+}
+
+/// Render the dependency graph of the given units.
+///
+/// Units are grouped by the system they belong to, and edges point from a unit to the units that
+/// depend on it.
+pub fn render(units: &[SystemUnit], format: Format) -> String {
+    let mut providers = HashMap::<Dependency, Vec<UnitId>>::new();
+
+    for unit in units {
+        providers
+            .entry(Dependency::Unit(unit.id))
+            .or_default()
+            .push(unit.id);
+
+        for provided in &unit.provides {
+            providers.entry(*provided).or_default().push(unit.id);
+        }
+    }
+
+    let mut systems = BTreeMap::<Option<&str>, Vec<&SystemUnit>>::new();
+    let mut edges = BTreeSet::new();
+
+    for unit in units {
+        systems.entry(unit.system_label()).or_default().push(unit);
+
+        for dependency in &unit.dependencies {
+            // NB: dependencies on paths which already exist have no provider.
+            for provider in providers.get(dependency).into_iter().flatten() {
+                edges.insert((*provider, unit.id));
+            }
+        }
+    }
+
+    for units in systems.values_mut() {
+        units.sort_by_key(|unit| unit.id);
+    }
+
+    let mut out = String::new();
+
+    match format {
+        Format::Dot => {
+            out.push_str("digraph quickcfg {\n");
+
+            for (i, (system, units)) in systems.iter().enumerate() {
+                let indent = match system {
+                    Some(system) => {
+                        let _ = writeln!(out, "  subgraph cluster_{} {{", i);
+                        let _ = writeln!(out, "    label = \"{}\";", escape_dot(system));
+                        "    "
+                    }
+                    None => "  ",
+                };
+
+                for unit in units {
+                    let label = escape_dot(&unit.describe());
+                    let _ = writeln!(out, "{}u{} [label=\"{}\"];", indent, unit.id, label);
+                }
+
+                if system.is_some() {
+                    out.push_str("  }\n");
+                }
+            }
+
+            for (from, to) in &edges {
+                let _ = writeln!(out, "  u{} -> u{};", from, to);
+            }
+
+            out.push_str("}\n");
+        }
+        Format::Mermaid => {
+            out.push_str("flowchart TD\n");
+
+            for (i, (system, units)) in systems.iter().enumerate() {
+                let indent = match system {
+                    Some(system) => {
+                        let _ = writeln!(out, "  subgraph s{} [\"{}\"]", i, escape_mermaid(system));
+                        "    "
+                    }
+                    None => "  ",
+                };
+
+                for unit in units {
+                    let label = escape_mermaid(&unit.describe());
+                    let _ = writeln!(out, "{}u{}[\"{}\"]", indent, unit.id, label);
+                }
+
+                if system.is_some() {
+                    out.push_str("  end\n");
+                }
+            }
+
+            for (from, to) in &edges {
+                let _ = writeln!(out, "  u{} --> u{}", from, to);
+            }
+        }
+    }
+
+    out
+}
+
+/// Escape a string for use in a quoted DOT label.
+fn escape_dot(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+/// Escape a string for use in a quoted mermaid label.
+fn escape_mermaid(s: &str) -> String {
+    s.replace('"', "#quot;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{render, Format};
+    use crate::unit::{CreateDir, Dependency, SystemUnit};
+    use std::path::PathBuf;
+
+    #[test]
+    fn test_render() {
+        let mut dir = SystemUnit::new(0, CreateDir(PathBuf::from("home/.config")));
+        dir.system = Some(String::from("dotfiles"));
+        dir.provides.push(Dependency::Dir(0));
+
+        let mut sub = SystemUnit::new(1, CreateDir(PathBuf::from("home/.config/\"sub\"")));
+        sub.dependencies.push(Dependency::Dir(0));
+        sub.dependencies.push(Dependency::File(42));
+
+        let units = vec![dir, sub];
+
+        assert_eq!(
+            render(&units, Format::Dot),
+            "digraph quickcfg {\n\
+             \x20 u1 [label=\"create directory home/.config/\\\"sub\\\"\"];\n\
+             \x20 subgraph cluster_1 {\n\
+             \x20   label = \"dotfiles\";\n\
+             \x20   u0 [label=\"create directory home/.config\"];\n\
+             \x20 }\n\
+             \x20 u0 -> u1;\n\
+             }\n"
+        );
+
+        assert_eq!(
+            render(&units, Format::Mermaid),
+            "flowchart TD\n\
+             \x20 u1[\"create directory home/.config/#quot;sub#quot;\"]\n\
+             \x20 subgraph s1 [\"dotfiles\"]\n\
+             \x20   u0[\"create directory home/.config\"]\n\
+             \x20 end\n\
+             \x20 u0 --> u1\n"
+        );
+    }
+}
@@ -1,22 +1,48 @@
 //! Dealing with the hierarchy of data.
 use crate::{environment as e, facts::Facts, Template};
-use anyhow::{anyhow, bail, Error};
+use anyhow::Error;
+use handlebars::JsonValue;
 use serde::Deserialize;
 use serde_yaml::{Mapping, Value};
 use std::env;
 use std::fs::File;
 use std::io;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::time::SystemTime;
 
 const HEADER: &str = "quickcfg:";
 
+/// Errors raised when loading data from the hierarchy.
+#[derive(Debug, thiserror::Error)]
+pub enum HierarchyError {
+    /// A file in the hierarchy couldn't be opened.
+    #[error("failed to open: {}", .0.display())]
+    Open(PathBuf, #[source] io::Error),
+    /// A file in the hierarchy isn't valid YAML.
+    #[error("failed to load: {}", .0.display())]
+    Parse(PathBuf, #[source] serde_yaml::Error),
+    /// A file in the hierarchy isn't a mapping.
+    #[error("failed to load: {}: exists, but is not a mapping", .0.display())]
+    NotMapping(PathBuf),
+    /// A key requested by a file header is missing.
+    #[error("missing key `{0}` in hierarchy")]
+    MissingKey(String),
+    /// A part of a file header is malformed.
+    #[error("bad part in specification `{part}`: {reason}")]
+    BadSpec { part: String, reason: String },
+    /// An environment variable requested by a file header couldn't be loaded.
+    #[error("failed to load environment variable `{0}`")]
+    Env(String, #[source] env::VarError),
+}
+
 /// Wrapper for hierarchy data.
 pub struct Data {
     /// The last modification timestamp for a file in the hierarchy.
     pub last_modified: Option<SystemTime>,
     /// The hierarchy with data.
     hierarchy: Vec<Mapping>,
+    /// The files that each layer of the hierarchy was loaded from, if known.
+    paths: Vec<PathBuf>,
 }
 
 impl Data {
@@ -25,7 +51,39 @@ impl Data {
         Data {
             last_modified,
             hierarchy: data.into_iter().collect(),
+            paths: Vec::new(),
+        }
+    }
+
+    /// Add the layers of another hierarchy, with lower precedence than the existing ones.
+    pub fn extend(&mut self, other: Data) {
+        self.last_modified = match (self.last_modified, other.last_modified) {
+            (Some(a), Some(b)) => Some(a.max(b)),
+            (a, b) => a.or(b),
+        };
+
+        // NB: paths are only tracked if they are known for every layer.
+        if self.paths.len() == self.hierarchy.len() && other.paths.len() == other.hierarchy.len() {
+            self.paths.extend(other.paths);
+        } else {
+            self.paths.clear();
+        }
+
+        self.hierarchy.extend(other.hierarchy);
+    }
+
+    /// Find every layer which defines the given key, together with the file it was loaded from.
+    pub fn sources<'a>(&'a self, key: &str) -> Vec<(Option<&'a Path>, &'a Value)> {
+        let key = Value::String(key.to_string());
+        let mut out = Vec::new();
+
+        for (index, m) in self.hierarchy.iter().enumerate() {
+            if let Some(value) = m.get(&key) {
+                out.push((self.paths.get(index).map(PathBuf::as_path), value));
+            }
         }
+
+        out
     }
 
     /// Load the given key.
@@ -70,6 +128,21 @@ impl Data {
         Ok(out)
     }
 
+    /// Merge all layers of the hierarchy into a single JSON value.
+    ///
+    /// Keys in earlier layers take precedence, like with [`Data::load`].
+    pub fn to_json(&self) -> JsonValue {
+        let mut object = serde_json::Map::new();
+
+        for m in self.hierarchy.iter().rev() {
+            if let JsonValue::Object(m) = handlebars::to_json(m) {
+                object.extend(m);
+            }
+        }
+
+        JsonValue::Object(object)
+    }
+
     /// Load data based on a file spec.
     /// This is typically in the first couple of lines in a file.
     pub fn load_from_spec(&self, content: &str) -> Result<Mapping, Error> {
@@ -95,24 +168,32 @@ impl Data {
 
                 let key = match it.next() {
                     Some(key) => key,
-                    None => bail!("bad part in specification `{}`: missing key", part),
+                    None => {
+                        return Err(HierarchyError::BadSpec {
+                            part: part.to_string(),
+                            reason: String::from("missing key"),
+                        }
+                        .into())
+                    }
                 };
 
                 let value = match it.next() {
                     Some("array") => Value::Sequence(self.load_array::<Value>(key)?),
                     Some("env") => {
-                        let value = match env::var(key) {
-                            Ok(value) => value,
-                            Err(e) => bail!("failed to load environment variable `{}`: {}", key, e),
-                        };
+                        let value =
+                            env::var(key).map_err(|e| HierarchyError::Env(key.to_string(), e))?;
 
                         Value::String(value)
                     }
                     None => self
                         .load::<Value>(key)?
-                        .ok_or_else(|| anyhow!("missing key `{}` in hierarchy", key))?,
+                        .ok_or_else(|| HierarchyError::MissingKey(key.to_string()))?,
                     Some(other) => {
-                        bail!("bad part in specification `{}`: bad type `{}`", part, other);
+                        return Err(HierarchyError::BadSpec {
+                            part: part.to_string(),
+                            reason: format!("bad type `{}`", other),
+                        }
+                        .into());
                     }
                 };
 
@@ -131,9 +212,10 @@ pub fn load<'a>(
     it: impl IntoIterator<Item = &'a Template>,
     root: &Path,
     facts: &Facts,
-    environment: impl Copy + e::Environment,
+    environment: impl e::Environment,
 ) -> Result<Data, Error> {
     let mut stages = Vec::new();
+    let mut paths = Vec::new();
     let mut last_modified = None;
 
     for h in it {
@@ -162,33 +244,31 @@ pub fn load<'a>(
             _ => modified,
         });
 
-        let map = load_mapping(&path)
-            .map_err(|e| anyhow!("failed to load: {}: {}", path.display(), e))?;
+        let map = load_mapping(&path)?;
 
         stages.push(map);
+        paths.push(path);
     }
 
-    return Ok(Data::new(last_modified, stages));
+    let mut data = Data::new(last_modified, stages);
+    data.paths = paths;
+    return Ok(data);
 
     /// Extend the existing mapping from the given hierarchy.
-    fn load_mapping(path: &Path) -> Result<serde_yaml::Mapping, Error> {
-        let file = match File::open(&path) {
-            Ok(file) => file,
-            Err(e) => match e.kind() {
-                _ => bail!("failed to open file: {}", e),
-            },
-        };
+    fn load_mapping(path: &Path) -> Result<serde_yaml::Mapping, HierarchyError> {
+        let file = File::open(path).map_err(|e| HierarchyError::Open(path.to_owned(), e))?;
 
-        match serde_yaml::from_reader(file)? {
-            Value::Mapping(m) => Ok(m),
-            _ => bail!("exists, but is not a mapping"),
+        match serde_yaml::from_reader(file) {
+            Ok(Value::Mapping(m)) => Ok(m),
+            Ok(..) => Err(HierarchyError::NotMapping(path.to_owned())),
+            Err(e) => Err(HierarchyError::Parse(path.to_owned(), e)),
         }
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::Data;
+    use super::{Data, HierarchyError};
     use serde_yaml::{Mapping, Value};
 
     #[test]
@@ -224,5 +304,57 @@ mod tests {
                 .expect("merged array from layers"),
             vec![String::from("item1"), String::from("item2")],
         );
+
+        let mut overlay = Data::new(None, vec![]);
+        let mut layer3 = Mapping::new();
+        layer3.insert("foo".into(), "base value".into());
+        overlay.extend(Data::new(None, vec![layer3]));
+        overlay.extend(data);
+
+        assert_eq!(
+            overlay
+                .load::<String>("foo")
+                .expect("extended key as string"),
+            Some("base value".into()),
+        );
+
+        let data = overlay;
+        let sources = data.sources("seq");
+        assert_eq!(sources.len(), 2);
+        assert_eq!(sources[0], (None, &Value::from(vec![Value::from("item1")])));
+        assert!(data.sources("missing").is_empty());
+    }
+
+    #[test]
+    fn test_to_json() {
+        let mut layer1 = Mapping::new();
+        let mut layer2 = Mapping::new();
+
+        layer1.insert("foo".into(), "foo value".into());
+        layer2.insert("foo".into(), "overridden".into());
+        layer2.insert("bar".into(), vec![Value::from("item")].into());
+
+        let data = Data::new(None, vec![layer1, layer2]);
+        let json = data.to_json();
+
+        assert_eq!(json["foo"], "foo value");
+        assert_eq!(json["bar"][0], "item");
+    }
+
+    #[test]
+    fn test_load_from_spec_errors() {
+        let data = Data::new(None, vec![]);
+
+        let e = data.load_from_spec("# quickcfg: missing").unwrap_err();
+        assert!(matches!(
+            e.downcast_ref::<HierarchyError>(),
+            Some(HierarchyError::MissingKey(key)) if key == "missing"
+        ));
+
+        let e = data.load_from_spec("# quickcfg: foo:bar").unwrap_err();
+        assert!(matches!(
+            e.downcast_ref::<HierarchyError>(),
+            Some(HierarchyError::BadSpec { part, .. }) if part == "foo:bar"
+        ));
     }
 }
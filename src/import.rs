@@ -0,0 +1,891 @@
+//! Import configuration from other dotfile managers, by generating the equivalent systems.
+
+use anyhow::{anyhow, bail, Context as _, Error};
+use serde::Serialize;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Directory in the configuration repository where files that need to be translated by hand are
+/// copied to when importing from chezmoi.
+const CHEZMOI_TODO: &str = "chezmoi-todo";
+
+/// Everything generated by an importer.
+#[derive(Debug, Default)]
+pub struct Import {
+    /// Systems to add to the configuration.
+    pub systems: Vec<Imported>,
+    /// Files to copy into the configuration repository.
+    pub files: Vec<ImportedFile>,
+    /// Files to add to the hierarchy.
+    pub hierarchy: Vec<String>,
+}
+
+impl Import {
+    /// Test if nothing was imported.
+    pub fn is_empty(&self) -> bool {
+        self.systems.is_empty() && self.files.is_empty() && self.hierarchy.is_empty()
+    }
+
+    /// Copy imported files into the given configuration repository.
+    ///
+    /// Nothing is copied if any of the files already exist.
+    pub fn copy_files(&self, root: &Path) -> Result<(), Error> {
+        for file in &self.files {
+            let to = root.join(&file.to);
+
+            if to.exists() {
+                bail!("Cannot import, file already exists: {}", to.display());
+            }
+        }
+
+        for file in &self.files {
+            let to = root.join(&file.to);
+
+            if let Some(parent) = to.parent() {
+                fs::create_dir_all(parent)?;
+            }
+
+            fs::copy(&file.from, &to).with_context(|| {
+                anyhow!("Failed to copy {} to {}", file.from.display(), to.display())
+            })?;
+
+            #[cfg(unix)]
+            {
+                use std::os::unix::fs::PermissionsExt as _;
+
+                if let Some(mode) = file.mode {
+                    fs::set_permissions(&to, fs::Permissions::from_mode(mode))?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Add the imported systems and hierarchy to the given configuration.
+    pub fn apply(&self, config: &str) -> Result<String, Error> {
+        let mut config = config.to_string();
+
+        for path in &self.hierarchy {
+            config = add_hierarchy(&config, path)?;
+        }
+
+        if !self.systems.is_empty() {
+            config = append(&config, &self.systems)?;
+        }
+
+        Ok(config)
+    }
+}
+
+/// A file to copy into the configuration repository.
+#[derive(Debug, PartialEq, Eq)]
+pub struct ImportedFile {
+    /// The file to copy.
+    pub from: PathBuf,
+    /// Where to copy it, relative to the root of the configuration repository.
+    pub to: String,
+    /// Permissions to set on the copied file.
+    pub mode: Option<u32>,
+}
+
+/// A system generated by an importer.
+#[derive(Serialize, Debug, PartialEq, Eq)]
+#[serde(tag = "type", rename_all = "kebab-case")]
+pub enum Imported {
+    /// Copy every file in a directory.
+    CopyDir {
+        name: String,
+        from: String,
+        to: String,
+    },
+    /// Link every file in a directory.
+    LinkDir {
+        name: String,
+        from: String,
+        to: String,
+    },
+    /// Link a single file.
+    Link {
+        name: String,
+        path: String,
+        link: String,
+    },
+}
+
+/// Options for importing a directory of GNU stow packages.
+pub struct Stow<'a> {
+    /// The stow directory, where each subdirectory is a package.
+    pub dir: &'a Path,
+    /// Where packages are linked to, like `home://`.
+    pub target: &'a str,
+    /// Rename files prefixed with `dot-` to start with a dot, like `stow --dotfiles`.
+    pub dotfiles: bool,
+}
+
+/// Generate systems which link every package in a stow directory, the same way stow would.
+///
+/// A package becomes a single `link-dir` system, unless it contains files which stow would ignore
+/// or rename. Then the affected directories are split up into one system for each entry.
+pub fn stow(root: &Path, stow: &Stow<'_>) -> Result<Import, Error> {
+    if !stow.dir.is_dir() {
+        bail!("Not a directory: {}", stow.dir.display());
+    }
+
+    let from = config_path(root, stow.dir)?;
+    let mut systems = Vec::new();
+
+    for (name, path) in entries(stow.dir)? {
+        // NB: stow ignores files and hidden directories next to the packages.
+        if name.starts_with('.') || !path.is_dir() {
+            continue;
+        }
+
+        if path.join(".stow-local-ignore").is_file() {
+            log::warn!(
+                "{}: .stow-local-ignore is not supported, using the default ignore list",
+                name
+            );
+        }
+
+        let importer = StowPackage {
+            name: format!("stow {}", name),
+            dotfiles: stow.dotfiles,
+        };
+
+        importer.walk(
+            &path,
+            join(&from, &name),
+            stow.target.to_string(),
+            true,
+            &mut systems,
+        )?;
+    }
+
+    Ok(Import {
+        systems,
+        ..Import::default()
+    })
+}
+
+/// A single stow package being imported.
+struct StowPackage {
+    name: String,
+    dotfiles: bool,
+}
+
+impl StowPackage {
+    /// Generate systems for the given directory of the package.
+    fn walk(
+        &self,
+        dir: &Path,
+        from: String,
+        to: String,
+        top: bool,
+        systems: &mut Vec<Imported>,
+    ) -> Result<(), Error> {
+        if !self.needs_split(dir, top)? {
+            systems.push(Imported::LinkDir {
+                name: self.name.clone(),
+                from,
+                to,
+            });
+
+            return Ok(());
+        }
+
+        for (name, path) in entries(dir)? {
+            if is_stow_ignored(&name, top) {
+                continue;
+            }
+
+            let from = join(&from, &name);
+            let to = join(&to, &self.target_name(&name));
+
+            if path.is_dir() {
+                self.walk(&path, from, to, false, systems)?;
+            } else {
+                systems.push(Imported::Link {
+                    name: self.name.clone(),
+                    path: to,
+                    link: from,
+                });
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Test if anything in the given directory is ignored or renamed, so that it can't be linked
+    /// with a single system.
+    fn needs_split(&self, dir: &Path, top: bool) -> Result<bool, Error> {
+        for (name, path) in entries(dir)? {
+            if is_stow_ignored(&name, top) || self.target_name(&name) != name {
+                return Ok(true);
+            }
+
+            if path.is_dir() && self.needs_split(&path, false)? {
+                return Ok(true);
+            }
+        }
+
+        Ok(false)
+    }
+
+    /// The name that the given file is linked as.
+    fn target_name(&self, name: &str) -> String {
+        match name.strip_prefix("dot-") {
+            Some(rest) if self.dotfiles => format!(".{}", rest),
+            _ => name.to_string(),
+        }
+    }
+}
+
+/// Test if the given file name is in the default ignore list of stow.
+fn is_stow_ignored(name: &str, top: bool) -> bool {
+    const IGNORED: &[&str] = &[
+        "RCS",
+        "CVS",
+        ".cvsignore",
+        ".svn",
+        "_darcs",
+        ".hg",
+        ".git",
+        ".gitignore",
+        ".gitmodules",
+        ".stow-local-ignore",
+    ];
+
+    if IGNORED.contains(&name)
+        || name.ends_with(",v")
+        || name.starts_with(".#")
+        || name.ends_with('~')
+        || (name.len() > 1 && name.starts_with('#') && name.ends_with('#'))
+    {
+        return true;
+    }
+
+    // NB: these are only ignored at the top of a package.
+    top && (name.starts_with("README") || name.starts_with("LICENSE") || name == "COPYING")
+}
+
+/// Options for importing the source state of chezmoi.
+pub struct Chezmoi<'a> {
+    /// The source directory, like `~/.local/share/chezmoi`.
+    pub source: &'a Path,
+    /// Directory in the configuration repository to copy files to.
+    pub dest: &'a str,
+}
+
+/// Translate the source state of chezmoi into a directory which is copied to the home directory.
+///
+/// Templates, encrypted files, scripts, and files which modify existing files can't be translated.
+/// They are copied to a separate directory, so that they can be translated by hand.
+pub fn chezmoi(chezmoi: &Chezmoi<'_>) -> Result<Import, Error> {
+    if !chezmoi.source.is_dir() {
+        bail!("Not a directory: {}", chezmoi.source.display());
+    }
+
+    let mut source = chezmoi.source.to_owned();
+
+    if let Some(meta) = crate::FileSystem::try_open_meta(&source.join(".chezmoiroot"))? {
+        if meta.is_file() {
+            let sub = fs::read_to_string(source.join(".chezmoiroot"))?;
+            source = source.join(sub.trim());
+        }
+    }
+
+    let mut importer = ChezmoiImporter {
+        dest: chezmoi.dest,
+        import: Import::default(),
+    };
+
+    importer.data(&source)?;
+    importer.walk(&source, "", "", true)?;
+
+    let mut import = importer.import;
+
+    let dest = format!("{}/", chezmoi.dest.trim_end_matches('/'));
+
+    if import.files.iter().any(|f| f.to.starts_with(&dest)) {
+        import.systems.insert(
+            0,
+            Imported::CopyDir {
+                name: String::from("chezmoi"),
+                from: chezmoi.dest.to_string(),
+                to: String::from("home://"),
+            },
+        );
+    }
+
+    Ok(import)
+}
+
+/// The state of importing from chezmoi.
+struct ChezmoiImporter<'a> {
+    dest: &'a str,
+    import: Import,
+}
+
+impl ChezmoiImporter<'_> {
+    /// Import data used by templates into the hierarchy.
+    fn data(&mut self, source: &Path) -> Result<(), Error> {
+        let mut files = Vec::new();
+
+        for (name, path) in entries(source)? {
+            if name.starts_with(".chezmoidata.") {
+                files.push((String::from("chezmoi"), name, path));
+            } else if name == ".chezmoidata" && path.is_dir() {
+                for (name, path) in entries(&path)? {
+                    let stem = name.split('.').next().unwrap_or_default();
+                    let to = format!("chezmoi-{}", stem);
+                    files.push((to, format!(".chezmoidata/{}", name), path));
+                }
+            }
+        }
+
+        for (to, name, path) in files {
+            if name.ends_with(".yaml") || name.ends_with(".yml") || name.ends_with(".json") {
+                // NB: JSON is valid YAML, so it can be used as-is.
+                let to = format!("db/{}.yml", to);
+                self.copy(&path, to.clone(), None);
+                self.import.hierarchy.push(to);
+            } else {
+                log::warn!("{}: only YAML and JSON data can be imported", name);
+                self.todo(&path, &name);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Import the given directory of the source state.
+    ///
+    /// `source` is the path of the directory relative to the source state, and `target` where
+    /// it ends up relative to the home directory.
+    fn walk(&mut self, dir: &Path, source: &str, target: &str, top: bool) -> Result<(), Error> {
+        for (name, path) in entries(dir)? {
+            let source = join(source, &name);
+
+            if name.starts_with('.') {
+                match name.as_str() {
+                    ".chezmoiscripts" if top => {
+                        for (name, path) in entries(&path)? {
+                            let source = join(&source, &name);
+                            log::warn!("{}: scripts need to be run by hand", source);
+                            self.todo(&path, &source);
+                        }
+                    }
+                    ".chezmoiignore" | ".chezmoitemplates" | ".chezmoiremove" => {
+                        log::warn!("{}: not supported", source);
+                    }
+                    _ if name.starts_with(".chezmoiexternal") => {
+                        log::warn!("{}: not supported", source);
+                    }
+                    // NB: chezmoi ignores other hidden files, like `.git`.
+                    _ => (),
+                }
+
+                continue;
+            }
+
+            let attributes = ChezmoiAttributes::parse(&name);
+            let target = join(target, &attributes.name);
+
+            if path.is_dir() {
+                if attributes.has("remove_") || attributes.has("external_") {
+                    log::warn!("{}: not supported", source);
+                    continue;
+                }
+
+                if attributes.has("exact_") {
+                    log::warn!("{}: files which aren't managed are not removed", source);
+                }
+
+                self.walk(&path, &source, &target, false)?;
+                continue;
+            }
+
+            if attributes.has("remove_") {
+                log::warn!("{}: removing files is not supported", source);
+            } else if attributes.has("run_") {
+                log::warn!("{}: scripts need to be run by hand", source);
+                self.todo(&path, &source);
+            } else if attributes.has("modify_") {
+                log::warn!("{}: modifying files needs to be translated by hand", source);
+                self.todo(&path, &source);
+            } else if attributes.has("encrypted_") {
+                log::warn!("{}: encrypted files need to be decrypted by hand", source);
+                self.todo(&path, &source);
+            } else if attributes.template {
+                log::warn!("{}: templates need to be translated by hand", source);
+                self.todo(&path, &source);
+            } else if attributes.has("symlink_") {
+                let link = fs::read_to_string(&path)?.trim().to_string();
+
+                // NB: a relative link is relative to the directory of the link.
+                let link = if Path::new(&link).is_absolute() {
+                    link
+                } else {
+                    join(&format!("home://{}", parent(&target)), &link)
+                };
+
+                self.import.systems.push(Imported::Link {
+                    name: String::from("chezmoi"),
+                    path: format!("home://{}", target),
+                    link,
+                });
+            } else {
+                let mut mode = None;
+
+                if attributes.has("executable_") {
+                    mode = Some(0o755);
+                }
+
+                if attributes.has("private_") {
+                    mode = Some(mode.unwrap_or(0o644) & 0o700);
+                }
+
+                if attributes.has("readonly_") {
+                    mode = Some(mode.unwrap_or(0o644) & !0o222);
+                }
+
+                let to = join(self.dest, &target);
+                self.copy(&path, to, mode);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Copy a file into the configuration repository.
+    fn copy(&mut self, from: &Path, to: String, mode: Option<u32>) {
+        self.import.files.push(ImportedFile {
+            from: from.to_owned(),
+            to,
+            mode,
+        });
+    }
+
+    /// Copy a file which needs to be translated by hand.
+    fn todo(&mut self, from: &Path, source: &str) {
+        self.copy(from, join(CHEZMOI_TODO, source), None);
+    }
+}
+
+/// The attributes of a file in the source state of chezmoi, which are encoded in its name.
+struct ChezmoiAttributes {
+    /// Prefixes of the file name.
+    prefixes: Vec<&'static str>,
+    /// If the file is a template.
+    template: bool,
+    /// The name of the file in the home directory.
+    name: String,
+}
+
+impl ChezmoiAttributes {
+    /// Parse the attributes from a file name.
+    fn parse(name: &str) -> Self {
+        const PREFIXES: &[&str] = &[
+            "create_",
+            "dot_",
+            "empty_",
+            "encrypted_",
+            "exact_",
+            "executable_",
+            "external_",
+            "literal_",
+            "modify_",
+            "private_",
+            "readonly_",
+            "remove_",
+            "run_",
+            "symlink_",
+        ];
+
+        // NB: these are only attributes of scripts.
+        const SCRIPT_PREFIXES: &[&str] = &["once_", "onchange_", "before_", "after_"];
+
+        let mut prefixes = Vec::new();
+        let mut rest = name;
+
+        loop {
+            let prefix = PREFIXES
+                .iter()
+                .chain(
+                    SCRIPT_PREFIXES
+                        .iter()
+                        .filter(|_| prefixes.contains(&"run_")),
+                )
+                .find(|p| rest.starts_with(**p));
+
+            let prefix = match prefix {
+                Some(prefix) => *prefix,
+                None => break,
+            };
+
+            rest = &rest[prefix.len()..];
+
+            // NB: `dot_` is the last attribute, and `literal_` stops parsing them.
+            if prefix == "literal_" {
+                break;
+            }
+
+            prefixes.push(prefix);
+
+            if prefix == "dot_" {
+                break;
+            }
+        }
+
+        let mut template = false;
+
+        if let Some(r) = rest.strip_suffix(".literal") {
+            rest = r;
+        } else {
+            if prefixes.contains(&"encrypted_") {
+                rest = rest
+                    .strip_suffix(".age")
+                    .or_else(|| rest.strip_suffix(".asc"))
+                    .unwrap_or(rest);
+            }
+
+            if let Some(r) = rest.strip_suffix(".tmpl") {
+                rest = r;
+                template = true;
+            }
+        }
+
+        let name = if prefixes.contains(&"dot_") {
+            format!(".{}", rest)
+        } else {
+            rest.to_string()
+        };
+
+        ChezmoiAttributes {
+            prefixes,
+            template,
+            name,
+        }
+    }
+
+    /// Test if the file has the given prefix.
+    fn has(&self, prefix: &str) -> bool {
+        self.prefixes.contains(&prefix)
+    }
+}
+
+/// The parent of a path in the configuration.
+fn parent(path: &str) -> &str {
+    match path.rfind('/') {
+        Some(n) => &path[..n],
+        None => "",
+    }
+}
+
+/// The entries of a directory with their names, sorted by name.
+fn entries(dir: &Path) -> Result<Vec<(String, PathBuf)>, Error> {
+    let mut entries = Vec::new();
+
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+
+        let name = path
+            .file_name()
+            .and_then(|n| n.to_str())
+            .ok_or_else(|| anyhow!("Unsupported file name: {}", path.display()))?
+            .to_string();
+
+        entries.push((name, path));
+    }
+
+    entries.sort();
+    Ok(entries)
+}
+
+/// The path to use in the configuration for the given path, which is relative to the root if
+/// it's inside of it.
+fn config_path(root: &Path, path: &Path) -> Result<String, Error> {
+    let root = fs::canonicalize(root)?;
+    let path = fs::canonicalize(path)?;
+
+    let path = match path.strip_prefix(&root) {
+        Ok(relative) => relative,
+        Err(_) => path.as_path(),
+    };
+
+    path.to_str()
+        .map(|p| p.replace('\\', "/"))
+        .ok_or_else(|| anyhow!("Unsupported path: {}", path.display()))
+}
+
+/// Join a file name to a path in the configuration.
+fn join(base: &str, name: &str) -> String {
+    if base.is_empty() || base.ends_with('/') {
+        format!("{}{}", base, name)
+    } else {
+        format!("{}/{}", base, name)
+    }
+}
+
+/// Add the given systems to the end of the `systems` in a configuration.
+///
+/// This edits the configuration as text, so that comments and formatting are preserved. Which is
+/// only possible if `systems` is the last key in it.
+pub fn append(config: &str, systems: &[Imported]) -> Result<String, Error> {
+    let yaml = serde_yaml::to_string(systems)?;
+    let yaml = yaml.strip_prefix("---\n").unwrap_or(&yaml);
+
+    let mut out = config.to_string();
+
+    let last_key = config
+        .lines()
+        .rev()
+        .filter(|l| !l.is_empty())
+        .find(|l| !l.starts_with(|c: char| c.is_whitespace() || c == '#' || c == '-'))
+        .map(str::trim_end);
+
+    if !out.is_empty() && !out.ends_with('\n') {
+        out.push('\n');
+    }
+
+    if config.lines().any(|l| l.starts_with("systems:")) {
+        if last_key != Some("systems:") {
+            bail!("`systems` must be the last key in the configuration to import into it");
+        }
+    } else {
+        out.push_str("systems:\n");
+    }
+
+    for line in yaml.lines() {
+        out.push_str("  ");
+        out.push_str(line);
+        out.push('\n');
+    }
+
+    Ok(out)
+}
+
+/// Add a file to the `hierarchy` of a configuration.
+///
+/// Like [append], this edits the configuration as text, and only supports a `hierarchy` which is
+/// missing, empty, or a list with one item on each line.
+pub fn add_hierarchy(config: &str, path: &str) -> Result<String, Error> {
+    let mut lines = config.lines().map(String::from).collect::<Vec<_>>();
+    let item = format!("  - {}", path);
+
+    match lines.iter().position(|l| l.starts_with("hierarchy:")) {
+        None => {
+            lines.insert(0, String::from("hierarchy:"));
+            lines.insert(1, item);
+
+            if lines.len() > 2 {
+                lines.insert(2, String::new());
+            }
+        }
+        Some(n) => match lines[n].trim_end() {
+            "hierarchy: []" => {
+                lines[n] = String::from("hierarchy:");
+                lines.insert(n + 1, item);
+            }
+            "hierarchy:" => {
+                let mut end = n + 1;
+
+                while end < lines.len() && lines[end].trim_start().starts_with("- ") {
+                    end += 1;
+                }
+
+                lines.insert(end, item);
+            }
+            _ => bail!(
+                "Cannot add to the `hierarchy` in the configuration, add `{}` by hand",
+                path
+            ),
+        },
+    }
+
+    let mut out = lines.join("\n");
+    out.push('\n');
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{add_hierarchy, append, chezmoi, stow, Chezmoi, ChezmoiAttributes, Imported, Stow};
+    use std::fs;
+
+    #[test]
+    fn test_stow() {
+        let root = std::env::temp_dir().join(format!("quickcfg-stow-{}", std::process::id()));
+        let dir = root.join("dotfiles");
+
+        fs::create_dir_all(dir.join("vim/.vim/autoload")).unwrap();
+        fs::write(dir.join("vim/.vimrc"), "").unwrap();
+        fs::write(dir.join("vim/.vim/autoload/plug.vim"), "").unwrap();
+        fs::create_dir_all(dir.join("bash")).unwrap();
+        fs::write(dir.join("bash/dot-bashrc"), "").unwrap();
+        fs::write(dir.join("bash/README.md"), "").unwrap();
+        fs::write(dir.join("notes.txt"), "").unwrap();
+
+        let import = stow(
+            &root,
+            &Stow {
+                dir: &dir,
+                target: "home://",
+                dotfiles: true,
+            },
+        )
+        .unwrap();
+
+        fs::remove_dir_all(&root).unwrap();
+
+        assert_eq!(
+            import.systems,
+            vec![
+                Imported::Link {
+                    name: String::from("stow bash"),
+                    path: String::from("home://.bashrc"),
+                    link: String::from("dotfiles/bash/dot-bashrc"),
+                },
+                Imported::LinkDir {
+                    name: String::from("stow vim"),
+                    from: String::from("dotfiles/vim"),
+                    to: String::from("home://"),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_append() {
+        let systems = vec![Imported::LinkDir {
+            name: String::from("stow vim"),
+            from: String::from("dotfiles/vim"),
+            to: String::from("home://"),
+        }];
+
+        let expected =
+            "  - type: link-dir\n    name: stow vim\n    from: dotfiles/vim\n    to: \"home://\"\n";
+
+        assert_eq!(
+            append("# comment\nsystems:\n  - type: install\n", &systems).unwrap(),
+            format!("# comment\nsystems:\n  - type: install\n{}", expected)
+        );
+
+        assert_eq!(
+            append("hierarchy: []", &systems).unwrap(),
+            format!("hierarchy: []\nsystems:\n{}", expected)
+        );
+
+        assert_eq!(
+            append("", &systems).unwrap(),
+            format!("systems:\n{}", expected)
+        );
+
+        assert!(append("systems: []\nhierarchy: []\n", &systems).is_err());
+    }
+
+    #[test]
+    fn test_chezmoi_attributes() {
+        let a = ChezmoiAttributes::parse("private_executable_dot_local");
+        assert_eq!(a.prefixes, vec!["private_", "executable_", "dot_"]);
+        assert_eq!(a.name, ".local");
+        assert!(!a.template);
+
+        let a = ChezmoiAttributes::parse("encrypted_dot_netrc.tmpl.age");
+        assert_eq!(a.prefixes, vec!["encrypted_", "dot_"]);
+        assert_eq!(a.name, ".netrc");
+        assert!(a.template);
+
+        let a = ChezmoiAttributes::parse("literal_dot_run_x.tmpl.literal");
+        assert!(a.prefixes.is_empty());
+        assert_eq!(a.name, "dot_run_x.tmpl");
+        assert!(!a.template);
+
+        let a = ChezmoiAttributes::parse("run_once_before_install.sh");
+        assert_eq!(a.prefixes, vec!["run_", "once_", "before_"]);
+
+        let a = ChezmoiAttributes::parse("once_upon.txt");
+        assert!(a.prefixes.is_empty());
+        assert_eq!(a.name, "once_upon.txt");
+    }
+
+    #[test]
+    fn test_chezmoi() {
+        let dir = std::env::temp_dir().join(format!("quickcfg-chezmoi-{}", std::process::id()));
+
+        fs::create_dir_all(dir.join("dot_config/private_nvim")).unwrap();
+        fs::create_dir_all(dir.join(".git")).unwrap();
+        fs::write(dir.join("dot_bashrc"), "").unwrap();
+        fs::write(dir.join("dot_gitconfig.tmpl"), "").unwrap();
+        fs::write(dir.join("symlink_dot_vimrc"), ".config/nvim/init.vim\n").unwrap();
+        fs::write(dir.join("dot_config/private_nvim/executable_run.sh"), "").unwrap();
+        fs::write(dir.join(".chezmoidata.yaml"), "name: John\n").unwrap();
+
+        let import = chezmoi(&Chezmoi {
+            source: &dir,
+            dest: "home",
+        })
+        .unwrap();
+
+        fs::remove_dir_all(&dir).unwrap();
+
+        let files = import
+            .files
+            .iter()
+            .map(|f| (f.to.as_str(), f.mode))
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            files,
+            vec![
+                ("db/chezmoi.yml", None),
+                ("home/.bashrc", None),
+                ("home/.config/nvim/run.sh", Some(0o755)),
+                ("chezmoi-todo/dot_gitconfig.tmpl", None),
+            ]
+        );
+
+        assert_eq!(import.hierarchy, vec![String::from("db/chezmoi.yml")]);
+
+        assert_eq!(
+            import.systems,
+            vec![
+                Imported::CopyDir {
+                    name: String::from("chezmoi"),
+                    from: String::from("home"),
+                    to: String::from("home://"),
+                },
+                Imported::Link {
+                    name: String::from("chezmoi"),
+                    path: String::from("home://.vimrc"),
+                    link: String::from("home://.config/nvim/init.vim"),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_add_hierarchy() {
+        assert_eq!(
+            add_hierarchy("systems: []\n", "db/chezmoi.yml").unwrap(),
+            "hierarchy:\n  - db/chezmoi.yml\n\nsystems: []\n"
+        );
+
+        assert_eq!(
+            add_hierarchy(
+                "hierarchy:\n  - db/common.yml\nsystems: []\n",
+                "db/chezmoi.yml"
+            )
+            .unwrap(),
+            "hierarchy:\n  - db/common.yml\n  - db/chezmoi.yml\nsystems: []\n"
+        );
+
+        assert_eq!(
+            add_hierarchy("hierarchy: []\n", "db/chezmoi.yml").unwrap(),
+            "hierarchy:\n  - db/chezmoi.yml\n"
+        );
+
+        assert!(add_hierarchy("hierarchy: [db/common.yml]\n", "db/chezmoi.yml").is_err());
+    }
+}
@@ -0,0 +1,60 @@
+//! Handling of Ctrl-C, so that an interrupted run stops gracefully and keeps its progress.
+//!
+//! The first interrupt only sets a flag, which is checked before scheduling more units. A second
+//! interrupt exits immediately.
+
+use anyhow::Error;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+/// Test if the process has been interrupted.
+pub fn is_interrupted() -> bool {
+    INTERRUPTED.load(Ordering::SeqCst)
+}
+
+/// Install the interrupt handler.
+#[cfg(unix)]
+pub fn install() -> Result<(), Error> {
+    extern "C" fn handler(_: libc::c_int) {
+        if INTERRUPTED.swap(true, Ordering::SeqCst) {
+            // NB: only async-signal-safe functions may be called here.
+            unsafe { libc::_exit(130) };
+        }
+    }
+
+    let handler = handler as extern "C" fn(libc::c_int) as libc::sighandler_t;
+
+    if unsafe { libc::signal(libc::SIGINT, handler) } == libc::SIG_ERR {
+        return Err(std::io::Error::last_os_error().into());
+    }
+
+    Ok(())
+}
+
+/// Install the interrupt handler.
+#[cfg(windows)]
+pub fn install() -> Result<(), Error> {
+    use winapi::shared::minwindef::{BOOL, DWORD, FALSE, TRUE};
+    use winapi::um::consoleapi::SetConsoleCtrlHandler;
+    use winapi::um::wincon::CTRL_C_EVENT;
+
+    unsafe extern "system" fn handler(event: DWORD) -> BOOL {
+        if event != CTRL_C_EVENT {
+            return FALSE;
+        }
+
+        // NB: returning FALSE lets the default handler terminate the process.
+        if INTERRUPTED.swap(true, Ordering::SeqCst) {
+            FALSE
+        } else {
+            TRUE
+        }
+    }
+
+    if unsafe { SetConsoleCtrlHandler(Some(handler), TRUE) } == FALSE {
+        return Err(std::io::Error::last_os_error().into());
+    }
+
+    Ok(())
+}
@@ -1,27 +1,94 @@
-mod command;
+//! Apply configuration to a machine, like dotfiles, packages, and downloads.
+//!
+//! The `qc` binary is a thin command line interface on top of this crate. Other tools can embed
+//! the same engine: load a [Config], open its [DiskState], and apply it with a [Session].
+//!
+//! ```no_run
+//! use quickcfg::{git, opts::Opts, Config, DiskState, Load, Save, Session, Timestamp};
+//! use std::path::Path;
+//!
+//! # fn main() -> anyhow::Result<()> {
+//! let root = Path::new("/home/user/.config/quickcfg");
+//! let state_dir = root.join(".state");
+//! let state_path = root.join(".state.yml");
+//!
+//! let config = Config::load(&root.join("quickcfg.yml"))?.unwrap_or_default();
+//! let opts = Opts::default();
+//! let now = Timestamp::now();
+//!
+//! let git_system = git::setup(git::Backend::Auto, git::Auth::default(), git::Fetch::default())?;
+//! let mut state = DiskState::open(&state_path)?
+//!     .unwrap_or_default()
+//!     .into_state(&config, now);
+//!
+//! let session = Session {
+//!     opts: &opts,
+//!     config: &config,
+//!     root,
+//!     state_dir: &state_dir,
+//!     base_dirs: None,
+//!     git_system: &*git_system,
+//!     now,
+//!     preloaded: None,
+//! };
+//!
+//! let result = session.apply(&mut state);
+//!
+//! if let Some(serialized) = state.serialize() {
+//!     serialized.save(&state_path)?;
+//! }
+//!
+//! let summary = result?;
+//! println!("{}", summary.render());
+//! # Ok(())
+//! # }
+//! ```
+
+pub mod archive;
+pub mod cache;
+pub mod command;
 mod config;
+mod deadline;
+pub mod doctor;
 pub mod environment;
 pub mod facts;
 pub mod ffi;
 mod file_operations;
 mod file_system;
 pub mod git;
+pub mod graph;
 pub mod hierarchy;
+pub mod import;
+pub mod interrupt;
+pub mod lock;
+pub mod logging;
+pub mod net;
+pub mod notify;
 pub mod opts;
 mod os;
 pub mod packages;
+pub mod report;
 mod rustup;
+pub mod self_update;
+pub mod session;
 pub mod stage;
-mod state;
+pub mod state;
+pub mod state_dir;
+pub mod summary;
 pub mod system;
 mod template;
 mod timestamp;
 pub mod unit;
+pub mod verify;
 
 pub use self::command::Command;
-pub use self::config::Config;
-pub use self::file_operations::{Load, Save};
+pub use self::config::{
+    Concern, Config, ConfigError, Http, LogSink, Notify, PromptPolicy, Prompts, Repo, Report,
+    SelfUpdate, StateLocation, SymlinkFallback,
+};
+pub use self::file_operations::{Load, LoadError, Save};
 pub use self::file_system::FileSystem;
+pub use self::session::Session;
 pub use self::state::{DiskState, State};
 pub use self::template::Template;
 pub use self::timestamp::Timestamp;
@@ -0,0 +1,143 @@
+//! Advisory lock which prevents multiple instances of quickcfg from running at the same time.
+
+use anyhow::{anyhow, bail, Context as _, Error};
+use std::fs::{self, File, OpenOptions, TryLockError};
+use std::io::{self, Write as _};
+use std::path::Path;
+
+/// A held lock, which is released when dropped.
+#[derive(Debug)]
+pub struct Lock {
+    file: File,
+}
+
+impl Lock {
+    /// Acquire the lock at the given path.
+    ///
+    /// The lock is held through the open lock file, so it's released by the operating system if
+    /// the instance holding it goes away without cleaning up.
+    pub fn acquire(path: &Path) -> Result<Lock, Error> {
+        let mut file = open(path)?;
+
+        match file.try_lock() {
+            Ok(()) => (),
+            Err(TryLockError::WouldBlock) => {
+                // NB: the pid is written after the lock is taken, so it might not be there yet.
+                match read_pid(path).ok().flatten() {
+                    Some(pid) => bail!("Another instance of quickcfg is running (pid {})", pid),
+                    None => bail!("Another instance of quickcfg is running"),
+                }
+            }
+            Err(TryLockError::Error(e)) => {
+                return Err(e).with_context(|| anyhow!("Failed to lock: {}", path.display()))
+            }
+        }
+
+        file.set_len(0)?;
+        write!(file, "{}", std::process::id())?;
+        Ok(Lock { file })
+    }
+}
+
+impl Drop for Lock {
+    fn drop(&mut self) {
+        // NB: the file itself is left in place, since another instance might already have it open
+        // to wait for the lock.
+        if let Err(e) = self.file.set_len(0) {
+            log::warn!("Failed to release lock: {}", e);
+        }
+    }
+}
+
+/// Test if there's a lock at the given path which was left behind by an instance which is no
+/// longer running.
+pub fn is_stale(path: &Path) -> Result<bool, Error> {
+    if !path.exists() {
+        return Ok(false);
+    }
+
+    match open(path)?.try_lock() {
+        Ok(()) => Ok(read_pid(path)?.is_some()),
+        Err(TryLockError::WouldBlock) => Ok(false),
+        Err(TryLockError::Error(e)) => {
+            Err(e).with_context(|| anyhow!("Failed to lock: {}", path.display()))
+        }
+    }
+}
+
+/// Open the lock file, creating it if it doesn't exist.
+fn open(path: &Path) -> Result<File, Error> {
+    OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(path)
+        .with_context(|| anyhow!("Failed to open: {}", path.display()))
+}
+
+/// Read the process id from a lock file.
+///
+/// A lock which is missing, or which was released, doesn't have a process id.
+fn read_pid(path: &Path) -> Result<Option<u32>, Error> {
+    match fs::read_to_string(path) {
+        Ok(content) => Ok(content.trim().parse().ok()),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e).with_context(|| anyhow!("Failed to read: {}", path.display())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{is_stale, Lock};
+    use std::fs;
+
+    #[test]
+    fn test_lock() {
+        let dir = std::env::temp_dir().join(format!("quickcfg-lock-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("lock");
+
+        let lock = Lock::acquire(&path).unwrap();
+        assert!(Lock::acquire(&path).is_err());
+        assert!(!is_stale(&path).unwrap());
+        drop(lock);
+        assert_eq!(fs::read_to_string(&path).unwrap(), "");
+        assert!(!is_stale(&path).unwrap());
+
+        // NB: left behind by an instance which didn't get to release it.
+        fs::write(&path, u32::MAX.to_string()).unwrap();
+        assert!(is_stale(&path).unwrap());
+        let lock = Lock::acquire(&path).unwrap();
+
+        // NB: the lock keeps other handles from reading the file on Windows.
+        #[cfg(unix)]
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            std::process::id().to_string()
+        );
+
+        drop(lock);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_lock_without_pid() {
+        let dir = std::env::temp_dir().join(format!("quickcfg-lock-empty-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("lock");
+
+        // NB: an instance which has taken the lock, but not written its pid yet.
+        let lock = Lock::acquire(&path).unwrap();
+        fs::write(&path, "").unwrap();
+
+        let e = Lock::acquire(&path).unwrap_err();
+        assert_eq!(e.to_string(), "Another instance of quickcfg is running");
+        assert!(!is_stale(&path).unwrap());
+        drop(lock);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
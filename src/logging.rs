@@ -0,0 +1,385 @@
+//! Logging to stderr, and to additional sinks selected in the configuration.
+//!
+//! Sinks are configured after the logger is installed, since where they log to is only known once
+//! the configuration has been loaded.
+
+use crate::{config::LogSink, state_dir::StateFile};
+use anyhow::{anyhow, Context as _, Error};
+use std::cell::RefCell;
+use std::fs;
+use std::io::Write as _;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, RwLock};
+
+/// Default size at which the log file is rotated.
+pub(crate) const DEFAULT_MAX_SIZE: u64 = 1024 * 1024;
+
+/// Default number of rotated log files to keep.
+pub(crate) const DEFAULT_KEEP: usize = 5;
+
+/// Identifier used for syslog and journald.
+const IDENTIFIER: &str = "quickcfg";
+
+/// Additional sinks that every record is written to.
+static SINKS: RwLock<Vec<Sink>> = RwLock::new(Vec::new());
+
+thread_local! {
+    /// The system and unit that the current thread is applying.
+    static CONTEXT: RefCell<Option<Context>> = const { RefCell::new(None) };
+}
+
+/// The unit that a record was logged while applying.
+#[derive(Clone)]
+struct Context {
+    system: Option<String>,
+    unit: String,
+}
+
+/// Run the given function while applying a unit, so that records it logs can be attributed to
+/// the unit by sinks with structured fields.
+pub(crate) fn with_unit<T>(system: Option<&str>, unit: String, f: impl FnOnce() -> T) -> T {
+    let context = Context {
+        system: system.map(String::from),
+        unit,
+    };
+
+    // NB: restores the previous context even if `f` panics.
+    struct Restore(Option<Context>);
+
+    impl Drop for Restore {
+        fn drop(&mut self) {
+            CONTEXT.with(|c| c.replace(self.0.take()));
+        }
+    }
+
+    let _restore = Restore(CONTEXT.with(|c| c.replace(Some(context))));
+    f()
+}
+
+/// Install the logger, which logs to the given logger for stderr and to all configured sinks.
+pub fn init(stderr: Box<dyn log::Log>) -> Result<(), Error> {
+    log::set_boxed_logger(Box::new(Logger { stderr }))
+        .map_err(|e| anyhow!("Failed to install logger: {}", e))
+}
+
+/// Set up the sinks from the configuration, replacing any previous ones.
+///
+/// Log files are stored in the `logs` directory of the given state directory.
+pub fn configure(sinks: &[LogSink], state_dir: &Path) -> Result<(), Error> {
+    let mut configured = Vec::new();
+
+    for sink in sinks {
+        let sink = match *sink {
+            LogSink::File { max_size, keep } => {
+                let dir = StateFile::Logs.path(state_dir);
+
+                fs::create_dir_all(&dir).with_context(|| {
+                    anyhow!("Failed to create log directory: {}", dir.display())
+                })?;
+
+                Sink::File(Mutex::new(LogFile::open(
+                    dir.join("quickcfg.log"),
+                    max_size,
+                    keep,
+                )?))
+            }
+            #[cfg(unix)]
+            LogSink::Journald => Sink::Journald(unix::socket()?),
+            #[cfg(unix)]
+            LogSink::Syslog => Sink::Syslog(unix::socket()?),
+            #[cfg(not(unix))]
+            LogSink::Journald | LogSink::Syslog => {
+                log::warn!("Logging to {:?} is not supported on this platform", sink);
+                continue;
+            }
+        };
+
+        configured.push(sink);
+    }
+
+    *SINKS.write().unwrap_or_else(|e| e.into_inner()) = configured;
+    Ok(())
+}
+
+struct Logger {
+    stderr: Box<dyn log::Log>,
+}
+
+impl log::Log for Logger {
+    fn enabled(&self, metadata: &log::Metadata<'_>) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &log::Record<'_>) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+
+        self.stderr.log(record);
+
+        let sinks = SINKS.read().unwrap_or_else(|e| e.into_inner());
+
+        if sinks.is_empty() {
+            return;
+        }
+
+        let context = CONTEXT.with(|c| c.borrow().clone());
+
+        // NB: there's nowhere to report errors in logging.
+        for sink in sinks.iter() {
+            let _ = sink.log(record, context.as_ref());
+        }
+    }
+
+    fn flush(&self) {
+        self.stderr.flush();
+    }
+}
+
+/// A configured sink.
+enum Sink {
+    File(Mutex<LogFile>),
+    #[cfg(unix)]
+    Journald(std::os::unix::net::UnixDatagram),
+    #[cfg(unix)]
+    Syslog(std::os::unix::net::UnixDatagram),
+}
+
+impl Sink {
+    fn log(&self, record: &log::Record<'_>, context: Option<&Context>) -> Result<(), Error> {
+        match self {
+            Sink::File(file) => {
+                let now = humantime::format_rfc3339_seconds(std::time::SystemTime::now());
+
+                let line = match context {
+                    Some(c) => format!(
+                        "{} {:5} {} [{}] {}\n",
+                        now,
+                        record.level(),
+                        record.target(),
+                        c.system.as_deref().unwrap_or("-"),
+                        record.args()
+                    ),
+                    None => format!(
+                        "{} {:5} {} {}\n",
+                        now,
+                        record.level(),
+                        record.target(),
+                        record.args()
+                    ),
+                };
+
+                file.lock()
+                    .unwrap_or_else(|e| e.into_inner())
+                    .write(line.as_bytes())
+            }
+            #[cfg(unix)]
+            Sink::Journald(socket) => {
+                let message = unix::journald(record, context);
+                socket.send_to(&message, unix::JOURNALD)?;
+                Ok(())
+            }
+            #[cfg(unix)]
+            Sink::Syslog(socket) => {
+                let message = unix::syslog(record);
+                socket.send_to(message.as_bytes(), unix::SYSLOG)?;
+                Ok(())
+            }
+        }
+    }
+}
+
+/// A log file which is rotated when it grows too large.
+struct LogFile {
+    path: PathBuf,
+    file: fs::File,
+    size: u64,
+    max_size: u64,
+    keep: usize,
+}
+
+impl LogFile {
+    fn open(path: PathBuf, max_size: u64, keep: usize) -> Result<Self, Error> {
+        let file = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .with_context(|| anyhow!("Failed to open log file: {}", path.display()))?;
+
+        let size = file.metadata()?.len();
+
+        Ok(LogFile {
+            path,
+            file,
+            size,
+            max_size,
+            keep,
+        })
+    }
+
+    fn write(&mut self, data: &[u8]) -> Result<(), Error> {
+        if self.size > 0 && self.size + data.len() as u64 > self.max_size {
+            self.rotate()?;
+        }
+
+        self.file.write_all(data)?;
+        self.size += data.len() as u64;
+        Ok(())
+    }
+
+    /// Rotate the log file, so that `quickcfg.log` becomes `quickcfg.log.1` and so forth.
+    fn rotate(&mut self) -> Result<(), Error> {
+        let rotated = |n: usize| PathBuf::from(format!("{}.{}", self.path.display(), n));
+
+        if self.keep == 0 {
+            fs::remove_file(&self.path)?;
+        } else {
+            for n in (1..self.keep).rev() {
+                if rotated(n).is_file() {
+                    fs::rename(rotated(n), rotated(n + 1))?;
+                }
+            }
+
+            fs::rename(&self.path, rotated(1))?;
+        }
+
+        *self = LogFile::open(self.path.clone(), self.max_size, self.keep)?;
+        Ok(())
+    }
+}
+
+#[cfg(unix)]
+mod unix {
+    use super::{Context, IDENTIFIER};
+    use anyhow::Error;
+    use std::os::unix::net::UnixDatagram;
+
+    /// Socket that journald receives records on.
+    pub(super) const JOURNALD: &str = "/run/systemd/journal/socket";
+
+    /// Socket that syslog receives records on.
+    pub(super) const SYSLOG: &str = "/dev/log";
+
+    /// Syslog facility for user-level messages.
+    const FACILITY_USER: u8 = 1;
+
+    pub(super) fn socket() -> Result<UnixDatagram, Error> {
+        Ok(UnixDatagram::unbound()?)
+    }
+
+    /// Syslog severity of a record, which journald also uses as its priority.
+    fn severity(level: log::Level) -> u8 {
+        match level {
+            log::Level::Error => 3,
+            log::Level::Warn => 4,
+            log::Level::Info => 6,
+            log::Level::Debug | log::Level::Trace => 7,
+        }
+    }
+
+    /// Encode a record using the native protocol of journald.
+    pub(super) fn journald(record: &log::Record<'_>, context: Option<&Context>) -> Vec<u8> {
+        let mut out = Vec::new();
+
+        let mut field = |key: &str, value: &str| {
+            out.extend_from_slice(key.as_bytes());
+
+            // NB: values with newlines are length-prefixed.
+            if value.contains('\n') {
+                out.push(b'\n');
+                out.extend_from_slice(&(value.len() as u64).to_le_bytes());
+            } else {
+                out.push(b'=');
+            }
+
+            out.extend_from_slice(value.as_bytes());
+            out.push(b'\n');
+        };
+
+        field("MESSAGE", &record.args().to_string());
+        field("PRIORITY", &severity(record.level()).to_string());
+        field("SYSLOG_IDENTIFIER", IDENTIFIER);
+        field("QUICKCFG_TARGET", record.target());
+
+        if let Some(context) = context {
+            if let Some(system) = context.system.as_deref() {
+                field("QUICKCFG_SYSTEM", system);
+            }
+
+            field("QUICKCFG_UNIT", &context.unit);
+        }
+
+        out
+    }
+
+    /// Format a record for syslog.
+    pub(super) fn syslog(record: &log::Record<'_>) -> String {
+        let priority = FACILITY_USER * 8 + severity(record.level());
+
+        format!(
+            "<{}>{}[{}]: {}",
+            priority,
+            IDENTIFIER,
+            std::process::id(),
+            record.args()
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::LogFile;
+    use std::fs;
+
+    #[test]
+    fn test_rotate() {
+        let dir = std::env::temp_dir().join(format!("quickcfg-log-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("quickcfg.log");
+
+        let mut file = LogFile::open(path.clone(), 10, 2).unwrap();
+
+        for line in &["first\n", "second\n", "third\n", "fourth\n"] {
+            file.write(line.as_bytes()).unwrap();
+        }
+
+        assert_eq!(fs::read_to_string(&path).unwrap(), "fourth\n");
+        assert_eq!(
+            fs::read_to_string(dir.join("quickcfg.log.1")).unwrap(),
+            "third\n"
+        );
+        assert_eq!(
+            fs::read_to_string(dir.join("quickcfg.log.2")).unwrap(),
+            "second\n"
+        );
+        assert!(!dir.join("quickcfg.log.3").exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_journald() {
+        let record = log::Record::builder()
+            .args(format_args!("two\nlines"))
+            .level(log::Level::Warn)
+            .target("quickcfg")
+            .build();
+
+        let context = super::Context {
+            system: Some(String::from("vim")),
+            unit: String::from("create directory /home/user/.vim"),
+        };
+
+        let message = super::unix::journald(&record, Some(&context));
+
+        let mut expected = b"MESSAGE\n".to_vec();
+        expected.extend_from_slice(&9u64.to_le_bytes());
+        expected.extend_from_slice(
+            b"two\nlines\nPRIORITY=4\nSYSLOG_IDENTIFIER=quickcfg\nQUICKCFG_TARGET=quickcfg\n\
+              QUICKCFG_SYSTEM=vim\nQUICKCFG_UNIT=create directory /home/user/.vim\n",
+        );
+
+        assert_eq!(message, expected);
+    }
+}
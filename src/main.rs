@@ -1,49 +1,29 @@
 use anyhow::{anyhow, bail, Context as _, Error};
 use directories::BaseDirs;
-use log;
 use quickcfg::{
-    environment as e,
-    facts::Facts,
-    git, hierarchy,
+    archive, cache, command, doctor, environment as e, git, import,
+    lock::Lock,
+    logging, net, notify,
     opts::{self, Opts},
-    packages, stage,
-    system::{self, SystemInput},
-    unit::{self, Unit, UnitAllocator, UnitInput},
-    Config, DiskState, FileSystem, Load, Save, State, Timestamp,
+    report, self_update,
+    session::{load_data, load_facts, load_repos, repo_dir, report_error, Preloaded, UnitsFailed},
+    state,
+    state_dir::StateFile,
+    summary::Summary,
+    Concern, Config, DiskState, FileSystem, Load, Save, Session, State, StateLocation, Timestamp,
 };
-use std::collections::HashMap;
 use std::fs;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::process;
 
-fn report_error(e: Error) {
-    let mut it = e.chain();
+/// Exit code used by `--check` and `self-update --check` when there are updates available.
+const UPDATES_AVAILABLE: i32 = 2;
 
-    if let Some(e) = it.next() {
-        eprintln!("Error: {}", e);
-
-        #[cfg(feature = "nightly")]
-        {
-            if let Some(bt) = e.backtrace() {
-                eprintln!("{}", bt);
-            }
-        }
-    }
-
-    for e in it {
-        eprintln!("Caused by: {}", e);
-
-        #[cfg(feature = "nightly")]
-        {
-            if let Some(bt) = e.backtrace() {
-                eprintln!("{}", bt);
-            }
-        }
-    }
-}
+/// Fraction of `git_refresh` before a check for updates is due, in which the configuration is
+/// checked in the background instead.
+const EARLY_CHECK_FRACTION: f64 = 0.1;
 
 fn main() {
-    use std::process;
-
     if let Err(e) = try_main() {
         report_error(e);
         process::exit(1);
@@ -51,20 +31,30 @@ fn main() {
 }
 
 fn try_main() -> Result<(), Error> {
-    pretty_env_logger::formatted_builder()
+    let stderr = pretty_env_logger::formatted_builder()
         .parse_filters("trace")
-        .init();
+        .build();
+    log::set_max_level(stderr.filter());
+    logging::init(Box::new(stderr))?;
 
     let base_dirs = BaseDirs::new();
 
     let mut opts = opts::opts()?;
     let root = opts.root(base_dirs.as_ref())?;
 
-    let config_path = root.join("quickcfg.yml");
+    let config_path = Config::path(&root)?;
     let state_path = root.join(".state.yml");
     let state_dir = root.join(".state");
 
     if opts.paths {
+        // NB: the location of state depends on the configuration, if there is one.
+        let config = Config::load(&config_path)
+            .ok()
+            .flatten()
+            .unwrap_or_default();
+        let (state_path, state_dir) =
+            state_location(&config, base_dirs.as_ref(), &root, &state_path, &state_dir)?;
+
         println!("OS: {}", std::env::consts::OS);
         println!("Root: {}", root.display());
         println!("Configuration File: {}", config_path.display());
@@ -79,20 +69,49 @@ fn try_main() -> Result<(), Error> {
         log::set_max_level(log::LevelFilter::Info);
     }
 
-    if !root.is_dir()
-        && opts.init.is_none()
-        && opts.prompt(
+    command::set_tracing(opts.trace_commands);
+
+    if !root.is_dir() && opts.init.is_none() {
+        if opts.is_non_interactive() {
+            bail!(
+                "No configuration directory at {}, run with --init <url> to set it up",
+                root.display()
+            );
+        }
+
+        if opts.prompt(
             "No configuration directory, would you like to set it up?",
             true,
-        )?
-    {
-        opts.init = opts.input("[Git Repository]")?;
+        )? {
+            opts.init = opts.input("[Git Repository or Archive URL]")?;
+        }
     }
 
-    let git_system = git::setup().with_context(|| "failed to set up git system")?;
+    if opts.bootstrap && root.exists() {
+        bail!(
+            "Cannot bootstrap, configuration directory already exists: {}",
+            root.display()
+        );
+    }
 
-    if let Some(init) = opts.init.as_ref() {
+    let initialized = opts.init.is_some();
+
+    if let Some(init) = opts
+        .init
+        .as_ref()
+        .filter(|init| archive::is_archive_url(init))
+    {
+        log::info!("Initializing {} from {}", root.display(), init);
+        try_init_archive(init, &root, &state_dir)?;
+    } else if let Some(init) = opts.init.as_ref() {
         log::info!("Initializing {} from {}", root.display(), init);
+        let auth = git::Auth {
+            non_interactive: opts.is_non_interactive(),
+            ..git::Auth::default()
+        };
+
+        let git_system = git::setup(git::Backend::Auto, auth, opts.git_fetch.unwrap_or_default())
+            .with_context(|| "failed to set up git system")?;
         try_init(&*git_system, init, &root)?;
     } else {
         log::trace!("Using config from {}", root.display());
@@ -102,23 +121,101 @@ fn try_main() -> Result<(), Error> {
         bail!("Missing configuration directory: {}", root.display());
     }
 
-    if !state_dir.is_dir() {
-        fs::create_dir(&state_dir).with_context(|| {
-            anyhow!("Failed to create state directory: {}", state_dir.display())
-        })?;
+    if let Some(import) = opts.import.as_ref() {
+        return try_import(&opts, import, base_dirs.as_ref(), &root, &config_path);
     }
 
     let config = Config::load(&config_path)
         .with_context(|| anyhow!("Failed to load configuration: {}", config_path.display()))?
         .unwrap_or_default();
+
+    // NB: state of a simulation is kept in the overlay, so that it doesn't touch the host.
+    let (state_path, state_dir) = match opts.simulate.as_deref() {
+        Some(overlay) => try_simulate(overlay)?,
+        None => {
+            let (path, dir) =
+                state_location(&config, base_dirs.as_ref(), &root, &state_path, &state_dir)?;
+
+            if path != state_path {
+                migrate_state(&state_path, &state_dir, &path, &dir)?;
+            }
+
+            (path, dir)
+        }
+    };
+
+    if !state_dir.is_dir() {
+        fs::create_dir_all(&state_dir).with_context(|| {
+            anyhow!("Failed to create state directory: {}", state_dir.display())
+        })?;
+    }
+
+    logging::configure(&config.log, &state_dir)?;
+    net::configure(&config.http)?;
+    cache::configure(&state_dir, config.http.cache_size);
+
     let now = Timestamp::now();
 
-    let state = match DiskState::load(&state_path) {
+    if let Some(key) = opts.data.as_ref() {
+        return try_data(&opts, &config, now, &root, &state_path, &state_dir, key);
+    }
+
+    if opts.status {
+        return try_status(&opts, &config, now, &state_path);
+    }
+
+    if opts.doctor {
+        return try_doctor(&opts, &config, now, &root, &state_path, &state_dir);
+    }
+
+    if opts.self_update {
+        return try_self_update(&opts, &config);
+    }
+
+    let (auth, preloaded) = git_auth(&opts, &config, now, base_dirs.as_ref(), &root, &state_dir)
+        .with_context(|| "failed to set up git authentication")?;
+    let git_system = git::setup(
+        config.git_backend,
+        auth,
+        opts.git_fetch.unwrap_or(config.git_fetch),
+    )
+    .with_context(|| "failed to set up git system")?;
+
+    if opts.push {
+        let git = git_system.open(&root)?;
+
+        if !try_push(&*git)? {
+            log::info!("No changes to commit");
+        }
+
+        return Ok(());
+    }
+
+    if opts.check {
+        if try_check(&*git_system, &opts, &config, &root, &state_dir)? {
+            process::exit(UPDATES_AVAILABLE);
+        }
+
+        return Ok(());
+    }
+
+    if opts.cache_clean {
+        return try_cache_clean(&state_dir);
+    }
+
+    // NB: held until we've written the state.
+    let _lock = Lock::acquire(&StateFile::Lock.path(&state_dir))?;
+
+    let state = match DiskState::open(&state_path) {
         Ok(state) => state.unwrap_or_default(),
+        // NB: never throw away state written by a newer version.
+        Err(err) if err.is::<state::UnsupportedVersion>() => {
+            return Err(err.context(anyhow!("Invalid disk state `{}`", state_path.display())));
+        }
         Err(err) => {
             log::error!("Invalid disk state `{}`: {}", state_path.display(), err);
 
-            if !opts.prompt("Remove it?", true)? {
+            if !opts.confirm("Remove it?")? {
                 return Ok(());
             }
 
@@ -128,291 +225,961 @@ fn try_main() -> Result<(), Error> {
 
     let mut state = state.into_state(&config, now);
 
-    let result = try_apply_config(
-        &*git_system,
-        &opts,
-        &config,
-        now,
-        base_dirs.as_ref(),
-        &root,
-        &state_dir,
-        &mut state,
-    );
+    if let Some(profile) = opts.profile.as_deref() {
+        config.check_profile(profile)?;
+        state.set_profile(profile);
+    }
+
+    if initialized {
+        state.touch("git");
+    }
+
+    let result = if opts.uninstall {
+        try_uninstall(&opts, &mut state).map(|()| Summary::default())
+    } else {
+        try_apply_config(
+            &*git_system,
+            &opts,
+            &config,
+            now,
+            base_dirs.as_ref(),
+            &root,
+            &state_dir,
+            preloaded.as_ref(),
+            &mut state,
+        )
+    };
+
+    let summary = match &result {
+        Ok(summary) => Some(summary),
+        Err(e) => e.downcast_ref::<UnitsFailed>().map(|f| &f.summary),
+    };
+
+    if let Some(summary) = summary {
+        if opts.timings {
+            // NB: compared against previous runs, so it's rendered before this run is recorded.
+            print!("{}", summary.render_timings(&state.average_timings()));
+        }
+
+        if opts.json {
+            println!("{}", summary.to_json());
+        }
+
+        state.record_timings(summary);
+    }
 
     if let Some(serialized) = state.serialize() {
         log::trace!("Writing state: {}", state_path.display());
         serialized.save(&state_path)?;
     }
 
-    result
-}
+    if let Some(message) = notify::message(config.notify, &result) {
+        if let Err(e) = notify::send(&message) {
+            log::warn!("Failed to show notification: {}", e);
+        }
+    }
 
-/// Try to initialize the repository from the given path.
-fn try_init(git_system: &dyn git::GitSystem, url: &str, root: &Path) -> Result<(), Error> {
-    let _ = git::GitSystem::clone(git_system, url, root)?;
-    Ok(())
+    // NB: only unattended runs are reported, since someone is watching interactive ones.
+    if let Some(report) = config
+        .report
+        .as_ref()
+        .filter(|_| opts.is_non_interactive() && opts.simulate.is_none())
+    {
+        if let Err(e) = report::send(report, &result) {
+            log::warn!("Failed to send report to {}: {}", report.url, e);
+        }
+    }
+
+    result.map(|_| ())
 }
 
-#[allow(clippy::too_many_arguments)]
-/// Internal method to try to apply the given configuration.
-fn try_apply_config(
-    git_system: &dyn git::GitSystem,
+/// Check for common problems, and print how to fix them.
+fn try_doctor(
     opts: &Opts,
     config: &Config,
     now: Timestamp,
+    root: &Path,
+    state_path: &Path,
+    state_dir: &Path,
+) -> Result<(), Error> {
+    let facts = load_facts(opts, config, now, root, state_dir)?;
+    let findings = doctor::run(config, root, state_path, state_dir, &facts, now);
+
+    for finding in &findings {
+        println!("{}", finding);
+    }
+
+    let problems = findings.iter().map(|f| f.problems.len()).sum::<usize>();
+
+    if problems > 0 {
+        bail!("Found {} problem(s)", problems);
+    }
+
+    Ok(())
+}
+
+/// Remove all cached downloads.
+fn try_cache_clean(state_dir: &Path) -> Result<(), Error> {
+    // NB: downloads in progress would otherwise be removed from under the running instance.
+    let _lock = Lock::acquire(&StateFile::Lock.path(state_dir))?;
+
+    let dir = cache::dir(state_dir);
+    let freed = cache::Cache::new(&dir, 0).clean()?;
+    log::info!(
+        "Removed {} bytes of cached downloads: {}",
+        freed,
+        dir.display()
+    );
+    Ok(())
+}
+
+/// Replace the running binary with the latest release, if it's newer.
+fn try_self_update(opts: &Opts, config: &Config) -> Result<(), Error> {
+    let release = self_update::latest(&config.self_update.repo)?;
+
+    if !release.is_newer() {
+        log::info!("quickcfg {} is up to date", opts::VERSION);
+        return Ok(());
+    }
+
+    if opts.self_update_check {
+        log::info!(
+            "quickcfg {} is available, running {}",
+            release.version,
+            opts::VERSION
+        );
+        process::exit(UPDATES_AVAILABLE);
+    }
+
+    let public_key = match config.self_update.public_key.as_deref() {
+        Some(public_key) => public_key,
+        None => {
+            bail!("Refusing to update without `self_update.public_key` to verify the release with")
+        }
+    };
+
+    let exe = self_update::install(&release, public_key)?;
+    log::info!(
+        "Updated {} from {} to {}",
+        exe.display(),
+        opts::VERSION,
+        release.version
+    );
+    Ok(())
+}
+
+/// Set up a simulation in the given overlay directory.
+///
+/// Scripted results for commands are loaded from `commands.yml` in the overlay.
+///
+/// Returns the state file and state directory to use in the overlay.
+fn try_simulate(overlay: &Path) -> Result<(PathBuf, PathBuf), Error> {
+    let mut fake = e::Fake::new(overlay);
+
+    fs::create_dir_all(fake.home())
+        .with_context(|| anyhow!("Failed to create overlay: {}", overlay.display()))?;
+
+    let scripts_path = overlay.join("commands.yml");
+
+    let scripts = Vec::<e::Script>::load(&scripts_path)
+        .with_context(|| anyhow!("Failed to load scripts: {}", scripts_path.display()))?
+        .unwrap_or_default();
+
+    for script in scripts {
+        fake.script(script);
+    }
+
+    log::info!("Simulating in {}", overlay.display());
+    e::simulate(fake)?;
+    Ok((overlay.join(".state.yml"), overlay.join(".state")))
+}
+
+/// Get the state file and state directory to use for the given configuration.
+///
+/// `state_path` and `state_dir` are the locations in the configuration root.
+fn state_location(
+    config: &Config,
     base_dirs: Option<&BaseDirs>,
     root: &Path,
+    state_path: &Path,
     state_dir: &Path,
-    state: &mut State<'_>,
+) -> Result<(PathBuf, PathBuf), Error> {
+    if let StateLocation::Root = config.state_location {
+        return Ok((state_path.to_owned(), state_dir.to_owned()));
+    }
+
+    let base_dirs = match base_dirs {
+        Some(base_dirs) => base_dirs,
+        None => bail!("No base directories available"),
+    };
+
+    // NB: only some platforms have a dedicated directory for state.
+    let base = base_dirs
+        .state_dir()
+        .unwrap_or_else(|| base_dirs.data_local_dir());
+
+    // NB: each configuration root gets its own state.
+    // NB: the name must be stable across releases and platforms, so hash the bytes of the path.
+    let root = fs::canonicalize(root).unwrap_or_else(|_| root.to_owned());
+    let hash = blake3::hash(root.as_os_str().as_encoded_bytes()).to_hex();
+
+    let dir = base.join("quickcfg").join(&hash[..16]);
+    Ok((dir.join("state.yml"), dir.join("state")))
+}
+
+/// Move state from the configuration root to a new location, unless it has already been moved.
+fn migrate_state(
+    from_path: &Path,
+    from_dir: &Path,
+    to_path: &Path,
+    to_dir: &Path,
 ) -> Result<(), Error> {
-    use rayon::prelude::*;
+    if !from_path.is_file() && !from_dir.is_dir() {
+        return Ok(());
+    }
 
-    let pool = rayon::ThreadPoolBuilder::new()
-        .build()
-        .with_context(|| anyhow!("Failed to construct thread pool"))?;
+    if to_path.is_file() || to_dir.is_dir() {
+        log::warn!(
+            "Ignoring old state in {} and {}, since state has already been moved to {}",
+            from_path.display(),
+            from_dir.display(),
+            to_dir.display()
+        );
+        return Ok(());
+    }
 
-    if !try_update_config(git_system, opts, config, now, root, state)? {
-        // if we only want to run on updates, exit now.
-        if opts.updates_only {
+    log::info!("Moving state to {}", to_dir.display());
+
+    if let Some(parent) = to_dir.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    for (from, to) in [(from_dir, to_dir), (from_path, to_path)] {
+        if FileSystem::try_open_meta(from)?.is_some() {
+            move_path(from, to).with_context(|| {
+                anyhow!(
+                    "Failed to move state from {} to {}",
+                    from.display(),
+                    to.display()
+                )
+            })?;
+        }
+    }
+
+    // NB: backups of replaced files are recorded with their full path.
+    let mut state = match DiskState::open(to_path)? {
+        Some(state) => state,
+        None => return Ok(()),
+    };
+
+    let mut moved = false;
+
+    for deployed in state.deployed.values_mut() {
+        let rest = match deployed.backup.as_ref().map(|b| b.strip_prefix(from_dir)) {
+            Some(Ok(rest)) => rest.to_owned(),
+            _ => continue,
+        };
+
+        deployed.backup = Some(to_dir.join(rest));
+        moved = true;
+    }
+
+    if moved {
+        state.save(to_path)?;
+    }
+
+    Ok(())
+}
+
+/// Move a file or directory, copying it if it can't be renamed.
+///
+/// Renaming fails if the new location is on another file system, like when the configuration root
+/// is on a separate mount.
+fn move_path(from: &Path, to: &Path) -> Result<(), Error> {
+    if fs::rename(from, to).is_ok() {
+        return Ok(());
+    }
+
+    if let Err(e) = copy_path(from, to) {
+        // NB: a partial copy would be mistaken for state that has already been moved.
+        if to.is_dir() {
+            let _ = fs::remove_dir_all(to);
+        } else {
+            let _ = fs::remove_file(to);
+        }
+
+        return Err(e);
+    }
+
+    if from.is_dir() {
+        fs::remove_dir_all(from)?;
+    } else {
+        fs::remove_file(from)?;
+    }
+
+    Ok(())
+}
+
+/// Recursively copy a file or directory.
+fn copy_path(from: &Path, to: &Path) -> Result<(), Error> {
+    let meta = fs::symlink_metadata(from)?;
+
+    if meta.is_dir() {
+        fs::create_dir(to)?;
+
+        for entry in fs::read_dir(from)? {
+            let entry = entry?;
+            copy_path(&entry.path(), &to.join(entry.file_name()))?;
+        }
+
+        return Ok(());
+    }
+
+    #[cfg(unix)]
+    {
+        if meta.file_type().is_symlink() {
+            std::os::unix::fs::symlink(fs::read_link(from)?, to)?;
             return Ok(());
         }
     }
 
-    if opts.updates_only {
-        log::info!("Updated found, running...");
+    fs::copy(from, to)?;
+    Ok(())
+}
+
+/// Print what quickcfg has changed, optionally only since the given duration ago.
+fn try_status(
+    opts: &Opts,
+    config: &Config,
+    now: Timestamp,
+    state_path: &Path,
+) -> Result<(), Error> {
+    let state = DiskState::open(state_path)
+        .with_context(|| anyhow!("Invalid disk state `{}`", state_path.display()))?
+        .unwrap_or_default()
+        .into_state(config, now);
+
+    let since = match opts.changed_since {
+        Some(duration) => now - duration,
+        None => Timestamp::from(std::time::UNIX_EPOCH),
+    };
+
+    let changed = state.changed_since(since);
+
+    if changed.is_empty() {
+        log::info!("No changes");
+        return Ok(());
     }
 
-    let facts = Facts::load().with_context(|| "Failed to load facts")?;
-    let environment = e::Real;
-    let data = hierarchy::load(&config.hierarchy, root, &facts, environment)
-        .with_context(|| "Failed to load hierarchy")?;
+    for (unit, changed) in changed {
+        match changed.system.as_deref() {
+            Some(system) => println!("{}  {}: {}", changed.updated, system, unit),
+            None => println!("{}  {}", changed.updated, unit),
+        }
+    }
 
-    let packages = packages::detect(&facts)?;
+    Ok(())
+}
 
-    let allocator = UnitAllocator::default();
+/// Remove everything that has been deployed, optionally only by the given system.
+fn try_uninstall(opts: &Opts, state: &mut State<'_>) -> Result<(), Error> {
+    let mut paths = state
+        .deployed
+        .iter()
+        .filter(|(_, d)| opts.system.is_none() || d.system.as_deref() == opts.system.as_deref())
+        .map(|(path, _)| path.clone())
+        .collect::<Vec<_>>();
+
+    if paths.is_empty() {
+        log::info!("Nothing to uninstall");
+        return Ok(());
+    }
+
+    if !opts.confirm(&format!("Uninstall {} path(s)?", paths.len()))? {
+        return Ok(());
+    }
+
+    // NB: remove the content of directories before the directories themselves.
+    paths.sort_by(|a, b| b.cmp(a));
+
+    for path in paths {
+        let deployed = match state.deployed.get(&path) {
+            Some(deployed) => deployed.clone(),
+            None => continue,
+        };
 
-    let file_system = FileSystem::new(opts, state_dir, &allocator, &data);
+        if !uninstall_path(&path, &deployed)? {
+            continue;
+        }
+
+        if let Some(backup) = deployed.backup.as_ref() {
+            log::info!("restoring {} from {}", path.display(), backup.display());
+            fs::rename(backup, &path)
+                .with_context(|| anyhow!("Failed to restore backup: {}", backup.display()))?;
+        }
+
+        state.undeploy(&path);
+    }
+
+    Ok(())
+}
 
-    // post-hook for all systems, mapped by id.
-    let mut post_systems = HashMap::new();
-    let mut all_units = Vec::new();
-    let mut pre_systems = Vec::new();
-    let mut errors = Vec::new();
+/// Remove a single deployed path, unless it has been modified since it was deployed.
+///
+/// Returns `true` if the path no longer exists.
+fn uninstall_path(path: &Path, deployed: &state::Deployed) -> Result<bool, Error> {
+    use state::DeployedKind;
 
-    // translate systems that needs translation.
-    let systems = {
-        use std::collections::VecDeque;
+    let meta = match FileSystem::try_open_meta(path)? {
+        Some(meta) => meta,
+        None => return Ok(true),
+    };
 
-        let mut out = Vec::with_capacity(config.systems.len());
-        let mut queue = VecDeque::new();
-        queue.extend(&config.systems);
+    let unmodified = match deployed.kind {
+        DeployedKind::File => {
+            let modified = meta.modified().ok().map(Timestamp::from);
 
-        while let Some(system) = queue.pop_back() {
-            match system.translate() {
-                system::Translation::Discard => {}
-                system::Translation::Keep => out.push(system),
-                system::Translation::Expand(systems) => queue.extend(systems),
+            meta.is_file()
+                && match (modified, deployed.modified) {
+                    (Some(a), Some(b)) => a.same_millis(b),
+                    _ => false,
+                }
+        }
+        DeployedKind::Symlink => {
+            meta.file_type().is_symlink()
+                && fs::read_link(path).ok().as_ref() == deployed.link.as_ref()
+        }
+        DeployedKind::Dir => {
+            if !meta.is_dir() {
+                false
+            } else if fs::read_dir(path)?.next().is_some() {
+                log::info!("leaving non-empty directory: {}", path.display());
+                return Ok(false);
+            } else {
+                true
             }
         }
+    };
+
+    if !unmodified {
+        log::warn!("leaving modified path: {}", path.display());
+        return Ok(false);
+    }
+
+    log::info!("removing {}", path.display());
+
+    if meta.is_dir() {
+        fs::remove_dir(path)?;
+    } else {
+        fs::remove_file(path)?;
+    }
 
-        out
+    Ok(true)
+}
+
+/// Build the options used to authenticate with git.
+///
+/// Returns the facts and hierarchy if they had to be loaded, so that they can be reused.
+fn git_auth(
+    opts: &Opts,
+    config: &Config,
+    now: Timestamp,
+    base_dirs: Option<&BaseDirs>,
+    root: &Path,
+    state_dir: &Path,
+) -> Result<(git::Auth, Option<Preloaded>), Error> {
+    let mut auth = git::Auth {
+        credential_helper: config.git_auth.credential_helper.clone(),
+        non_interactive: opts.is_non_interactive(),
+        ..git::Auth::default()
     };
 
-    pool.install(|| {
-        let res = systems.par_iter().map(|system| {
-            let res = system.apply(SystemInput {
-                root: &root,
-                base_dirs,
-                facts: &facts,
-                data: &data,
-                packages: &packages,
-                environment,
-                allocator: &allocator,
-                file_system: &file_system,
-                state,
-                now,
-                opts,
-                git_system,
-            });
-
-            match res {
-                Ok(units) => Ok((system, units)),
-                Err(e) => Err((system, e)),
+    if config.git_auth.ssh_key.is_none() && config.git_auth.token_key.is_none() {
+        return Ok((auth, None));
+    }
+
+    let facts = load_facts(opts, config, now, root, state_dir)?;
+
+    if let Some(ssh_key) = config.git_auth.ssh_key.as_ref() {
+        auth.ssh_key = ssh_key.as_path(root, base_dirs, &facts, e::Real)?;
+    }
+
+    let mut preloaded = Preloaded { facts, data: None };
+
+    if let Some(token_key) = config.git_auth.token_key.as_ref() {
+        let repos = load_repos(config, state_dir)?;
+        let profile = opts.profile.clone();
+        let data = load_data(config, root, &repos, &preloaded.facts, profile.as_deref())?;
+
+        match data.load::<String>(token_key)? {
+            Some(token) => auth.token = Some((config.git_auth.token_user.clone(), token)),
+            None => log::warn!("Missing git token `{}` in hierarchy", token_key),
+        }
+
+        preloaded.data = Some((profile, data));
+    }
+
+    Ok((auth, Some(preloaded)))
+}
+
+/// Try to initialize the repository from the given path.
+fn try_init(git_system: &dyn git::GitSystem, url: &str, root: &Path) -> Result<(), Error> {
+    let git = git::GitSystem::clone(git_system, url, root)?;
+
+    // NB: errors in the configuration are reported once we try to apply it.
+    let submodules = Config::path(root)
+        .and_then(|path| Config::load(&path))
+        .ok()
+        .flatten()
+        .map(|config| config.git_submodules)
+        .unwrap_or(true);
+
+    if submodules {
+        git.update_submodules()?;
+    }
+
+    Ok(())
+}
+
+/// Try to initialize the configuration from the archive at the given URL.
+fn try_init_archive(url: &str, root: &Path, state_dir: &Path) -> Result<(), Error> {
+    fs::create_dir_all(state_dir)
+        .with_context(|| anyhow!("Failed to create state directory: {}", state_dir.display()))?;
+
+    let mut source = archive::Source::new(url);
+    source.update(root)?;
+    source.save(state_dir)?;
+    Ok(())
+}
+
+#[allow(clippy::too_many_arguments)]
+/// Internal method to try to apply the given configuration.
+fn try_apply_config<'c>(
+    git_system: &'c dyn git::GitSystem,
+    opts: &'c Opts,
+    config: &'c Config,
+    now: Timestamp,
+    base_dirs: Option<&'c BaseDirs>,
+    root: &'c Path,
+    state_dir: &'c Path,
+    mut preloaded: Option<&'c Preloaded>,
+    state: &mut State<'c>,
+) -> Result<Summary, Error> {
+    let mut early_check = None;
+
+    // NB: garbage collection and graphs only look at the configuration as it is.
+    if !opts.gc && opts.graph.is_none() && opts.simulate.is_none() {
+        let updated = try_update_config(git_system, opts, config, now, root, state_dir, state)?;
+        let repos_updated = try_update_repos(git_system, opts, config, now, state_dir, state)?;
+
+        // NB: fact scripts and the hierarchy might have changed with the update.
+        if updated || repos_updated {
+            preloaded = None;
+        }
+
+        if !updated && !repos_updated {
+            // if we only want to run on updates, exit now.
+            if opts.updates_only {
+                return Ok(Summary::default());
             }
-        });
-
-        // Collect all units and map out a unit id to each system that can be used as a dependency.
-        for res in res.collect::<Vec<_>>() {
-            let (system, mut units) = match res {
-                Ok(result) => result,
-                Err((system, e)) => {
-                    errors.push((system, e));
-                    continue;
+        }
+
+        if opts.updates_only {
+            log::info!("Updated found, running...");
+        }
+
+        if !updated {
+            early_check =
+                early_config_check(git_system, opts, config, now, root, state_dir, state)?;
+        }
+    }
+
+    let session = Session {
+        opts,
+        config,
+        root,
+        state_dir,
+        base_dirs,
+        git_system,
+        now,
+        preloaded,
+    };
+
+    let git = match early_check {
+        Some(git) => git,
+        None => return session.apply(state),
+    };
+
+    let revision = config_revision(opts, config);
+
+    std::thread::scope(|s| {
+        log::trace!("Checking for updates to the configuration in the background");
+        let check = s.spawn(move || git.needs_update(revision));
+
+        session.apply_joining(state, |state| {
+            let result = check
+                .join()
+                .map_err(|_| anyhow!("Checking for updates panicked"))
+                .and_then(|result| result);
+
+            match result {
+                Ok(true) => {
+                    // NB: the configuration has already been loaded, so the update is applied by
+                    // the next run, which doesn't have to wait for the interval to expire.
+                    log::info!(
+                        "Configuration has updates to {}, which are applied on the next run",
+                        revision
+                    );
+                    state.expire("git");
+                }
+                Ok(false) => state.touch("git"),
+                Err(e) => {
+                    log::warn!("Failed to check for updates in the background");
+                    report_error(e);
                 }
+            }
+
+            Ok(())
+        })
+    })
+}
+
+/// Open the configuration repository to check it for updates in the background, if a check isn't
+/// due yet but will be soon.
+///
+/// Checking early means that the run which the check would be due in doesn't have to wait for it.
+fn early_config_check(
+    git_system: &dyn git::GitSystem,
+    opts: &Opts,
+    config: &Config,
+    now: Timestamp,
+    root: &Path,
+    state_dir: &Path,
+    state: &State,
+) -> Result<Option<Box<dyn git::Git>>, Error> {
+    // NB: nothing is checked in the background which would prompt.
+    if opts.init.is_some() || opts.git_ref.is_some() || !opts.is_non_interactive() {
+        return Ok(None);
+    }
+
+    if opts.decide(Concern::Update, config.prompts.update, state) != Some(true) {
+        return Ok(None);
+    }
+
+    // NB: also skips the repository if it was checked by this run.
+    let last_update = match state.last_update("git") {
+        Some(last_update) if *last_update < now => *last_update,
+        _ => return Ok(None),
+    };
+
+    let early = config.git_refresh.mul_f64(1.0 - EARLY_CHECK_FRACTION);
+
+    if now.duration_since(last_update).unwrap_or_default() < early {
+        return Ok(None);
+    }
+
+    // NB: archives aren't fetched separately from being updated.
+    if archive::Source::open(state_dir)?.is_some() || !git_system.test()? {
+        return Ok(None);
+    }
+
+    Ok(Some(git_system.open(root)?))
+}
+
+/// Generate systems from another dotfile manager, and add them to the configuration.
+fn try_import(
+    opts: &Opts,
+    import: &opts::Import,
+    base_dirs: Option<&BaseDirs>,
+    root: &Path,
+    config_path: &Path,
+) -> Result<(), Error> {
+    let import = match import {
+        opts::Import::Stow {
+            dir,
+            target,
+            dotfiles,
+        } => import::stow(
+            root,
+            &import::Stow {
+                dir,
+                target,
+                dotfiles: *dotfiles,
+            },
+        )?,
+        opts::Import::Chezmoi { source, dest } => {
+            let source = match source {
+                Some(source) => source.clone(),
+                // NB: chezmoi uses the same default on every operating system.
+                None => match base_dirs {
+                    Some(base_dirs) => base_dirs.home_dir().join(".local/share/chezmoi"),
+                    None => bail!("No base directories available"),
+                },
             };
 
-            if !system.requires().is_empty() {
-                // Unit that all contained units depend on.
-                // This unit finishes _before_ any unit in the system.
-                let pre = allocator.unit(Unit::System);
+            import::chezmoi(&import::Chezmoi {
+                source: &source,
+                dest,
+            })?
+        }
+    };
 
-                for unit in &mut units {
-                    unit.dependencies.push(unit::Dependency::Unit(pre.id));
-                }
+    if import.is_empty() {
+        log::info!("Nothing to import");
+        return Ok(());
+    }
 
-                pre_systems.push((pre, system::Dependency::Transitive(system.requires())));
-            }
+    if opts.dry_run {
+        for file in &import.files {
+            println!("# copy: {} -> {}", file.from.display(), file.to);
+        }
 
-            if let Some(system_id) = system.id() {
-                if units.is_empty() {
-                    // If system is empty, there is nothing to depend on.
-                    post_systems
-                        .insert(system_id, system::Dependency::Transitive(system.requires()));
-                    continue;
-                }
+        print!("{}", import.apply("")?);
+        return Ok(());
+    }
 
-                // Unit that other systems depend on.
-                // This unit finishes _after_ all units in the system have finished.
-                // System units depend on all units it contains.
-                let mut post = allocator.unit(Unit::System);
-                post.dependencies
-                    .extend(units.iter().map(|u| unit::Dependency::Unit(u.id)));
-                post_systems.insert(system_id, system::Dependency::Direct(post.id));
-                all_units.push(post);
-            }
+    // NB: systems are added by editing the YAML, to preserve comments and formatting.
+    if config_path.extension().is_some_and(|e| e == "toml") {
+        bail!(
+            "Importing into {} is not supported, use --dry-run and convert the systems to TOML",
+            config_path.display()
+        );
+    }
+
+    let config = match fs::read_to_string(config_path) {
+        Ok(config) => config,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+        Err(e) => return Err(e.into()),
+    };
+
+    let config = import
+        .apply(&config)
+        .with_context(|| anyhow!("Failed to import into: {}", config_path.display()))?;
+
+    import.copy_files(root)?;
+
+    FileSystem::write_atomic(config_path, true, |f| {
+        use std::io::Write as _;
+        Ok(f.write_all(config.as_bytes())?)
+    })?;
+
+    log::info!(
+        "Added {} systems and copied {} files to {}",
+        import.systems.len(),
+        import.files.len(),
+        root.display()
+    );
+
+    Ok(())
+}
+
+/// Print the value of the given hierarchy key, and which files in the hierarchy define it.
+fn try_data(
+    opts: &Opts,
+    config: &Config,
+    now: Timestamp,
+    root: &Path,
+    state_path: &Path,
+    state_dir: &Path,
+    key: &str,
+) -> Result<(), Error> {
+    use serde_yaml::Value;
+
+    // NB: look up the key like it would be with the profile of the machine.
+    let profile = match opts.profile.clone() {
+        Some(profile) => Some(profile),
+        None => DiskState::open(state_path)?.and_then(|state| state.profile),
+    };
+
+    if let Some(profile) = profile.as_deref() {
+        config.check_profile(profile)?;
+    }
+
+    let facts = load_facts(opts, config, now, root, state_dir)?;
+    let repos = load_repos(config, state_dir)?;
+    let data = load_data(config, root, &repos, &facts, profile.as_deref())?;
 
-            all_units.extend(units);
+    let sources = data.sources(key);
+
+    let (_, value) = match sources.first() {
+        Some(first) => *first,
+        None => bail!("Key `{}` is not defined in the hierarchy", key),
+    };
+
+    println!("# value:");
+    print!("{}", to_yaml(value)?);
+
+    if sources
+        .iter()
+        .all(|(_, v)| matches!(v, Value::Sequence(..)))
+    {
+        let merged = data.load_array::<Value>(key)?;
+        println!("# merged array (key:array):");
+        print!("{}", to_yaml(&Value::Sequence(merged))?);
+    }
+
+    for (path, value) in sources {
+        match path {
+            Some(path) => println!("# from: {}", path.display()),
+            None => println!("# from: <unknown>"),
         }
-    });
 
-    file_system.validate()?;
+        print!("{}", to_yaml(value)?);
+    }
 
-    if !errors.is_empty() {
-        for (system, e) in errors.into_iter() {
-            log::error!("System failed: {}", system);
-            report_error(e);
+    return Ok(());
+
+    fn to_yaml(value: &Value) -> Result<String, Error> {
+        let mut out = serde_yaml::to_string(value)?;
+
+        if let Some(rest) = out.strip_prefix("---\n") {
+            out = rest.to_string();
         }
 
-        bail!("Failed to run all systems");
+        if !out.ends_with('\n') {
+            out.push('\n');
+        }
+
+        Ok(out)
     }
+}
 
-    // Wire up systems that have requires.
-    for (mut pre, depend) in pre_systems {
-        pre.dependencies.extend(depend.resolve(&post_systems));
-        all_units.push(pre);
+/// Try to clone or update all additional repositories.
+///
+/// Returns `true` if any repository was cloned or updated.
+fn try_update_repos(
+    git_system: &dyn git::GitSystem,
+    opts: &Opts,
+    config: &Config,
+    now: Timestamp,
+    state_dir: &Path,
+    state: &mut State,
+) -> Result<bool, Error> {
+    if config.repos.is_empty() {
+        return Ok(false);
     }
 
-    // Schedule all units into stages that can be run independently in parallel.
-    let mut scheduler = stage::Stager::new(all_units);
+    if !git_system.test()? {
+        log::warn!("no working git command found");
+        return Ok(false);
+    }
 
-    let mut errors = Vec::new();
-    let mut i = 0;
+    let mut updated = false;
 
-    // Note: convert into a scoped pool that feeds units to be scheduled.
-    pool.install(|| {
-        while let Some(stage) = scheduler.stage() {
-            i += 1;
+    for repo in &config.repos {
+        let path = repo_dir(state_dir, repo)?;
+        let id = format!("git:{}", repo.name);
 
-            if log::log_enabled!(log::Level::Trace) {
-                log::trace!(
-                    "Running stage #{} ({} unit(s)) (thread_local: {})",
-                    i,
-                    stage.units.len(),
-                    stage.thread_local
-                );
+        let revision = repo.revision();
 
-                for (i, unit) in stage.units.iter().enumerate() {
-                    log::trace!("{:2}: {}", i, unit);
-                }
-            }
+        if !path.is_dir() {
+            log::info!("Cloning repo `{}` from {}", repo.name, repo.url);
+            let git = git::GitSystem::clone(git_system, &repo.url, &path)?;
 
-            if stage.thread_local {
-                for unit in stage.units {
-                    let mut s = State::new(&config, now);
-
-                    match unit.apply(UnitInput {
-                        data: &data,
-                        packages: &packages,
-                        read_state: &state,
-                        state: &mut s,
-                        now,
-                        git_system,
-                    }) {
-                        Ok(()) => {
-                            scheduler.mark(unit);
-                        }
-                        Err(e) => {
-                            errors.push((unit, e));
-                        }
-                    }
-
-                    state.extend(s);
+            if let git::Revision::Branch(..) = revision {
+                if git.needs_update(revision)? {
+                    git.update(revision)?;
                 }
+            }
 
-                continue;
+            if config.git_submodules {
+                git.update_submodules()?;
             }
 
-            let results = stage
-                .units
-                .into_par_iter()
-                .map(|unit| {
-                    let mut s = State::new(&config, now);
-
-                    let res = unit.apply(UnitInput {
-                        data: &data,
-                        packages: &packages,
-                        read_state: &state,
-                        state: &mut s,
-                        now,
-                        git_system,
-                    });
-
-                    (res, unit, s)
-                })
-                .collect::<Vec<_>>();
-
-            for (res, unit, s) in results {
-                match res {
-                    Ok(()) => {
-                        scheduler.mark(unit);
-                    }
-                    Err(e) => {
-                        errors.push((unit, e));
-                    }
-                }
+            state.touch(&id);
+            updated = true;
+            continue;
+        }
 
-                state.extend(s);
+        if let Some(last_update) = state.last_update(&id) {
+            if now.duration_since(*last_update)? < repo.refresh_interval(config) {
+                continue;
             }
         }
-    });
 
-    if !errors.is_empty() {
-        for (i, (unit, e)) in errors.into_iter().enumerate() {
-            log::error!("{:2}: {}", i, unit);
-            report_error(e);
+        let git = git_system.open(&path)?;
+
+        if git.needs_update(revision)? {
+            log::info!("Updating repo `{}` to {}", repo.name, revision);
+            updated |= try_update_repo(&*git, revision, opts, config)?;
         }
 
-        bail!("Failed to run all units");
+        state.touch(&id);
     }
 
-    let unscheduled = scheduler.into_unstaged();
+    Ok(updated)
+}
 
-    if !unscheduled.is_empty() {
-        if log::log_enabled!(log::Level::Trace) {
-            log::trace!("Unable to schedule the following units:");
+/// The revision of the configuration repository to track.
+fn config_revision<'a>(opts: &'a Opts, config: &'a Config) -> git::Revision<'a> {
+    match (
+        opts.git_ref.as_deref().or(config.git_ref.as_deref()),
+        config.git_branch.as_deref(),
+    ) {
+        (Some(git_ref), _) => git::Revision::Ref(git_ref),
+        (None, Some(branch)) => git::Revision::Branch(branch),
+        (None, None) => git::Revision::Head,
+    }
+}
 
-            for (i, unit) in unscheduled.into_iter().enumerate() {
-                log::trace!("{:2}: {}", i, unit);
-            }
+/// Check the configuration and all additional repositories for updates, without applying them.
+///
+/// Returns `true` if any of them has updates.
+fn try_check(
+    git_system: &dyn git::GitSystem,
+    opts: &Opts,
+    config: &Config,
+    root: &Path,
+    state_dir: &Path,
+) -> Result<bool, Error> {
+    let mut behind = false;
+
+    if let Some(source) = archive::Source::open(state_dir)? {
+        if source.needs_update()? {
+            println!("Configuration has changed: {}", source.url);
+            behind = true;
+        } else {
+            println!("Configuration is up to date");
+        }
+    } else {
+        let git = git_system.open(root)?;
+        behind |= check_repo(&*git, "Configuration", config_revision(opts, config))?;
+    }
+
+    for repo in &config.repos {
+        let path = repo_dir(state_dir, repo)?;
+        let name = format!("Repo `{}`", repo.name);
+
+        if !path.is_dir() {
+            println!("{} is not cloned yet", name);
+            behind = true;
+            continue;
         }
 
-        bail!("Could not schedule all units");
+        let git = git_system.open(&path)?;
+        behind |= check_repo(&*git, &name, repo.revision())?;
     }
 
-    Ok(())
+    Ok(behind)
 }
 
-/// Try to update config from git.
+/// Report if the given repository is behind the given revision.
+fn check_repo(git: &dyn git::Git, name: &str, revision: git::Revision<'_>) -> Result<bool, Error> {
+    if !git.needs_update(revision)? {
+        println!("{} is up to date", name);
+        return Ok(false);
+    }
+
+    match git.commits_behind(revision)? {
+        0 => println!("{} needs to be updated to {}", name, revision),
+        1 => println!("{} is 1 commit behind {}", name, revision),
+        n => println!("{} is {} commits behind {}", name, n, revision),
+    }
+
+    Ok(true)
+}
+
+/// Try to update config from git, or from an archive if that is where it came from.
 ///
 /// Returns `true` if we have successfully downloaded a new update. `false` otherwise.
 fn try_update_config(
@@ -421,22 +1188,50 @@ fn try_update_config(
     config: &Config,
     now: Timestamp,
     root: &Path,
+    state_dir: &Path,
     state: &mut State,
 ) -> Result<bool, Error> {
-    if let Some(last_update) = state.last_update("git") {
-        let duration = now.duration_since(last_update.clone())?;
+    // NB: no need to check for updates in a repository we just cloned.
+    if opts.init.is_some() {
+        return Ok(false);
+    }
+
+    let revision = config_revision(opts, config);
+
+    // NB: explicitly pinning from the command line always checks the repo.
+    if let (Some(last_update), None) = (state.last_update("git"), opts.git_ref.as_ref()) {
+        let duration = now.duration_since(*last_update)?;
 
-        if duration < config.git_refresh {
+        if duration < config.refresh_interval() {
             return Ok(false);
         }
 
         log::info!("{}s since last git update...", duration.as_secs());
     };
 
-    if !opts.prompt("Do you want to check for updates?", true)? {
+    if opts.git_ref.is_none()
+        && !opts.ask(
+            Concern::Update,
+            config.prompts.update,
+            "Do you want to check for updates?",
+            state,
+        )?
+    {
         return Ok(false);
     }
 
+    if let Some(mut source) = archive::Source::open(state_dir)? {
+        log::info!("Checking for updates to {}", source.url);
+        let updated = source.update(root)?;
+
+        if updated {
+            source.save(state_dir)?;
+        }
+
+        state.touch("git");
+        return Ok(updated);
+    }
+
     if !git_system.test()? {
         log::warn!("no working git command found");
         state.touch("git");
@@ -445,17 +1240,116 @@ fn try_update_config(
 
     let git = git_system.open(root)?;
 
-    if !git.needs_update()? {
-        state.touch("git");
-        return Ok(false);
+    let updated = if git.needs_update(revision)? {
+        log::info!("Updating configuration to {}", revision);
+        try_update_repo(&*git, revision, opts, config)?
+    } else {
+        false
+    };
+
+    if config.git_auto_push {
+        // NB: failing to push shouldn't prevent us from applying the configuration.
+        if let Err(e) = try_push(&*git) {
+            log::warn!("Failed to push local changes");
+            report_error(e);
+        }
     }
 
-    if opts.force {
-        git.force_update()?;
+    state.touch("git");
+    Ok(updated)
+}
+
+/// Commit all local changes in the given repository and push them.
+///
+/// Returns `true` if there were any changes to commit.
+fn try_push(git: &dyn git::Git) -> Result<bool, Error> {
+    let files = git.stage_all()?;
+
+    if !files.is_empty() {
+        let mut message = format!("Update {} file(s) with quickcfg\n\n", files.len());
+
+        for file in &files {
+            message.push_str(&format!("* {}\n", file));
+        }
+
+        log::info!("Committing changes to: {}", files.join(", "));
+        git.commit(&message)?;
+    }
+
+    // NB: always push, in case an earlier push failed.
+    log::info!("Pushing {}", git.path().display());
+    git.push()?;
+    Ok(!files.is_empty())
+}
+
+/// Update the given repository to a revision that has already been fetched, handling local changes
+/// according to the configured policy.
+///
+/// Returns `true` if the repository was updated.
+fn try_update_repo(
+    git: &dyn git::Git,
+    revision: git::Revision<'_>,
+    opts: &Opts,
+    config: &Config,
+) -> Result<bool, Error> {
+    use git::LocalChanges;
+
+    let policy = if opts.force {
+        LocalChanges::Discard
     } else {
-        git.update()?;
+        config.git_local_changes
+    };
+
+    let dirty = git.dirty_files()?;
+
+    if !dirty.is_empty() {
+        log::warn!(
+            "Uncommitted changes in {}: {}",
+            git.path().display(),
+            dirty.join(", ")
+        );
+    }
+
+    match policy {
+        LocalChanges::Abort if !dirty.is_empty() => {
+            log::warn!("Not updating, commit or discard the changes, or run with --force");
+            return Ok(false);
+        }
+        LocalChanges::Discard => {
+            git.force_update(revision)?;
+        }
+        LocalChanges::Stash if !dirty.is_empty() => {
+            git.stash()?;
+
+            if let Err(e) = git.update(revision) {
+                // NB: put the local changes back, since nothing was updated.
+                if let Err(pop) = git.stash_pop() {
+                    log::warn!("Failed to reapply local changes: {}", pop);
+
+                    return Err(e.context(anyhow!(
+                        "Local changes in {} are kept in the stash",
+                        git.path().display()
+                    )));
+                }
+
+                return Err(e);
+            }
+
+            git.stash_pop().with_context(|| {
+                anyhow!(
+                    "Failed to reapply local changes in {}, they are kept in the stash",
+                    git.path().display()
+                )
+            })?;
+        }
+        _ => {
+            git.update(revision)?;
+        }
+    }
+
+    if config.git_submodules {
+        git.update_submodules()?;
     }
 
-    state.touch("git");
     Ok(true)
 }
@@ -0,0 +1,226 @@
+//! Shared HTTP client, so that everything which fetches over the network is configured the same
+//! way.
+//!
+//! The client is configured once the configuration has been loaded. Anything fetched before then,
+//! like the archive when initializing, uses the default configuration.
+
+use crate::config::Http;
+use anyhow::{anyhow, Context as _, Error};
+use reqwest::blocking::{Client as Inner, RequestBuilder, Response};
+use reqwest::{header, StatusCode};
+use std::fs;
+use std::sync::{Arc, RwLock};
+use std::thread;
+use std::time::Duration;
+
+/// Delay before the first retry, which doubles for every retry after it.
+const RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// The configured client.
+static CLIENT: RwLock<Option<Arc<Client>>> = RwLock::new(None);
+
+/// Configure the shared client, replacing any previous configuration.
+pub fn configure(http: &Http) -> Result<(), Error> {
+    let client = Client::new(http)?;
+    *CLIENT.write().unwrap_or_else(|e| e.into_inner()) = Some(Arc::new(client));
+    Ok(())
+}
+
+/// Get the shared client, using the default configuration unless it has been configured.
+pub fn client() -> Result<Arc<Client>, Error> {
+    if let Some(client) = CLIENT.read().unwrap_or_else(|e| e.into_inner()).as_ref() {
+        return Ok(Arc::clone(client));
+    }
+
+    let client = Arc::new(Client::new(&Http::default())?);
+    *CLIENT.write().unwrap_or_else(|e| e.into_inner()) = Some(Arc::clone(&client));
+    Ok(client)
+}
+
+/// The result of a conditional request.
+pub enum Conditional {
+    /// The resource hasn't changed since it was fetched with the given ETag.
+    NotModified,
+    /// The resource has changed, or it was fetched without an ETag.
+    Modified {
+        /// The response with the resource.
+        response: Box<Response>,
+        /// The ETag of the resource, to use for the next request.
+        etag: Option<String>,
+    },
+}
+
+/// A configured HTTP client.
+pub struct Client {
+    inner: Inner,
+    timeout: Option<Duration>,
+    retries: u32,
+}
+
+impl Client {
+    /// Construct a new client with the given configuration.
+    pub fn new(http: &Http) -> Result<Self, Error> {
+        let mut builder = Inner::builder()
+            .user_agent(concat!("quickcfg/", env!("CARGO_PKG_VERSION")))
+            .connect_timeout(http.connect_timeout)
+            // NB: timeouts are set on each request, since they depend on the deadline of the unit.
+            .timeout(None);
+
+        // NB: without a configured proxy, the `HTTPS_PROXY` and `HTTP_PROXY` environment
+        // variables are used.
+        if let Some(proxy) = &http.proxy {
+            let proxy =
+                reqwest::Proxy::all(proxy).with_context(|| anyhow!("bad `proxy`: {}", proxy))?;
+            builder = builder.proxy(proxy);
+        }
+
+        if let Some(path) = &http.ca_certificate {
+            let pem = fs::read(path)
+                .with_context(|| anyhow!("failed to read `ca_certificate`: {}", path.display()))?;
+
+            let certificate = reqwest::Certificate::from_pem(&pem)
+                .with_context(|| anyhow!("bad `ca_certificate`: {}", path.display()))?;
+
+            builder = builder.add_root_certificate(certificate);
+        }
+
+        Ok(Client {
+            inner: builder.build()?,
+            timeout: http.timeout,
+            retries: http.retries,
+        })
+    }
+
+    /// Get the given URL.
+    pub fn get(&self, url: &str) -> Result<Response, Error> {
+        self.send(|| self.inner.get(url), self.timeout, self.retries)
+            .with_context(|| anyhow!("download url: {}", url))
+    }
+
+    /// Get the given URL, unless it hasn't changed since it was fetched with the given ETag.
+    pub fn get_conditional(&self, url: &str, etag: Option<&str>) -> Result<Conditional, Error> {
+        let request = || {
+            let request = self.inner.get(url);
+
+            match etag {
+                Some(etag) => request.header(header::IF_NONE_MATCH, etag),
+                None => request,
+            }
+        };
+
+        let response = self
+            .send(request, self.timeout, self.retries)
+            .with_context(|| anyhow!("download url: {}", url))?;
+
+        if response.status() == StatusCode::NOT_MODIFIED {
+            return Ok(Conditional::NotModified);
+        }
+
+        let etag = response
+            .headers()
+            .get(header::ETAG)
+            .and_then(|etag| etag.to_str().ok())
+            .map(String::from);
+
+        Ok(Conditional::Modified {
+            response: Box::new(response),
+            etag,
+        })
+    }
+
+    /// Post the given JSON to the given URL, with a timeout and number of retries which override
+    /// the configured ones.
+    pub fn post_json(
+        &self,
+        url: &str,
+        body: &str,
+        timeout: Duration,
+        retries: u32,
+    ) -> Result<(), Error> {
+        let request = || {
+            self.inner
+                .post(url)
+                .header(header::CONTENT_TYPE, "application/json")
+                .body(body.to_string())
+        };
+
+        self.send(request, Some(timeout), retries)?;
+        Ok(())
+    }
+
+    /// Send a request, retrying it with an increasing delay if it fails in a way which might be
+    /// temporary.
+    fn send(
+        &self,
+        request: impl Fn() -> RequestBuilder,
+        timeout: Option<Duration>,
+        retries: u32,
+    ) -> Result<Response, Error> {
+        let mut delay = RETRY_DELAY;
+        let mut attempt = 0;
+
+        loop {
+            let mut request = request();
+
+            // NB: the deadline of the unit, if any, takes precedence.
+            if let Some(timeout) = min(timeout, crate::deadline::remaining()) {
+                request = request.timeout(timeout);
+            }
+
+            let result = request.send().and_then(|response| {
+                if is_retryable(response.status()) {
+                    response.error_for_status()
+                } else {
+                    Ok(response)
+                }
+            });
+
+            let error = match result {
+                Ok(response) => return Ok(response.error_for_status()?),
+                Err(error) => error,
+            };
+
+            if attempt >= retries || crate::deadline::has_passed() {
+                return Err(error.into());
+            }
+
+            log::warn!("Request failed, retrying in {:?}: {}", delay, error);
+            thread::sleep(delay);
+            delay *= 2;
+            attempt += 1;
+        }
+    }
+}
+
+/// Test if a response with the given status is worth retrying.
+fn is_retryable(status: StatusCode) -> bool {
+    status.is_server_error() || status == StatusCode::TOO_MANY_REQUESTS
+}
+
+/// The smallest of two optional durations.
+fn min(a: Option<Duration>, b: Option<Duration>) -> Option<Duration> {
+    match (a, b) {
+        (Some(a), Some(b)) => Some(a.min(b)),
+        (a, b) => a.or(b),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{is_retryable, min};
+    use reqwest::StatusCode;
+    use std::time::Duration;
+
+    #[test]
+    fn test_retry_policy() {
+        assert!(is_retryable(StatusCode::BAD_GATEWAY));
+        assert!(is_retryable(StatusCode::TOO_MANY_REQUESTS));
+        assert!(!is_retryable(StatusCode::NOT_FOUND));
+        assert!(!is_retryable(StatusCode::NOT_MODIFIED));
+
+        let s = Duration::from_secs;
+        assert_eq!(min(Some(s(1)), Some(s(2))), Some(s(1)));
+        assert_eq!(min(None, Some(s(2))), Some(s(2)));
+        assert_eq!(min(None, None), None);
+    }
+}
@@ -0,0 +1,133 @@
+//! Desktop notifications about the result of a run, for when quickcfg runs unattended.
+
+use crate::{command::Command, config::Notify, session::UnitsFailed, summary::Summary};
+use anyhow::Error;
+
+/// Title of every notification.
+const TITLE: &str = "quickcfg";
+
+/// Environment variables used to pass the notification to scripts which show it, so that it
+/// doesn't have to be escaped.
+#[cfg(any(target_os = "macos", windows))]
+const TITLE_VAR: &str = "QC_NOTIFY_TITLE";
+#[cfg(any(target_os = "macos", windows))]
+const BODY_VAR: &str = "QC_NOTIFY_BODY";
+
+/// The message to show for the result of a run, if any.
+pub fn message(notify: Notify, result: &Result<Summary, Error>) -> Option<String> {
+    match (notify, result) {
+        (Notify::Never, _) => None,
+        (Notify::Always, Ok(summary)) => match summary.total().changed {
+            0 => None,
+            1 => Some(String::from("1 change applied")),
+            n => Some(format!("{} changes applied", n)),
+        },
+        (Notify::Failure, Ok(..)) => None,
+        (_, Err(e)) => {
+            let systems = e
+                .downcast_ref::<UnitsFailed>()
+                .map(|f| f.summary.failed_systems())
+                .unwrap_or_default();
+
+            if systems.is_empty() {
+                Some(format!("failed: {}", e))
+            } else {
+                Some(format!("failed: {}", systems.join(", ")))
+            }
+        }
+    }
+}
+
+/// Show a desktop notification with the given message.
+pub fn send(message: &str) -> Result<(), Error> {
+    command(message).run_checked()
+}
+
+#[cfg(target_os = "macos")]
+fn command(message: &str) -> Command {
+    let mut command = Command::new("osascript");
+    command.env(TITLE_VAR, TITLE);
+    command.env(BODY_VAR, message);
+    command.arg("-e");
+    command.arg(format!(
+        "display notification (system attribute \"{}\") with title (system attribute \"{}\")",
+        BODY_VAR, TITLE_VAR
+    ));
+    command
+}
+
+#[cfg(windows)]
+fn command(message: &str) -> Command {
+    // NB: toasts have to be shown on behalf of a registered application, so borrow the id of
+    // PowerShell.
+    const SCRIPT: &str = r#"
+[Windows.UI.Notifications.ToastNotificationManager, Windows.UI.Notifications, ContentType = WindowsRuntime] | Out-Null
+$template = [Windows.UI.Notifications.ToastNotificationManager]::GetTemplateContent([Windows.UI.Notifications.ToastTemplateType]::ToastText02)
+$text = $template.GetElementsByTagName('text')
+$text.Item(0).AppendChild($template.CreateTextNode($env:QC_NOTIFY_TITLE)) | Out-Null
+$text.Item(1).AppendChild($template.CreateTextNode($env:QC_NOTIFY_BODY)) | Out-Null
+$toast = [Windows.UI.Notifications.ToastNotification]::new($template)
+$app = '{1AC14E77-02E7-4E5D-B744-2EB1AE5198B7}\WindowsPowerShell\v1.0\powershell.exe'
+[Windows.UI.Notifications.ToastNotificationManager]::CreateToastNotifier($app).Show($toast)
+"#;
+
+    let mut command = Command::new("powershell");
+    command.args(&["-NoProfile", "-NonInteractive", "-Command", SCRIPT]);
+    command.env(TITLE_VAR, TITLE);
+    command.env(BODY_VAR, message);
+    command
+}
+
+#[cfg(not(any(target_os = "macos", windows)))]
+fn command(message: &str) -> Command {
+    let mut command = Command::new("notify-send");
+    command.arg("--app-name=quickcfg");
+    command.arg(TITLE);
+    command.arg(message);
+    command
+}
+
+#[cfg(test)]
+mod tests {
+    use super::message;
+    use crate::{config::Notify, session::UnitsFailed, summary::Summary, unit::Outcome};
+
+    #[test]
+    fn test_message() {
+        let mut summary = Summary::default();
+        summary.record(Some("dotfiles"), Outcome::Changed);
+        summary.record(Some("dotfiles"), Outcome::Changed);
+        summary.record(Some("dotfiles"), Outcome::Unchanged);
+
+        let ok = Ok(summary);
+        assert_eq!(message(Notify::Never, &ok), None);
+        assert_eq!(message(Notify::Failure, &ok), None);
+        assert_eq!(
+            message(Notify::Always, &ok).as_deref(),
+            Some("2 changes applied")
+        );
+
+        let nothing = Ok(Summary::default());
+        assert_eq!(message(Notify::Always, &nothing), None);
+
+        let mut summary = Summary::default();
+        summary.record_failed(Some("install_packages"));
+
+        let failed = Err(anyhow::Error::new(UnitsFailed {
+            message: String::from("1 unit(s) failed, and 0 skipped"),
+            summary,
+        }));
+
+        assert_eq!(
+            message(Notify::Failure, &failed).as_deref(),
+            Some("failed: install_packages")
+        );
+
+        let failed = Err(anyhow::anyhow!("Failed to run all systems"));
+
+        assert_eq!(
+            message(Notify::Always, &failed).as_deref(),
+            Some("failed: Failed to run all systems")
+        );
+    }
+}
@@ -1,9 +1,14 @@
 //! Set up options.
 
-use anyhow::{bail, Error};
-use clap::{App, Arg};
+use crate::{
+    config::{Concern, PromptPolicy},
+    git, graph, State,
+};
+use anyhow::{anyhow, bail, Context as _, Error};
+use clap::{App, Arg, SubCommand};
 use directories::BaseDirs;
 use std::path::PathBuf;
+use std::time::Duration;
 
 pub const VERSION: &str = env!("CARGO_PKG_VERSION");
 
@@ -24,6 +29,19 @@ fn app() -> App<'static, 'static> {
                 .help("Initialize against the given repository.")
                 .takes_value(true),
         )
+        .arg(
+            Arg::with_name("ref")
+                .long("ref")
+                .help("Pin the configuration to the given tag or commit.")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("fetch")
+                .long("fetch")
+                .help("How much of the configuration repository to fetch when cloning and updating it.")
+                .possible_values(&["full", "shallow", "blobless"])
+                .takes_value(true),
+        )
         .arg(
             Arg::with_name("paths")
                 .long("paths")
@@ -34,6 +52,11 @@ fn app() -> App<'static, 'static> {
                 .long("force")
                 .help("When updating configuration, force the update."),
         )
+        .arg(
+            Arg::with_name("full-hash")
+                .long("full-hash")
+                .help("Compare the contents of every copied file, even if its modified time and size are unchanged."),
+        )
         .arg(
             Arg::with_name("debug")
                 .long("debug")
@@ -44,30 +67,329 @@ fn app() -> App<'static, 'static> {
                 .long("non-interactive")
                 .help("Force to run in non-interactive mode."),
         )
+        .arg(
+            Arg::with_name("yes")
+                .long("yes")
+                .short("y")
+                .help("Answer yes to every confirmation, like removing invalid state or uninstalling."),
+        )
+        .arg(
+            Arg::with_name("check")
+                .long("check")
+                .help("Only report if there are updates to the configuration, without applying them."),
+        )
+        .arg(
+            Arg::with_name("keep-going")
+                .long("keep-going")
+                .help("Run every unit that doesn't depend on a failed unit, and report all failures at the end."),
+        )
+        .arg(
+            Arg::with_name("fail-fast")
+                .long("fail-fast")
+                .help("Stop running units after the first one which fails.")
+                .conflicts_with("keep-going"),
+        )
+        .arg(
+            Arg::with_name("retry-failed")
+                .long("retry-failed")
+                .help("Only run the units which failed or were skipped in the previous run."),
+        )
+        .arg(
+            Arg::with_name("refresh-facts")
+                .long("refresh-facts")
+                .help("Ignore cached facts and run all fact scripts."),
+        )
+        .arg(
+            Arg::with_name("simulate")
+                .long("simulate")
+                .help("Apply the configuration to the given overlay directory without running any commands, implies --non-interactive.")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("timings")
+                .long("timings")
+                .help("Print how long each system and the slowest units took to apply."),
+        )
+        .arg(
+            Arg::with_name("trace-commands")
+                .long("trace-commands")
+                .help("Log every command that is run, with its arguments, exit status and duration, and include them in --json."),
+        )
+        .arg(
+            Arg::with_name("json")
+                .long("json")
+                .help("Print a report of the run as JSON to stdout."),
+        )
+        .arg(
+            Arg::with_name("profile")
+                .long("profile")
+                .help("Select the profile to apply, which is remembered for later runs.")
+                .takes_value(true),
+        )
         .arg(
             Arg::with_name("updates-only")
                 .long("updates-only")
                 .help("Only run if there are updates."),
         )
+        .subcommand(
+            SubCommand::with_name("bootstrap")
+                .about("Clone the given configuration repository and apply it.")
+                .arg(
+                    Arg::with_name("url")
+                        .help("The git repository to clone.")
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("path")
+                        .help("Where to clone the repository, defaults to the configuration root."),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("push")
+                .about("Commit all changes in the configuration repository and push them."),
+        )
+        .subcommand(
+            SubCommand::with_name("uninstall")
+                .about("Remove everything that has been deployed, and restore what it replaced.")
+                .arg(
+                    Arg::with_name("system")
+                        .long("system")
+                        .help("Only remove what was deployed by the system with the given id.")
+                        .takes_value(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("status")
+                .about("Print when units last changed something, most recent first.")
+                .arg(
+                    Arg::with_name("changed-since")
+                        .long("changed-since")
+                        .help("Only print changes made within the given duration, like `7d`.")
+                        .takes_value(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("graph")
+                .about("Print the dependency graph of all units, without applying them.")
+                .arg(
+                    Arg::with_name("format")
+                        .long("format")
+                        .help("The format to print the graph in.")
+                        .possible_values(&["dot", "mermaid"])
+                        .default_value("dot")
+                        .takes_value(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("doctor")
+                .about("Check for common problems with the environment, and suggest how to fix them."),
+        )
+        .subcommand(
+            SubCommand::with_name("self-update")
+                .about("Replace the quickcfg binary with the latest release, once its signature is verified.")
+                .arg(
+                    Arg::with_name("check")
+                        .long("check")
+                        .help("Only report if there is a newer release, exiting with status code 2 if there is."),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("gc")
+                .about("Remove state for systems and repositories which no longer exist."),
+        )
+        .subcommand(
+            SubCommand::with_name("cache")
+                .about("Manage the cache of downloads.")
+                .subcommand(
+                    SubCommand::with_name("clean").about("Remove all cached downloads."),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("data")
+                .about("Print the value of a key in the hierarchy, and where it is defined.")
+                .arg(
+                    Arg::with_name("key")
+                        .help("The hierarchy key to look up.")
+                        .required(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("import")
+                .about("Generate systems from the configuration of another dotfile manager.")
+                .arg(
+                    Arg::with_name("dry-run")
+                        .long("dry-run")
+                        .help("Print the generated systems instead of adding them to quickcfg.yml.")
+                        .global(true),
+                )
+                .subcommand(
+                    SubCommand::with_name("stow")
+                        .about("Import a directory of GNU stow packages.")
+                        .arg(
+                            Arg::with_name("dir")
+                                .help("The stow directory, where each subdirectory is a package.")
+                                .required(true),
+                        )
+                        .arg(
+                            Arg::with_name("target")
+                                .long("target")
+                                .help("Where the packages are linked to.")
+                                .default_value("home://")
+                                .takes_value(true),
+                        )
+                        .arg(
+                            Arg::with_name("dotfiles")
+                                .long("dotfiles")
+                                .help("Rename files prefixed with `dot-` to start with a dot, like `stow --dotfiles`."),
+                        ),
+                )
+                .subcommand(
+                    SubCommand::with_name("chezmoi")
+                        .about("Import the source state of chezmoi.")
+                        .arg(
+                            Arg::with_name("source")
+                                .help("The source directory of chezmoi, defaults to `~/.local/share/chezmoi`."),
+                        )
+                        .arg(
+                            Arg::with_name("dest")
+                                .long("dest")
+                                .help("Directory in the configuration repository to copy files to.")
+                                .default_value("home")
+                                .takes_value(true),
+                        ),
+                ),
+        )
 }
 
 /// Parse command-line options.
 pub fn opts() -> Result<Opts, Error> {
     let matches = app().get_matches();
 
-    let mut opts = Opts::default();
+    let changed_since = match matches
+        .subcommand_matches("status")
+        .and_then(|m| m.value_of("changed-since"))
+    {
+        Some(duration) => Some(
+            humantime::parse_duration(duration)
+                .with_context(|| anyhow!("bad --changed-since: {}", duration))?,
+        ),
+        None => None,
+    };
+
+    let mut opts = Opts {
+        root: matches.value_of("root").map(PathBuf::from),
+        init: matches.value_of("init").map(String::from),
+        bootstrap: false,
+        git_ref: matches.value_of("ref").map(String::from),
+        git_fetch: match matches.value_of("fetch") {
+            Some("full") => Some(git::Fetch::Full),
+            Some("shallow") => Some(git::Fetch::Shallow),
+            Some("blobless") => Some(git::Fetch::Blobless),
+            _ => None,
+        },
+        paths: matches.is_present("paths"),
+        force: matches.is_present("force"),
+        full_hash: matches.is_present("full-hash"),
+        non_interactive: matches.is_present("non-interactive") || matches.is_present("simulate"),
+        yes: matches.is_present("yes"),
+        updates_only: matches.is_present("updates-only"),
+        profile: matches.value_of("profile").map(String::from),
+        check: matches.is_present("check"),
+        keep_going: matches.is_present("keep-going"),
+        fail_fast: matches.is_present("fail-fast"),
+        retry_failed: matches.is_present("retry-failed"),
+        refresh_facts: matches.is_present("refresh-facts"),
+        simulate: matches.value_of("simulate").map(PathBuf::from),
+        timings: matches.is_present("timings"),
+        trace_commands: matches.is_present("trace-commands"),
+        json: matches.is_present("json"),
+        debug: matches.is_present("debug"),
+        push: matches.subcommand_matches("push").is_some(),
+        status: matches.subcommand_matches("status").is_some(),
+        changed_since,
+        graph: matches
+            .subcommand_matches("graph")
+            .map(|m| match m.value_of("format") {
+                Some("mermaid") => graph::Format::Mermaid,
+                _ => graph::Format::Dot,
+            }),
+        doctor: matches.subcommand_matches("doctor").is_some(),
+        gc: matches.subcommand_matches("gc").is_some(),
+        self_update: matches.subcommand_matches("self-update").is_some(),
+        self_update_check: matches
+            .subcommand_matches("self-update")
+            .is_some_and(|m| m.is_present("check")),
+        cache_clean: false,
+        uninstall: matches.subcommand_matches("uninstall").is_some(),
+        system: matches
+            .subcommand_matches("uninstall")
+            .and_then(|m| m.value_of("system"))
+            .map(String::from),
+        data: matches
+            .subcommand_matches("data")
+            .and_then(|m| m.value_of("key"))
+            .map(String::from),
+        import: None,
+        dry_run: false,
+    };
+
+    if let Some(m) = matches.subcommand_matches("cache") {
+        opts.cache_clean = match m.subcommand() {
+            ("clean", Some(_)) => true,
+            _ => bail!("Missing what to do with the cache, see `qc cache --help`"),
+        };
+    }
+
+    if let Some(m) = matches.subcommand_matches("import") {
+        opts.import = match m.subcommand() {
+            ("stow", Some(m)) => Some(Import::Stow {
+                dir: m.value_of("dir").map(PathBuf::from).unwrap_or_default(),
+                target: m.value_of("target").unwrap_or("home://").to_string(),
+                dotfiles: m.is_present("dotfiles"),
+            }),
+            ("chezmoi", Some(m)) => Some(Import::Chezmoi {
+                source: m.value_of("source").map(PathBuf::from),
+                dest: m.value_of("dest").unwrap_or("home").to_string(),
+            }),
+            _ => bail!("Missing what to import from, see `qc import --help`"),
+        };
+
+        opts.dry_run = m.is_present("dry-run");
+    }
 
-    opts.root = matches.value_of("root").map(PathBuf::from);
-    opts.init = matches.value_of("init").map(String::from);
-    opts.paths = matches.is_present("paths");
-    opts.force = matches.is_present("force");
-    opts.non_interactive = matches.is_present("non-interactive");
-    opts.updates_only = matches.is_present("updates-only");
-    opts.debug = matches.is_present("debug");
+    if let Some(m) = matches.subcommand_matches("bootstrap") {
+        opts.init = m.value_of("url").map(String::from);
+
+        if let Some(path) = m.value_of("path") {
+            opts.root = Some(PathBuf::from(path));
+        }
+
+        opts.bootstrap = true;
+    }
 
     Ok(opts)
 }
 
+/// What to import systems from.
+pub enum Import {
+    /// A directory of GNU stow packages.
+    Stow {
+        /// The stow directory.
+        dir: PathBuf,
+        /// Where packages are linked to.
+        target: String,
+        /// Rename files prefixed with `dot-` to start with a dot.
+        dotfiles: bool,
+    },
+    /// The source state of chezmoi.
+    Chezmoi {
+        /// The source directory, if not the default.
+        source: Option<PathBuf>,
+        /// Directory in the configuration repository to copy files to.
+        dest: String,
+    },
+}
+
 /// A set of parsed options.
 #[derive(Default)]
 pub struct Opts {
@@ -75,16 +397,75 @@ pub struct Opts {
     pub root: Option<PathBuf>,
     /// Initialize the project from the given repo.
     pub init: Option<String>,
+    /// Bootstrap a fresh machine, which requires that the root doesn't exist.
+    pub bootstrap: bool,
+    /// Pin the configuration to the given tag or commit.
+    pub git_ref: Option<String>,
+    /// How much of the configuration repository to fetch.
+    pub git_fetch: Option<git::Fetch>,
     /// Print paths used by quickcfg.
     pub paths: bool,
     /// Force update.
     pub force: bool,
+    /// Compare the contents of every copied file.
+    pub full_hash: bool,
     /// Run in non-interactive mode.
     non_interactive: bool,
+    /// Answer yes to every confirmation.
+    pub yes: bool,
     /// Only run if there are updates to the repo.
     pub updates_only: bool,
+    /// Select the profile of the machine.
+    pub profile: Option<String>,
+    /// Only report if there are updates, without updating or applying anything.
+    pub check: bool,
+    /// Run every unit which doesn't depend on a failed unit, overriding `fail_fast` in the
+    /// configuration.
+    pub keep_going: bool,
+    /// Stop running units after the first one which fails.
+    pub fail_fast: bool,
+    /// Only run units which failed or were skipped in the previous run.
+    pub retry_failed: bool,
+    /// Ignore cached facts.
+    pub refresh_facts: bool,
+    /// Apply the configuration to the given overlay directory, without running commands.
+    pub simulate: Option<PathBuf>,
+    /// Print how long systems and units took to apply.
+    pub timings: bool,
+    /// Log every command which is run.
+    pub trace_commands: bool,
+    /// Print a report of the run as JSON.
+    pub json: bool,
     /// Enable debug logging.
     pub debug: bool,
+    /// Commit and push all changes in the configuration repository.
+    pub push: bool,
+    /// Print what has changed instead of applying the configuration.
+    pub status: bool,
+    /// Only print what has changed within the given duration.
+    pub changed_since: Option<Duration>,
+    /// Print the dependency graph of all units in the given format, instead of applying them.
+    pub graph: Option<graph::Format>,
+    /// Check for common problems instead of applying the configuration.
+    pub doctor: bool,
+    /// Remove unused state without applying the configuration.
+    pub gc: bool,
+    /// Update the quickcfg binary instead of applying the configuration.
+    pub self_update: bool,
+    /// Only report if there is a newer release of quickcfg.
+    pub self_update_check: bool,
+    /// Remove all cached downloads without applying the configuration.
+    pub cache_clean: bool,
+    /// Remove everything that has been deployed instead of applying the configuration.
+    pub uninstall: bool,
+    /// Only uninstall what was deployed by the system with the given id.
+    pub system: Option<String>,
+    /// Print the value of the given hierarchy key instead of applying the configuration.
+    pub data: Option<String>,
+    /// Generate systems from another dotfile manager instead of applying the configuration.
+    pub import: Option<Import>,
+    /// Print imported systems instead of adding them to the configuration.
+    pub dry_run: bool,
 }
 
 impl Opts {
@@ -99,37 +480,115 @@ impl Opts {
         }
     }
 
+    /// Test if we are running in non-interactive mode.
+    pub fn is_non_interactive(&self) -> bool {
+        self.non_interactive
+    }
+
     /// Prompt for yes/no.
+    ///
+    /// Answers yes with `--yes`, and picks the default in non-interactive mode.
     pub fn prompt(&self, question: &str, default: bool) -> Result<bool, Error> {
-        use std::io::{self, Write};
+        if self.yes {
+            return Ok(true);
+        }
 
         if self.non_interactive {
             return Ok(default);
         }
 
-        let stdin = io::stdin();
-        let mut stdout = io::stdout();
-        let mut input = String::new();
-
         let p = if default { "[Y/n]" } else { "[y/N]" };
 
-        loop {
-            write!(stdout, "{} {} ", question, p)?;
-            stdout.flush()?;
+        read_choice(
+            question,
+            p,
+            "Please response with 'yes' or 'no' (or 'y' or 'n')",
+            |input| match input {
+                // NB: default.
+                "" => Some(default),
+                "y" | "ye" | "yes" => Some(true),
+                "n" | "no" => Some(false),
+                _ => None,
+            },
+        )
+    }
 
-            input.clear();
-            stdin.read_line(&mut input)?;
+    /// Decide whether to go ahead with an action of the given concern without asking.
+    ///
+    /// Returns `None` if the user has to be asked.
+    pub fn decide(&self, concern: Concern, policy: PromptPolicy, state: &State) -> Option<bool> {
+        match policy {
+            PromptPolicy::Never => return Some(true),
+            _ if self.yes => return Some(true),
+            _ => (),
+        }
 
-            match input.to_lowercase().as_str().trim() {
-                // NB: default.
-                "" => return Ok(default),
-                "y" | "ye" | "yes" => return Ok(true),
-                "n" | "no" => return Ok(false),
-                _ => {
-                    writeln!(stdout, "Please response with 'yes' or 'no' (or 'y' or 'n')")?;
-                }
+        if let Some(answer) = state.answer(concern.name()) {
+            return Some(answer);
+        }
+
+        match policy {
+            _ if !self.non_interactive => None,
+            PromptPolicy::InteractiveOnly => Some(true),
+            _ => Some(false),
+        }
+    }
+
+    /// Ask for confirmation before an action of the given concern, according to its policy.
+    ///
+    /// Answering `always` or `never` is remembered in the state, so that it isn't asked again.
+    pub fn ask(
+        &self,
+        concern: Concern,
+        policy: PromptPolicy,
+        question: &str,
+        state: &mut State,
+    ) -> Result<bool, Error> {
+        if let Some(answer) = self.decide(concern, policy, state) {
+            if !answer && self.non_interactive && state.answer(concern.name()).is_none() {
+                log::warn!(
+                    "Skipping in non-interactive mode, since `prompts.{}` is `always` (use --yes to confirm): {}",
+                    concern.name(),
+                    question
+                );
             }
+
+            return Ok(answer);
+        }
+
+        let (answer, remember) = read_choice(
+            question,
+            "[Y/n/always/never]",
+            "Please response with 'yes', 'no', 'always' or 'never' (or 'y' or 'n')",
+            |input| match input {
+                "" | "y" | "ye" | "yes" => Some((true, false)),
+                "n" | "no" => Some((false, false)),
+                "always" => Some((true, true)),
+                "never" => Some((false, true)),
+                _ => None,
+            },
+        )?;
+
+        if remember {
+            state.remember(concern.name(), answer);
+        }
+
+        Ok(answer)
+    }
+
+    /// Ask for confirmation before doing something which can't be undone.
+    ///
+    /// Unlike [`Opts::prompt`], this fails in non-interactive mode unless `--yes` is used, since
+    /// there's no safe default to pick.
+    pub fn confirm(&self, question: &str) -> Result<bool, Error> {
+        if self.non_interactive && !self.yes {
+            bail!(
+                "{} Refusing to continue in non-interactive mode, run with --yes to confirm",
+                question
+            );
         }
+
+        self.prompt(question, true)
     }
 
     /// Prompt for input.
@@ -152,3 +611,103 @@ impl Opts {
         Ok(Some(input.trim().to_string()))
     }
 }
+
+/// Ask the given question until the answer is understood by `parse`, which gets the answer trimmed
+/// and in lowercase.
+fn read_choice<T>(
+    question: &str,
+    choices: &str,
+    help: &str,
+    parse: impl Fn(&str) -> Option<T>,
+) -> Result<T, Error> {
+    use std::io::{self, Write};
+
+    let stdin = io::stdin();
+    let mut stdout = io::stdout();
+    let mut input = String::new();
+
+    loop {
+        write!(stdout, "{} {} ", question, choices)?;
+        stdout.flush()?;
+
+        input.clear();
+        stdin.read_line(&mut input)?;
+
+        match parse(input.to_lowercase().as_str().trim()) {
+            Some(answer) => return Ok(answer),
+            None => writeln!(stdout, "{}", help)?,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Opts;
+
+    #[test]
+    fn test_non_interactive() {
+        let opts = Opts {
+            non_interactive: true,
+            ..Opts::default()
+        };
+
+        assert!(!opts.prompt("Continue?", false).unwrap());
+        assert!(opts.confirm("Remove it?").is_err());
+        assert_eq!(opts.input("Name:").unwrap(), None);
+
+        let opts = Opts {
+            non_interactive: true,
+            yes: true,
+            ..Opts::default()
+        };
+
+        assert!(opts.prompt("Continue?", false).unwrap());
+        assert!(opts.confirm("Remove it?").unwrap());
+    }
+
+    #[test]
+    fn test_decide() {
+        use crate::config::{Concern, PromptPolicy};
+        use crate::{Config, State, Timestamp};
+
+        let config = Config::default();
+        let mut state = State::new(&config, Timestamp::now());
+
+        let opts = Opts {
+            non_interactive: true,
+            ..Opts::default()
+        };
+
+        let decide =
+            |opts: &Opts, policy, state: &State| opts.decide(Concern::Install, policy, state);
+
+        assert_eq!(decide(&opts, PromptPolicy::Never, &state), Some(true));
+        assert_eq!(
+            decide(&opts, PromptPolicy::InteractiveOnly, &state),
+            Some(true)
+        );
+        assert_eq!(decide(&opts, PromptPolicy::Always, &state), Some(false));
+
+        let interactive = Opts::default();
+        assert_eq!(decide(&interactive, PromptPolicy::Always, &state), None);
+        assert_eq!(
+            decide(&interactive, PromptPolicy::InteractiveOnly, &state),
+            None
+        );
+
+        state.remember("install", true);
+        assert_eq!(decide(&opts, PromptPolicy::Always, &state), Some(true));
+        assert_eq!(
+            decide(&interactive, PromptPolicy::Always, &state),
+            Some(true)
+        );
+        assert_eq!(
+            decide(
+                &interactive,
+                PromptPolicy::Always,
+                &State::new(&config, Timestamp::now())
+            ),
+            None
+        );
+    }
+}
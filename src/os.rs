@@ -4,6 +4,13 @@ mod internal;
 
 #[cfg(unix)]
 #[path = "os/unix.rs"]
+mod unix;
+
+#[cfg(all(unix, not(target_os = "macos")))]
+use self::unix as internal;
+
+#[cfg(target_os = "macos")]
+#[path = "os/macos.rs"]
 mod internal;
 
 pub use self::internal::*;
@@ -0,0 +1,238 @@
+//! macOS-specific implementations.
+//!
+//! Mostly the same as other unixes, except that Homebrew installs commands into a prefix which
+//! isn't always in `PATH`, like when running from launchd, and which depends on the kind of chip.
+
+use crate::config::SymlinkFallback;
+use crate::unit::{Linked, Symlink};
+use anyhow::{anyhow, bail, Context as _, Error};
+use std::borrow::Cow;
+use std::env;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+
+pub use super::unix::{add_mode, exe_path, set_mode, set_user_path, user_path};
+
+/// Paths which are protected by System Integrity Protection, and can't be written to even by root.
+///
+/// Note that `/usr/local` is an exception to `/usr`.
+const PROTECTED: &[&str] = &["/System", "/bin", "/sbin", "/usr"];
+
+/// Where Homebrew installs to on Apple silicon.
+const HOMEBREW_APPLE: &str = "/opt/homebrew";
+
+/// Where Homebrew installs to on Intel, and under Rosetta.
+const HOMEBREW_INTEL: &str = "/usr/local";
+
+/// Get the prefix that Homebrew is installed in, if it's installed.
+pub fn homebrew_prefix() -> Option<&'static Path> {
+    static PREFIX: OnceLock<Option<PathBuf>> = OnceLock::new();
+
+    PREFIX
+        .get_or_init(|| {
+            if let Some(prefix) = env::var_os("HOMEBREW_PREFIX").filter(|p| !p.is_empty()) {
+                return Some(PathBuf::from(prefix));
+            }
+
+            // NB: both can exist on Apple silicon if an Intel install was migrated, in which case
+            // the one native to the architecture we're built for is preferred.
+            let candidates = if cfg!(target_arch = "aarch64") {
+                [HOMEBREW_APPLE, HOMEBREW_INTEL]
+            } else {
+                [HOMEBREW_INTEL, HOMEBREW_APPLE]
+            };
+
+            candidates
+                .iter()
+                .map(Path::new)
+                .find(|prefix| prefix.join("bin").join("brew").is_file())
+                .map(Path::to_owned)
+        })
+        .as_deref()
+}
+
+/// Convert the given command into a path.
+///
+/// Commands which aren't in `PATH` are looked up in the Homebrew prefix.
+pub fn command(base: &str) -> Cow<'_, Path> {
+    if base.contains('/') || find_in_path(base).is_some() {
+        return Cow::from(Path::new(base));
+    }
+
+    let brewed = homebrew_prefix().and_then(|prefix| {
+        ["bin", "sbin"]
+            .iter()
+            .map(|dir| prefix.join(dir).join(base))
+            .find(|path| path.is_file())
+    });
+
+    match brewed {
+        Some(path) => Cow::from(path),
+        None => Cow::from(Path::new(base)),
+    }
+}
+
+/// Test if the login keychain has a password for the given service and account.
+pub fn has_credential(service: &str, account: &str) -> Result<bool, Error> {
+    use crate::command::Command;
+
+    let mut find = Command::new("security");
+    find.args(&["find-generic-password", "-s", service, "-a", account]);
+    Ok(find.run()?.status.success())
+}
+
+/// Store a password for the given service and account in the login keychain.
+pub fn store_credential(service: &str, account: &str, secret: &str) -> Result<(), Error> {
+    use crate::command::Command;
+
+    let quote = |s: &str| format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\""));
+
+    // NB: commands are read from stdin, so that the password never shows up in the arguments.
+    let mut security = Command::new("security");
+    security.arg("-i");
+    security.stdin(format!(
+        "add-generic-password -s {} -a {} -w {}\n",
+        quote(service),
+        quote(account),
+        quote(secret)
+    ));
+    security.run_checked()
+}
+
+/// Detect git command.
+///
+/// `/usr/bin/git` is a shim which asks to install the Command Line Tools if they aren't installed,
+/// so it's only used if they are.
+#[allow(unused)]
+pub fn detect_git() -> Result<PathBuf, Error> {
+    use crate::command::Command;
+
+    if let Some(git) = homebrew_prefix()
+        .map(|prefix| prefix.join("bin").join("git"))
+        .filter(|git| git.is_file())
+    {
+        return Ok(git);
+    }
+
+    let mut xcode_select = Command::new("xcode-select");
+    xcode_select.arg("-p");
+
+    if !xcode_select.run()?.status.success() {
+        bail!("git is not installed, install it with `xcode-select --install`");
+    }
+
+    Ok(PathBuf::from("/usr/bin/git"))
+}
+
+/// Detect platform-specific facts.
+///
+/// This is the product version and build, the kind of chip, and the Homebrew prefix.
+pub fn detect_facts() -> Result<Vec<(&'static str, String)>, Error> {
+    use crate::{command::Command, facts};
+
+    let mut out = Vec::new();
+
+    let sw_vers = |arg: &str| {
+        let mut command = Command::new("sw_vers");
+        command.arg(arg);
+        command.run_stdout().map(|s| s.trim().to_string())
+    };
+
+    for (key, arg) in [
+        (facts::OS_VERSION, "-productVersion"),
+        (facts::OS_BUILD, "-buildVersion"),
+    ] {
+        match sw_vers(arg) {
+            Ok(value) => out.push((key, value)),
+            Err(e) => log::warn!("Failed to detect `{}` fact: {}", key, e),
+        }
+    }
+
+    let mut sysctl = Command::new("sysctl");
+    sysctl.args(&["-n", "machdep.cpu.brand_string"]);
+
+    // NB: brand string is something like `Apple M1` on Apple silicon, also under Rosetta.
+    let chip = if sysctl.run_stdout()?.trim().starts_with("Apple") {
+        "apple"
+    } else {
+        "intel"
+    };
+
+    out.push((facts::CHIP, chip.to_string()));
+
+    if let Some(prefix) = homebrew_prefix() {
+        out.push((facts::HOMEBREW_PREFIX, prefix.display().to_string()));
+    }
+
+    Ok(out)
+}
+
+/// Create a symlink.
+///
+/// Symlinks are always available, so there's nothing to fall back to.
+pub fn create_symlink(symlink: &Symlink, _: SymlinkFallback) -> Result<Linked, Error> {
+    use std::{fs, os::unix};
+
+    let Symlink {
+        remove,
+        ref path,
+        ref link,
+    } = *symlink;
+
+    if is_protected(path) {
+        bail!(
+            "{} is protected by System Integrity Protection, link somewhere under /usr/local instead",
+            path.display()
+        );
+    }
+
+    if remove {
+        log::info!("re-linking {} to {}", path.display(), link.display());
+        // NB: the default filesystem is case-insensitive, so this might remove a file which only
+        // differs in case, which is what we would link over anyways.
+        fs::remove_file(path)?;
+    } else {
+        log::info!("linking {} to {}", path.display(), link.display());
+    }
+
+    unix::fs::symlink(link, path).with_context(|| {
+        anyhow!(
+            "failed to link {}, if it's in a protected folder like ~/Library, quickcfg might need Full Disk Access",
+            path.display()
+        )
+    })?;
+
+    Ok(Linked::Symlink)
+}
+
+/// Test if the given path is protected by System Integrity Protection.
+fn is_protected(path: &Path) -> bool {
+    if path.starts_with("/usr/local") {
+        return false;
+    }
+
+    PROTECTED.iter().any(|p| path.starts_with(p))
+}
+
+/// Find the given command in `PATH`.
+fn find_in_path(base: &str) -> Option<PathBuf> {
+    let path = env::var_os("PATH")?;
+
+    env::split_paths(&path)
+        .map(|dir| dir.join(base))
+        .find(|path| path.is_file())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::is_protected;
+    use std::path::Path;
+
+    #[test]
+    fn test_is_protected() {
+        assert!(is_protected(Path::new("/usr/bin/tool")));
+        assert!(is_protected(Path::new("/System/Library")));
+        assert!(!is_protected(Path::new("/usr/local/bin/tool")));
+        assert!(!is_protected(Path::new("/Users/me/.zshrc")));
+    }
+}
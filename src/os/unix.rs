@@ -1,7 +1,12 @@
 //! Unix-specific implementations.
+//!
+//! These are also the defaults for macOS, which overrides some of them.
 
-use crate::unit::{AddMode, Symlink};
-use anyhow::{anyhow, Context as _, Error};
+#![cfg_attr(target_os = "macos", allow(dead_code))]
+
+use crate::config::SymlinkFallback;
+use crate::unit::{AddMode, FileMode, Linked, Symlink};
+use anyhow::{anyhow, bail, Context as _, Error};
 use std::borrow::Cow;
 use std::path::{Path, PathBuf};
 
@@ -23,6 +28,46 @@ pub fn detect_git() -> Result<PathBuf, Error> {
     Ok(PathBuf::from("git"))
 }
 
+/// Detect platform-specific facts.
+pub fn detect_facts() -> Result<Vec<(&'static str, String)>, Error> {
+    Ok(Vec::new())
+}
+
+/// Get the PATH of the user, which is only stored separately from shell files on Windows.
+pub fn user_path() -> Result<Vec<String>, Error> {
+    bail!("the user PATH can only be read on Windows")
+}
+
+/// Set the PATH of the user, which is only stored separately from shell files on Windows.
+pub fn set_user_path(_: &[String]) -> Result<(), Error> {
+    bail!("the user PATH can only be set on Windows")
+}
+
+/// Test if the keyring has a secret for the given service and account, through the Secret Service.
+pub fn has_credential(service: &str, account: &str) -> Result<bool, Error> {
+    use crate::command::Command;
+
+    let mut lookup = Command::new("secret-tool");
+    lookup.args(&["lookup", "service", service, "account", account]);
+    let output = lookup.run()?;
+
+    // NB: exits with status 1 and no output if there is no such secret.
+    Ok(output.status.success() && !output.stdout.is_empty())
+}
+
+/// Store a secret for the given service and account in the keyring, through the Secret Service.
+pub fn store_credential(service: &str, account: &str, secret: &str) -> Result<(), Error> {
+    use crate::command::Command;
+
+    let mut store = Command::new("secret-tool");
+    store.arg("store");
+    store.arg(format!("--label={} ({})", service, account));
+    store.args(&["service", service, "account", account]);
+    // NB: the secret is read from stdin, so it never shows up in the arguments.
+    store.stdin(secret);
+    store.run_checked()
+}
+
 /// Add the given modes (on top of the existing ones).
 pub fn add_mode(add_mode: &AddMode) -> Result<(), Error> {
     use std::fs;
@@ -38,8 +83,31 @@ pub fn add_mode(add_mode: &AddMode) -> Result<(), Error> {
     Ok(())
 }
 
+/// Set the permissions of the given file.
+///
+/// Returns `true` if they were changed.
+pub fn set_mode(path: &Path, mode: FileMode) -> Result<bool, Error> {
+    use std::fs;
+    use std::os::unix::fs::PermissionsExt;
+
+    let mut perm = path.metadata()?.permissions();
+
+    if perm.mode() & 0o7777 == mode.bits() {
+        return Ok(false);
+    }
+
+    perm.set_mode(mode.bits());
+
+    fs::set_permissions(path, perm)
+        .with_context(|| anyhow!("failed to set mode: {}", path.display()))?;
+
+    Ok(true)
+}
+
 /// Create a symlink.
-pub fn create_symlink(symlink: &Symlink) -> Result<(), Error> {
+///
+/// Symlinks are always available, so there's nothing to fall back to.
+pub fn create_symlink(symlink: &Symlink, _: SymlinkFallback) -> Result<Linked, Error> {
     use std::{fs, os::unix};
 
     let Symlink {
@@ -50,11 +118,11 @@ pub fn create_symlink(symlink: &Symlink) -> Result<(), Error> {
 
     if remove {
         log::info!("re-linking {} to {}", path.display(), link.display());
-        fs::remove_file(&path)?;
+        fs::remove_file(path)?;
     } else {
         log::info!("linking {} to {}", path.display(), link.display());
     }
 
     unix::fs::symlink(link, path)?;
-    Ok(())
+    Ok(Linked::Symlink)
 }
@@ -1,7 +1,8 @@
 //! Windows-specific implementations.
 
-use crate::unit::{AddMode, Symlink};
-use anyhow::{bail, Error};
+use crate::config::SymlinkFallback;
+use crate::unit::{AddMode, FileMode, Linked, Symlink};
+use anyhow::{anyhow, bail, Context as _, Error};
 use std::borrow::Cow;
 use std::env::consts;
 use std::path::{Path, PathBuf};
@@ -23,8 +24,163 @@ pub fn command<'a>(base: &'a str) -> Cow<'a, Path> {
     Cow::from(exe_path(PathBuf::from(base)))
 }
 
+/// Detect platform-specific facts.
+///
+/// On Windows, this is the version and build number, if developer mode is enabled, and if we
+/// have the privilege to create symlinks.
+pub fn detect_facts() -> Result<Vec<(&'static str, String)>, Error> {
+    use crate::{command::Command, facts};
+
+    let mut out = Vec::new();
+
+    let mut ver = Command::new("cmd");
+    ver.args(&["/C", "ver"]);
+
+    // NB: output is something like `Microsoft Windows [Version 10.0.22631.4037]`.
+    let output = ver.run_stdout()?;
+
+    if let Some(version) = output
+        .split("Version ")
+        .nth(1)
+        .and_then(|rest| rest.split(']').next())
+    {
+        if let Some(build) = version.split('.').nth(2) {
+            out.push((facts::OS_BUILD, build.to_string()));
+        }
+
+        out.push((facts::OS_VERSION, version.trim().to_string()));
+    }
+
+    let mut reg = Command::new("reg");
+    reg.args(&[
+        "query",
+        r"HKLM\SOFTWARE\Microsoft\Windows\CurrentVersion\AppModelUnlock",
+        "/v",
+        "AllowDevelopmentWithoutDevLicense",
+    ]);
+
+    let developer_mode = match reg.run_stdout() {
+        Ok(output) => output.contains("0x1"),
+        // NB: key is missing unless developer mode has been toggled.
+        Err(_) => false,
+    };
+
+    out.push((facts::DEVELOPER_MODE, developer_mode.to_string()));
+    out.push((facts::SYMLINKS, can_symlink().to_string()));
+    Ok(out)
+}
+
+/// Test if the Credential Manager has a credential for the given service and account.
+pub fn has_credential(service: &str, account: &str) -> Result<bool, Error> {
+    let user_name = crate::ffi::win::wincred::read_user_name(service)
+        .with_context(|| anyhow!("failed to read credential for `{}`", service))?;
+
+    Ok(user_name.as_deref() == Some(account))
+}
+
+/// Store a credential for the given service and account in the Credential Manager.
+///
+/// This calls `CredWriteW` directly, so the secret never ends up in the arguments or the
+/// environment of another process.
+pub fn store_credential(service: &str, account: &str, secret: &str) -> Result<(), Error> {
+    crate::ffi::win::wincred::write(service, account, secret)
+        .with_context(|| anyhow!("failed to store credential for `{}`", service))
+}
+
+/// Registry key with the environment variables of the user.
+const USER_ENVIRONMENT: &str = r"HKCU\Environment";
+
+/// Get the PATH of the user from the registry.
+pub fn user_path() -> Result<Vec<String>, Error> {
+    use crate::command::Command;
+
+    let mut reg = Command::new("reg");
+    reg.args(&["query", USER_ENVIRONMENT, "/v", "Path"]);
+
+    let output = reg.run()?;
+
+    // NB: the value is missing if the user has never had a PATH of their own.
+    if !output.status.success() {
+        return Ok(Vec::new());
+    }
+
+    // NB: output is something like `    Path    REG_EXPAND_SZ    C:\Tools;C:\Go\bin`.
+    let value = output
+        .stdout
+        .lines()
+        .find_map(|line| {
+            let line = line.trim_start();
+
+            if !line.to_lowercase().starts_with("path ") {
+                return None;
+            }
+
+            ["REG_EXPAND_SZ", "REG_SZ"]
+                .iter()
+                .find_map(|ty| line.split_once(ty))
+                .map(|(_, value)| value.trim())
+        })
+        .unwrap_or_default();
+
+    Ok(value
+        .split(';')
+        .filter(|dir| !dir.is_empty())
+        .map(String::from)
+        .collect())
+}
+
+/// Set the PATH of the user in the registry, and tell running programs that it changed.
+pub fn set_user_path(path: &[String]) -> Result<(), Error> {
+    use crate::command::Command;
+
+    let mut reg = Command::new("reg");
+    reg.args(&[
+        "add",
+        USER_ENVIRONMENT,
+        "/v",
+        "Path",
+        "/t",
+        "REG_EXPAND_SZ",
+        "/f",
+        "/d",
+    ]);
+    reg.arg(path.join(";"));
+    reg.run_checked()?;
+
+    crate::ffi::win::winuser::broadcast_environment_change();
+    Ok(())
+}
+
+/// Test if we have the privilege to create symlinks by creating one in the temporary directory.
+fn can_symlink() -> bool {
+    use std::fs;
+    use std::os::windows::fs::symlink_file;
+    use std::sync::OnceLock;
+
+    static CAN_SYMLINK: OnceLock<bool> = OnceLock::new();
+
+    *CAN_SYMLINK.get_or_init(|| {
+        let link =
+            std::env::temp_dir().join(format!("quickcfg-symlink-test-{}", std::process::id()));
+        let _ = fs::remove_file(&link);
+
+        match symlink_file("target", &link) {
+            Ok(()) => {
+                let _ = fs::remove_file(&link);
+                true
+            }
+            Err(_) => false,
+        }
+    })
+}
+
 /// Add the given modes (on top of the existing ones).
+///
+/// Windows doesn't have modes, so files are executable if they have the .exe extension, and
+/// writable unless they're read-only.
 pub fn add_mode(mode: &AddMode) -> Result<(), Error> {
+    use std::fs;
+
     if mode.is_executable() {
         // NB: windows files are executable if they have the .exe extension.
         if mode.path.extension() != Some(consts::EXE_EXTENSION.as_ref()) {
@@ -32,13 +188,152 @@ pub fn add_mode(mode: &AddMode) -> Result<(), Error> {
         }
     }
 
+    if mode.is_user_writable() {
+        let mut perm = mode.path.metadata()?.permissions();
+
+        if perm.readonly() {
+            // NB: this only clears the read-only attribute, it doesn't make the file writable by
+            // everyone like it would on unix.
+            #[allow(clippy::permissions_set_readonly_false)]
+            perm.set_readonly(false);
+
+            fs::set_permissions(&mode.path, perm)
+                .with_context(|| anyhow!("failed to add mode: {}", mode.path.display()))?;
+        }
+    }
+
     Ok(())
 }
 
-/// Create a symlink.
-pub fn create_symlink(symlink: &Symlink) -> Result<(), Error> {
+/// Attribute of files which are hidden.
+const FILE_ATTRIBUTE_HIDDEN: u32 = 0x2;
+
+/// Set the permissions of the given file.
+///
+/// Windows doesn't have modes, so the closest equivalents are used. Files which their owner can't
+/// write to are read-only, and files which nobody but their owner can access get an ACL which
+/// only grants access to the current user, like OpenSSH requires of private keys. Dotfiles are
+/// also hidden, like they are on other platforms.
+///
+/// Returns `true` if anything was changed.
+pub fn set_mode(path: &Path, mode: FileMode) -> Result<bool, Error> {
+    use crate::command::Command;
+    use std::fs;
+    use std::os::windows::fs::MetadataExt;
+
+    let mut changed = false;
+
+    if mode.is_private() {
+        let user = current_user()?;
+
+        let long = long_path(path);
+
+        let mut icacls = Command::new("icacls");
+        icacls.arg(long.as_ref());
+        let lines = icacls.run_lines()?;
+
+        if !is_private_acl(&lines, &long.display().to_string(), &user) {
+            log::info!("restricting access to {} to {}", path.display(), user);
+
+            // NB: removes inherited entries, and replaces all explicit ones.
+            let mut icacls = Command::new("icacls");
+            icacls.arg(long.as_ref());
+            icacls.args(&["/inheritance:r", "/grant:r"]);
+            icacls.arg(format!("{}:F", user));
+            icacls.run_checked()?;
+            changed = true;
+        }
+    }
+
+    let mut perm = path.metadata()?.permissions();
+    let readonly = !mode.is_user_writable();
+
+    if perm.readonly() != readonly {
+        #[allow(clippy::permissions_set_readonly_false)]
+        perm.set_readonly(readonly);
+
+        fs::set_permissions(path, perm)
+            .with_context(|| anyhow!("failed to set mode: {}", path.display()))?;
+        changed = true;
+    }
+
+    // NB: setting permissions above writes every attribute, so this must come after it.
+    let hidden = path.metadata()?.file_attributes() & FILE_ATTRIBUTE_HIDDEN != 0;
+
+    if is_dotfile(path) && !hidden {
+        let mut attrib = Command::new("attrib");
+        attrib.arg("+h");
+        attrib.arg(path);
+        attrib.run_checked()?;
+        changed = true;
+    }
+
+    Ok(changed)
+}
+
+/// The current user, qualified by their domain like `icacls` prints it.
+fn current_user() -> Result<String, Error> {
+    use std::env;
+
+    let user = env::var("USERNAME").map_err(|_| anyhow!("USERNAME is not set"))?;
+
+    match env::var("USERDOMAIN") {
+        Ok(domain) if !domain.is_empty() => Ok(format!("{}\\{}", domain, user)),
+        _ => Ok(user),
+    }
+}
+
+/// Test if the output of `icacls <path>` shows an ACL which only grants access to the given user,
+/// and doesn't inherit any entries.
+///
+/// Output is something like this, with one entry per line:
+///
+/// ```text
+/// C:\Users\me\.ssh\id_ed25519 DESKTOP\me:(F)
+///                             NT AUTHORITY\SYSTEM:(I)(F)
+///
+/// Successfully processed 1 files; Failed processing 0 files
+/// ```
+fn is_private_acl(lines: &[String], path: &str, user: &str) -> bool {
+    let mut any = false;
+
+    for line in lines {
+        let entry = line.strip_prefix(path).unwrap_or(line).trim();
+
+        let principal = match entry.find(":(") {
+            Some(n) => &entry[..n],
+            None => continue,
+        };
+
+        if !principal.eq_ignore_ascii_case(user) || entry.contains("(I)") {
+            return false;
+        }
+
+        any = true;
+    }
+
+    any
+}
+
+/// Test if the given path is a dotfile.
+fn is_dotfile(path: &Path) -> bool {
+    path.file_name()
+        .map(|name| name.to_string_lossy().starts_with('.'))
+        .unwrap_or_default()
+}
+
+/// Error raised when creating a symlink without the privilege to do so.
+const ERROR_PRIVILEGE_NOT_HELD: i32 = 1314;
+
+/// Create a symlink at `path` which points to `link`, like on other platforms.
+///
+/// Windows has different kinds of symlinks for files and directories, so which one is created
+/// depends on what `link` points to. Links to things which don't exist are file links.
+///
+/// Without the privilege to create symlinks, directories are linked with junctions, and files are
+/// copied if `fallback` allows it.
+pub fn create_symlink(symlink: &Symlink, fallback: SymlinkFallback) -> Result<Linked, Error> {
     use std::fs;
-    use std::os::windows::fs::{symlink_dir, symlink_file};
 
     let Symlink {
         remove,
@@ -48,23 +343,227 @@ pub fn create_symlink(symlink: &Symlink) -> Result<(), Error> {
 
     if remove {
         log::info!("re-linking {} to {}", path.display(), link.display());
-        fs::remove_file(&path)?;
+
+        // NB: directory symlinks and junctions are removed like directories.
+        if fs::remove_file(path).is_err() {
+            fs::remove_dir(path)?;
+        }
     } else {
         log::info!("linking {} to {}", path.display(), link.display());
     }
 
-    if path.is_file() {
-        symlink_file(path, path.join(&link))?;
-        return Ok(());
+    if symlink.target().is_dir() {
+        link_dir(symlink, fallback)
+    } else {
+        link_file(symlink, fallback)
+    }
+}
+
+/// Link a file, falling back to copying it if that's allowed.
+fn link_file(symlink: &Symlink, fallback: SymlinkFallback) -> Result<Linked, Error> {
+    use std::fs;
+    use std::os::windows::fs::symlink_file;
+
+    let e = match symlink_file(&symlink.link, &symlink.path) {
+        Ok(()) => return Ok(Linked::Symlink),
+        Err(e) if e.raw_os_error() == Some(ERROR_PRIVILEGE_NOT_HELD) => e,
+        Err(e) => return Err(e.into()),
+    };
+
+    if fallback != SymlinkFallback::Copy {
+        return Err(Error::from(e).context(anyhow!(
+            "cannot symlink `{}`: enable Developer Mode in Settings, or set `symlink_fallback: copy`",
+            symlink.path.display()
+        )));
+    }
+
+    warn_fallback();
+
+    let target = symlink.target();
+    fs::copy(&target, &symlink.path)
+        .with_context(|| anyhow!("failed to copy `{}`", target.display()))?;
+    Ok(Linked::Copy)
+}
+
+/// Link a directory, falling back to a junction if that's allowed.
+fn link_dir(symlink: &Symlink, fallback: SymlinkFallback) -> Result<Linked, Error> {
+    use crate::command::Command;
+    use std::os::windows::fs::symlink_dir;
+
+    let e = match symlink_dir(&symlink.link, &symlink.path) {
+        Ok(()) => return Ok(Linked::Symlink),
+        Err(e) if e.raw_os_error() == Some(ERROR_PRIVILEGE_NOT_HELD) => e,
+        Err(e) => return Err(e.into()),
+    };
+
+    if fallback == SymlinkFallback::None {
+        return Err(Error::from(e).context(anyhow!(
+            "cannot symlink `{}`: enable Developer Mode in Settings",
+            symlink.path.display()
+        )));
+    }
+
+    let target = symlink.target();
+
+    // NB: like home directories on domain-joined machines.
+    if is_network_path(&target) {
+        return Err(Error::from(e).context(anyhow!(
+            "cannot link `{}`: junctions can't point to network shares like `{}`, enable Developer Mode in Settings",
+            symlink.path.display(),
+            target.display()
+        )));
     }
 
-    if path.is_dir() {
-        symlink_dir(path, path.join(&link))?;
-        return Ok(());
+    warn_fallback();
+
+    // NB: junctions don't need any privileges, but must point to an absolute path.
+    let mut mklink = Command::new("cmd");
+    mklink.args(&["/C", "mklink", "/J"]);
+    mklink.arg(long_path(&symlink.path).as_ref());
+    mklink.arg(long_path(&target).as_ref());
+    mklink.run_checked()?;
+    Ok(Linked::Junction)
+}
+
+/// Length from which paths need to be in their extended-length form, which is the limit for
+/// directories since they must leave room for an 8.3 file name.
+const MAX_PATH: usize = 248;
+
+/// Convert the given path into its extended-length form (`\\?\`) if it's too long to be used
+/// otherwise.
+///
+/// The standard library already does this for its own file system operations, but commands that
+/// we run like `mklink` and `icacls` are given paths as is. Paths on network shares, like home
+/// directories on domain-joined machines, use the `\\?\UNC\` form.
+///
+/// Extended-length paths aren't normalized by Windows, so separators are normalized and `.` and
+/// `..` are resolved here.
+fn long_path(path: &Path) -> Cow<'_, Path> {
+    use std::ffi::OsString;
+    use std::path::{Component, Prefix};
+
+    if path.as_os_str().len() < MAX_PATH {
+        return Cow::from(path);
     }
 
-    bail!(
-        "cannot symlink `{}`: not a file or directory",
-        path.display()
-    );
+    let mut components = path.components();
+
+    let mut out = match components.next() {
+        Some(Component::Prefix(prefix)) => match prefix.kind() {
+            Prefix::Disk(letter) => PathBuf::from(format!(r"\\?\{}:\", letter as char)),
+            Prefix::UNC(server, share) => {
+                let mut out = OsString::from(r"\\?\UNC\");
+                out.push(server);
+                out.push(r"\");
+                out.push(share);
+                out.push(r"\");
+                PathBuf::from(out)
+            }
+            // NB: already extended-length, or a device.
+            _ => return Cow::from(path),
+        },
+        // NB: relative paths can't be extended-length.
+        _ => return Cow::from(path),
+    };
+
+    for component in components {
+        match component {
+            Component::Prefix(..) | Component::RootDir | Component::CurDir => (),
+            Component::ParentDir => {
+                out.pop();
+            }
+            Component::Normal(c) => out.push(c),
+        }
+    }
+
+    Cow::from(out)
+}
+
+/// Test if the given path is on a network share.
+fn is_network_path(path: &Path) -> bool {
+    use std::path::{Component, Prefix};
+
+    match path.components().next() {
+        Some(Component::Prefix(prefix)) => {
+            matches!(prefix.kind(), Prefix::UNC(..) | Prefix::VerbatimUNC(..))
+        }
+        _ => false,
+    }
+}
+
+/// Warn once about falling back to junctions or copies.
+fn warn_fallback() {
+    use std::sync::Once;
+
+    static WARNED: Once = Once::new();
+
+    WARNED.call_once(|| {
+        log::warn!(
+            "No privilege to create symlinks, falling back to junctions and copies. \
+             To use real symlinks, enable Developer Mode in Settings, or run as administrator."
+        );
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{is_network_path, is_private_acl, long_path};
+    use std::path::{Path, PathBuf};
+
+    #[test]
+    fn test_is_private_acl() {
+        let path = r"C:\Users\me\.ssh\id_ed25519";
+
+        let lines = |entries: &[&str]| {
+            let mut out = Vec::new();
+
+            for (i, entry) in entries.iter().enumerate() {
+                if i == 0 {
+                    out.push(format!("{} {}", path, entry));
+                } else {
+                    out.push(format!("                            {}", entry));
+                }
+            }
+
+            out.push(String::new());
+            out.push(String::from(
+                "Successfully processed 1 files; Failed processing 0 files",
+            ));
+            out
+        };
+
+        let user = r"DESKTOP\me";
+        assert!(is_private_acl(&lines(&[r"desktop\me:(F)"]), path, user));
+        assert!(!is_private_acl(&lines(&[r"DESKTOP\me:(I)(F)"]), path, user));
+        assert!(!is_private_acl(
+            &lines(&[r"DESKTOP\me:(F)", r"NT AUTHORITY\SYSTEM:(F)"]),
+            path,
+            user
+        ));
+        assert!(!is_private_acl(&lines(&[]), path, user));
+    }
+
+    #[test]
+    fn test_long_path() {
+        let short = Path::new(r"C:\Users\me\.vimrc");
+        assert_eq!(short, long_path(short).as_ref());
+
+        let name = "a".repeat(100);
+
+        let disk = PathBuf::from(format!(r"C:\Users\me\{}\.\{}/x\..\{}", name, name, name));
+        let expected = PathBuf::from(format!(r"\\?\C:\Users\me\{}\{}\{}", name, name, name));
+        assert_eq!(expected, long_path(&disk).as_ref());
+
+        let unc = PathBuf::from(format!(r"\\server\home\me\{}\{}\{}", name, name, name));
+        let expected = PathBuf::from(format!(
+            r"\\?\UNC\server\home\me\{}\{}\{}",
+            name, name, name
+        ));
+        assert_eq!(expected, long_path(&unc).as_ref());
+        assert_eq!(expected, long_path(&expected).as_ref());
+
+        assert!(is_network_path(&unc));
+        assert!(is_network_path(&expected));
+        assert!(!is_network_path(&disk));
+    }
 }
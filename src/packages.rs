@@ -2,20 +2,41 @@
 //!
 //! Can check which packages are installed.
 
+mod arch;
 mod cargo;
 mod debian;
 mod fedora;
+mod homebrew;
+mod nix;
 mod python;
 mod ruby;
 mod rustup_components;
 mod rustup_toolchains;
 mod winget;
 
-use crate::facts::{self, Facts};
-use anyhow::{bail, Error};
+use crate::{
+    facts::{self, Facts},
+    state::PackageListing,
+    Timestamp,
+};
+use anyhow::{anyhow, Error};
 use log::warn;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
 use std::fmt;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+/// Errors raised by package managers.
+#[derive(Debug, thiserror::Error)]
+pub enum PackagesError {
+    /// There's no package manager with the given name.
+    #[error("No package manager provider for `{0}`")]
+    NoProvider(String),
+    /// A package manager printed something which couldn't be parsed.
+    #[error("unexpected output from package manager: {0}")]
+    UnexpectedOutput(&'static str),
+}
 
 /// Information about an installed package.
 #[derive(Debug)]
@@ -23,12 +44,125 @@ pub struct Package {
     pub name: String,
 }
 
+/// Where the listing of a single package manager is cached.
+///
+/// The lock is held while listing, so that concurrent lookups wait for it instead of listing again.
+type Slot = Arc<Mutex<Option<PackageListing>>>;
+
 /// A provider of package managers.
 pub struct Provider {
     default: Option<Arc<dyn PackageManager>>,
+    /// Packages listed as installed, by the name of the package manager.
+    listings: Mutex<HashMap<String, Slot>>,
 }
 
 impl Provider {
+    /// Construct a provider with the given default package manager.
+    fn new(default: Option<Arc<dyn PackageManager>>) -> Self {
+        Provider {
+            default,
+            listings: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Reuse listings from an earlier run which were listed no longer than `ttl` ago.
+    pub fn seed(&self, listings: &BTreeMap<String, PackageListing>, now: Timestamp, ttl: Duration) {
+        for (name, listing) in listings {
+            let age = now.duration_since(listing.updated).unwrap_or_default();
+
+            if ttl.as_secs() == 0 || age >= ttl || listing.updated > now {
+                continue;
+            }
+
+            *self.slot(name).lock().unwrap_or_else(|e| e.into_inner()) = Some(listing.clone());
+        }
+    }
+
+    /// List the packages which are installed by the given package manager.
+    ///
+    /// Each package manager is only asked once per run, unless it's invalidated by installing
+    /// packages.
+    pub fn list_packages(
+        &self,
+        manager: &dyn PackageManager,
+        now: Timestamp,
+    ) -> Result<BTreeSet<String>, Error> {
+        let slot = self.slot(manager.name());
+        let mut slot = slot.lock().map_err(|_| anyhow!("Lock poisoned"))?;
+
+        if let Some(listing) = slot.as_ref() {
+            log::trace!("Using cached listing of packages for `{}`", manager.name());
+            return Ok(listing.packages.clone());
+        }
+
+        let packages = manager
+            .list_packages()?
+            .into_iter()
+            .map(|p| p.name)
+            .collect::<BTreeSet<_>>();
+
+        *slot = Some(PackageListing {
+            updated: now,
+            packages: packages.clone(),
+        });
+
+        Ok(packages)
+    }
+
+    /// Start listing the packages of the given package manager in the background, so that it's
+    /// cached by the time it's needed.
+    pub fn prefetch(&self, manager: Arc<dyn PackageManager>, now: Timestamp) {
+        let slot = self.slot(manager.name());
+
+        thread::spawn(move || {
+            let mut slot = match slot.lock() {
+                Ok(slot) => slot,
+                Err(..) => return,
+            };
+
+            if slot.is_some() {
+                return;
+            }
+
+            // NB: errors are raised again when the listing is needed.
+            match manager.list_packages() {
+                Ok(packages) => {
+                    *slot = Some(PackageListing {
+                        updated: now,
+                        packages: packages.into_iter().map(|p| p.name).collect(),
+                    });
+                }
+                Err(e) => {
+                    log::trace!("Failed to list packages for `{}`: {}", manager.name(), e);
+                }
+            }
+        });
+    }
+
+    /// Forget the listing of the given package manager, since its packages have changed.
+    pub fn invalidate(&self, name: &str) {
+        *self.slot(name).lock().unwrap_or_else(|e| e.into_inner()) = None;
+    }
+
+    /// All listings which are cached, by the name of the package manager.
+    pub fn listings(&self) -> BTreeMap<String, PackageListing> {
+        let listings = self.listings.lock().unwrap_or_else(|e| e.into_inner());
+
+        listings
+            .iter()
+            .filter_map(|(name, slot)| {
+                let listing = slot.lock().ok()?.clone()?;
+                Some((name.clone(), listing))
+            })
+            .collect()
+    }
+
+    /// Get the slot for the listing of the given package manager.
+    fn slot(&self, name: &str) -> Slot {
+        let mut listings = self.listings.lock().unwrap_or_else(|e| e.into_inner());
+        Arc::clone(listings.entry(name.to_string()).or_default())
+    }
+
     /// Access the default package manager if it is available.
     pub fn default(&self) -> Option<Arc<dyn PackageManager>> {
         self.default.as_ref().map(Arc::clone)
@@ -45,44 +179,52 @@ impl Provider {
         match name {
             "debian" => test(debian::PackageManager::new()),
             "fedora" => test(fedora::PackageManager::new()),
+            "arch" => test(arch::PackageManager::new()),
             "pip" => test(python::PackageManager::new("pip")),
             "pip3" => test(python::PackageManager::new("pip3")),
+            "homebrew" => test(homebrew::PackageManager::new()),
+            "nix" => test(nix::PackageManager::new()),
             "gem" => test(ruby::PackageManager::new()),
             "cargo" => test(cargo::PackageManager::new()),
             "winget" => test(winget::PackageManager::new()),
             "rust toolchains" => test(rustup_toolchains::PackageManager::new()),
             "rust components" => test(rustup_components::PackageManager::new()),
-            _ => bail!("No package manager provider for `{}`", name),
+            _ => Err(PackagesError::NoProvider(name.to_string()).into()),
         }
     }
 }
 
 /// Detect which package provider to use.
 pub fn detect(facts: &Facts) -> Result<Provider, Error> {
-    let default = if let Some(default) = by_distro(facts)? {
-        Some(default)
-    } else if let Some(default) = by_os(facts)? {
-        Some(default)
-    } else {
-        None
+    let default = match by_distro(facts)? {
+        Some(default) => Some(default),
+        None => by_os(facts)?,
     };
 
-    Ok(Provider { default })
+    Ok(Provider::new(default))
 }
 
-/// Detect package manager by distro.
+/// Detect package manager by distro family.
+///
+/// Matching on the family means that derivatives like Ubuntu or Manjaro use the package manager of
+/// the distro they are based on.
 fn by_distro(facts: &Facts) -> Result<Option<Arc<dyn PackageManager>>, Error> {
-    let distro = match facts.get(facts::DISTRO) {
+    let family = match facts
+        .get(facts::DISTRO_FAMILY)
+        .or_else(|| facts.get(facts::DISTRO))
+    {
         // NB: unsupported distro, good luck!
         None => return Ok(None),
-        Some(distro) => distro,
+        Some(family) => family,
     };
 
-    match distro {
+    match family {
         "debian" => test(debian::PackageManager::new()),
-        "fedora" => test(fedora::PackageManager::new()),
-        distro => {
-            warn!("no package integration for distro: {}", distro);
+        "rhel" | "fedora" => test(fedora::PackageManager::new()),
+        "arch" => test(arch::PackageManager::new()),
+        "nixos" => test(nix::PackageManager::new()),
+        family => {
+            warn!("no package integration for distro family: {}", family);
             Ok(None)
         }
     }
@@ -98,6 +240,7 @@ fn by_os(facts: &Facts) -> Result<Option<Arc<dyn PackageManager>>, Error> {
 
     match os {
         "windows" => test(winget::PackageManager::new()),
+        "macos" => test(homebrew::PackageManager::new()),
         os => {
             warn!("no package integration for os: {}", os);
             Ok(None)
@@ -143,3 +286,68 @@ pub trait PackageManager: fmt::Debug + Sync + Send {
     /// Install the given packages.
     fn install_packages(&self, packages: &[String]) -> Result<(), Error>;
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{Package, PackageManager, Provider};
+    use crate::Timestamp;
+    use anyhow::Error;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    #[derive(Debug, Default)]
+    struct Counting {
+        listed: AtomicUsize,
+    }
+
+    impl PackageManager for Counting {
+        fn name(&self) -> &str {
+            "counting"
+        }
+
+        fn test(&self) -> Result<bool, Error> {
+            Ok(true)
+        }
+
+        fn list_packages(&self) -> Result<Vec<Package>, Error> {
+            self.listed.fetch_add(1, Ordering::SeqCst);
+
+            Ok(vec![Package {
+                name: String::from("git"),
+            }])
+        }
+
+        fn install_packages(&self, _: &[String]) -> Result<(), Error> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_list_packages() -> Result<(), Error> {
+        let now = Timestamp::now();
+        let earlier = now - Duration::from_secs(60);
+        let manager = Counting::default();
+        let provider = Provider::new(None);
+
+        assert!(provider.list_packages(&manager, earlier)?.contains("git"));
+        assert!(provider.list_packages(&manager, earlier)?.contains("git"));
+        assert_eq!(1, manager.listed.load(Ordering::SeqCst));
+
+        provider.invalidate("counting");
+        provider.list_packages(&manager, earlier)?;
+        assert_eq!(2, manager.listed.load(Ordering::SeqCst));
+
+        let listings = provider.listings();
+
+        let fresh = Provider::new(None);
+        fresh.seed(&listings, now, Duration::from_secs(300));
+        fresh.list_packages(&manager, now)?;
+        assert_eq!(2, manager.listed.load(Ordering::SeqCst));
+
+        let stale = Provider::new(None);
+        stale.seed(&listings, now, Duration::from_secs(30));
+        stale.list_packages(&manager, now)?;
+        assert_eq!(3, manager.listed.load(Ordering::SeqCst));
+        Ok(())
+    }
+}
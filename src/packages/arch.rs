@@ -0,0 +1,146 @@
+//! Packages abstraction for Arch Linux.
+
+use crate::{
+    command, os,
+    packages::{Package, PackagesError},
+};
+use anyhow::Error;
+use std::ffi::OsStr;
+use std::io;
+
+#[derive(Debug)]
+pub struct Pacman {
+    sudo: command::Command,
+    pacman: command::Command,
+}
+
+impl Pacman {
+    /// Create a new pacman command wrapper.
+    pub fn new() -> Self {
+        Pacman {
+            sudo: command::Command::new(os::command("sudo")),
+            pacman: command::Command::new(os::command("pacman")),
+        }
+    }
+
+    /// Test that the command is available.
+    pub fn test(&self) -> Result<bool, Error> {
+        let mut pacman = self.pacman.clone();
+        pacman.arg("--version");
+
+        match pacman.run() {
+            Ok(output) => Ok(output.status.success()),
+            Err(e) => match e.kind() {
+                // no such command.
+                io::ErrorKind::NotFound => Ok(false),
+                _ => Err(Error::from(e)),
+            },
+        }
+    }
+
+    /// Install the given packages.
+    pub fn install_packages<I>(&self, packages: I) -> Result<(), Error>
+    where
+        I: IntoIterator,
+        I::Item: AsRef<OsStr>,
+    {
+        let mut sudo = self.sudo.clone();
+        sudo.args(&["-p", "[sudo] password for %u to install packages: ", "--"]);
+        sudo.args(&["pacman", "-S", "--needed", "--noconfirm"]);
+        sudo.args(packages);
+        sudo.run_inherited()?;
+        Ok(())
+    }
+
+    /// List all the packages which are installed.
+    pub fn list_installed(&self) -> Result<Vec<Package>, Error> {
+        let mut pacman = self.pacman.clone();
+        pacman.arg("-Qq");
+        parse_installed(&pacman.run_lines()?)
+    }
+}
+
+/// Parse the output of `pacman -Qq`, which is one package name per line.
+fn parse_installed(lines: &[String]) -> Result<Vec<Package>, Error> {
+    let mut out = Vec::new();
+
+    for line in lines {
+        let line = line.trim();
+
+        if line.is_empty() {
+            continue;
+        }
+
+        let name = line
+            .split(' ')
+            .next()
+            .ok_or(PackagesError::UnexpectedOutput("expected package name"))?;
+
+        out.push(Package {
+            name: name.to_string(),
+        });
+    }
+
+    Ok(out)
+}
+
+/// Packages abstraction for Arch Linux.
+#[derive(Debug)]
+pub struct PackageManager {
+    pacman: Pacman,
+}
+
+impl PackageManager {
+    /// Construct a new pacman package manager.
+    pub fn new() -> Self {
+        PackageManager {
+            pacman: Pacman::new(),
+        }
+    }
+}
+
+impl super::PackageManager for PackageManager {
+    fn primary(&self) -> bool {
+        true
+    }
+
+    fn needs_interaction(&self) -> bool {
+        // needs interaction because we use `sudo`.
+        true
+    }
+
+    fn name(&self) -> &str {
+        "arch"
+    }
+
+    /// Test that we have everything we need.
+    fn test(&self) -> Result<bool, Error> {
+        self.pacman.test()
+    }
+
+    fn list_packages(&self) -> Result<Vec<Package>, Error> {
+        self.pacman.list_installed()
+    }
+
+    fn install_packages(&self, packages: &[String]) -> Result<(), Error> {
+        self.pacman.install_packages(packages)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::parse_installed;
+
+    #[test]
+    fn test_parse_installed() {
+        let lines = vec!["base".to_string(), "".to_string(), "git ".to_string()];
+
+        let names = parse_installed(&lines)
+            .expect("parse packages")
+            .into_iter()
+            .map(|p| p.name)
+            .collect::<Vec<_>>();
+
+        assert_eq!(names, vec!["base", "git"]);
+    }
+}
@@ -1,7 +1,10 @@
 //! Packages abstraction for Cargo.
 
-use crate::{command, os, packages::Package};
-use anyhow::{anyhow, Error};
+use crate::{
+    command, os,
+    packages::{Package, PackagesError},
+};
+use anyhow::Error;
 use std::ffi::OsStr;
 use std::io;
 
@@ -60,13 +63,15 @@ impl Cargo {
 
             let line = line.trim();
 
-            if line == "" {
+            if line.is_empty() {
                 continue;
             }
 
             let mut it = line.split(' ');
 
-            let name = it.next().ok_or_else(|| anyhow!("expected package name"))?;
+            let name = it
+                .next()
+                .ok_or(PackagesError::UnexpectedOutput("expected package name"))?;
 
             out.push(Package {
                 name: name.to_string(),
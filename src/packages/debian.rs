@@ -1,7 +1,10 @@
 //! Packages abstraction for Debian.
 
-use crate::{command, os, packages::Package};
-use anyhow::{anyhow, Error};
+use crate::{
+    command, os,
+    packages::{Package, PackagesError},
+};
+use anyhow::Error;
 use std::ffi::OsStr;
 use std::io;
 
@@ -73,13 +76,17 @@ impl DpkgQuery {
         for line in dpkg_query.run_lines()? {
             let line = line.trim();
 
-            if line == "" {
+            if line.is_empty() {
                 continue;
             }
 
             let mut it = line.split(' ');
-            let status = it.next().ok_or_else(|| anyhow!("expected status"))?;
-            let name = it.next().ok_or_else(|| anyhow!("expected package name"))?;
+            let status = it
+                .next()
+                .ok_or(PackagesError::UnexpectedOutput("expected status"))?;
+            let name = it
+                .next()
+                .ok_or(PackagesError::UnexpectedOutput("expected package name"))?;
 
             if status != "ii" {
                 continue;
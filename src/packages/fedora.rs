@@ -1,7 +1,10 @@
 //! Packages abstraction for Fedora.
 
-use crate::{command, os, packages::Package};
-use anyhow::{anyhow, Error};
+use crate::{
+    command, os,
+    packages::{Package, PackagesError},
+};
+use anyhow::Error;
 use std::ffi::OsStr;
 use std::io;
 
@@ -59,16 +62,18 @@ impl Dnf {
         for line in dnf.run_lines()?.into_iter().skip(1) {
             let line = line.trim();
 
-            if line == "" {
+            if line.is_empty() {
                 continue;
             }
 
             let mut it = line.split(' ');
-            let name = it.next().ok_or_else(|| anyhow!("expected package name"))?;
+            let name = it
+                .next()
+                .ok_or(PackagesError::UnexpectedOutput("expected package name"))?;
 
             let name = name
                 .split_once('.')
-                .ok_or_else(|| anyhow!("illegal name"))?
+                .ok_or(PackagesError::UnexpectedOutput("illegal name"))?
                 .0;
 
             out.push(Package {
@@ -0,0 +1,139 @@
+//! Packages abstraction for Homebrew on macOS.
+
+use crate::{
+    command, os,
+    packages::{Package, PackagesError},
+};
+use anyhow::Error;
+use std::ffi::OsStr;
+use std::io;
+
+#[derive(Debug)]
+pub struct Brew {
+    brew: command::Command,
+}
+
+impl Brew {
+    /// Create a new brew command wrapper.
+    ///
+    /// NB: brew refuses to run as root, so unlike other primary package managers it doesn't use
+    /// `sudo`.
+    pub fn new() -> Self {
+        Brew {
+            brew: command::Command::new(os::command("brew")),
+        }
+    }
+
+    /// Test that the command is available.
+    pub fn test(&self) -> Result<bool, Error> {
+        let mut brew = self.brew.clone();
+        brew.arg("--version");
+
+        match brew.run() {
+            Ok(output) => Ok(output.status.success()),
+            Err(e) => match e.kind() {
+                // no such command.
+                io::ErrorKind::NotFound => Ok(false),
+                _ => Err(Error::from(e)),
+            },
+        }
+    }
+
+    /// Install the given packages.
+    pub fn install_packages<I>(&self, packages: I) -> Result<(), Error>
+    where
+        I: IntoIterator,
+        I::Item: AsRef<OsStr>,
+    {
+        let mut brew = self.brew.clone();
+        brew.arg("install");
+        brew.args(packages);
+        brew.run_inherited()?;
+        Ok(())
+    }
+
+    /// List all the packages which are installed, both formulae and casks.
+    pub fn list_installed(&self) -> Result<Vec<Package>, Error> {
+        let mut brew = self.brew.clone();
+        brew.args(&["list", "-1"]);
+        parse_installed(&brew.run_lines()?)
+    }
+}
+
+/// Parse the output of `brew list -1`, which is one package name per line.
+fn parse_installed(lines: &[String]) -> Result<Vec<Package>, Error> {
+    let mut out = Vec::new();
+
+    for line in lines {
+        let line = line.trim();
+
+        if line.is_empty() {
+            continue;
+        }
+
+        let name = line
+            .split_whitespace()
+            .next()
+            .ok_or(PackagesError::UnexpectedOutput("expected package name"))?;
+
+        out.push(Package {
+            name: name.to_string(),
+        });
+    }
+
+    Ok(out)
+}
+
+/// Packages abstraction for Homebrew.
+#[derive(Debug)]
+pub struct PackageManager {
+    brew: Brew,
+}
+
+impl PackageManager {
+    /// Construct a new homebrew package manager.
+    pub fn new() -> Self {
+        PackageManager { brew: Brew::new() }
+    }
+}
+
+impl super::PackageManager for PackageManager {
+    fn primary(&self) -> bool {
+        true
+    }
+
+    fn name(&self) -> &str {
+        "homebrew"
+    }
+
+    /// Test that we have everything we need.
+    fn test(&self) -> Result<bool, Error> {
+        self.brew.test()
+    }
+
+    fn list_packages(&self) -> Result<Vec<Package>, Error> {
+        self.brew.list_installed()
+    }
+
+    fn install_packages(&self, packages: &[String]) -> Result<(), Error> {
+        self.brew.install_packages(packages)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::parse_installed;
+
+    #[test]
+    fn test_parse_installed() {
+        let lines = vec!["git".to_string(), "".to_string(), "ripgrep ".to_string()];
+
+        let names = parse_installed(&lines)
+            .expect("parse packages")
+            .into_iter()
+            .map(|p| p.name)
+            .collect::<Vec<_>>();
+
+        assert_eq!(names, vec!["git", "ripgrep"]);
+    }
+}
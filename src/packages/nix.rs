@@ -0,0 +1,181 @@
+//! Packages abstraction for Nix, which is the package manager on NixOS.
+//!
+//! Packages are installed into the profile of the user from `nixpkgs`, since the packages of the
+//! system are declared in its configuration.
+
+use crate::{
+    command, os,
+    packages::{Package, PackagesError},
+};
+use anyhow::Error;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::io;
+
+/// Features which `nix profile` needs, which aren't enabled by default.
+const FEATURES: &[&str] = &["--extra-experimental-features", "nix-command flakes"];
+
+#[derive(Debug)]
+pub struct Nix {
+    nix: command::Command,
+}
+
+impl Nix {
+    /// Create a new nix command wrapper.
+    pub fn new() -> Self {
+        Nix {
+            nix: command::Command::new(os::command("nix")),
+        }
+    }
+
+    /// Test that the command is available.
+    pub fn test(&self) -> Result<bool, Error> {
+        let mut nix = self.nix.clone();
+        nix.arg("--version");
+
+        match nix.run() {
+            Ok(output) => Ok(output.status.success()),
+            Err(e) => match e.kind() {
+                // no such command.
+                io::ErrorKind::NotFound => Ok(false),
+                _ => Err(Error::from(e)),
+            },
+        }
+    }
+
+    /// Install the given packages from `nixpkgs`.
+    pub fn install_packages(&self, packages: &[String]) -> Result<(), Error> {
+        let mut nix = self.nix.clone();
+        nix.args(FEATURES);
+        nix.args(&["profile", "install"]);
+        nix.args(packages.iter().map(|p| format!("nixpkgs#{}", p)));
+        nix.run_inherited()?;
+        Ok(())
+    }
+
+    /// List all the packages which are installed in the profile of the user.
+    pub fn list_installed(&self) -> Result<Vec<Package>, Error> {
+        let mut nix = self.nix.clone();
+        nix.args(FEATURES);
+        nix.args(&["profile", "list", "--json"]);
+        parse_installed(&nix.run_stdout()?)
+    }
+}
+
+/// Output of `nix profile list --json`.
+#[derive(Deserialize)]
+struct ProfileList {
+    elements: Elements,
+}
+
+/// Elements of a profile, which are keyed by name since Nix 2.20 and a list before that.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Elements {
+    Map(BTreeMap<String, Element>),
+    List(Vec<Element>),
+}
+
+#[derive(Deserialize)]
+struct Element {
+    /// Attribute that the element was installed from, like `legacyPackages.x86_64-linux.ripgrep`.
+    #[serde(default, rename = "attrPath")]
+    attr_path: Option<String>,
+}
+
+/// Parse the output of `nix profile list --json`.
+///
+/// Packages are named by the last component of the attribute they were installed from, which is
+/// what they're installed by. Elements installed from store paths don't have one and are skipped.
+fn parse_installed(output: &str) -> Result<Vec<Package>, Error> {
+    let list: ProfileList = serde_json::from_str(output)
+        .map_err(|_| PackagesError::UnexpectedOutput("expected profile list as JSON"))?;
+
+    let elements = match list.elements {
+        Elements::Map(elements) => elements.into_values().collect(),
+        Elements::List(elements) => elements,
+    };
+
+    let mut out = Vec::new();
+
+    for element in elements {
+        let attr_path = match element.attr_path {
+            Some(attr_path) => attr_path,
+            None => continue,
+        };
+
+        let name = attr_path.rsplit('.').next().unwrap_or_default();
+
+        out.push(Package {
+            name: name.to_string(),
+        });
+    }
+
+    Ok(out)
+}
+
+/// Packages abstraction for Nix.
+#[derive(Debug)]
+pub struct PackageManager {
+    nix: Nix,
+}
+
+impl PackageManager {
+    /// Construct a new nix package manager.
+    pub fn new() -> Self {
+        PackageManager { nix: Nix::new() }
+    }
+}
+
+impl super::PackageManager for PackageManager {
+    fn primary(&self) -> bool {
+        true
+    }
+
+    fn name(&self) -> &str {
+        "nix"
+    }
+
+    /// Test that we have everything we need.
+    fn test(&self) -> Result<bool, Error> {
+        self.nix.test()
+    }
+
+    fn list_packages(&self) -> Result<Vec<Package>, Error> {
+        self.nix.list_installed()
+    }
+
+    fn install_packages(&self, packages: &[String]) -> Result<(), Error> {
+        self.nix.install_packages(packages)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::parse_installed;
+
+    fn names(output: &str) -> Vec<String> {
+        parse_installed(output)
+            .expect("parse packages")
+            .into_iter()
+            .map(|p| p.name)
+            .collect()
+    }
+
+    #[test]
+    fn test_parse_installed() {
+        let map = r#"{"elements": {
+            "ripgrep": {"attrPath": "legacyPackages.x86_64-linux.ripgrep"},
+            "hello": {"storePaths": ["/nix/store/abc-hello"]}
+        }, "version": 3}"#;
+
+        assert_eq!(names(map), vec!["ripgrep"]);
+
+        let list = r#"{"elements": [
+            {"attrPath": "legacyPackages.aarch64-darwin.git"}
+        ], "version": 2}"#;
+
+        assert_eq!(names(list), vec!["git"]);
+        assert!(parse_installed("0 flake:nixpkgs#ripgrep").is_err());
+    }
+}
@@ -1,7 +1,10 @@
 //! Packages abstraction for Ruby.
 
-use crate::{command, os, packages::Package};
-use anyhow::{anyhow, Error};
+use crate::{
+    command, os,
+    packages::{Package, PackagesError},
+};
+use anyhow::Error;
 use std::ffi::OsStr;
 use std::io;
 
@@ -56,13 +59,15 @@ impl Gem {
         for line in gem.run_lines()? {
             let line = line.trim();
 
-            if line == "" {
+            if line.is_empty() {
                 continue;
             }
 
             let mut it = line.split(' ');
 
-            let name = it.next().ok_or_else(|| anyhow!("expected package name"))?;
+            let name = it
+                .next()
+                .ok_or(PackagesError::UnexpectedOutput("expected package name"))?;
 
             out.push(Package {
                 name: name.to_string(),
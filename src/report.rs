@@ -0,0 +1,114 @@
+//! Reporting the result of runs to a webhook, to keep an eye on machines which run unattended.
+
+use crate::{
+    config::{ConfigError, Report},
+    facts,
+    file_operations::LoadError,
+    git::GitError,
+    hierarchy::HierarchyError,
+    packages::PackagesError,
+    session::UnitsFailed,
+    state::UnsupportedVersion,
+    summary::Summary,
+    unit::UnitError,
+    verify::VerifyError,
+    Facts,
+};
+use anyhow::Error;
+use serde_json::{json, Value};
+/// The report to send for the result of a run.
+///
+/// This is the summary of the run as JSON, together with the host it ran on and what went wrong,
+/// if anything.
+pub fn payload(result: &Result<Summary, Error>, hostname: Option<&str>) -> Value {
+    let (summary, error) = match result {
+        Ok(summary) => (Some(summary), None),
+        Err(e) => (
+            e.downcast_ref::<UnitsFailed>().map(|f| &f.summary),
+            Some(e.to_string()),
+        ),
+    };
+
+    json!({
+        "hostname": hostname,
+        "success": error.is_none(),
+        "error": error,
+        "kind": result.as_ref().err().and_then(kind),
+        "summary": summary.map(Summary::to_json),
+    })
+}
+
+/// The kind of an error, so that reports can be grouped by what went wrong.
+pub fn kind(e: &Error) -> Option<&'static str> {
+    // NB: downcasting also looks through context which has been added to the error.
+    if e.is::<UnitsFailed>() {
+        Some("units")
+    } else if e.is::<ConfigError>() || e.is::<LoadError>() {
+        Some("config")
+    } else if e.is::<HierarchyError>() {
+        Some("hierarchy")
+    } else if e.is::<GitError>() {
+        Some("git")
+    } else if e.is::<PackagesError>() {
+        Some("packages")
+    } else if e.is::<UnitError>() {
+        Some("unit")
+    } else if e.is::<VerifyError>() {
+        Some("verify")
+    } else if e.is::<UnsupportedVersion>() {
+        Some("state")
+    } else {
+        None
+    }
+}
+
+/// Send the report for the result of a run to the configured webhook.
+pub fn send(report: &Report, result: &Result<Summary, Error>) -> Result<(), Error> {
+    // NB: the hostname is only used to tell machines apart, so it's fine if it's missing.
+    let facts = Facts::load().ok();
+    let hostname = facts.as_ref().and_then(|f| f.get(facts::HOSTNAME));
+    let body = payload(result, hostname).to_string();
+
+    crate::net::client()?.post_json(&report.url, &body, report.timeout, report.retries)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::payload;
+    use crate::{git::GitError, session::UnitsFailed, summary::Summary, unit::Outcome};
+
+    #[test]
+    fn test_payload() {
+        let mut summary = Summary::default();
+        summary.record(Some("dotfiles"), Outcome::Changed);
+
+        let ok = Ok(summary);
+        let report = payload(&ok, Some("laptop"));
+        assert_eq!(report["hostname"], "laptop");
+        assert_eq!(report["success"], true);
+        assert!(report["error"].is_null());
+        assert_eq!(report["summary"]["total"]["changed"], 1);
+
+        let mut summary = Summary::default();
+        summary.record_failed(Some("install_packages"));
+
+        let failed = Err(anyhow::Error::new(UnitsFailed {
+            message: String::from("1 unit(s) failed, and 0 skipped"),
+            summary,
+        }));
+
+        let report = payload(&failed, None);
+        assert_eq!(report["success"], false);
+        assert_eq!(report["error"], "1 unit(s) failed, and 0 skipped");
+        assert_eq!(report["kind"], "units");
+        assert_eq!(report["summary"]["total"]["failed"], 1);
+
+        let failed = Err(anyhow::anyhow!("Failed to load configuration"));
+        let report = payload(&failed, None);
+        assert!(report["summary"].is_null());
+        assert!(report["kind"].is_null());
+
+        let failed = Err(anyhow::Error::new(GitError::Detached).context("Failed to update"));
+        assert_eq!(payload(&failed, None)["kind"], "git");
+    }
+}
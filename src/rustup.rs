@@ -70,7 +70,7 @@ impl Rustup {
 
             let line = line.trim();
 
-            if line == "" {
+            if line.is_empty() {
                 continue;
             }
 
@@ -0,0 +1,183 @@
+//! Updating the quickcfg binary in place from the latest release.
+//!
+//! Releases are looked up on GitHub. Every release has a binary for each platform, named like
+//! `qc-x86_64-linux`, next to a minisign signature of it with `.minisig` appended.
+
+use crate::{
+    net,
+    opts::VERSION,
+    verify::{Minisign, Verify},
+    FileSystem,
+};
+use anyhow::{anyhow, bail, Context as _, Error};
+use serde::Deserialize;
+use std::cmp::Ordering;
+use std::env::consts;
+use std::fs;
+use std::path::PathBuf;
+
+/// A release with a binary for the current platform.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Release {
+    /// The version of the release, without any leading `v`.
+    pub version: String,
+    /// URL of the binary for the current platform.
+    pub url: String,
+}
+
+impl Release {
+    /// Test if the release is newer than the running binary.
+    pub fn is_newer(&self) -> bool {
+        compare_versions(&self.version, VERSION) == Ordering::Greater
+    }
+}
+
+/// A release as returned by the GitHub API.
+#[derive(Deserialize)]
+struct GithubRelease {
+    tag_name: String,
+    #[serde(default)]
+    assets: Vec<Asset>,
+}
+
+#[derive(Deserialize)]
+struct Asset {
+    name: String,
+    browser_download_url: String,
+}
+
+/// Name of the release asset with the binary for the current platform.
+pub fn asset_name() -> String {
+    format!("qc-{}-{}{}", consts::ARCH, consts::OS, consts::EXE_SUFFIX)
+}
+
+/// Look up the latest release in the given GitHub repository.
+pub fn latest(repo: &str) -> Result<Release, Error> {
+    let url = format!("https://api.github.com/repos/{}/releases/latest", repo);
+
+    let release: GithubRelease = serde_json::from_reader(net::client()?.get(&url)?)
+        .with_context(|| anyhow!("bad release: {}", url))?;
+
+    parse_release(release, &asset_name())
+}
+
+/// Find the binary with the given name in a release.
+fn parse_release(release: GithubRelease, name: &str) -> Result<Release, Error> {
+    let version = release.tag_name.trim_start_matches('v').to_string();
+
+    let asset = match release.assets.into_iter().find(|a| a.name == name) {
+        Some(asset) => asset,
+        None => bail!(
+            "Release {} doesn't have a binary for this platform, expected `{}`",
+            version,
+            name
+        ),
+    };
+
+    Ok(Release {
+        version,
+        url: asset.browser_download_url,
+    })
+}
+
+/// Replace the running binary with the binary of the given release, once its signature has been
+/// verified with the given minisign public key.
+///
+/// Returns the path of the binary which was replaced.
+pub fn install(release: &Release, public_key: &str) -> Result<PathBuf, Error> {
+    let exe = std::env::current_exe()?;
+    // NB: replace the binary itself, and not a symlink to it.
+    let exe =
+        fs::canonicalize(&exe).with_context(|| anyhow!("failed to resolve: {}", exe.display()))?;
+
+    let verify = Verify {
+        minisign: Some(Minisign {
+            public_key: public_key.to_string(),
+            signature: None,
+        }),
+        ..Verify::default()
+    };
+
+    // NB: a running binary can't be replaced on Windows, but it can be moved out of the way.
+    let old = exe.with_extension("old");
+
+    if cfg!(windows) {
+        if FileSystem::try_open_meta(&old)?.is_some() {
+            fs::remove_file(&old)?;
+        }
+
+        fs::rename(&exe, &old)
+            .with_context(|| anyhow!("failed to move aside: {}", exe.display()))?;
+    }
+
+    let result = FileSystem::write_atomic(&exe, true, |out| {
+        verify.check_stream(&release.url, out, |out| {
+            net::client()?.get(&release.url)?.copy_to(out)?;
+            Ok(())
+        })
+    });
+
+    if let Err(e) = result {
+        if cfg!(windows) {
+            let _ = fs::rename(&old, &exe);
+        }
+
+        return Err(e);
+    }
+
+    Ok(exe)
+}
+
+/// Compare two versions like `0.6.0`, where a pre-release like `0.7.0-rc.1` comes before the
+/// release itself.
+fn compare_versions(a: &str, b: &str) -> Ordering {
+    fn parse(version: &str) -> (Vec<u64>, bool) {
+        let (version, pre) = match version.split_once('-') {
+            Some((version, _)) => (version, true),
+            None => (version, false),
+        };
+
+        let parts = version
+            .split('.')
+            .map(|p| p.parse().unwrap_or_default())
+            .collect();
+
+        (parts, pre)
+    }
+
+    let (a, a_pre) = parse(a);
+    let (b, b_pre) = parse(b);
+    a.cmp(&b).then(b_pre.cmp(&a_pre))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{compare_versions, parse_release, GithubRelease};
+    use std::cmp::Ordering;
+
+    #[test]
+    fn test_compare_versions() {
+        assert_eq!(compare_versions("0.7.0", "0.6.0"), Ordering::Greater);
+        assert_eq!(compare_versions("0.10.0", "0.9.1"), Ordering::Greater);
+        assert_eq!(compare_versions("0.6.0", "0.6.0"), Ordering::Equal);
+        assert_eq!(compare_versions("0.7.0-rc.1", "0.7.0"), Ordering::Less);
+        assert_eq!(compare_versions("0.7.0-rc.1", "0.6.0"), Ordering::Greater);
+    }
+
+    #[test]
+    fn test_parse_release() {
+        let release = |json: &str| serde_json::from_str::<GithubRelease>(json).unwrap();
+
+        let json = r#"{"tag_name": "v0.7.0", "assets": [
+            {"name": "qc-x86_64-linux", "browser_download_url": "https://example.com/qc-x86_64-linux"},
+            {"name": "qc-x86_64-linux.minisig", "browser_download_url": "https://example.com/qc-x86_64-linux.minisig"}
+        ]}"#;
+
+        let found = parse_release(release(json), "qc-x86_64-linux").unwrap();
+        assert_eq!(found.version, "0.7.0");
+        assert_eq!(found.url, "https://example.com/qc-x86_64-linux");
+
+        let e = parse_release(release(json), "qc-aarch64-macos").unwrap_err();
+        assert!(e.to_string().contains("expected `qc-aarch64-macos`"));
+    }
+}
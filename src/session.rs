@@ -0,0 +1,1208 @@
+//! Driver which applies a configuration, so that the engine can be embedded in other tools.
+
+use crate::{
+    environment as e,
+    facts::{self, Facts, FactsCache},
+    git, graph,
+    hierarchy::{self, Data},
+    interrupt, logging,
+    opts::Opts,
+    packages, stage, state,
+    state_dir::StateFile,
+    summary::Summary,
+    system::{self, SystemInput},
+    template,
+    unit::{self, Outcome, Unit, UnitAllocator, UnitError, UnitInput},
+    Concern, Config, FileSystem, Load, PromptPolicy, Repo, Save, State, Timestamp,
+};
+use anyhow::{anyhow, bail, Context as _, Error};
+use directories::BaseDirs;
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+
+/// A single run which applies a configuration.
+pub struct Session<'a> {
+    /// Options for the run.
+    pub opts: &'a Opts,
+    /// The configuration to apply.
+    pub config: &'a Config,
+    /// The root directory of the configuration.
+    pub root: &'a Path,
+    /// The directory where state is stored.
+    pub state_dir: &'a Path,
+    /// Known base directories to use.
+    pub base_dirs: Option<&'a BaseDirs>,
+    /// The git system to use.
+    pub git_system: &'a dyn git::GitSystem,
+    /// Current time.
+    pub now: Timestamp,
+    /// Facts and hierarchy which have already been loaded, if any.
+    pub preloaded: Option<&'a Preloaded>,
+}
+
+/// Facts and hierarchy which were loaded before a session, like to set up git authentication, so
+/// that the session doesn't load them again.
+pub struct Preloaded {
+    /// Facts about the system.
+    pub facts: Facts,
+    /// The hierarchy, and the profile it was loaded with.
+    pub data: Option<(Option<String>, Data)>,
+}
+
+impl<'a> Session<'a> {
+    /// Apply the configuration, recording what was done in the given state.
+    ///
+    /// This doesn't update the configuration or any additional repositories.
+    ///
+    /// Returns a summary of what happened to each unit. If any unit failed, this fails with
+    /// [UnitsFailed] which holds the summary.
+    ///
+    /// Once units start running, a Ctrl-C handler is installed which stops scheduling new units.
+    pub fn apply(&self, state: &mut State<'a>) -> Result<Summary, Error> {
+        self.apply_joining(state, |_| Ok(()))
+    }
+
+    /// Apply the configuration like [Session::apply], calling `join` right before any system is
+    /// applied.
+    ///
+    /// This is used to wait for work that was started in the background, like checking for
+    /// updates, which can overlap with loading facts and the hierarchy.
+    pub fn apply_joining<J>(&self, state: &mut State<'a>, join: J) -> Result<Summary, Error>
+    where
+        J: FnOnce(&mut State<'a>) -> Result<(), Error>,
+    {
+        // NB: when simulating, paths are resolved in the fake environment.
+        match e::simulation() {
+            Some(fake) => self.apply_in(state, fake, join),
+            None => self.apply_in(state, e::Real, join),
+        }
+    }
+
+    /// Apply the configuration in the given environment.
+    fn apply_in<E, J>(
+        &self,
+        state: &mut State<'a>,
+        environment: E,
+        join: J,
+    ) -> Result<Summary, Error>
+    where
+        E: e::Environment + Send + Sync,
+        J: FnOnce(&mut State<'a>) -> Result<(), Error>,
+    {
+        use rayon::prelude::*;
+
+        let Session {
+            opts,
+            config,
+            root,
+            state_dir,
+            base_dirs,
+            git_system,
+            now,
+            preloaded,
+        } = *self;
+
+        let templates = template::render_stats();
+
+        crate::command::set_non_interactive(opts.is_non_interactive());
+
+        let pool = rayon::ThreadPoolBuilder::new()
+            .build()
+            .with_context(|| anyhow!("Failed to construct thread pool"))?;
+
+        let loaded_facts;
+
+        let facts = match preloaded {
+            Some(preloaded) => &preloaded.facts,
+            None => {
+                loaded_facts = load_facts(opts, config, now, root, state_dir)?;
+                &loaded_facts
+            }
+        };
+
+        let vars = config
+            .render_vars(facts, environment)
+            .with_context(|| "Failed to render vars")?;
+
+        // NB: the profile might have been removed from the configuration since it was selected.
+        let profile = state.profile.clone();
+
+        if let Some(profile) = profile.as_deref() {
+            config.check_profile(profile).with_context(|| {
+                "Selected profile is missing, select another one with --profile"
+            })?;
+        }
+
+        let profile = profile.as_deref();
+
+        let repos = load_repos(config, state_dir)?;
+        let loaded_data;
+
+        // NB: the hierarchy has to be loaded again if it was loaded with another profile.
+        let data = match preloaded.and_then(|preloaded| preloaded.data.as_ref()) {
+            Some((p, data)) if p.as_deref() == profile => data,
+            _ => {
+                loaded_data = load_data(config, root, &repos, facts, profile)?;
+                &loaded_data
+            }
+        };
+
+        let context = template::Context::default();
+
+        let packages = packages::detect(facts)?;
+        packages.seed(&state.listings, now, config.package_listing_ttl);
+
+        let allocator = UnitAllocator::default();
+
+        let file_system = FileSystem::new(opts, state_dir, &allocator, data);
+
+        // post-hook for all systems, mapped by id.
+        let mut post_systems = HashMap::new();
+        let mut all_units = Vec::new();
+        let mut pre_systems = Vec::new();
+        let mut errors = Vec::new();
+
+        system::validate_requires(
+            repos
+                .iter()
+                .flat_map(|(_, repo_config)| repo_config.systems(facts, profile))
+                .chain(config.systems(facts, profile)),
+        )?;
+
+        // translate systems that needs translation.
+        let systems = {
+            use std::collections::VecDeque;
+
+            let mut out = Vec::with_capacity(config.systems.len());
+            let mut queue = VecDeque::new();
+
+            for (repo_root, repo_config) in &repos {
+                queue.extend(
+                    repo_config
+                        .systems(facts, profile)
+                        .into_iter()
+                        .map(|system| (repo_root.as_path(), system)),
+                );
+            }
+
+            queue.extend(
+                config
+                    .systems(facts, profile)
+                    .into_iter()
+                    .map(|system| (root, system)),
+            );
+
+            while let Some((root, system)) = queue.pop_back() {
+                match system.translate(facts) {
+                    system::Translation::Discard => {}
+                    system::Translation::Keep => out.push((root, system)),
+                    system::Translation::Expand(systems) => {
+                        queue.extend(systems.iter().map(|system| (root, system)))
+                    }
+                }
+            }
+
+            out
+        };
+
+        // NB: systems in a cycle would never be scheduled.
+        if let Some(cycle) = system::find_cycle(systems.iter().map(|&(_, system)| system)) {
+            bail!(
+                "Systems require each other in a cycle: {}",
+                cycle.join(" -> ")
+            );
+        }
+
+        // NB: listing installed packages can be slow, so it's started before any system applies.
+        for &(_, system) in &systems {
+            if let system::System::Install(install) = system {
+                if let Ok(Some(manager)) = install.stale_package_manager(&packages, data, state) {
+                    packages.prefetch(manager, now);
+                }
+            }
+        }
+
+        join(state)?;
+
+        pool.install(|| {
+            let res = systems.par_iter().map(|&(root, system)| {
+                let res = (|| {
+                    let depends = match system.depends_hash(root)? {
+                        Some((id, hash)) if state.is_hash_current(&id, &hash) => {
+                            log::trace!("Skipping `{}` since its dependencies are unchanged", id);
+                            return Ok((Vec::new(), None));
+                        }
+                        depends => depends,
+                    };
+
+                    let units = system.apply(SystemInput {
+                        root,
+                        base_dirs,
+                        facts,
+                        vars: &vars,
+                        data,
+                        packages: &packages,
+                        environment,
+                        allocator: &allocator,
+                        file_system: &file_system,
+                        state,
+                        now,
+                        opts,
+                        git_system,
+                        item: None,
+                        context: &context,
+                    })?;
+
+                    Ok((units, depends))
+                })();
+
+                match res {
+                    Ok((units, depends)) => Ok((system, units, depends)),
+                    Err(e) => Err((system, e)),
+                }
+            });
+
+            // Collect all units and map out a unit id to each system that can be used as a dependency.
+            for res in res.collect::<Vec<_>>() {
+                let (system, mut units, depends) = match res {
+                    Ok(result) => result,
+                    Err((system, e)) => {
+                        errors.push((system, e));
+                        continue;
+                    }
+                };
+
+                if facts.get(facts::SELINUX).is_some() {
+                    add_restore_contexts(&allocator, &mut units);
+                }
+
+                // NB: hooks only run if the system has something to do, or if the files it depends
+                // on have changed.
+                if !units.is_empty() || depends.is_some() {
+                    add_hooks(&allocator, root, system, &mut units);
+
+                    if let Ok(Some(id)) = system.once_id() {
+                        let mut mark = allocator.unit(unit::MarkOnce { id });
+                        mark.dependencies
+                            .extend(units.iter().map(|u| unit::Dependency::Unit(u.id)));
+                        units.push(mark);
+                    }
+                }
+
+                if let Some((id, hash)) = depends {
+                    let mut mark = allocator.unit(unit::MarkHash { id, hash });
+                    mark.dependencies
+                        .extend(units.iter().map(|u| unit::Dependency::Unit(u.id)));
+                    units.push(mark);
+                }
+
+                for unit in &mut units {
+                    unit.system = system.id().map(String::from);
+                    unit.system_name = system.name().map(String::from);
+                    unit.retries = system.retries();
+                    unit.retry_delay = system.retry_delay();
+                    unit.timeout = system.timeout().or(config.unit_timeout);
+                    unit.serial = system.serial();
+                    unit.order = system.order();
+                }
+
+                if !system.requires().is_empty() {
+                    // Unit that all contained units depend on.
+                    // This unit finishes _before_ any unit in the system.
+                    let mut pre = allocator.unit(Unit::System);
+                    pre.system = system.id().map(String::from);
+                    pre.system_name = system.name().map(String::from);
+
+                    for unit in &mut units {
+                        unit.dependencies.push(unit::Dependency::Unit(pre.id));
+                    }
+
+                    pre_systems.push((pre, system::Dependency::Transitive(system.requires())));
+                }
+
+                if let Some(system_id) = system.id() {
+                    if units.is_empty() {
+                        // If system is empty, there is nothing to depend on.
+                        post_systems
+                            .insert(system_id, system::Dependency::Transitive(system.requires()));
+                        continue;
+                    }
+
+                    // Unit that other systems depend on.
+                    // This unit finishes _after_ all units in the system have finished.
+                    // System units depend on all units it contains.
+                    let mut post = allocator.unit(Unit::System);
+                    post.system = Some(system_id.to_string());
+                    post.system_name = system.name().map(String::from);
+                    post.dependencies
+                        .extend(units.iter().map(|u| unit::Dependency::Unit(u.id)));
+                    post_systems.insert(system_id, system::Dependency::Direct(post.id));
+                    all_units.push(post);
+                }
+
+                all_units.extend(units);
+            }
+        });
+
+        let state_paths = file_system.used_state_paths()?;
+        file_system.validate()?;
+
+        if !errors.is_empty() {
+            for (system, e) in errors.into_iter() {
+                log::error!("System failed: {}", system);
+                report_error(e);
+            }
+
+            bail!("Failed to run all systems");
+        }
+
+        if opts.gc {
+            let (entries, paths) = try_gc(config, state_dir, &state_paths, state)?;
+            log::info!(
+                "Removed {} unused state entries and {} unused paths",
+                entries,
+                paths
+            );
+            return Ok(Summary::default());
+        }
+
+        // Wire up systems that have requires.
+        for (mut pre, depend) in pre_systems {
+            pre.dependencies.extend(depend.resolve(&post_systems));
+            all_units.push(pre);
+        }
+
+        let mut all_units = stage::dedup(all_units);
+
+        if let Some(format) = opts.graph {
+            let all_units = stage::batch_installs(all_units);
+            print!("{}", graph::render(&all_units, format));
+            return Ok(Summary::default());
+        }
+
+        let declined = confirm_units(opts, config, state, &mut all_units)?;
+
+        // NB: batching moves packages between units, so a retried unit could otherwise depend on
+        // a unit which isn't retried to install its packages.
+        let all_units = if opts.retry_failed {
+            all_units
+        } else {
+            stage::batch_installs(all_units)
+        };
+
+        // NB: units which aren't retried are treated as if they succeeded, so that their dependents
+        // are still scheduled.
+        let retry = if opts.retry_failed {
+            if state.failed.is_empty() {
+                log::info!("No failed units to retry");
+                return Ok(Summary::default());
+            }
+
+            // NB: fixing a failed unit might change it, so retry everything in its system.
+            let systems = state
+                .failed
+                .values()
+                .flat_map(|f| f.system.as_deref())
+                .collect::<BTreeSet<_>>();
+
+            let failed = all_units
+                .iter()
+                .filter(|unit| {
+                    state.failed.contains_key(&unit.describe())
+                        || unit.system.as_deref().is_some_and(|s| systems.contains(s))
+                })
+                .map(|unit| unit.id);
+
+            Some(stage::dependents(&all_units, failed))
+        } else {
+            None
+        };
+
+        let is_selected = |unit: &unit::SystemUnit| {
+            retry.as_ref().is_none_or(|r| r.contains(&unit.id)) && !declined.contains(&unit.id)
+        };
+
+        // NB: interrupting stops scheduling new units, so that the state gets written. Before this,
+        // nothing checks if we've been interrupted, so the default handler exits right away.
+        interrupt::install()?;
+
+        // Dispatch units onto the thread pool as soon as their dependencies are satisfied.
+        let mut scheduler = stage::Scheduler::new(all_units);
+
+        let fail_fast = opts.fail_fast || (config.fail_fast && !opts.keep_going);
+        let mut errors = Vec::new();
+        let mut skipped = Vec::new();
+        let mut states = Vec::new();
+        let mut summary = Summary::default();
+
+        {
+            use std::collections::VecDeque;
+            use std::panic::{self, AssertUnwindSafe};
+            use std::sync::mpsc;
+
+            // NB: state is only extended once all units have run, since it is read by running units.
+            let read_state: &State<'a> = state;
+
+            let apply = |unit: &unit::SystemUnit, s: &mut State<'a>| {
+                if !is_selected(unit) {
+                    log::trace!("Not running: {}", unit);
+                    return (Ok(Outcome::Skipped), None);
+                }
+
+                let start = Instant::now();
+
+                let res = panic::catch_unwind(AssertUnwindSafe(|| {
+                    logging::with_unit(unit.system.as_deref(), unit.describe(), || {
+                        unit.apply(UnitInput {
+                            facts,
+                            vars: &vars,
+                            data,
+                            packages: &packages,
+                            read_state,
+                            state: s,
+                            now,
+                            git_system,
+                            system: unit.system.as_deref(),
+                            state_dir,
+                            item: unit.item.as_deref(),
+                        })
+                    })
+                }))
+                .unwrap_or_else(|_| Err(UnitError::Panicked(unit.to_string()).into()));
+
+                if let Ok(Outcome::Changed) = res {
+                    s.touch_changed(&unit.describe(), unit.system.as_deref());
+                }
+
+                (res, Some(start.elapsed()))
+            };
+
+            let (tx, rx) = mpsc::channel();
+            let mut parallel = VecDeque::new();
+            let mut thread_locals = VecDeque::new();
+            let mut serials = VecDeque::new();
+
+            // NB: the scope runs on the current thread, which is where thread-local units are run.
+            pool.in_place_scope(|scope| {
+                let mut running = 0;
+                let mut stopped = false;
+                // If a serial unit is currently running.
+                let mut exclusive = false;
+
+                loop {
+                    let mut finished = None;
+
+                    if !stopped && interrupt::is_interrupted() {
+                        log::warn!(
+                            "Interrupted, waiting for {} running unit(s) to finish (press Ctrl-C again to exit immediately)",
+                            running
+                        );
+
+                        stopped = true;
+                    }
+
+                    if !stopped {
+                        for unit in scheduler.ready() {
+                            if unit.serial {
+                                serials.push_back(unit);
+                            } else if unit.thread_local {
+                                thread_locals.push_back(unit);
+                            } else {
+                                parallel.push_back(unit);
+                            }
+                        }
+                    }
+
+                    // NB: serial units run on their own, so nothing else is started while one is
+                    // waiting for running units to finish.
+                    let mut spawn = Vec::new();
+
+                    if !stopped && !exclusive {
+                        if serials.is_empty() {
+                            spawn.extend(parallel.drain(..));
+                        } else if running == 0 {
+                            if let Some(unit) = serials.pop_front() {
+                                if unit.thread_local {
+                                    log::trace!("Running serially on main thread: {}", unit);
+                                    let mut s = State::new(config, now);
+                                    let res = apply(&unit, &mut s);
+                                    finished = Some((unit, res, s));
+                                } else {
+                                    exclusive = true;
+                                    spawn.push(unit);
+                                }
+                            }
+                        }
+                    }
+
+                    for unit in spawn {
+                        log::trace!("Running: {}", unit);
+                        running += 1;
+
+                        let tx = tx.clone();
+                        let apply = &apply;
+
+                        scope.spawn(move |_| {
+                            let mut s = State::new(config, now);
+                            let res = apply(&unit, &mut s);
+                            let _ = tx.send((unit, res, s));
+                        });
+                    }
+
+                    let can_run_thread_local = !stopped && !exclusive && serials.is_empty();
+
+                    // NB: prefer handling finished units, since they might make more units ready.
+                    let (unit, res, s) = if let Some(finished) = finished {
+                        finished
+                    } else if let Ok(finished) = rx.try_recv() {
+                        running -= 1;
+                        finished
+                    } else if let Some(unit) =
+                        thread_locals.pop_front().filter(|_| can_run_thread_local)
+                    {
+                        log::trace!("Running on main thread: {}", unit);
+                        let mut s = State::new(config, now);
+                        let res = apply(&unit, &mut s);
+                        (unit, res, s)
+                    } else if running > 0 {
+                        running -= 1;
+
+                        match rx.recv() {
+                            Ok(finished) => finished,
+                            Err(..) => break,
+                        }
+                    } else {
+                        break;
+                    };
+
+                    if unit.serial {
+                        exclusive = false;
+                    }
+
+                    states.push(s);
+
+                    let (res, elapsed) = res;
+
+                    if let Some(elapsed) = elapsed.filter(|_| !unit.is_system()) {
+                        summary.record_duration(unit.system_label(), unit.describe(), elapsed);
+                    }
+
+                    match res {
+                        Ok(outcome) => {
+                            if !unit.is_system() {
+                                summary.record(unit.system_label(), outcome);
+                            }
+
+                            scheduler.mark(&unit);
+                        }
+                        Err(e) => {
+                            summary.record_failed(unit.system_label());
+                            errors.push((unit, e));
+                            stopped |= fail_fast;
+                        }
+                    }
+                }
+            });
+
+            skipped.extend(parallel);
+            skipped.extend(thread_locals);
+            skipped.extend(serials);
+        }
+
+        for s in states {
+            state.extend(s);
+        }
+
+        state.set_listings(packages.listings());
+        summary.record_templates(template::render_stats().since(templates));
+        summary.record_commands(crate::command::take_traced());
+
+        let interrupted = interrupt::is_interrupted();
+
+        if !errors.is_empty() || interrupted {
+            let failed = errors.len();
+
+            // NB: units which depend on a failed unit are never scheduled, and with fail-fast or when
+            // interrupted nothing is scheduled after that.
+            skipped.extend(scheduler.into_pending());
+            skipped.retain(|unit| !unit.is_system());
+
+            for unit in &skipped {
+                summary.record(unit.system_label(), Outcome::Skipped);
+            }
+
+            state.set_failed(
+                errors
+                    .iter()
+                    .map(|(unit, _)| unit)
+                    .chain(&skipped)
+                    .map(|unit| {
+                        let failed = state::Failed {
+                            system: unit.system.clone(),
+                        };
+
+                        (unit.describe(), failed)
+                    })
+                    .collect(),
+            );
+
+            for (i, (unit, e)) in errors.into_iter().enumerate() {
+                log::error!("{:2}: {}", i, unit);
+                report_error(e);
+            }
+
+            if !skipped.is_empty() {
+                if interrupted {
+                    log::warn!("Did not apply {} unit(s):", skipped.len());
+                } else if fail_fast {
+                    log::warn!("Skipped {} unit(s) after the first failure:", skipped.len());
+                } else {
+                    log::warn!(
+                        "Skipped {} unit(s) which depend on failed units:",
+                        skipped.len()
+                    );
+                }
+
+                for unit in &skipped {
+                    match unit.system_label() {
+                        Some(system) => log::warn!("  {}: {}", system, unit.describe()),
+                        None => log::warn!("  {}", unit.describe()),
+                    }
+                }
+            }
+
+            log_summary(&summary);
+
+            let message = if interrupted {
+                let total = summary.total();
+
+                format!(
+                    "Interrupted after applying {} unit(s), {} failed, and {} not applied",
+                    total.changed + total.unchanged,
+                    failed,
+                    skipped.len()
+                )
+            } else {
+                format!("{} unit(s) failed, and {} skipped", failed, skipped.len())
+            };
+
+            return Err(Error::new(UnitsFailed { message, summary }));
+        }
+
+        let unscheduled = scheduler.into_pending();
+
+        if !unscheduled.is_empty() {
+            if log::log_enabled!(log::Level::Trace) {
+                log::trace!("Unable to schedule the following units:");
+
+                for (i, unit) in unscheduled.into_iter().enumerate() {
+                    log::trace!("{:2}: {}", i, unit);
+                }
+            }
+
+            bail!("Could not schedule all units");
+        }
+
+        state.set_failed(Default::default());
+        log_summary(&summary);
+
+        // NB: units which weren't retried haven't accessed their state.
+        if retry.is_none() {
+            try_gc(config, state_dir, &state_paths, state)?;
+        }
+
+        Ok(summary)
+    }
+}
+
+/// Error raised when units failed to apply, or weren't applied because the run was interrupted.
+#[derive(Debug, thiserror::Error)]
+#[error("{message}")]
+pub struct UnitsFailed {
+    /// What went wrong.
+    pub message: String,
+    /// Summary of the run.
+    pub summary: Summary,
+}
+
+/// Print an error and everything that caused it to stderr.
+pub fn report_error(e: Error) {
+    let mut it = e.chain();
+
+    if let Some(e) = it.next() {
+        eprintln!("Error: {}", e);
+    }
+
+    for e in it {
+        eprintln!("Caused by: {}", e);
+    }
+}
+
+/// Load facts, including the ones provided by scripts in the configured facts directory.
+pub fn load_facts(
+    opts: &Opts,
+    config: &Config,
+    now: Timestamp,
+    root: &Path,
+    state_dir: &Path,
+) -> Result<Facts, Error> {
+    let mut facts = Facts::load().with_context(|| "Failed to load facts")?;
+
+    if let Some(facts_dir) = config.facts_dir.as_ref() {
+        let cache_path = StateFile::Facts.path(state_dir);
+
+        let mut cache = if opts.refresh_facts {
+            FactsCache::default()
+        } else {
+            match FactsCache::load(&cache_path) {
+                Ok(cache) => cache.unwrap_or_default(),
+                Err(e) => {
+                    log::warn!(
+                        "Ignoring invalid facts cache `{}`: {}",
+                        cache_path.display(),
+                        e
+                    );
+                    FactsCache::default()
+                }
+            }
+        };
+
+        facts
+            .load_scripts(&facts_dir.to_path(root), &mut cache, &config.facts_ttl, now)
+            .with_context(|| "Failed to load facts from scripts")?;
+
+        if cache.dirty {
+            cache.save(&cache_path)?;
+        }
+    }
+
+    Ok(facts)
+}
+
+/// Load the configuration of all additional repositories, in the order they are declared.
+pub fn load_repos(config: &Config, state_dir: &Path) -> Result<Vec<(PathBuf, Config)>, Error> {
+    let mut out = Vec::new();
+
+    for repo in &config.repos {
+        let repo_root = repo_dir(state_dir, repo)?;
+
+        if !repo_root.is_dir() {
+            log::warn!("Skipping repo `{}` which is not cloned", repo.name);
+            continue;
+        }
+
+        let config_path = Config::path(&repo_root)?;
+
+        let repo_config = Config::load(&config_path)
+            .with_context(|| anyhow!("Failed to load configuration: {}", config_path.display()))?
+            .ok_or_else(|| anyhow!("Missing configuration: {}", config_path.display()))?;
+
+        out.push((repo_root, repo_config));
+    }
+
+    Ok(out)
+}
+
+/// Load the hierarchy of the configuration and all additional repositories.
+pub fn load_data(
+    config: &Config,
+    root: &Path,
+    repos: &[(PathBuf, Config)],
+    facts: &Facts,
+    profile: Option<&str>,
+) -> Result<Data, Error> {
+    let mut data = hierarchy::load(config.hierarchy(profile), root, facts, e::Real)
+        .with_context(|| "Failed to load hierarchy")?;
+
+    // NB: later repos take precedence over earlier ones, and the main configuration over all.
+    for (repo_root, repo_config) in repos.iter().rev() {
+        let repo_data = hierarchy::load(repo_config.hierarchy(profile), repo_root, facts, e::Real)
+            .with_context(|| anyhow!("Failed to load hierarchy: {}", repo_root.display()))?;
+        data.extend(repo_data);
+    }
+
+    Ok(data)
+}
+
+/// The directory that the given additional repository is cloned into.
+pub fn repo_dir(state_dir: &Path, repo: &Repo) -> Result<PathBuf, Error> {
+    if matches!(repo.name.as_str(), "" | "." | "..") || repo.name.contains(['/', '\\']) {
+        bail!("Bad repo name `{}`", repo.name);
+    }
+
+    Ok(StateFile::Repos.path(state_dir).join(&repo.name))
+}
+
+/// Add the pre and post hooks of a system as units, which run in order before and after all other
+/// units of the system.
+fn add_hooks(
+    allocator: &UnitAllocator,
+    root: &Path,
+    system: &system::System,
+    units: &mut Vec<unit::SystemUnit>,
+) {
+    // NB: hooks run with the environment of the system, which has been rendered for its units.
+    let env = units.first().map(|u| u.env.clone()).unwrap_or_default();
+
+    let hook = |command: &String| {
+        let mut hook = allocator.unit(unit::RunHook {
+            command: command.clone(),
+            working_directory: root.to_owned(),
+        });
+
+        hook.env = env.clone();
+        hook
+    };
+
+    let mut pre = system.pre().iter().map(hook).collect::<Vec<_>>();
+    let mut post = system.post().iter().map(hook).collect::<Vec<_>>();
+
+    for i in 1..pre.len() {
+        let previous = unit::Dependency::Unit(pre[i - 1].id);
+        pre[i].dependencies.push(previous);
+    }
+
+    if let Some(last) = pre.last() {
+        for unit in units.iter_mut() {
+            unit.dependencies.push(unit::Dependency::Unit(last.id));
+        }
+    }
+
+    if let Some(first) = post.first_mut() {
+        first
+            .dependencies
+            .extend(units.iter().map(|u| unit::Dependency::Unit(u.id)));
+    }
+
+    for i in 1..post.len() {
+        let previous = unit::Dependency::Unit(post[i - 1].id);
+        post[i].dependencies.push(previous);
+    }
+
+    units.extend(pre);
+    units.extend(post);
+}
+
+/// Add a unit which restores the SELinux contexts of everything the units of a system write, once
+/// they have all run.
+fn add_restore_contexts(allocator: &UnitAllocator, units: &mut Vec<unit::SystemUnit>) {
+    let paths = units
+        .iter()
+        .filter_map(|u| u.written_path())
+        .map(Path::to_owned)
+        .collect::<Vec<_>>();
+
+    if paths.is_empty() {
+        return;
+    }
+
+    let mut restore = allocator.unit(unit::RestoreContexts { paths });
+
+    restore.dependencies.extend(
+        units
+            .iter()
+            .filter(|u| u.written_path().is_some())
+            .map(|u| unit::Dependency::Unit(u.id)),
+    );
+
+    units.push(restore);
+}
+
+/// Ask for confirmation before units which install packages or overwrite unmanaged files,
+/// according to `prompts` in the configuration.
+///
+/// Returns the ids of the units which were declined, which are skipped. Declined installs have
+/// nothing left to install, so that they aren't batched with other installs.
+fn confirm_units(
+    opts: &Opts,
+    config: &Config,
+    state: &mut State<'_>,
+    units: &mut [unit::SystemUnit],
+) -> Result<HashSet<unit::UnitId>, Error> {
+    let mut declined = HashSet::new();
+
+    for unit in units.iter_mut() {
+        let id = unit.id;
+
+        if let Some(install) = unit.install_mut().filter(|i| !i.to_install.is_empty()) {
+            let question = format!(
+                "Install packages for `{}`: {}?",
+                install.id,
+                install.to_install.join(", ")
+            );
+
+            if !opts.ask(Concern::Install, config.prompts.install, &question, state)? {
+                install.to_install.clear();
+                declined.insert(id);
+            }
+
+            continue;
+        }
+
+        // NB: avoid looking up every file which is replaced if it's never asked about.
+        if config.prompts.overwrite == PromptPolicy::Never {
+            continue;
+        }
+
+        let path = match unit.replaces() {
+            Some(path) if !state.is_deployed(path) => path,
+            _ => continue,
+        };
+
+        if FileSystem::try_open_meta(path)?.is_none() {
+            continue;
+        }
+
+        let question = format!(
+            "Overwrite {}, which isn't managed by quickcfg?",
+            path.display()
+        );
+
+        if !opts.ask(
+            Concern::Overwrite,
+            config.prompts.overwrite,
+            &question,
+            state,
+        )? {
+            declined.insert(id);
+        }
+    }
+
+    // NB: a system isn't done if any of its units were declined.
+    for unit in units.iter().filter(|u| u.is_mark()) {
+        let declined_any = unit
+            .dependencies
+            .iter()
+            .any(|d| matches!(d, unit::Dependency::Unit(id) if declined.contains(id)));
+
+        if declined_any {
+            declined.insert(unit.id);
+        }
+    }
+
+    Ok(declined)
+}
+
+/// Log the summary of a run, unless no units ran.
+fn log_summary(summary: &Summary) {
+    if summary.total().total() == 0 {
+        return;
+    }
+
+    for line in summary.render().lines() {
+        log::info!("{}", line);
+    }
+}
+
+/// Remove state which isn't used by the current configuration.
+///
+/// This relies on all state that is used having been accessed, which systems do while setting up
+/// their units. State which is only checked once a unit is applied is marked as used when the unit
+/// is set up, so that this can also run without applying anything, like with `qc gc`.
+///
+/// Returns the number of state entries and paths in the state directory that were removed.
+fn try_gc(
+    config: &Config,
+    state_dir: &Path,
+    state_paths: &BTreeSet<String>,
+    state: &mut State<'_>,
+) -> Result<(usize, usize), Error> {
+    // NB: repositories are not checked for updates every time, so keep their state explicitly.
+    state.mark_used("git");
+
+    for repo in &config.repos {
+        state.mark_used(&format!("git:{}", repo.name));
+    }
+
+    let entries = state.gc();
+
+    for id in &entries {
+        log::trace!("Removed unused state: {}", id);
+    }
+
+    let mut paths = 0;
+
+    for entry in fs::read_dir(state_dir)? {
+        let entry = entry?;
+        let name = entry.file_name();
+        let name = name.to_string_lossy();
+
+        match StateFile::from_name(&name) {
+            Some(StateFile::Repos) => {
+                paths += gc_repos(config, &entry.path())?;
+                continue;
+            }
+            Some(..) => continue,
+            None => (),
+        }
+
+        // NB: executables are stored with an extension on some platforms.
+        let stem = Path::new(name.as_ref())
+            .file_stem()
+            .map(|s| s.to_string_lossy());
+
+        let used = state_paths.contains(name.as_ref())
+            || stem
+                .map(|stem| state_paths.contains(stem.as_ref()))
+                .unwrap_or_default();
+
+        if !used {
+            remove_path(&entry.path())?;
+            paths += 1;
+        }
+    }
+
+    Ok((entries.len(), paths))
+}
+
+/// Remove clones of repositories which are no longer configured.
+fn gc_repos(config: &Config, repos_dir: &Path) -> Result<usize, Error> {
+    let mut paths = 0;
+
+    for entry in fs::read_dir(repos_dir)? {
+        let entry = entry?;
+
+        if !config
+            .repos
+            .iter()
+            .any(|repo| entry.file_name() == repo.name.as_str())
+        {
+            remove_path(&entry.path())?;
+            paths += 1;
+        }
+    }
+
+    Ok(paths)
+}
+
+/// Remove the given file or directory.
+fn remove_path(path: &Path) -> Result<(), Error> {
+    log::trace!("Removing unused path: {}", path.display());
+
+    let result = if path.is_dir() {
+        fs::remove_dir_all(path)
+    } else {
+        fs::remove_file(path)
+    };
+
+    result.with_context(|| anyhow!("Failed to remove: {}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{add_restore_contexts, try_gc};
+    use crate::hierarchy::Data;
+    use crate::opts::Opts;
+    use crate::unit::{CopyTemplate, CreateDir, Dependency, RunHook, UnitAllocator};
+    use crate::{cache, state_dir::StateFile, Config, FileSystem, State, Timestamp};
+    use std::collections::BTreeSet;
+    use std::fs;
+    use std::path::PathBuf;
+
+    #[test]
+    fn test_gc_keeps_managed_state() -> anyhow::Result<()> {
+        let dir = std::env::temp_dir().join(format!("quickcfg-gc-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+
+        let downloads = cache::dir(&dir);
+        fs::create_dir_all(&downloads)?;
+        fs::write(downloads.join("blob"), b"hello")?;
+        fs::create_dir_all(StateFile::Logs.path(&dir))?;
+        fs::write(StateFile::Logs.path(&dir).join("quickcfg.log"), b"")?;
+        fs::write(dir.join("unused"), b"")?;
+
+        let config = Config::default();
+        let mut state = State::new(&config, Timestamp::now());
+        let (_, paths) = try_gc(&config, &dir, &BTreeSet::new(), &mut state)?;
+
+        assert_eq!(1, paths);
+        assert!(downloads.join("blob").is_file());
+        assert!(StateFile::Logs.path(&dir).join("quickcfg.log").is_file());
+        assert!(!dir.join("unused").exists());
+
+        fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_gc_keeps_template_hash() -> anyhow::Result<()> {
+        let dir = std::env::temp_dir().join(format!("quickcfg-gc-template-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir)?;
+
+        let from = dir.join("template");
+        let to = dir.join("rendered");
+        fs::write(&from, "{{name}}")?;
+        fs::write(&to, "world")?;
+        FileSystem::touch(&to, &std::time::SystemTime::UNIX_EPOCH)?;
+
+        let opts = Opts::default();
+        let allocator = UnitAllocator::default();
+        let data = Data::new(None, Vec::new());
+        let file_system = FileSystem::new(&opts, &dir, &allocator, &data);
+        let config = Config::default();
+
+        let setup = |state: &State| -> anyhow::Result<()> {
+            let (from_meta, to_meta) = (from.metadata()?, to.metadata()?);
+            let unit = file_system.copy_file(&from, from_meta, &to, Some(&to_meta), true, state)?;
+            assert!(unit.is_some());
+            Ok(())
+        };
+
+        let id = CopyTemplate {
+            from: from.clone(),
+            from_modified: from.metadata()?.modified()?,
+            to: to.clone(),
+            to_exists: true,
+        }
+        .id();
+
+        // NB: the hash recorded when the template was last rendered.
+        let mut state = State::new(&config, Timestamp::now());
+        state.touch_hash(&id, "world")?;
+        let disk = state.serialize().expect("dirty state");
+
+        // NB: like `qc gc`, which sets up units without applying them.
+        let mut state = disk.into_state(&config, Timestamp::now());
+        setup(&state)?;
+        let (entries, _) = try_gc(&config, &dir, &BTreeSet::new(), &mut state)?;
+
+        assert_eq!(0, entries);
+        assert!(state.is_hash_current(&id, "world"));
+
+        fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_add_restore_contexts() {
+        let allocator = UnitAllocator::default();
+
+        let mut units = vec![
+            allocator.unit(CreateDir(PathBuf::from("a"))),
+            allocator.unit(RunHook {
+                command: "true".to_string(),
+                working_directory: PathBuf::from("."),
+            }),
+            allocator.unit(CreateDir(PathBuf::from("b"))),
+        ];
+
+        add_restore_contexts(&allocator, &mut units);
+
+        assert_eq!(units.len(), 4);
+        assert_eq!(
+            units[3].dependencies,
+            vec![Dependency::Unit(units[0].id), Dependency::Unit(units[2].id)]
+        );
+
+        let mut units = vec![allocator.unit(RunHook {
+            command: "true".to_string(),
+            working_directory: PathBuf::from("."),
+        })];
+
+        add_restore_contexts(&allocator, &mut units);
+        assert_eq!(units.len(), 1);
+    }
+}
@@ -1,93 +1,561 @@
-//! Utilities to process a set of units into a set of inter-dependent stages.
+//! Utilities to schedule a set of inter-dependent units as soon as their dependencies are
+//! satisfied.
 
-use crate::unit::{Dependency, SystemUnit};
-use std::collections::HashSet;
+use crate::unit::{Dependency, SystemUnit, UnitId};
+use std::collections::{HashMap, HashSet};
 
-/// Discrete stage to run.
-/// Unless a stage is marked as `true` in thread_local, units in this stage can be run in parallel.
-pub struct Stage {
-    pub thread_local: bool,
-    pub units: Vec<SystemUnit>,
-}
-
-/// Stager that incrementally schedules stages to be run.
-pub struct Stager {
-    units: Vec<SystemUnit>,
+/// Scheduler that hands out units as soon as everything they depend on has been provided.
+pub struct Scheduler {
+    /// Units which haven't been handed out yet, by index.
+    units: Vec<Option<SystemUnit>>,
+    /// Number of dependencies that each unit is still waiting for.
+    remaining: Vec<usize>,
+    /// Units waiting for each dependency.
+    waiting: HashMap<Dependency, Vec<usize>>,
+    /// Dependencies which have been provided.
     provided: HashSet<Dependency>,
-    thread_locals: Vec<SystemUnit>,
-    stage: Vec<SystemUnit>,
+    /// Units which are ready to run.
+    ready: Vec<usize>,
 }
 
-impl Stager {
-    /// Construct a new stager out of an iterator of units.
+impl Scheduler {
+    /// Construct a new scheduler out of an iterator of units.
     pub fn new(units: impl IntoIterator<Item = SystemUnit>) -> Self {
-        Stager {
-            units: units.into_iter().collect::<Vec<_>>(),
+        let mut scheduler = Scheduler {
+            units: Vec::new(),
+            remaining: Vec::new(),
+            waiting: HashMap::new(),
             provided: HashSet::new(),
-            thread_locals: Vec::new(),
-            stage: Vec::new(),
+            ready: Vec::new(),
+        };
+
+        for (index, unit) in units.into_iter().enumerate() {
+            let dependencies = unit.dependencies.iter().collect::<HashSet<_>>();
+
+            for dependency in &dependencies {
+                scheduler
+                    .waiting
+                    .entry(**dependency)
+                    .or_default()
+                    .push(index);
+            }
+
+            if dependencies.is_empty() {
+                scheduler.ready.push(index);
+            }
+
+            scheduler.remaining.push(dependencies.len());
+            scheduler.units.push(Some(unit));
         }
+
+        scheduler
     }
 
-    /// Plans and returns the next stage to run.
-    pub fn stage(&mut self) -> Option<Stage> {
-        let Stager {
-            ref mut units,
-            ref provided,
-            ref mut thread_locals,
-            ref mut stage,
-        } = *self;
+    /// Take all units which are ready to run, in the order they should be started.
+    pub fn ready(&mut self) -> Vec<SystemUnit> {
+        let mut ready = std::mem::take(&mut self.ready);
+        let units = &self.units;
+        ready.sort_unstable_by_key(|&index| (units[index].as_ref().map(|u| u.order), index));
 
-        loop {
-            // Units that roll over into the next scheduling phase.
-            let mut next = Vec::new();
+        ready
+            .into_iter()
+            .flat_map(|index| self.units[index].take())
+            .collect()
+    }
+
+    /// Mark the specified unit as successfully processed, making units which depend on it ready.
+    pub fn mark(&mut self, unit: &SystemUnit) {
+        log::trace!("Mark: {}", unit);
+
+        let provides = unit
+            .provides
+            .iter()
+            .copied()
+            .chain(std::iter::once(Dependency::Unit(unit.id)));
 
-            for unit in units.drain(..) {
-                if !unit.dependencies.iter().all(|d| provided.contains(d)) {
-                    next.push(unit);
-                    continue;
+        for dependency in provides {
+            if !self.provided.insert(dependency) {
+                continue;
+            }
+
+            for index in self.waiting.remove(&dependency).into_iter().flatten() {
+                self.remaining[index] -= 1;
+
+                if self.remaining[index] == 0 {
+                    self.ready.push(index);
                 }
+            }
+        }
+    }
+
+    /// Convert into units which were never ready to run.
+    pub fn into_pending(self) -> Vec<SystemUnit> {
+        self.units.into_iter().flatten().collect()
+    }
+}
+
+/// Merge units which do exactly the same thing, like several systems creating the same directory.
+///
+/// The first of the identical units is kept, and it takes on the dependencies of the others and
+/// provides for everything which depends on them. Units are only merged if they have the same
+/// environment, since the merged unit runs with the environment of the first one, and if neither
+/// depends on the other, since the merged unit would otherwise depend on itself.
+pub fn dedup(units: Vec<SystemUnit>) -> Vec<SystemUnit> {
+    let mut providers = HashMap::<Dependency, Vec<usize>>::new();
+
+    for (index, unit) in units.iter().enumerate() {
+        let provides = unit
+            .provides
+            .iter()
+            .copied()
+            .chain(std::iter::once(Dependency::Unit(unit.id)));
+
+        for dependency in provides {
+            providers.entry(dependency).or_default().push(index);
+        }
+    }
+
+    // NB: the index of the first identical unit, for each unit which is merged into another.
+    let mut merge_into = vec![None; units.len()];
+    // NB: the units merged into each unit, including itself.
+    let mut members = (0..units.len())
+        .map(|index| vec![index])
+        .collect::<Vec<_>>();
+    let mut seen = HashMap::<_, Vec<usize>>::new();
+
+    for (index, unit) in units.iter().enumerate() {
+        let key = match unit.dedup_key() {
+            Some(key) => (key, &unit.env),
+            None => continue,
+        };
+
+        let candidates = seen.entry(key).or_default();
+
+        let graph = Graph {
+            units: &units,
+            providers: &providers,
+            merge_into: &merge_into,
+            members: &members,
+        };
+
+        let first = candidates
+            .iter()
+            .copied()
+            .find(|&first| !graph.reaches(index, first) && !graph.reaches(first, index));
+
+        match first {
+            Some(first) => {
+                merge_into[index] = Some(first);
+                members[first].push(index);
+            }
+            None => candidates.push(index),
+        }
+    }
+
+    let mut out = Vec::<SystemUnit>::with_capacity(units.len());
+    let mut positions = vec![0; units.len()];
+
+    for (index, unit) in units.into_iter().enumerate() {
+        let first = match merge_into[index] {
+            Some(first) => &mut out[positions[first]],
+            None => {
+                positions[index] = out.len();
+                out.push(unit);
+                continue;
+            }
+        };
+
+        log::trace!("Merging {} into {}", unit, first);
+
+        for dependency in unit.dependencies {
+            if !first.dependencies.contains(&dependency) {
+                first.dependencies.push(dependency);
+            }
+        }
+
+        for provided in unit.provides {
+            if !first.provides.contains(&provided) {
+                first.provides.push(provided);
+            }
+        }
+
+        first.provides.push(Dependency::Unit(unit.id));
+        first.thread_local |= unit.thread_local;
+        first.serial |= unit.serial;
+        first.order = first.order.min(unit.order);
+        first.retries = first.retries.max(unit.retries);
+        first.retry_delay = first.retry_delay.max(unit.retry_delay);
+
+        first.timeout = match (first.timeout, unit.timeout) {
+            (Some(a), Some(b)) => Some(a.max(b)),
+            _ => None,
+        };
+    }
+
+    out
+}
+
+/// Units as they are being merged, to find out if merging two of them would form a cycle.
+struct Graph<'a> {
+    units: &'a [SystemUnit],
+    providers: &'a HashMap<Dependency, Vec<usize>>,
+    merge_into: &'a [Option<usize>],
+    members: &'a [Vec<usize>],
+}
 
-                if unit.thread_local {
-                    thread_locals.push(unit);
-                } else {
-                    stage.push(unit);
+impl Graph<'_> {
+    /// Test if the unit at `from` depends on the unit at `to`, directly or transitively.
+    ///
+    /// Units which have been merged are treated as one, with the dependencies of all of them.
+    fn reaches(&self, from: usize, to: usize) -> bool {
+        let mut visited = HashSet::new();
+        let mut queue = vec![from];
+
+        while let Some(current) = queue.pop() {
+            if !visited.insert(current) {
+                continue;
+            }
+
+            for &member in &self.members[current] {
+                for dependency in &self.units[member].dependencies {
+                    let providers = self.providers.get(dependency).into_iter().flatten();
+
+                    for &provider in providers {
+                        let provider = self.merge_into[provider].unwrap_or(provider);
+
+                        if provider == to {
+                            return true;
+                        }
+
+                        queue.push(provider);
+                    }
                 }
             }
+        }
+
+        false
+    }
+}
+
+/// Merge the packages that units install with the same package manager into the first of them,
+/// so that the package manager only runs once.
+///
+/// The other units still record that their packages are installed once the first one has run.
+/// Only units which don't depend on anything are merged, since the first unit would otherwise have
+/// to wait for what the others depend on, which might depend on it in turn. Units are also only
+/// merged if they have the same environment, since the package manager runs with the environment
+/// of the first unit.
+pub fn batch_installs(mut units: Vec<SystemUnit>) -> Vec<SystemUnit> {
+    let mut first = HashMap::<(String, Vec<(String, String)>), usize>::new();
+
+    for index in 0..units.len() {
+        if !units[index].dependencies.is_empty() {
+            continue;
+        }
+
+        let install = match units[index].install_mut() {
+            Some(install) if !install.to_install.is_empty() => install,
+            _ => continue,
+        };
+
+        let key = (
+            install.package_manager.name().to_string(),
+            units[index].env.clone(),
+        );
+
+        let leader = match first.get(&key) {
+            Some(&leader) => leader,
+            None => {
+                first.insert(key, index);
+                continue;
+            }
+        };
+
+        let to_install = match units[index].install_mut() {
+            Some(install) => std::mem::take(&mut install.to_install),
+            None => continue,
+        };
 
-            units.extend(next);
+        let (head, tail) = units.split_at_mut(index);
+        let (leader, unit) = (&mut head[leader], &mut tail[0]);
+        log::trace!("Installing packages of {} with {}", unit, leader);
 
-            if !stage.is_empty() {
-                return Some(Stage {
-                    thread_local: false,
-                    units: stage.drain(..).collect(),
-                });
+        if let Some(install) = leader.install_mut() {
+            for package in to_install {
+                if !install.to_install.contains(&package) {
+                    install.to_install.push(package);
+                }
             }
+        }
+
+        leader.serial |= unit.serial;
+
+        leader.timeout = match (leader.timeout, unit.timeout) {
+            (Some(a), Some(b)) => Some(a.max(b)),
+            _ => None,
+        };
+
+        unit.dependencies.push(Dependency::Unit(leader.id));
+    }
+
+    units
+}
+
+/// Find the given units and all units which depend on them, directly or transitively.
+pub fn dependents(
+    units: &[SystemUnit],
+    roots: impl IntoIterator<Item = UnitId>,
+) -> HashSet<UnitId> {
+    let mut dependents = HashMap::<Dependency, Vec<UnitId>>::new();
+    let by_id = units
+        .iter()
+        .map(|unit| (unit.id, unit))
+        .collect::<HashMap<_, _>>();
+
+    for unit in units {
+        for dependency in &unit.dependencies {
+            dependents.entry(*dependency).or_default().push(unit.id);
+        }
+    }
+
+    let mut found = HashSet::new();
+    let mut queue = roots.into_iter().collect::<Vec<_>>();
+
+    while let Some(id) = queue.pop() {
+        if !found.insert(id) {
+            continue;
+        }
+
+        let provides = by_id
+            .get(&id)
+            .into_iter()
+            .flat_map(|unit| unit.provides.iter().copied());
+
+        for dependency in provides.chain(std::iter::once(Dependency::Unit(id))) {
+            queue.extend(dependents.get(&dependency).into_iter().flatten().copied());
+        }
+    }
+
+    found
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{batch_installs, dedup, dependents, Scheduler};
+    use crate::packages::{Package, PackageManager};
+    use crate::unit::{CreateDir, Dependency, Install, SystemUnit};
+    use anyhow::Error;
+    use std::path::PathBuf;
+    use std::sync::Arc;
+
+    fn unit(id: usize, dependencies: &[Dependency]) -> SystemUnit {
+        let mut unit = SystemUnit::new(id, CreateDir(PathBuf::from(id.to_string())));
+        unit.dependencies.extend(dependencies.iter().copied());
+        unit
+    }
+
+    fn ids(units: &[SystemUnit]) -> Vec<usize> {
+        units.iter().map(|unit| unit.id).collect()
+    }
+
+    #[test]
+    fn test_scheduler() {
+        let mut dir = unit(0, &[]);
+        dir.provides.push(Dependency::Dir(10));
+
+        let mut scheduler = Scheduler::new(vec![
+            dir,
+            unit(1, &[]),
+            unit(2, &[Dependency::Dir(10), Dependency::Unit(1)]),
+            unit(3, &[Dependency::Unit(1)]),
+            unit(4, &[Dependency::File(11)]),
+        ]);
+
+        let ready = scheduler.ready();
+        assert_eq!(ids(&ready), vec![0, 1]);
+        assert!(scheduler.ready().is_empty());
+
+        // NB: unit 3 doesn't have to wait for unrelated units.
+        scheduler.mark(&ready[1]);
+        assert_eq!(ids(&scheduler.ready()), vec![3]);
+
+        scheduler.mark(&ready[0]);
+        assert_eq!(ids(&scheduler.ready()), vec![2]);
+
+        assert_eq!(ids(&scheduler.into_pending()), vec![4]);
+    }
+
+    #[test]
+    fn test_order() {
+        let mut heavy = unit(0, &[]);
+        heavy.order = 10;
+        let mut cheap = unit(2, &[]);
+        cheap.order = -1;
+
+        let mut scheduler = Scheduler::new(vec![heavy, unit(1, &[]), cheap]);
+        assert_eq!(ids(&scheduler.ready()), vec![2, 1, 0]);
+    }
 
-            if !thread_locals.is_empty() {
-                let units = thread_locals.drain(..).collect();
+    #[test]
+    fn test_dedup() {
+        let mut a = unit(0, &[]);
+        a.provides.push(Dependency::Dir(10));
 
-                return Some(Stage {
-                    thread_local: true,
-                    units,
-                });
+        let mut b = SystemUnit::new(1, CreateDir(PathBuf::from("0")));
+        b.dependencies.push(Dependency::Dir(11));
+        b.provides.push(Dependency::Dir(10));
+        b.retries = 2;
+
+        // NB: units with a different environment are left alone.
+        let mut c = SystemUnit::new(5, CreateDir(PathBuf::from("0")));
+        c.env.push(("UMASK".to_string(), "077".to_string()));
+
+        let units = dedup(vec![a, b, unit(2, &[Dependency::Unit(1)]), c]);
+        assert_eq!(ids(&units), vec![0, 2, 5]);
+        assert_eq!(units[0].dependencies, vec![Dependency::Dir(11)]);
+        assert_eq!(
+            units[0].provides,
+            vec![Dependency::Dir(10), Dependency::Unit(1)]
+        );
+        assert_eq!(units[0].retries, 2);
+
+        // NB: units which depended on the merged unit are scheduled once the first one has run.
+        let mut scheduler = Scheduler::new(vec![unit(3, &[]), unit(4, &[Dependency::Unit(1)])]);
+        assert_eq!(ids(&scheduler.ready()), vec![3]);
+        scheduler.mark(&units[0]);
+        assert_eq!(ids(&scheduler.ready()), vec![4]);
+
+        // NB: a system which requires another can't have its units merged into the other one,
+        // since they would then wait for themselves.
+        let dir = |id, dependencies: &[Dependency]| {
+            let mut unit = SystemUnit::new(id, CreateDir(PathBuf::from("x")));
+            unit.dependencies.extend(dependencies.iter().copied());
+            unit
+        };
+
+        let units = dedup(vec![
+            dir(0, &[]),
+            unit(1, &[Dependency::Unit(0)]),
+            unit(2, &[Dependency::Unit(1)]),
+            dir(3, &[Dependency::Unit(2)]),
+            unit(4, &[Dependency::Unit(3)]),
+            dir(5, &[]),
+        ]);
+
+        assert_eq!(ids(&units), vec![0, 1, 2, 3, 4]);
+        assert_eq!(units[0].provides, vec![Dependency::Unit(5)]);
+
+        let mut scheduler = Scheduler::new(units);
+        let mut order = Vec::new();
+
+        loop {
+            let ready = scheduler.ready();
+
+            if ready.is_empty() {
+                break;
             }
 
-            if thread_locals.is_empty() && stage.is_empty() {
-                return None;
+            for unit in &ready {
+                scheduler.mark(unit);
             }
+
+            order.extend(ids(&ready));
         }
+
+        assert_eq!(order, vec![0, 1, 2, 3, 4]);
     }
 
-    /// Mark the specified unit as successfully processed.
-    pub fn mark(&mut self, unit: SystemUnit) {
-        log::trace!("Mark: {}", unit);
-        self.provided.extend(unit.provides.into_iter());
-        self.provided.insert(Dependency::Unit(unit.id));
+    #[test]
+    fn test_dependents() {
+        let mut dir = unit(0, &[]);
+        dir.provides.push(Dependency::Dir(10));
+
+        let units = vec![
+            dir,
+            unit(1, &[Dependency::Dir(10)]),
+            unit(2, &[Dependency::Unit(1)]),
+            unit(3, &[]),
+        ];
+
+        let mut found = dependents(&units, vec![0]).into_iter().collect::<Vec<_>>();
+        found.sort_unstable();
+        assert_eq!(found, vec![0, 1, 2]);
+
+        let mut found = dependents(&units, vec![1, 3])
+            .into_iter()
+            .collect::<Vec<_>>();
+        found.sort_unstable();
+        assert_eq!(found, vec![1, 2, 3]);
+    }
+
+    #[derive(Debug)]
+    struct Apt;
+
+    impl PackageManager for Apt {
+        fn name(&self) -> &str {
+            "apt"
+        }
+
+        fn test(&self) -> Result<bool, Error> {
+            Ok(true)
+        }
+
+        fn list_packages(&self) -> Result<Vec<Package>, Error> {
+            Ok(Vec::new())
+        }
+
+        fn install_packages(&self, _: &[String]) -> Result<(), Error> {
+            Ok(())
+        }
+    }
+
+    fn install(id: usize, packages: &[&str]) -> SystemUnit {
+        let packages = packages.iter().map(|p| p.to_string()).collect::<Vec<_>>();
+
+        SystemUnit::new(
+            id,
+            Install {
+                package_manager: Arc::new(Apt),
+                all_packages: packages.iter().cloned().collect(),
+                to_install: packages,
+                id: format!("install-{}", id),
+            },
+        )
     }
 
-    /// Convert into unscheduled units.
-    pub fn into_unstaged(self) -> Vec<SystemUnit> {
-        self.units
+    fn to_install(unit: &mut SystemUnit) -> Vec<String> {
+        unit.install_mut().expect("install").to_install.clone()
+    }
+
+    #[test]
+    fn test_batch_installs() {
+        let mut after = install(2, &["git"]);
+        after.dependencies.push(Dependency::Unit(0));
+
+        let mut proxied = install(4, &["curl"]);
+        proxied
+            .env
+            .push(("http_proxy".to_string(), "http://proxy".to_string()));
+
+        let mut units = batch_installs(vec![
+            install(0, &["vim", "git"]),
+            install(1, &["git", "tmux"]),
+            after,
+            install(3, &[]),
+            proxied,
+        ]);
+
+        assert_eq!(to_install(&mut units[0]), vec!["vim", "git", "tmux"]);
+        assert!(to_install(&mut units[1]).is_empty());
+        assert_eq!(units[1].dependencies, vec![Dependency::Unit(0)]);
+
+        // NB: units with dependencies of their own are left alone.
+        assert_eq!(to_install(&mut units[2]), vec!["git"]);
+        assert_eq!(units[2].dependencies, vec![Dependency::Unit(0)]);
+        assert!(units[3].dependencies.is_empty());
+
+        // NB: units with a different environment are left alone.
+        assert_eq!(to_install(&mut units[4]), vec!["curl"]);
+        assert!(units[4].dependencies.is_empty());
     }
 }
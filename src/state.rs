@@ -1,26 +1,184 @@
 //! Model for state file.
 
 use crate::config::Config;
-use crate::Timestamp;
-use anyhow::Error;
+use crate::summary::Summary;
+use crate::{Load as _, Timestamp};
+use anyhow::{anyhow, bail, Context as _, Error};
 use fxhash::FxHasher64;
 use serde::{Deserialize, Serialize};
-use std::collections::BTreeMap;
+use serde_yaml::{Mapping, Value};
+use std::collections::{BTreeMap, BTreeSet};
 use std::hash::{Hash, Hasher};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::Duration;
+use thiserror::Error;
 
-#[derive(Deserialize, Serialize, Debug, PartialEq, Eq)]
+/// The current version of the state format.
+///
+/// The version only changes when existing state has to be rewritten. New fields default to being
+/// empty, so adding them doesn't need a new version.
+///
+/// * 1 - The original format, which didn't have a version.
+/// * 2 - Adds paths which have been deployed.
+/// * 3 - Hashes are stored as strings, together with the algorithm used to calculate them.
+pub const STATE_VERSION: u32 = 3;
+
+/// Number of recent runs to keep timings for.
+const TIMINGS_HISTORY: usize = 20;
+
+/// A migration of serialized state from one version to the next.
+type Migration = fn(&mut Mapping) -> Result<(), Error>;
+
+/// Migrations from each version of the state format to the next, starting with version 1.
+const MIGRATIONS: &[Migration] = &[migrate_v1, migrate_v2];
+
+/// The algorithm used to calculate new hashes.
+const DEFAULT_ALGORITHM: Algorithm = Algorithm::Blake3;
+
+/// Error raised when the state was written by a newer version of quickcfg.
+#[derive(Debug, Error)]
+#[error("state has version {found}, but this version of quickcfg only supports up to {supported}")]
+pub struct UnsupportedVersion {
+    found: u32,
+    supported: u32,
+}
+
+/// Algorithm used to calculate a hash.
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Algorithm {
+    /// FxHash, which was used before hashes were tagged with their algorithm.
+    Fx,
+    /// BLAKE3.
+    Blake3,
+}
+
+impl Algorithm {
+    /// Hash the given value, returning the hash as a hex string.
+    pub fn hash<H: Hash>(self, value: H) -> String {
+        match self {
+            Algorithm::Fx => {
+                let mut state = FxHasher64::default();
+                value.hash(&mut state);
+                format!("{:x}", state.finish())
+            }
+            Algorithm::Blake3 => {
+                let mut state = Blake3(blake3::Hasher::new());
+                value.hash(&mut state);
+                state.0.finalize().to_hex().to_string()
+            }
+        }
+    }
+}
+
+/// Adapter to feed values implementing [Hash] into BLAKE3.
+struct Blake3(blake3::Hasher);
+
+impl Hasher for Blake3 {
+    fn write(&mut self, bytes: &[u8]) {
+        self.0.update(bytes);
+    }
+
+    fn finish(&self) -> u64 {
+        let mut bytes = [0u8; 8];
+        bytes.copy_from_slice(&self.0.finalize().as_bytes()[..8]);
+        u64::from_le_bytes(bytes)
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
 #[serde(deny_unknown_fields)]
 pub struct Hashed {
+    /// The algorithm used to calculate the hash.
+    pub algorithm: Algorithm,
     /// The last calculated hash.
-    pub hash: u64,
+    pub hash: String,
     /// When it was last updated.
     pub updated: Timestamp,
 }
 
+/// The kind of path that was deployed.
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum DeployedKind {
+    /// A copied or templated file.
+    File,
+    /// A symbolic link.
+    Symlink,
+    /// A directory.
+    Dir,
+}
+
+/// A path which has been deployed by quickcfg.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct Deployed {
+    /// The kind of path deployed.
+    pub kind: DeployedKind,
+    /// The id of the system which deployed the path.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub system: Option<String>,
+    /// The modification time of a deployed file, used to tell if it has been modified since.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub modified: Option<Timestamp>,
+    /// The size of a deployed file, used together with `modified`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub size: Option<u64>,
+    /// The target of a deployed symbolic link.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub link: Option<PathBuf>,
+    /// Where the path which was replaced when deploying is backed up.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub backup: Option<PathBuf>,
+}
+
+/// When a unit last changed something.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct Changed {
+    /// The id of the system that the unit belongs to.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub system: Option<String>,
+    /// When the unit last changed something.
+    pub updated: Timestamp,
+}
+
+/// A unit which failed or was skipped in the last run.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct Failed {
+    /// The id of the system that the unit belongs to.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub system: Option<String>,
+}
+
+/// How long systems took to apply in a run.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct RunTimings {
+    /// When the run happened.
+    pub started: Timestamp,
+    /// Milliseconds spent applying the units of each system, by the id of the system.
+    pub systems: BTreeMap<String, u64>,
+}
+
+/// The packages which a package manager listed as installed.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct PackageListing {
+    /// When the packages were listed.
+    pub updated: Timestamp,
+    /// Names of the installed packages.
+    pub packages: BTreeSet<String>,
+}
+
 /// The way the state is serialized.
-#[derive(Deserialize, Serialize, Default, Debug, PartialEq, Eq)]
+#[derive(Deserialize, Serialize, Debug, PartialEq, Eq)]
 #[serde(deny_unknown_fields)]
 pub struct DiskState {
+    /// The version of the state format.
+    pub version: u32,
     /// Last time git was updated.
     #[serde(default)]
     pub last_update: BTreeMap<String, Timestamp>,
@@ -29,9 +187,97 @@ pub struct DiskState {
     pub once: BTreeMap<String, Timestamp>,
     #[serde(default)]
     pub hashes: BTreeMap<String, Hashed>,
+    /// Paths which have been deployed.
+    #[serde(default)]
+    pub deployed: BTreeMap<PathBuf, Deployed>,
+    /// Units which have changed something, and when.
+    #[serde(default)]
+    pub changed: BTreeMap<String, Changed>,
+    /// Units which failed or were skipped in the last run.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub failed: BTreeMap<String, Failed>,
+    /// How long systems took to apply in recent runs, oldest first.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub timings: Vec<RunTimings>,
+    /// Installed packages by package manager, as of when they were last listed.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub listings: BTreeMap<String, PackageListing>,
+    /// Answers to prompts which shouldn't be asked again, by what they're about.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub answers: BTreeMap<String, bool>,
+    /// The profile which was selected for the machine.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub profile: Option<String>,
+}
+
+impl Default for DiskState {
+    fn default() -> Self {
+        DiskState {
+            version: STATE_VERSION,
+            last_update: BTreeMap::new(),
+            once: BTreeMap::new(),
+            hashes: BTreeMap::new(),
+            deployed: BTreeMap::new(),
+            changed: BTreeMap::new(),
+            failed: BTreeMap::new(),
+            timings: Vec::new(),
+            listings: BTreeMap::new(),
+            answers: BTreeMap::new(),
+            profile: None,
+        }
+    }
 }
 
 impl DiskState {
+    /// Load the state from the given path, migrating it if it was written in an older format.
+    ///
+    /// Fails with [UnsupportedVersion] if it was written by a newer version of quickcfg.
+    pub fn open(path: &Path) -> Result<Option<DiskState>, Error> {
+        match Value::load(path)? {
+            Some(value) => Ok(Some(Self::migrate(value)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Migrate the state from its serialized form.
+    fn migrate(mut value: Value) -> Result<DiskState, Error> {
+        let mapping = match &mut value {
+            Value::Mapping(mapping) => mapping,
+            _ => bail!("Expected state to be a mapping"),
+        };
+
+        let key = Value::from("version");
+
+        // NB: state without a version is from before the format was versioned.
+        let mut version = match mapping.get(&key) {
+            Some(version) => version
+                .as_u64()
+                .map(|version| version as u32)
+                .ok_or_else(|| anyhow!("Expected `version` to be a number"))?,
+            None => 1,
+        };
+
+        if version == 0 || version > STATE_VERSION {
+            return Err(UnsupportedVersion {
+                found: version,
+                supported: STATE_VERSION,
+            }
+            .into());
+        }
+
+        while version < STATE_VERSION {
+            log::trace!("Migrating state from version {}", version);
+
+            MIGRATIONS[version as usize - 1](mapping)
+                .with_context(|| anyhow!("Failed to migrate state from version {}", version))?;
+
+            version += 1;
+        }
+
+        mapping.insert(key, Value::from(u64::from(STATE_VERSION)));
+        Ok(serde_yaml::from_value(value)?)
+    }
+
     /// Convert into a state.
     pub fn into_state<'a>(self, config: &'a Config, now: Timestamp) -> State<'a> {
         State {
@@ -39,6 +285,15 @@ impl DiskState {
             last_update: self.last_update,
             once: self.once,
             hashes: self.hashes,
+            deployed: self.deployed,
+            changed: self.changed,
+            failed: self.failed,
+            timings: self.timings,
+            listings: self.listings,
+            answers: self.answers,
+            profile: self.profile,
+            rehashed: Mutex::default(),
+            used: Mutex::default(),
             config,
             now,
         }
@@ -48,7 +303,7 @@ impl DiskState {
 /// State model.
 /// This keeps track of any changes with the dirty flag, which is an indication whether it should
 /// be serialized or not.
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug)]
 pub struct State<'a> {
     pub dirty: bool,
     /// Last time git was updated.
@@ -57,6 +312,24 @@ pub struct State<'a> {
     pub once: BTreeMap<String, Timestamp>,
     /// Things that have been tested against a hash.
     pub hashes: BTreeMap<String, Hashed>,
+    /// Paths which have been deployed.
+    pub deployed: BTreeMap<PathBuf, Deployed>,
+    /// Units which have changed something, and when.
+    pub changed: BTreeMap<String, Changed>,
+    /// Units which failed or were skipped in the last run.
+    pub failed: BTreeMap<String, Failed>,
+    /// How long systems took to apply in recent runs, oldest first.
+    pub timings: Vec<RunTimings>,
+    /// Installed packages by package manager, as of when they were last listed.
+    pub listings: BTreeMap<String, PackageListing>,
+    /// Answers to prompts which shouldn't be asked again, by what they're about.
+    pub answers: BTreeMap<String, bool>,
+    /// The profile which was selected for the machine.
+    pub profile: Option<String>,
+    /// Hashes which have been recalculated with the default algorithm.
+    rehashed: Mutex<BTreeMap<String, Hashed>>,
+    /// Ids which have been accessed, and which are kept when garbage collecting.
+    used: Mutex<BTreeSet<String>>,
     /// The current configuration.
     pub config: &'a Config,
     /// Current timestamp.
@@ -70,62 +343,116 @@ impl<'a> State<'a> {
             last_update: Default::default(),
             once: Default::default(),
             hashes: Default::default(),
+            deployed: Default::default(),
+            changed: Default::default(),
+            failed: Default::default(),
+            timings: Default::default(),
+            listings: Default::default(),
+            answers: Default::default(),
+            profile: Default::default(),
+            rehashed: Default::default(),
+            used: Default::default(),
             config,
             now,
         }
     }
 
+    /// Mark the given id as used, so that it's kept when garbage collecting.
+    pub fn mark_used(&self, id: &str) {
+        // NB: a poisoned lock only means that we keep less when garbage collecting.
+        if let Ok(mut used) = self.used.lock() {
+            used.insert(id.to_string());
+        }
+    }
+
     /// Get the last update timestamp for the given thing named `name`.
     pub fn last_update<'time>(&'time self, name: &str) -> Option<&'time Timestamp> {
+        self.mark_used(name);
         self.last_update.get(name)
     }
 
     /// Touch the thing with the given name.
     pub fn touch(&mut self, name: &str) {
+        self.mark_used(name);
         self.dirty = true;
         self.last_update.insert(name.to_string(), Timestamp::now());
     }
 
+    /// Forget when the thing with the given name was last updated, so that it's updated next time.
+    pub fn expire(&mut self, name: &str) {
+        self.mark_used(name);
+
+        if self.last_update.remove(name).is_some() {
+            self.dirty = true;
+        }
+    }
+
     /// Check if the given ID has run once.
     pub fn has_run_once(&self, id: &str) -> bool {
+        self.mark_used(id);
         self.once.contains_key(id)
     }
 
     /// Mark that something has happened once.
     pub fn touch_once(&mut self, id: &str) {
+        self.mark_used(id);
         self.dirty = true;
         self.once.insert(id.to_string(), Timestamp::now());
     }
 
-    /// Touch the hashed item.
+    /// Test if the hashed item has the given hash, regardless of when it was hashed.
+    pub fn is_hash_current<H: Hash>(&self, id: &str, hash: H) -> bool {
+        self.current_hash(id, hash).is_some()
+    }
+
+    /// Test if the hashed item has the given hash, and was hashed within the package refresh
+    /// interval.
     pub fn is_hash_fresh<H: Hash>(&self, id: &str, hash: H) -> Result<bool, Error> {
-        let hashed = match self.hashes.get(id) {
+        let hashed = match self.current_hash(id, hash) {
             Some(hashed) => hashed,
             None => return Ok(false),
         };
 
-        let mut state = FxHasher64::default();
-        hash.hash(&mut state);
+        let age = self.now.duration_since(hashed.updated)?;
+        Ok(age < self.config.package_refresh)
+    }
 
-        if hashed.hash != state.finish() {
-            return Ok(false);
+    /// The hashed item, if it has the given hash.
+    fn current_hash<H: Hash>(&self, id: &str, hash: H) -> Option<&Hashed> {
+        self.mark_used(id);
+
+        let hashed = self.hashes.get(id)?;
+
+        if hashed.hash != hashed.algorithm.hash(&hash) {
+            return None;
         }
 
-        let age = self.now.duration_since(hashed.updated)?;
-        Ok(age < self.config.package_refresh)
+        // NB: upgrade the hash instead of treating it as stale, so that nothing has to re-run.
+        if hashed.algorithm != DEFAULT_ALGORITHM {
+            if let Ok(mut rehashed) = self.rehashed.lock() {
+                let hashed = Hashed {
+                    algorithm: DEFAULT_ALGORITHM,
+                    hash: DEFAULT_ALGORITHM.hash(&hash),
+                    updated: hashed.updated,
+                };
+
+                rehashed.insert(id.to_string(), hashed);
+            }
+        }
+
+        Some(hashed)
     }
 
     /// Touch the hashed item.
     pub fn touch_hash<H: Hash>(&mut self, id: &str, hash: H) -> Result<(), Error> {
-        let mut state = FxHasher64::default();
-        hash.hash(&mut state);
-
+        self.mark_used(id);
         self.dirty = true;
 
         self.hashes.insert(
             id.to_string(),
             Hashed {
-                hash: state.finish(),
+                algorithm: DEFAULT_ALGORITHM,
+                hash: DEFAULT_ALGORITHM.hash(hash),
                 updated: Timestamp::now(),
             },
         );
@@ -133,8 +460,134 @@ impl<'a> State<'a> {
         Ok(())
     }
 
+    /// Test if the given path has been deployed.
+    pub fn is_deployed(&self, path: &Path) -> bool {
+        self.deployed.contains_key(path)
+    }
+
+    /// Record that the given path has been deployed.
+    pub fn deploy(&mut self, path: &Path, deployed: Deployed) {
+        self.dirty = true;
+        insert_deployed(&mut self.deployed, path.to_owned(), deployed);
+    }
+
+    /// Forget about a deployed path.
+    pub fn undeploy(&mut self, path: &Path) -> Option<Deployed> {
+        self.dirty = true;
+        self.deployed.remove(path)
+    }
+
+    /// Record that the given unit changed something.
+    pub fn touch_changed(&mut self, unit: &str, system: Option<&str>) {
+        self.dirty = true;
+
+        self.changed.insert(
+            unit.to_string(),
+            Changed {
+                system: system.map(String::from),
+                updated: Timestamp::now(),
+            },
+        );
+    }
+
+    /// Record the units which failed or were skipped in this run.
+    pub fn set_failed(&mut self, failed: BTreeMap<String, Failed>) {
+        if self.failed != failed {
+            self.dirty = true;
+            self.failed = failed;
+        }
+    }
+
+    /// Record how long each system took to apply in this run, keeping a limited history.
+    pub fn record_timings(&mut self, summary: &Summary) {
+        let systems = summary
+            .system_durations()
+            .into_iter()
+            .flat_map(|(system, duration)| Some((system?.to_string(), duration.as_millis() as u64)))
+            .collect::<BTreeMap<_, _>>();
+
+        if systems.is_empty() {
+            return;
+        }
+
+        self.dirty = true;
+
+        self.timings.push(RunTimings {
+            started: self.now,
+            systems,
+        });
+
+        let excess = self.timings.len().saturating_sub(TIMINGS_HISTORY);
+        self.timings.drain(..excess);
+    }
+
+    /// Record the listings of installed packages which are still fresh.
+    pub fn set_listings(&mut self, listings: BTreeMap<String, PackageListing>) {
+        if self.listings != listings {
+            self.dirty = true;
+            self.listings = listings;
+        }
+    }
+
+    /// The remembered answer to prompts about the given concern, if any.
+    pub fn answer(&self, concern: &str) -> Option<bool> {
+        self.answers.get(concern).copied()
+    }
+
+    /// Remember the answer to prompts about the given concern, so that they aren't asked again.
+    pub fn remember(&mut self, concern: &str, answer: bool) {
+        self.dirty = true;
+        self.answers.insert(concern.to_string(), answer);
+    }
+
+    /// Select the profile of the machine, which is used by later runs until another one is
+    /// selected.
+    pub fn set_profile(&mut self, profile: &str) {
+        if self.profile.as_deref() != Some(profile) {
+            self.dirty = true;
+            self.profile = Some(profile.to_string());
+        }
+    }
+
+    /// How long each system took to apply on average in the recorded runs.
+    pub fn average_timings(&self) -> BTreeMap<String, Duration> {
+        let mut totals = BTreeMap::<&str, (u64, u32)>::new();
+
+        for run in &self.timings {
+            for (system, millis) in &run.systems {
+                let total = totals.entry(system).or_default();
+                total.0 += millis;
+                total.1 += 1;
+            }
+        }
+
+        totals
+            .into_iter()
+            .map(|(system, (millis, runs))| {
+                (system.to_string(), Duration::from_millis(millis) / runs)
+            })
+            .collect()
+    }
+
+    /// Get the units which have changed something since the given timestamp, most recent first.
+    pub fn changed_since(&self, since: Timestamp) -> Vec<(&str, &Changed)> {
+        let mut changed = self
+            .changed
+            .iter()
+            .filter(|(_, c)| c.updated >= since)
+            .map(|(unit, c)| (unit.as_str(), c))
+            .collect::<Vec<_>>();
+
+        changed.sort_by_key(|c| std::cmp::Reverse(c.1.updated));
+        changed
+    }
+
     /// Extend this state with another.
     pub fn extend(&mut self, other: State) {
+        if let (Ok(used), Ok(other)) = (self.used.get_mut(), other.used.into_inner()) {
+            used.extend(other);
+        }
+
         // nothing to extend.
         if !other.dirty {
             return;
@@ -144,18 +597,283 @@ impl<'a> State<'a> {
         self.last_update.extend(other.last_update);
         self.once.extend(other.once);
         self.hashes.extend(other.hashes);
+        self.changed.extend(other.changed);
+
+        for (path, deployed) in other.deployed {
+            insert_deployed(&mut self.deployed, path, deployed);
+        }
+    }
+
+    /// Remove all entries which haven't been used, returning the ids of the removed entries.
+    pub fn gc(&mut self) -> Vec<String> {
+        let used = match self.used.get_mut() {
+            Ok(used) => used,
+            Err(..) => return Vec::new(),
+        };
+
+        let mut removed = Vec::new();
+
+        for map in [&mut self.last_update, &mut self.once] {
+            removed.extend(map.keys().filter(|id| !used.contains(*id)).cloned());
+            map.retain(|id, _| used.contains(id));
+        }
+
+        removed.extend(self.hashes.keys().filter(|id| !used.contains(*id)).cloned());
+        self.hashes.retain(|id, _| used.contains(id));
+
+        if !removed.is_empty() {
+            self.dirty = true;
+        }
+
+        removed
     }
 
     /// Serialize the state, returning `None` unless it is dirty.
-    pub fn serialize(self) -> Option<DiskState> {
+    pub fn serialize(mut self) -> Option<DiskState> {
+        if let Ok(rehashed) = self.rehashed.into_inner() {
+            for (id, hashed) in rehashed {
+                // NB: the hash might have been updated since it was recalculated.
+                if let Some(existing) = self.hashes.get_mut(&id) {
+                    if existing.algorithm != DEFAULT_ALGORITHM {
+                        *existing = hashed;
+                        self.dirty = true;
+                    }
+                }
+            }
+        }
+
         if !self.dirty {
             return None;
         }
 
         Some(DiskState {
+            version: STATE_VERSION,
             last_update: self.last_update,
             once: self.once,
             hashes: self.hashes,
+            deployed: self.deployed,
+            changed: self.changed,
+            failed: self.failed,
+            timings: self.timings,
+            listings: self.listings,
+            answers: self.answers,
+            profile: self.profile,
         })
     }
 }
+
+/// Migrate from version 1, which doesn't need any changes since deployed paths are new.
+fn migrate_v1(_: &mut Mapping) -> Result<(), Error> {
+    Ok(())
+}
+
+/// Migrate from version 2, where hashes are numbers which are calculated with FxHash.
+fn migrate_v2(state: &mut Mapping) -> Result<(), Error> {
+    let hashes = match state.get_mut(&Value::from("hashes")) {
+        Some(Value::Mapping(hashes)) => hashes,
+        _ => return Ok(()),
+    };
+
+    for (_, hashed) in hashes.iter_mut() {
+        let hashed = match hashed {
+            Value::Mapping(hashed) => hashed,
+            _ => bail!("Expected hash to be a mapping"),
+        };
+
+        let key = Value::from("hash");
+
+        let hash = hashed
+            .get(&key)
+            .and_then(Value::as_u64)
+            .ok_or_else(|| anyhow!("Expected `hash` to be a number"))?;
+
+        hashed.insert(key, Value::from(format!("{:x}", hash)));
+        hashed.insert(Value::from("algorithm"), Value::from("fx"));
+    }
+
+    Ok(())
+}
+
+/// Insert a deployed path, keeping track of the backup of a path which was deployed before.
+fn insert_deployed(map: &mut BTreeMap<PathBuf, Deployed>, path: PathBuf, mut deployed: Deployed) {
+    if deployed.backup.is_none() {
+        deployed.backup = map.get(&path).and_then(|d| d.backup.clone());
+    }
+
+    map.insert(path, deployed);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        Algorithm, Deployed, DeployedKind, DiskState, Hashed, State, DEFAULT_ALGORITHM,
+        STATE_VERSION,
+    };
+    use crate::{Config, Timestamp};
+    use std::path::{Path, PathBuf};
+
+    #[test]
+    fn test_gc() {
+        let config = Config::default();
+        let mut state = State::new(&config, Timestamp::now());
+
+        state.touch("git");
+        state.touch_once("download");
+        state.touch_hash("install", 42).unwrap();
+
+        let mut disk = state.serialize().unwrap();
+        disk.hashes.insert(
+            String::from("copy-template/old"),
+            Hashed {
+                algorithm: Algorithm::Blake3,
+                hash: Algorithm::Blake3.hash(42),
+                updated: Timestamp::now(),
+            },
+        );
+
+        let mut state = disk.into_state(&config, Timestamp::now());
+        assert!(state.last_update("git").is_some());
+        state.is_hash_fresh("install", 42).unwrap();
+
+        assert_eq!(
+            state.gc(),
+            vec![String::from("download"), String::from("copy-template/old")]
+        );
+
+        let disk = state.serialize().unwrap();
+        assert_eq!(disk.last_update.len(), 1);
+        assert!(disk.once.is_empty());
+        assert_eq!(disk.hashes.keys().collect::<Vec<_>>(), vec!["install"]);
+
+        let mut state = disk.into_state(&config, Timestamp::now());
+        state.expire("git");
+        assert!(state.last_update("git").is_none());
+        assert!(state.serialize().unwrap().last_update.is_empty());
+    }
+
+    #[test]
+    fn test_deploy_keeps_backup() {
+        let config = Config::default();
+        let mut state = State::new(&config, Timestamp::now());
+        let path = Path::new("home/.bashrc");
+
+        let deployed = |backup: Option<&str>| Deployed {
+            kind: DeployedKind::File,
+            system: None,
+            modified: None,
+            size: None,
+            link: None,
+            backup: backup.map(PathBuf::from),
+        };
+
+        state.deploy(path, deployed(Some(".state/backups/bashrc")));
+
+        let mut other = State::new(&config, Timestamp::now());
+        other.deploy(path, deployed(None));
+        state.extend(other);
+
+        assert_eq!(
+            state.deployed[path].backup.as_deref(),
+            Some(Path::new(".state/backups/bashrc"))
+        );
+    }
+
+    #[test]
+    fn test_migrate() {
+        let value = serde_yaml::from_str(
+            "last_update:\n  git: 1000\nonce: {}\nhashes:\n  install:\n    hash: 255\n    updated: 1000\n",
+        );
+        let state = DiskState::migrate(value.unwrap()).unwrap();
+
+        assert_eq!(state.version, STATE_VERSION);
+        assert_eq!(state.last_update.len(), 1);
+        assert!(state.deployed.is_empty());
+        assert_eq!(state.hashes["install"].algorithm, Algorithm::Fx);
+        assert_eq!(state.hashes["install"].hash, "ff");
+
+        let value = serde_yaml::from_str(&format!("version: {}\n", STATE_VERSION + 1));
+        assert!(DiskState::migrate(value.unwrap()).is_err());
+    }
+
+    #[test]
+    fn test_rehash() {
+        let config = Config {
+            package_refresh: std::time::Duration::from_secs(3600),
+            ..Config::default()
+        };
+
+        let mut disk = DiskState::default();
+        let updated = Timestamp::now();
+
+        disk.hashes.insert(
+            String::from("install"),
+            Hashed {
+                algorithm: Algorithm::Fx,
+                hash: Algorithm::Fx.hash(42),
+                updated,
+            },
+        );
+
+        let state = disk.into_state(&config, Timestamp::now());
+        assert!(state.is_hash_fresh("install", 42).unwrap());
+
+        let disk = state.serialize().unwrap();
+        let hashed = &disk.hashes["install"];
+        assert_eq!(hashed.algorithm, DEFAULT_ALGORITHM);
+        assert_eq!(hashed.hash, DEFAULT_ALGORITHM.hash(42));
+        assert_eq!(hashed.updated, updated);
+    }
+
+    #[test]
+    fn test_changed_since() {
+        use std::time::Duration;
+
+        let config = Config::default();
+        let mut state = State::new(&config, Timestamp::now());
+        let start = Timestamp::now();
+
+        state.touch_changed("copy file a", Some("dotfiles"));
+        state.touch_changed("install packages", None);
+
+        let old = start - Duration::from_secs(3600);
+        state.changed.get_mut("install packages").unwrap().updated = old;
+
+        let changed = state.changed_since(start);
+        assert_eq!(changed.len(), 1);
+        assert_eq!(changed[0].0, "copy file a");
+        assert_eq!(changed[0].1.system.as_deref(), Some("dotfiles"));
+
+        let changed = state.changed_since(old);
+        assert_eq!(
+            changed.iter().map(|c| c.0).collect::<Vec<_>>(),
+            vec!["copy file a", "install packages"]
+        );
+    }
+
+    #[test]
+    fn test_timings() {
+        use crate::summary::Summary;
+        use std::time::Duration;
+
+        let config = Config::default();
+        let mut state = State::new(&config, Timestamp::now());
+
+        for millis in 0..25 {
+            let mut summary = Summary::default();
+            summary.record_duration(
+                Some("packages"),
+                String::from("install vim"),
+                Duration::from_millis(millis * 10),
+            );
+            summary.record_duration(None, String::from("copy a"), Duration::from_millis(5));
+            state.record_timings(&summary);
+        }
+
+        assert_eq!(state.timings.len(), 20);
+        assert_eq!(state.timings[0].systems["packages"], 50);
+        assert_eq!(
+            state.average_timings()["packages"],
+            Duration::from_millis(145)
+        );
+    }
+}
@@ -0,0 +1,58 @@
+//! Paths in the state directory which are managed by quickcfg itself.
+
+use std::path::{Path, PathBuf};
+
+macro_rules! state_files {
+    ($($(#[doc = $doc:literal])* $variant:ident => $name:literal,)*) => {
+        /// An entry in the state directory which belongs to quickcfg itself, rather than to a
+        /// system, and which is never garbage collected.
+        ///
+        /// Everything that stores something at a fixed location in the state directory gets its
+        /// path from here.
+        #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+        pub enum StateFile {
+            $($(#[doc = $doc])* $variant,)*
+        }
+
+        impl StateFile {
+            /// All entries.
+            pub const ALL: &'static [StateFile] = &[$(StateFile::$variant,)*];
+
+            /// The name of the entry in the state directory.
+            pub fn name(self) -> &'static str {
+                match self {
+                    $(StateFile::$variant => $name,)*
+                }
+            }
+        }
+    };
+}
+
+state_files! {
+    /// Cached facts provided by scripts.
+    Facts => "facts.yml",
+    /// Where the configuration archive came from.
+    Archive => "archive.yml",
+    /// Backups of files which were overwritten.
+    Backups => "backups",
+    /// Cached downloads.
+    Cache => "cache",
+    /// Lock held by the running instance.
+    Lock => "lock",
+    /// Log files.
+    Logs => "logs",
+    /// Clones of additional repositories.
+    Repos => "repos",
+}
+
+impl StateFile {
+    /// Find the entry with the given name.
+    pub fn from_name(name: &str) -> Option<StateFile> {
+        StateFile::ALL.iter().copied().find(|f| f.name() == name)
+    }
+
+    /// The path of the entry in the given state directory.
+    pub fn path(self, state_dir: &Path) -> PathBuf {
+        state_dir.join(self.name())
+    }
+}
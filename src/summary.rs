@@ -0,0 +1,422 @@
+//! Summary of what happened to each unit in a run, grouped by system.
+
+use crate::{command::Traced, template::RenderStats, unit::Outcome};
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::time::Duration;
+
+/// Number of units shown in the timings table.
+const SLOWEST_UNITS: usize = 10;
+
+/// Number of units with each result.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Counts {
+    pub changed: usize,
+    pub unchanged: usize,
+    pub skipped: usize,
+    pub failed: usize,
+}
+
+impl Counts {
+    /// Total number of units.
+    pub fn total(&self) -> usize {
+        self.changed + self.unchanged + self.skipped + self.failed
+    }
+
+    fn add(&mut self, other: &Counts) {
+        self.changed += other.changed;
+        self.unchanged += other.unchanged;
+        self.skipped += other.skipped;
+        self.failed += other.failed;
+    }
+}
+
+/// How long a unit took to apply.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Timing {
+    /// The system that the unit belongs to.
+    pub system: Option<String>,
+    /// Description of the unit.
+    pub unit: String,
+    /// Wall-clock time spent applying the unit.
+    pub duration: Duration,
+}
+
+/// Summary of a run.
+#[derive(Debug, Default)]
+pub struct Summary {
+    /// Counts by the id of the system, or `None` for units which don't belong to a system with an
+    /// id.
+    systems: BTreeMap<Option<String>, Counts>,
+    /// How long each unit which ran took.
+    timings: Vec<Timing>,
+    /// How many templates were rendered.
+    templates: RenderStats,
+    /// Commands which were run, if they were traced.
+    commands: Vec<Traced>,
+}
+
+impl Summary {
+    /// Record the outcome of a unit which ran.
+    pub fn record(&mut self, system: Option<&str>, outcome: Outcome) {
+        let counts = self.counts(system);
+
+        match outcome {
+            Outcome::Changed => counts.changed += 1,
+            Outcome::Unchanged => counts.unchanged += 1,
+            Outcome::Skipped => counts.skipped += 1,
+        }
+    }
+
+    /// Record a unit which failed.
+    pub fn record_failed(&mut self, system: Option<&str>) {
+        self.counts(system).failed += 1;
+    }
+
+    /// Record how long a unit took to apply.
+    pub fn record_duration(&mut self, system: Option<&str>, unit: String, duration: Duration) {
+        self.timings.push(Timing {
+            system: system.map(String::from),
+            unit,
+            duration,
+        });
+    }
+
+    /// Record how many templates were rendered, and how many were taken from the cache.
+    pub fn record_templates(&mut self, templates: RenderStats) {
+        self.templates = templates;
+    }
+
+    /// Record the commands which were run.
+    pub fn record_commands(&mut self, commands: Vec<Traced>) {
+        self.commands = commands;
+    }
+
+    /// How long each unit which ran took.
+    pub fn timings(&self) -> &[Timing] {
+        &self.timings
+    }
+
+    /// Total time spent applying the units of each system, by the id of the system.
+    pub fn system_durations(&self) -> BTreeMap<Option<&str>, Duration> {
+        let mut out = BTreeMap::new();
+
+        for timing in &self.timings {
+            *out.entry(timing.system.as_deref()).or_default() += timing.duration;
+        }
+
+        out
+    }
+
+    /// Counts for all systems.
+    pub fn total(&self) -> Counts {
+        let mut total = Counts::default();
+
+        for counts in self.systems.values() {
+            total.add(counts);
+        }
+
+        total
+    }
+
+    /// Names of the systems which had units that failed.
+    pub fn failed_systems(&self) -> Vec<&str> {
+        self.systems
+            .iter()
+            .filter(|(_, counts)| counts.failed > 0)
+            .flat_map(|(system, _)| system.as_deref())
+            .collect()
+    }
+
+    /// Render the summary as a table, with one row for each system and a total.
+    pub fn render(&self) -> String {
+        let name = |system: &Option<String>| system.as_deref().unwrap_or("-").to_string();
+
+        let width = self
+            .systems
+            .keys()
+            .map(|system| name(system).len())
+            .chain(Some("total".len()))
+            .max()
+            .unwrap_or_default();
+
+        let mut out = String::new();
+
+        let _ = writeln!(
+            out,
+            "{:width$}  {:>7}  {:>9}  {:>7}  {:>6}",
+            "system",
+            "changed",
+            "unchanged",
+            "skipped",
+            "failed",
+            width = width
+        );
+
+        let rows = self
+            .systems
+            .iter()
+            .map(|(system, counts)| (name(system), *counts))
+            .chain(Some((String::from("total"), self.total())));
+
+        for (system, c) in rows {
+            let _ = writeln!(
+                out,
+                "{:width$}  {:>7}  {:>9}  {:>7}  {:>6}",
+                system,
+                c.changed,
+                c.unchanged,
+                c.skipped,
+                c.failed,
+                width = width
+            );
+        }
+
+        out
+    }
+
+    /// Render how long each system took, slowest first, followed by the slowest units.
+    ///
+    /// `average` is how long each system took on average in previous runs, which is shown next to
+    /// it so that regressions stand out.
+    pub fn render_timings(&self, average: &BTreeMap<String, Duration>) -> String {
+        let mut systems = self.system_durations().into_iter().collect::<Vec<_>>();
+        systems.sort_by_key(|(_, duration)| std::cmp::Reverse(*duration));
+
+        let mut units = self.timings.iter().collect::<Vec<_>>();
+        units.sort_by_key(|t| std::cmp::Reverse(t.duration));
+        units.truncate(SLOWEST_UNITS);
+
+        let width = systems
+            .iter()
+            .map(|(system, _)| system.unwrap_or("-").len())
+            .chain(Some("system".len()))
+            .max()
+            .unwrap_or_default();
+
+        let mut out = String::new();
+
+        let _ = writeln!(
+            out,
+            "{:width$}  {:>10}  {:>10}",
+            "system",
+            "time",
+            "average",
+            width = width
+        );
+
+        for (system, duration) in systems {
+            let average = system
+                .and_then(|system| average.get(system))
+                .map(|d| format_duration(*d))
+                .unwrap_or_else(|| String::from("-"));
+
+            let _ = writeln!(
+                out,
+                "{:width$}  {:>10}  {:>10}",
+                system.unwrap_or("-"),
+                format_duration(duration),
+                average,
+                width = width
+            );
+        }
+
+        if !units.is_empty() {
+            let _ = writeln!(out);
+            let _ = writeln!(out, "{:>10}  unit", "time");
+
+            for timing in units {
+                let _ = match &timing.system {
+                    Some(system) => writeln!(
+                        out,
+                        "{:>10}  {}: {}",
+                        format_duration(timing.duration),
+                        system,
+                        timing.unit
+                    ),
+                    None => writeln!(
+                        out,
+                        "{:>10}  {}",
+                        format_duration(timing.duration),
+                        timing.unit
+                    ),
+                };
+            }
+        }
+
+        let RenderStats { rendered, cached } = self.templates;
+
+        if rendered + cached > 0 {
+            let _ = writeln!(out);
+            let _ = writeln!(
+                out,
+                "templates: {} rendered, {} from cache",
+                rendered, cached
+            );
+        }
+
+        out
+    }
+
+    /// The summary as JSON, for reporting the result of a run to other tools.
+    pub fn to_json(&self) -> Value {
+        let counts = |c: &Counts| {
+            json!({
+                "changed": c.changed,
+                "unchanged": c.unchanged,
+                "skipped": c.skipped,
+                "failed": c.failed,
+            })
+        };
+
+        let durations = self.system_durations();
+
+        let systems = self
+            .systems
+            .iter()
+            .map(|(system, c)| {
+                let duration = durations
+                    .get(&system.as_deref())
+                    .copied()
+                    .unwrap_or_default();
+
+                json!({
+                    "system": system,
+                    "counts": counts(c),
+                    "duration": duration.as_secs_f64(),
+                })
+            })
+            .collect::<Vec<_>>();
+
+        let units = self
+            .timings
+            .iter()
+            .map(|t| {
+                json!({
+                    "system": t.system,
+                    "unit": t.unit,
+                    "duration": t.duration.as_secs_f64(),
+                })
+            })
+            .collect::<Vec<_>>();
+
+        let commands = self
+            .commands
+            .iter()
+            .map(|c| {
+                json!({
+                    "program": c.program,
+                    "args": c.args,
+                    "working_directory": c.working_directory,
+                    "env": c.env,
+                    "status": c.status,
+                    "duration": c.duration.as_secs_f64(),
+                })
+            })
+            .collect::<Vec<_>>();
+
+        json!({
+            "total": counts(&self.total()),
+            "systems": systems,
+            "units": units,
+            "commands": commands,
+        })
+    }
+
+    fn counts(&mut self, system: Option<&str>) -> &mut Counts {
+        self.systems.entry(system.map(String::from)).or_default()
+    }
+}
+
+/// Format a duration with millisecond precision, like `1.250s`.
+fn format_duration(duration: Duration) -> String {
+    format!("{}.{:03}s", duration.as_secs(), duration.subsec_millis())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{RenderStats, Summary};
+    use crate::{command::Traced, unit::Outcome};
+    use std::collections::BTreeMap;
+    use std::path::PathBuf;
+    use std::time::Duration;
+
+    #[test]
+    fn test_render() {
+        let mut summary = Summary::default();
+        summary.record(Some("dotfiles"), Outcome::Changed);
+        summary.record(Some("dotfiles"), Outcome::Unchanged);
+        summary.record(None, Outcome::Skipped);
+        summary.record_failed(Some("dotfiles"));
+
+        assert_eq!(summary.total().total(), 4);
+        assert_eq!(summary.failed_systems(), vec!["dotfiles"]);
+
+        assert_eq!(
+            summary.render(),
+            "system    changed  unchanged  skipped  failed\n\
+             -               0          0        1       0\n\
+             dotfiles        1          1        0       1\n\
+             total           1          1        1       1\n"
+        );
+    }
+
+    #[test]
+    fn test_render_timings() {
+        let mut summary = Summary::default();
+        let ms = Duration::from_millis;
+        summary.record_duration(Some("packages"), String::from("install vim"), ms(2500));
+        summary.record_duration(Some("dotfiles"), String::from("copy a"), ms(10));
+        summary.record_duration(Some("dotfiles"), String::from("copy b"), ms(20));
+
+        let mut average = BTreeMap::new();
+        average.insert(String::from("packages"), ms(250));
+
+        let expected = [
+            "system          time     average",
+            "packages      2.500s      0.250s",
+            "dotfiles      0.030s           -",
+            "",
+            "      time  unit",
+            "    2.500s  packages: install vim",
+            "    0.020s  dotfiles: copy b",
+            "    0.010s  dotfiles: copy a",
+        ];
+
+        assert_eq!(
+            summary.render_timings(&average).lines().collect::<Vec<_>>(),
+            expected
+        );
+
+        summary.record_templates(RenderStats {
+            rendered: 2,
+            cached: 5,
+        });
+
+        assert_eq!(
+            summary.render_timings(&average).lines().last(),
+            Some("templates: 2 rendered, 5 from cache")
+        );
+
+        summary.record(Some("packages"), Outcome::Changed);
+
+        summary.record_commands(vec![Traced {
+            program: PathBuf::from("apt"),
+            args: vec![String::from("install"), String::from("vim")],
+            working_directory: None,
+            env: vec![String::from("DEBIAN_FRONTEND")],
+            status: Some(0),
+            duration: Duration::from_millis(500),
+        }]);
+
+        let json = summary.to_json();
+        assert_eq!(json["total"]["changed"], 1);
+        assert_eq!(json["systems"][0]["system"], "packages");
+        assert_eq!(json["systems"][0]["duration"], 2.5);
+        assert_eq!(json["units"][0]["unit"], "install vim");
+        assert_eq!(json["commands"][0]["program"], "apt");
+        assert_eq!(json["commands"][0]["args"][1], "vim");
+        assert_eq!(json["commands"][0]["status"], 0);
+    }
+}
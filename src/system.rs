@@ -1,37 +1,70 @@
 //! Things to do.
 
 use crate::{
-    environment as e, git, packages, state::State, Data, Facts, FileSystem, Opts, SystemUnit,
-    Timestamp, UnitAllocator, UnitId,
+    environment as e, git, packages,
+    state::State,
+    template::{Context, Scope},
+    Data, Facts, FileSystem, Opts, SystemUnit, Template, Timestamp, UnitAllocator, UnitId,
 };
 use anyhow::Error;
 use directories::BaseDirs;
+use relative_path::RelativePathBuf;
 use serde::Deserialize;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::fmt;
 use std::path::Path;
+use std::time::Duration;
+
+/// Default delay before retrying a unit which failed.
+const DEFAULT_RETRY_DELAY_SECONDS: u64 = 5;
 
 #[macro_use]
 mod macros;
+mod aliases;
+mod browser;
 mod copy_dir;
+mod credentials;
+mod dconf_load;
 mod download;
 mod download_and_run;
+mod env;
+mod file;
 mod from_db;
+mod git_hooks;
 mod git_sync;
+mod hosts;
 mod install;
 mod link;
 mod link_dir;
 mod only_for;
+mod path;
+mod plugin;
+mod self_update;
+mod toolchain;
+mod wasm;
 
+use self::aliases::Aliases;
+use self::browser::Browser;
 use self::copy_dir::CopyDir;
+use self::credentials::Credentials;
+use self::dconf_load::DconfLoad;
 use self::download::Download;
 use self::download_and_run::DownloadAndRun;
+use self::env::Env;
+use self::file::File;
 use self::from_db::FromDb;
+use self::git_hooks::GitHooks;
 use self::git_sync::GitSync;
+use self::hosts::Hosts;
 use self::install::Install;
 use self::link::Link;
 use self::link_dir::LinkDir;
 use self::only_for::OnlyFor;
+use self::path::AddPath;
+use self::plugin::Plugin;
+use self::self_update::SelfUpdate;
+use self::toolchain::Toolchain;
+use self::wasm::Wasm;
 
 /// What should happen after a system has been translated.
 pub enum Translation<'a> {
@@ -46,9 +79,19 @@ pub enum Translation<'a> {
 macro_rules! system_impl {
     ($($name:ident,)*) => {
         impl System {
-            pub fn translate(&self) -> Translation<'_> {
+            pub fn translate(&self, facts: &Facts) -> Translation<'_> {
                 use self::System::*;
 
+                // NB: systems for other operating systems are skipped entirely.
+                if !self.os().matches(std::env::consts::OS) {
+                    return Translation::Discard;
+                }
+
+                if let Some(condition) = skipped_by(self.skip_on(), facts) {
+                    log::info!("Skipping system `{}` on `{}`", self, condition);
+                    return Translation::Discard;
+                }
+
                 match self {
                     $($name(system) => system.translate(),)*
                 }
@@ -63,6 +106,15 @@ macro_rules! system_impl {
                 }
             }
 
+            /// Get the human-readable name of this system.
+            pub fn name(&self) -> Option<&str> {
+                use self::System::*;
+
+                match self {
+                    $($name(system) => system.name(),)*
+                }
+            }
+
             /// Get all things that this system depends on.
             pub fn requires(&self) -> &[String] {
                 use self::System::*;
@@ -72,21 +124,211 @@ macro_rules! system_impl {
                 }
             }
 
+            /// How many times to retry units of this system which fail.
+            pub fn retries(&self) -> u32 {
+                use self::System::*;
+
+                match self {
+                    $($name(system) => system.retries(),)*
+                }
+            }
+
+            /// How long to wait before retrying a unit which failed.
+            pub fn retry_delay(&self) -> Duration {
+                use self::System::*;
+
+                match self {
+                    $($name(system) => system.retry_delay(),)*
+                }
+            }
+
+            /// How long each unit of this system may run before it fails.
+            pub fn timeout(&self) -> Option<Duration> {
+                use self::System::*;
+
+                match self {
+                    $($name(system) => system.timeout(),)*
+                }
+            }
+
+            /// Whether units of this system must run on their own.
+            pub fn serial(&self) -> bool {
+                use self::System::*;
+
+                match self {
+                    $($name(system) => system.serial(),)*
+                }
+            }
+
+            /// Units of systems with a lower order are started first.
+            pub fn order(&self) -> i32 {
+                use self::System::*;
+
+                match self {
+                    $($name(system) => system.order(),)*
+                }
+            }
+
+            /// Environment variables to set for every command that this system spawns.
+            pub fn env(&self) -> &BTreeMap<String, Template> {
+                use self::System::*;
+
+                match self {
+                    $($name(system) => system.env(),)*
+                }
+            }
+
+            /// Operating systems that this system applies to.
+            pub fn os(&self) -> &Platforms {
+                use self::System::*;
+
+                match self {
+                    $($name(system) => system.os(),)*
+                }
+            }
+
+            /// Conditions under which this system is skipped.
+            pub fn skip_on(&self) -> &[String] {
+                use self::System::*;
+
+                match self {
+                    $($name(system) => system.skip_on(),)*
+                }
+            }
+
+            /// Whether this system should never run again after it has applied successfully once.
+            pub fn once(&self) -> bool {
+                use self::System::*;
+
+                match self {
+                    $($name(system) => system.once(),)*
+                }
+            }
+
+            /// The state id which marks that this system has applied once, if it should only
+            /// apply once.
+            pub fn once_id(&self) -> Result<Option<String>, Error> {
+                if !self.once() {
+                    return Ok(None);
+                }
+
+                match self.id() {
+                    Some(id) => Ok(Some(format!("system:{}", id))),
+                    None => anyhow::bail!("`once` requires the system to have an `id`"),
+                }
+            }
+
+            /// Files or directories which this system only applies again after they have changed.
+            pub fn depends(&self) -> &[RelativePathBuf] {
+                use self::System::*;
+
+                match self {
+                    $($name(system) => system.depends(),)*
+                }
+            }
+
+            /// The state id and the current hash of the files which this system depends on, if it
+            /// depends on any.
+            pub fn depends_hash(&self, root: &Path) -> Result<Option<(String, String)>, Error> {
+                use anyhow::{Context as _, anyhow};
+
+                if self.depends().is_empty() {
+                    return Ok(None);
+                }
+
+                let id = match self.id() {
+                    Some(id) => format!("depends:{}", id),
+                    None => anyhow::bail!("`depends` requires the system to have an `id`"),
+                };
+
+                let hash = hash_files(root, self.depends())
+                    .with_context(|| anyhow!("Failed to hash dependencies of system: {}", self))?;
+
+                Ok(Some((id, hash)))
+            }
+
+            /// Hierarchy key with a list, where this system is applied once for each item in it.
+            pub fn for_each(&self) -> Option<&str> {
+                use self::System::*;
+
+                match self {
+                    $($name(system) => system.for_each(),)*
+                }
+            }
+
+            /// Commands to run before the units of this system.
+            pub fn pre(&self) -> &[String] {
+                use self::System::*;
+
+                match self {
+                    $($name(system) => system.pre(),)*
+                }
+            }
+
+            /// Commands to run after all units of this system succeeded.
+            pub fn post(&self) -> &[String] {
+                use self::System::*;
+
+                match self {
+                    $($name(system) => system.post(),)*
+                }
+            }
+
             /// Apply changes for this system.
             #[allow(unused)]
             pub fn apply<E>(&self, input: $crate::system::SystemInput<E>)
                 -> Result<Vec<$crate::system::SystemUnit>, Error>
             where
-                E: Copy + $crate::environment::Environment,
+                E: Copy + $crate::environment::Environment + Sync,
             {
                 use anyhow::{Context as _, anyhow};
                 use self::System::*;
 
-                let res = match self {
-                    $($name(system) => system.apply(input),)*
-                };
+                let res = self.once_id().and_then(|once| {
+                    if let Some(once) = once {
+                        if input.state.has_run_once(&once) {
+                            log::trace!("Already applied once: {}", self);
+                            return Ok(Vec::new());
+                        }
+                    }
+
+                    let env = self.render_env(&input)?;
+
+                    let apply = |input| crate::command::with_env(&env, || match self {
+                        $($name(system) => system.apply(input),)*
+                    });
+
+                    let mut units = match self.for_each() {
+                        Some(key) => {
+                            let items = input.data.load_array::<serde_json::Value>(key)?;
+                            let mut units = Vec::new();
+
+                            for item in &items {
+                                let input = SystemInput { item: Some(item), ..input };
+                                let item = std::sync::Arc::new(item.clone());
+
+                                for mut unit in apply(input)? {
+                                    unit.item = Some(item.clone());
+                                    units.push(unit);
+                                }
+                            }
+
+                            units
+                        }
+                        None => apply(input)?,
+                    };
+
+                    for unit in &mut units {
+                        unit.env = env.clone();
+                    }
+
+                    Ok(units)
+                });
 
-                Ok(res.with_context(|| anyhow!("Failed to run system: {:?}", self))?)
+                res.with_context(|| match self.name() {
+                    Some(name) => anyhow!("Failed to run system `{}`", name),
+                    None => anyhow!("Failed to run system: {:?}", self),
+                })
             }
         }
 
@@ -95,7 +337,9 @@ macro_rules! system_impl {
                 match *self {
                     $(
                     System::$name(ref system) => {
-                        if let Some(id) = system.id() {
+                        if let Some(name) = system.name() {
+                            name.fmt(fmt)
+                        } else if let Some(id) = system.id() {
                             write!(fmt, "{}: {}", id, system)
                         } else {
                             system.fmt(fmt)
@@ -129,6 +373,32 @@ pub enum System {
     OnlyFor(OnlyFor),
     #[serde(rename = "from-db")]
     FromDb(FromDb),
+    #[serde(rename = "plugin")]
+    Plugin(Plugin),
+    #[serde(rename = "wasm")]
+    Wasm(Wasm),
+    #[serde(rename = "env")]
+    Env(Env),
+    #[serde(rename = "path")]
+    AddPath(AddPath),
+    #[serde(rename = "aliases")]
+    Aliases(Aliases),
+    #[serde(rename = "self-update")]
+    SelfUpdate(SelfUpdate),
+    #[serde(rename = "git-hooks")]
+    GitHooks(GitHooks),
+    #[serde(rename = "toolchain")]
+    Toolchain(Toolchain),
+    #[serde(rename = "file")]
+    File(File),
+    #[serde(rename = "dconf-load")]
+    DconfLoad(DconfLoad),
+    #[serde(rename = "browser")]
+    Browser(Browser),
+    #[serde(rename = "credentials")]
+    Credentials(Credentials),
+    #[serde(rename = "hosts")]
+    Hosts(Hosts),
 }
 
 system_impl![
@@ -141,8 +411,250 @@ system_impl![
     GitSync,
     OnlyFor,
     FromDb,
+    Plugin,
+    Wasm,
+    Env,
+    AddPath,
+    Aliases,
+    SelfUpdate,
+    GitHooks,
+    Toolchain,
+    File,
+    DconfLoad,
+    Browser,
+    Credentials,
+    Hosts,
 ];
 
+impl System {
+    /// Render the environment variables of this system.
+    fn render_env<E>(&self, input: &SystemInput<E>) -> Result<Vec<(String, String)>, Error>
+    where
+        E: Copy + e::Environment,
+    {
+        let mut env = Vec::new();
+
+        for (key, value) in self.env() {
+            let value = value
+                .as_string(input.vars(), input.environment)?
+                .ok_or_else(|| {
+                    anyhow::anyhow!("Failed to render environment variable `{}`", key)
+                })?;
+
+            env.push((key.clone(), value));
+        }
+
+        Ok(env)
+    }
+}
+
+/// Hash the names and contents of the given files and directories in the configuration, so that
+/// adding, removing, or changing any file in them changes the hash.
+fn hash_files(root: &Path, paths: &[RelativePathBuf]) -> Result<String, Error> {
+    use sha2::{Digest as _, Sha256};
+    use std::fs;
+    use std::io;
+
+    let mut hasher = Sha256::new();
+    let mut queue = paths
+        .iter()
+        .rev()
+        .map(|p| p.to_path(root))
+        .collect::<Vec<_>>();
+
+    while let Some(path) = queue.pop() {
+        let name = path.strip_prefix(root).unwrap_or(&path);
+        hasher.update(name.to_string_lossy().as_bytes());
+
+        let meta = match fs::metadata(&path) {
+            Ok(meta) => meta,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                hasher.update(b"\0missing\0");
+                continue;
+            }
+            Err(e) => return Err(Error::from(e).context(path.display().to_string())),
+        };
+
+        if meta.is_dir() {
+            hasher.update(b"\0dir\0");
+
+            let mut entries = fs::read_dir(&path)?
+                .map(|e| e.map(|e| e.path()))
+                .collect::<Result<Vec<_>, _>>()?;
+
+            // NB: the queue is popped from the back.
+            entries.sort_by(|a, b| b.cmp(a));
+            queue.extend(entries);
+        } else {
+            hasher.update(b"\0file\0");
+            hasher.update(meta.len().to_le_bytes());
+            io::copy(&mut fs::File::open(&path)?, &mut hasher)?;
+        }
+    }
+
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// Validate that every id in `requires` refers to a declared system.
+///
+/// Systems nested in `only-for` are included regardless of whether they apply to the current
+/// operating system, so that the configuration is valid everywhere.
+pub fn validate_requires<'a>(systems: impl IntoIterator<Item = &'a System>) -> Result<(), Error> {
+    let mut all = Vec::new();
+    let mut queue = systems.into_iter().collect::<Vec<_>>();
+
+    while let Some(system) = queue.pop() {
+        if let System::OnlyFor(only_for) = system {
+            queue.extend(&only_for.systems);
+        }
+
+        all.push(system);
+    }
+
+    let ids = all.iter().flat_map(|s| s.id()).collect::<HashSet<_>>();
+
+    // NB: report in declaration order.
+    for system in all.iter().rev() {
+        for id in system.requires() {
+            if !ids.contains(id.as_str()) {
+                anyhow::bail!("System `{}` requires unknown system id `{}`", system, id);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Find a cycle in the `requires` of the given systems, returning the ids of the systems which
+/// are part of it, starting and ending with the same id.
+pub fn find_cycle<'a>(systems: impl IntoIterator<Item = &'a System>) -> Option<Vec<&'a str>> {
+    let mut requires = BTreeMap::<&str, Vec<&str>>::new();
+
+    for system in systems {
+        if let Some(id) = system.id() {
+            requires
+                .entry(id)
+                .or_default()
+                .extend(system.requires().iter().map(String::as_str));
+        }
+    }
+
+    let mut done = HashSet::new();
+
+    for &start in requires.keys() {
+        // NB: the current path, with the index of the next requirement to visit for each system.
+        let mut path = vec![(start, 0)];
+
+        while let Some((id, next)) = path.last_mut() {
+            let required = requires.get(*id).and_then(|r| r.get(*next)).copied();
+            *next += 1;
+
+            let required = match required {
+                Some(required) => required,
+                None => {
+                    done.insert(*id);
+                    path.pop();
+                    continue;
+                }
+            };
+
+            if let Some(n) = path.iter().position(|(id, _)| *id == required) {
+                let mut cycle = path[n..].iter().map(|(id, _)| *id).collect::<Vec<_>>();
+                cycle.push(required);
+                return Some(cycle);
+            }
+
+            if !done.contains(required) {
+                path.push((required, 0));
+            }
+        }
+    }
+
+    None
+}
+
+/// Operating systems that a system applies to, like `linux` or `[linux, macos]`.
+///
+/// `unix` matches both `linux` and `macos`. No operating systems at all matches every one of them.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Platforms(Vec<String>);
+
+impl Platforms {
+    /// Test if the given operating system, as in `std::env::consts::OS`, matches.
+    pub fn matches(&self, os: &str) -> bool {
+        if self.0.is_empty() {
+            return true;
+        }
+
+        self.0.iter().any(|p| match p.as_str() {
+            "unix" => os == "linux" || os == "macos",
+            p => p == os,
+        })
+    }
+}
+
+impl<'de> Deserialize<'de> for Platforms {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Repr {
+            One(String),
+            Many(Vec<String>),
+        }
+
+        Ok(match Repr::deserialize(deserializer)? {
+            Repr::One(os) => Platforms(vec![os]),
+            Repr::Many(os) => Platforms(os),
+        })
+    }
+}
+
+impl fmt::Display for Platforms {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        if self.0.is_empty() {
+            return write!(fmt, "any");
+        }
+
+        write!(fmt, "{}", self.0.join(", "))
+    }
+}
+
+/// Find the condition in `skip_on` which matches the given facts, if any.
+///
+/// Conditions match the `os`, `distro`, `distro_id` or `distro_family` facts, like `nixos` or
+/// `debian`. `wsl`, `container` and `ci` match when running in one.
+fn skipped_by<'a>(conditions: &'a [String], facts: &Facts) -> Option<&'a str> {
+    use crate::facts;
+
+    let values = [
+        facts::OS,
+        facts::DISTRO,
+        facts::DISTRO_ID,
+        facts::DISTRO_FAMILY,
+    ]
+    .iter()
+    .flat_map(|&key| facts.get(key))
+    .collect::<Vec<_>>();
+
+    conditions
+        .iter()
+        .map(String::as_str)
+        .find(|&condition| match condition {
+            "wsl" => facts.get(facts::WSL).is_some(),
+            "container" => facts.get(facts::CONTAINER).is_some(),
+            "ci" => facts.get(facts::CI).is_some(),
+            condition => values.contains(&condition),
+        })
+}
+
+/// Return the default delay before retrying a unit which failed.
+pub(crate) fn default_retry_delay() -> Duration {
+    Duration::from_secs(DEFAULT_RETRY_DELAY_SECONDS)
+}
+
 /// All inputs for a system.
 #[derive(Clone, Copy)]
 pub struct SystemInput<'a, 'f, E>
@@ -155,6 +667,8 @@ where
     pub base_dirs: Option<&'a BaseDirs>,
     /// Set of facts.
     pub facts: &'a Facts,
+    /// User-defined vars from the configuration.
+    pub vars: &'a BTreeMap<String, String>,
     /// Data loaded from hierarchy.
     pub data: &'a Data,
     /// Source of environment variables.
@@ -173,24 +687,40 @@ where
     pub opts: &'a Opts,
     /// The current git system.
     pub git_system: &'a dyn git::GitSystem,
+    /// The current item of a system with `for_each`.
+    pub item: Option<&'a serde_json::Value>,
+    /// The serialized facts, vars and data used when rendering templates.
+    pub context: &'a Context,
+}
+
+impl<'a, E> SystemInput<'a, '_, E>
+where
+    E: e::Environment,
+{
+    /// Variables to use when rendering templates.
+    pub fn vars(&self) -> Scope<'a> {
+        Scope {
+            facts: self.facts,
+            vars: self.vars,
+            data: self.data,
+            item: self.item,
+            context: self.context,
+        }
+    }
 }
 
 /// Helper structure used to resolve dependencies.
+#[derive(Default)]
 pub enum Dependency<'a> {
     /// Transitive dependency, where we have to look up other systems to fully resolve.
     Transitive(&'a [String]),
     /// Direct dependency to another unit.
     Direct(UnitId),
     /// No dependencies.
+    #[default]
     None,
 }
 
-impl Default for Dependency<'_> {
-    fn default() -> Self {
-        Dependency::None
-    }
-}
-
 impl<'a> Dependency<'a> {
     /// Resolve all unit dependencies for the current dependency.
     pub fn resolve(
@@ -219,3 +749,156 @@ impl<'a> Dependency<'a> {
         ids
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{find_cycle, skipped_by, validate_requires, Platforms, System, Translation};
+    use crate::facts::Facts;
+
+    fn systems(yaml: &str) -> Vec<System> {
+        serde_yaml::from_str(yaml).unwrap()
+    }
+
+    #[test]
+    fn test_find_cycle() {
+        let ok = systems(
+            "- {type: install, id: a, requires: [b, c]}\n\
+             - {type: install, id: b, requires: [c]}\n\
+             - {type: install, id: c}\n\
+             - {type: install, requires: [a]}\n",
+        );
+
+        assert_eq!(find_cycle(&ok), None);
+
+        let cycle = systems(
+            "- {type: install, id: a, requires: [b]}\n\
+             - {type: install, id: b, requires: [c]}\n\
+             - {type: install, id: c, requires: [a]}\n",
+        );
+
+        assert_eq!(find_cycle(&cycle), Some(vec!["a", "b", "c", "a"]));
+
+        let own = systems("- {type: install, id: a, requires: [a]}\n");
+        assert_eq!(find_cycle(&own), Some(vec!["a", "a"]));
+    }
+
+    #[test]
+    fn test_display_name() {
+        let named = systems("- {type: install, id: a, name: work laptop packages}\n");
+        assert_eq!(named[0].to_string(), "work laptop packages");
+
+        let unnamed = systems("- {type: install, id: a}\n");
+        assert!(unnamed[0].to_string().starts_with("a: "));
+    }
+
+    #[test]
+    fn test_validate_requires() {
+        let ok = systems(
+            "- {type: install, id: a, requires: [b]}\n\
+             - {type: only-for, os: none, systems: [{type: install, id: b}]}\n",
+        );
+
+        assert!(validate_requires(&ok).is_ok());
+
+        let unknown = systems(
+            "- {type: install, id: a}\n\
+             - {type: only-for, systems: [{type: install, id: b, requires: [a, c]}]}\n",
+        );
+
+        let e = validate_requires(&unknown).unwrap_err();
+        assert!(e.to_string().contains("requires unknown system id `c`"));
+        assert!(e.to_string().starts_with("System `b: "));
+    }
+
+    #[test]
+    fn test_once_id() {
+        let once = systems(
+            "- {type: install, id: gpg, once: true}\n\
+             - {type: install, id: gpg}\n\
+             - {type: install, once: true}\n",
+        );
+
+        assert_eq!(once[0].once_id().unwrap(), Some(String::from("system:gpg")));
+        assert_eq!(once[1].once_id().unwrap(), None);
+        assert!(once[2].once_id().is_err());
+    }
+
+    #[test]
+    fn test_depends_hash() {
+        let root = std::env::temp_dir().join(format!("quickcfg-depends-{}", std::process::id()));
+        let themes = root.join("themes");
+        std::fs::create_dir_all(&themes).unwrap();
+        std::fs::write(themes.join("a.tmTheme"), "a").unwrap();
+
+        let depends = systems(
+            "- {type: install, id: bat, depends: [themes]}\n\
+             - {type: install, depends: [themes]}\n",
+        );
+
+        let hash = || depends[0].depends_hash(&root).unwrap().unwrap();
+        let (id, first) = hash();
+        assert_eq!(id, "depends:bat");
+        assert_eq!(hash().1, first);
+
+        std::fs::write(themes.join("b.tmTheme"), "b").unwrap();
+        let second = hash().1;
+        assert_ne!(second, first);
+
+        std::fs::write(themes.join("b.tmTheme"), "c").unwrap();
+        assert_ne!(hash().1, second);
+
+        assert!(depends[1].depends_hash(&root).is_err());
+        assert_eq!(
+            systems("- {type: install}")[0].depends_hash(&root).unwrap(),
+            None
+        );
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_platforms() {
+        let platforms = |yaml: &str| serde_yaml::from_str::<Platforms>(yaml).unwrap();
+
+        assert!(Platforms::default().matches("windows"));
+        assert!(platforms("linux").matches("linux"));
+        assert!(!platforms("linux").matches("windows"));
+        assert!(platforms("[windows, macos]").matches("macos"));
+        assert!(platforms("unix").matches("macos"));
+        assert!(!platforms("unix").matches("windows"));
+
+        let facts = Facts::new(Vec::new());
+        let skipped = systems("- {type: install, os: [none]}\n- {type: install, platform: none}\n");
+
+        for system in &skipped {
+            assert!(matches!(system.translate(&facts), Translation::Discard));
+        }
+
+        let kept = systems(&format!(
+            "- {{type: install, os: {}}}\n",
+            std::env::consts::OS
+        ));
+        assert!(matches!(kept[0].translate(&facts), Translation::Keep));
+    }
+
+    #[test]
+    fn test_skip_on() {
+        let facts = Facts::new(vec![
+            (String::from("distro_id"), String::from("nixos")),
+            (String::from("distro_family"), String::from("nixos")),
+            (String::from("wsl"), String::from("2")),
+        ]);
+
+        let skip_on = |conditions: &[&str]| {
+            let conditions = conditions.iter().map(|c| c.to_string()).collect::<Vec<_>>();
+            skipped_by(&conditions, &facts).map(String::from)
+        };
+
+        assert_eq!(skip_on(&["debian", "nixos"]), Some(String::from("nixos")));
+        assert_eq!(skip_on(&["wsl"]), Some(String::from("wsl")));
+        assert_eq!(skip_on(&["container", "debian"]), None);
+
+        let skipped = systems("- {type: install, skip_on: [nixos]}\n");
+        assert!(matches!(skipped[0].translate(&facts), Translation::Discard));
+    }
+}
@@ -0,0 +1,158 @@
+use crate::{
+    environment as e,
+    system::{
+        env::{default_dir, default_shells, write_files, Shell},
+        SystemInput,
+    },
+    template::Template,
+    unit::SystemUnit,
+};
+use anyhow::{anyhow, bail, Error};
+use std::collections::BTreeMap;
+use std::fmt;
+
+system_struct! {
+    #[doc = "Generates a file for each shell which defines aliases from the hierarchy."]
+    Aliases {
+        #[doc="Hierarchy key with the aliases to define."]
+        #[serde(default = "default_key")]
+        pub key: String,
+        #[doc="Directory to write the files to."]
+        #[serde(default = "default_dir")]
+        pub dir: Template,
+        #[doc="Shells to generate files for."]
+        #[serde(default = "default_shells")]
+        pub shells: Vec<Shell>,
+    }
+}
+
+fn default_key() -> String {
+    String::from("aliases")
+}
+
+impl Aliases {
+    system_defaults!(translate);
+
+    /// Write a file for each shell, if its content has changed.
+    pub fn apply<E>(&self, input: SystemInput<E>) -> Result<Vec<SystemUnit>, Error>
+    where
+        E: Copy + e::Environment,
+    {
+        let vars = input.vars();
+
+        let SystemInput {
+            root,
+            base_dirs,
+            data,
+            environment,
+            ..
+        } = input;
+
+        let dir = self
+            .dir
+            .as_path(root, base_dirs, vars, environment)?
+            .ok_or_else(|| anyhow!("Cannot render `dir`: {}", self.dir))?;
+
+        let aliases = data
+            .load::<BTreeMap<String, String>>(&self.key)?
+            .unwrap_or_default();
+
+        for name in aliases.keys() {
+            if !is_valid_name(name) {
+                bail!("`{}` in `{}` is not a valid alias name", name, self.key);
+            }
+        }
+
+        write_files(input, &dir, "aliases", &self.shells, |shell| {
+            render_file(shell, &aliases)
+        })
+    }
+}
+
+impl fmt::Display for Aliases {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(fmt, "aliases from `{}` to {}", self.key, self.dir)
+    }
+}
+
+/// Generate the content of the file which defines the given aliases.
+///
+/// PowerShell aliases can't have arguments, so functions are defined instead, which take
+/// precedence over built-in aliases with the same name once those are removed.
+fn render_file(shell: Shell, aliases: &BTreeMap<String, String>) -> String {
+    let mut out = shell.header();
+
+    for (name, command) in aliases {
+        let line = match shell {
+            Shell::Sh => format!("alias {}={}", name, shell.quote(command)),
+            Shell::Fish => format!("alias {} {}", name, shell.quote(command)),
+            Shell::Powershell => format!(
+                "Remove-Item -Path Alias:{name} -Force -ErrorAction SilentlyContinue\nfunction {name} {{ {command} @args }}",
+                name = name,
+                command = command
+            ),
+        };
+
+        out.push_str(&line);
+        out.push('\n');
+    }
+
+    out
+}
+
+/// Test if the given name is a valid name of an alias in every shell.
+fn is_valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && !name.starts_with('-')
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.'))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{is_valid_name, render_file, Shell};
+    use std::collections::BTreeMap;
+
+    #[test]
+    fn test_render_file() {
+        let mut aliases = BTreeMap::new();
+        aliases.insert(String::from("gs"), String::from("git status"));
+        aliases.insert(String::from("say"), String::from("echo 'hi'"));
+
+        let lines = |shell: Shell| {
+            render_file(shell, &aliases)
+                .lines()
+                .skip(1)
+                .map(String::from)
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            lines(Shell::Sh),
+            vec!["alias gs='git status'", "alias say='echo '\\''hi'\\'''"]
+        );
+
+        assert_eq!(
+            lines(Shell::Fish),
+            vec!["alias gs 'git status'", "alias say 'echo \\'hi\\''"]
+        );
+
+        assert_eq!(
+            lines(Shell::Powershell)[..2],
+            [
+                "Remove-Item -Path Alias:gs -Force -ErrorAction SilentlyContinue",
+                "function gs { git status @args }",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_is_valid_name() {
+        assert!(is_valid_name("ll"));
+        assert!(is_valid_name("git-up"));
+        assert!(!is_valid_name("-x"));
+        assert!(!is_valid_name("rm -rf"));
+        assert!(!is_valid_name(""));
+    }
+}
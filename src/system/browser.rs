@@ -0,0 +1,292 @@
+use crate::{
+    environment as e,
+    system::SystemInput,
+    template::Template,
+    unit::{SystemUnit, WriteSystemFile},
+    FileSystem,
+};
+use anyhow::{anyhow, Context as _, Error};
+use relative_path::RelativePath;
+use serde::Deserialize;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Name of the policy file written for Chrome and Chromium, next to policies from other sources.
+const CHROME_POLICY: &str = "quickcfg.json";
+
+system_struct! {
+    #[doc = "Deploys preferences and policies of browsers to where each browser looks for them."]
+    Browser {
+        #[serde(default)]
+        #[doc="Preferences and policies of Firefox."]
+        pub firefox: Option<Firefox>,
+        #[serde(default)]
+        #[doc="Policies of Google Chrome."]
+        pub chrome: Option<Chrome>,
+        #[serde(default)]
+        #[doc="Policies of Chromium."]
+        pub chromium: Option<Chrome>,
+    }
+}
+
+/// Preferences and policies of Firefox.
+#[derive(Deserialize, Debug, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct Firefox {
+    /// File to copy to `user.js` in every profile.
+    #[serde(default)]
+    pub user_js: Option<Template>,
+    /// File to install as `policies.json`.
+    #[serde(default)]
+    pub policies: Option<Template>,
+}
+
+/// Policies of a Chromium-based browser.
+#[derive(Deserialize, Debug, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct Chrome {
+    /// File to install as a managed policy.
+    pub policies: Template,
+}
+
+impl Browser {
+    system_defaults!(translate);
+
+    /// Copy `user.js` into every Firefox profile, and write policies which have changed.
+    pub fn apply<E>(&self, input: SystemInput<E>) -> Result<Vec<SystemUnit>, Error>
+    where
+        E: Copy + e::Environment,
+    {
+        let vars = input.vars();
+
+        let SystemInput {
+            root,
+            base_dirs,
+            environment,
+            file_system,
+            allocator,
+            state,
+            ..
+        } = input;
+
+        let home = match environment.home_dir() {
+            Some(home) => home.to_owned(),
+            None => base_dirs
+                .ok_or_else(|| anyhow!("Base dirs are required for home directory"))?
+                .home_dir()
+                .to_owned(),
+        };
+
+        let mut units = Vec::new();
+
+        let mut policy = |path: PathBuf, from: &Template| -> Result<(), Error> {
+            let from = match from.as_path(root, base_dirs, vars, environment)? {
+                Some(from) => from,
+                None => return Ok(()),
+            };
+
+            let content = fs::read(&from)
+                .with_context(|| anyhow!("failed to read policies: {}", from.display()))?;
+
+            // NB: browsers silently ignore policies which they can't parse.
+            serde_json::from_slice::<serde_json::Value>(&content)
+                .with_context(|| anyhow!("bad policies: {}", from.display()))?;
+
+            if FileSystem::try_open_meta(&path)?.is_some_and(|m| m.is_file())
+                && fs::read(&path)? == content
+            {
+                return Ok(());
+            }
+
+            units.push(allocator.unit(WriteSystemFile {
+                path,
+                content,
+                in_place: false,
+            }));
+            Ok(())
+        };
+
+        if let Some(firefox) = &self.firefox {
+            if let Some(policies) = &firefox.policies {
+                policy(firefox_policies(), policies)?;
+            }
+        }
+
+        for (name, chrome, dir) in [
+            ("Chrome", &self.chrome, "/etc/opt/chrome"),
+            ("Chromium", &self.chromium, "/etc/chromium"),
+        ] {
+            let chrome = match chrome {
+                Some(chrome) => chrome,
+                None => continue,
+            };
+
+            // NB: other platforms configure policies through the registry or configuration
+            // profiles.
+            if !cfg!(target_os = "linux") {
+                log::warn!("{} only reads policies from files on Linux", name);
+                continue;
+            }
+
+            let path = Path::new(dir).join("policies/managed").join(CHROME_POLICY);
+            policy(path, &chrome.policies)?;
+        }
+
+        let user_js = match self.firefox.as_ref().and_then(|f| f.user_js.as_ref()) {
+            Some(user_js) => user_js,
+            None => return Ok(units),
+        };
+
+        let from = match user_js.as_path(root, base_dirs, vars, environment)? {
+            Some(from) => from,
+            None => return Ok(units),
+        };
+
+        let from_meta = fs::metadata(&from)
+            .with_context(|| anyhow!("failed to read `user_js`: {}", from.display()))?;
+
+        let dir = firefox_dir(&home);
+
+        // NB: profiles only exist once Firefox has been started, so they get it on a later run.
+        let profiles = match fs::read_to_string(dir.join("profiles.ini")) {
+            Ok(content) => parse_profiles(&content, &dir),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                log::trace!("No Firefox profiles in: {}", dir.display());
+                return Ok(units);
+            }
+            Err(e) => return Err(e.into()),
+        };
+
+        for profile in profiles {
+            if !profile.is_dir() {
+                continue;
+            }
+
+            let to = profile.join("user.js");
+            let to_meta = FileSystem::try_open_meta(&to)?;
+
+            units.extend(file_system.copy_file(
+                &from,
+                from_meta.clone(),
+                &to,
+                to_meta.as_ref(),
+                false,
+                state,
+            )?);
+        }
+
+        Ok(units)
+    }
+}
+
+impl fmt::Display for Browser {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        let browsers = [
+            ("firefox", self.firefox.is_some()),
+            ("chrome", self.chrome.is_some()),
+            ("chromium", self.chromium.is_some()),
+        ];
+
+        let browsers = browsers
+            .iter()
+            .filter(|(_, enabled)| *enabled)
+            .map(|(name, _)| *name)
+            .collect::<Vec<_>>();
+
+        write!(fmt, "browser settings for {}", browsers.join(", "))
+    }
+}
+
+/// The directory where Firefox keeps `profiles.ini`.
+fn firefox_dir(home: &Path) -> PathBuf {
+    if cfg!(target_os = "macos") {
+        home.join("Library/Application Support/Firefox")
+    } else if cfg!(windows) {
+        home.join("AppData/Roaming/Mozilla/Firefox")
+    } else {
+        home.join(".mozilla/firefox")
+    }
+}
+
+/// Where Firefox reads `policies.json` from.
+fn firefox_policies() -> PathBuf {
+    if cfg!(target_os = "macos") {
+        PathBuf::from("/Applications/Firefox.app/Contents/Resources/distribution/policies.json")
+    } else if cfg!(windows) {
+        PathBuf::from(r"C:\Program Files\Mozilla Firefox\distribution\policies.json")
+    } else {
+        PathBuf::from("/etc/firefox/policies/policies.json")
+    }
+}
+
+/// Find the directories of all profiles in the given `profiles.ini`.
+fn parse_profiles(content: &str, dir: &Path) -> Vec<PathBuf> {
+    let mut profiles = Vec::new();
+    let mut current = None;
+
+    let mut finish = |current: Option<(Option<String>, bool)>| {
+        if let Some((Some(path), relative)) = current {
+            if relative {
+                profiles.push(RelativePath::new(&path).to_path(dir));
+            } else {
+                profiles.push(PathBuf::from(path));
+            }
+        }
+    };
+
+    for line in content.lines() {
+        let line = line.trim();
+
+        if let Some(section) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+            finish(current.take());
+
+            // NB: other sections, like `[Install...]`, point to profiles which are already listed.
+            if section.starts_with("Profile") {
+                current = Some((None, false));
+            }
+
+            continue;
+        }
+
+        let (path, relative) = match current.as_mut() {
+            Some(current) => current,
+            None => continue,
+        };
+
+        match line.split_once('=') {
+            Some(("Path", value)) => *path = Some(value.to_string()),
+            Some(("IsRelative", value)) => *relative = value == "1",
+            _ => (),
+        }
+    }
+
+    finish(current);
+    profiles
+}
+
+#[cfg(test)]
+mod tests {
+    use super::parse_profiles;
+    use std::path::{Path, PathBuf};
+
+    #[test]
+    fn test_parse_profiles() {
+        let content = "[Install4F96D1932A9F858E]\nDefault=Profiles/abc.default-release\n\n\
+                       [Profile1]\nName=default\nIsRelative=1\nPath=Profiles/xyz.default\n\n\
+                       [Profile0]\nName=default-release\nIsRelative=1\nPath=Profiles/abc.default-release\nDefault=1\n\n\
+                       [Profile2]\nName=work\nIsRelative=0\nPath=/data/firefox/work\n\n\
+                       [General]\nStartWithLastProfile=1\nVersion=2\n";
+
+        let dir = Path::new("/home/user/.mozilla/firefox");
+
+        assert_eq!(
+            parse_profiles(content, dir),
+            vec![
+                dir.join("Profiles").join("xyz.default"),
+                dir.join("Profiles").join("abc.default-release"),
+                PathBuf::from("/data/firefox/work"),
+            ]
+        );
+    }
+}
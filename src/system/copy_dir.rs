@@ -1,9 +1,17 @@
 use crate::{
-    environment as e, system::SystemInput, template::Template, unit::SystemUnit, FileSystem,
+    environment as e,
+    file_system::CaseCollisions,
+    system::SystemInput,
+    template::{Template, Vars},
+    unit::{Dependency, FileMode, SetMode, SystemUnit},
+    FileSystem,
 };
-use anyhow::{bail, Error};
+use anyhow::{anyhow, bail, Error};
+use relative_path::RelativePathBuf;
 use std::fmt;
 use std::fs;
+use std::path::Path;
+use std::sync::Mutex;
 
 system_struct! {
     #[doc = "Builds one unit for every directory and file that needs to be copied."]
@@ -13,8 +21,14 @@ system_struct! {
         #[doc="Where to copy to."]
         pub to: Template,
         #[serde(default)]
-        #[doc="If we should treat files as templates."]
+        #[doc="If we should treat files and their names as templates."]
         pub templates: bool,
+        #[serde(default)]
+        #[doc="Permissions to set on every copied file."]
+        pub mode: Option<FileMode>,
+        #[serde(default)]
+        #[doc="Only copy files which don't exist yet, and leave existing files alone."]
+        pub only_missing: bool,
     }
 }
 
@@ -24,44 +38,62 @@ impl CopyDir {
     /// Copy one directory to another.
     pub fn apply<E>(&self, input: SystemInput<E>) -> Result<Vec<SystemUnit>, Error>
     where
-        E: Copy + e::Environment,
+        E: Copy + e::Environment + Sync,
     {
+        let vars = input.vars();
+
         let SystemInput {
             root,
             base_dirs,
-            facts,
             environment,
             file_system,
+            state,
+            allocator,
             ..
         } = input;
 
-        let mut units = Vec::new();
-
-        let from = match self.from.as_path(root, base_dirs, facts, environment)? {
+        let from = match self.from.as_path(root, base_dirs, vars, environment)? {
             Some(from) => from,
-            None => return Ok(units),
+            None => return Ok(Vec::new()),
         };
 
         // resolve destination, if unspecified defaults to relative current directory.
-        let to = match self.to.as_path(root, base_dirs, facts, environment)? {
+        let to = match self.to.as_path(root, base_dirs, vars, environment)? {
             Some(to) => to,
-            None => return Ok(units),
+            None => return Ok(Vec::new()),
         };
 
-        for e in ignore::WalkBuilder::new(&from).hidden(false).build() {
-            let e = e?;
+        let collisions = Mutex::new(CaseCollisions::new());
+
+        FileSystem::walk_dir(&from, |e, units| {
             let from_path = e.path();
-            let to_path = to.join(from_path.strip_prefix(&from)?);
+            let relative = from_path.strip_prefix(&from)?;
+
+            collisions
+                .lock()
+                .map_err(|_| anyhow!("Lock poisoned"))?
+                .check(relative, from_path)?;
+
+            let to_path = if self.templates {
+                render_name(relative, vars, environment)?.to_path(&to)
+            } else {
+                to.join(relative)
+            };
 
             let from = from_path.symlink_metadata()?;
             let to = FileSystem::try_open_meta(&to_path)?;
 
             let source_type = from.file_type();
 
+            // NB: seed files are left for the user to modify once they exist.
+            if self.only_missing && to.is_some() && !source_type.is_dir() {
+                return Ok(());
+            }
+
             if source_type.is_symlink() {
                 let link = fs::read_link(from_path)?;
-                units.extend(file_system.symlink(&to_path, link, to.as_ref())?);
-                continue;
+                units.extend(file_system.symlink(&to_path, link, to.as_ref(), state)?);
+                return Ok(());
             }
 
             if source_type.is_dir() {
@@ -69,18 +101,34 @@ impl CopyDir {
                     units.extend(file_system.create_dir_all(&to_path)?);
                 }
 
-                continue;
+                return Ok(());
             }
 
             if source_type.is_file() {
-                units.extend(file_system.copy_file(
-                    &from_path,
+                let copy = file_system.copy_file(
+                    from_path,
                     from,
                     &to_path,
                     to.as_ref(),
                     self.templates,
-                )?);
-                continue;
+                    state,
+                )?;
+
+                if let Some(mode) = self.mode {
+                    let mut set_mode = allocator.unit(SetMode {
+                        path: to_path.clone(),
+                        mode,
+                    });
+
+                    if let Some(copy) = &copy {
+                        set_mode.dependencies.push(Dependency::Unit(copy.id));
+                    }
+
+                    units.push(set_mode);
+                }
+
+                units.extend(copy);
+                return Ok(());
             }
 
             bail!(
@@ -88,14 +136,93 @@ impl CopyDir {
                 from,
                 from_path.display()
             );
-        }
-
-        Ok(units)
+        })
     }
 }
 
+/// Render the name of a copied file, so that `for_each` can produce one file per item.
+fn render_name(
+    relative: &Path,
+    vars: impl Vars,
+    environment: impl e::Environment,
+) -> Result<RelativePathBuf, Error> {
+    let name = RelativePathBuf::from_path(relative)?;
+
+    Template::parse(name.as_str())?
+        .as_relative_path(vars, environment)?
+        .ok_or_else(|| anyhow!("Cannot render file name: {}", name))
+}
+
 impl fmt::Display for CopyDir {
     fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
         write!(fmt, "copy directory from `{}` to `{}`", self.from, self.to)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::CopyDir;
+    use crate::{
+        environment as e, git, opts::Opts, packages, system::SystemInput, template::Context,
+        unit::UnitAllocator, Config, Data, Facts, FileSystem, State, Timestamp,
+    };
+    use std::collections::BTreeMap;
+    use std::fs;
+
+    #[test]
+    fn test_only_missing() -> anyhow::Result<()> {
+        let root = std::env::temp_dir().join(format!("quickcfg-copy-dir-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(root.join("from"))?;
+        fs::create_dir_all(root.join("to"))?;
+        fs::write(root.join("from").join("existing"), b"new")?;
+        fs::write(root.join("from").join("missing"), b"new")?;
+        fs::write(root.join("to").join("existing"), b"old")?;
+
+        // NB: otherwise the existing file is skipped for being newer than its source.
+        fs::File::options()
+            .write(true)
+            .open(root.join("to").join("existing"))?
+            .set_modified(std::time::UNIX_EPOCH)?;
+
+        let copy_dir = serde_yaml::from_str::<CopyDir>("from: from\nto: to\nonly_missing: true")?;
+
+        let opts = Opts::default();
+        let config = Config::default();
+        let state = State::new(&config, Timestamp::now());
+        let facts = Facts::new(Vec::new());
+        let data = Data::new(None, vec![]);
+        let allocator = UnitAllocator::default();
+        let file_system = FileSystem::new(&opts, &root.join(".state"), &allocator, &data);
+        let git_system = git::setup(Default::default(), Default::default(), Default::default())?;
+
+        let units = copy_dir.apply(SystemInput {
+            root: &root,
+            base_dirs: None,
+            facts: &facts,
+            vars: &BTreeMap::new(),
+            data: &data,
+            environment: e::Real,
+            packages: &packages::detect(&facts)?,
+            allocator: &allocator,
+            file_system: &file_system,
+            state: &state,
+            now: Timestamp::now(),
+            opts: &opts,
+            git_system: &*git_system,
+            item: None,
+            context: &Context::default(),
+        })?;
+
+        let written = units
+            .iter()
+            .filter_map(|unit| unit.written_path())
+            .collect::<Vec<_>>();
+
+        assert_eq!(written, vec![root.join("to").join("missing")]);
+        assert_eq!(fs::read(root.join("to").join("existing"))?, b"old");
+
+        fs::remove_dir_all(&root)?;
+        Ok(())
+    }
+}
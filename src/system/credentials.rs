@@ -0,0 +1,89 @@
+use crate::{
+    environment as e,
+    system::SystemInput,
+    unit::{StoreCredential, SystemUnit},
+};
+use anyhow::{bail, Error};
+use serde::Deserialize;
+use std::fmt;
+
+system_struct! {
+    #[doc = "Stores secrets from the hierarchy in the keyring of the operating system."]
+    Credentials {
+        #[doc="Secrets to store."]
+        pub credentials: Vec<Credential>,
+    }
+}
+
+/// A secret to store in the keyring.
+#[derive(Deserialize, Debug, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct Credential {
+    /// The service the secret is for.
+    pub service: String,
+    /// The account the secret is for.
+    pub account: String,
+    /// Hierarchy key with the secret.
+    pub key: String,
+}
+
+impl Credentials {
+    system_defaults!(translate);
+
+    /// Store every secret which is in the hierarchy.
+    pub fn apply<E>(&self, input: SystemInput<E>) -> Result<Vec<SystemUnit>, Error>
+    where
+        E: Copy + e::Environment,
+    {
+        let SystemInput {
+            data, allocator, ..
+        } = input;
+
+        let mut units = Vec::new();
+
+        for credential in &self.credentials {
+            if credential.service.is_empty() || credential.account.is_empty() {
+                bail!("Credentials need both a `service` and an `account`");
+            }
+
+            // NB: secrets are usually only available on some machines.
+            let secret = match data.load::<String>(&credential.key)? {
+                Some(secret) => secret,
+                None => {
+                    log::trace!(
+                        "No secret in `{}` for `{}` in `{}`",
+                        credential.key,
+                        credential.account,
+                        credential.service
+                    );
+                    continue;
+                }
+            };
+
+            let mut unit = allocator.unit(StoreCredential {
+                service: credential.service.clone(),
+                account: credential.account.clone(),
+                secret,
+            });
+
+            // NB: keyrings might prompt to be unlocked, which is confusing to do from many
+            // threads at once.
+            unit.thread_local = true;
+            units.push(unit);
+        }
+
+        Ok(units)
+    }
+}
+
+impl fmt::Display for Credentials {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        let services = self
+            .credentials
+            .iter()
+            .map(|c| c.service.as_str())
+            .collect::<Vec<_>>();
+
+        write!(fmt, "store credentials for {}", services.join(", "))
+    }
+}
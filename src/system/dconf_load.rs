@@ -0,0 +1,62 @@
+use crate::{
+    environment as e,
+    system::SystemInput,
+    template::Template,
+    unit::{LoadDconf, SystemUnit},
+};
+use anyhow::{bail, Error};
+use std::fmt;
+
+system_struct! {
+    #[doc = "Loads a dump of dconf settings from the repository into a directory."]
+    DconfLoad {
+        #[doc="The dconf directory to load the settings into, like `/org/gnome/terminal/`."]
+        pub path: String,
+        #[doc="File with the settings, in the format of `dconf dump`."]
+        pub from: Template,
+    }
+}
+
+impl DconfLoad {
+    system_defaults!(translate);
+
+    /// Load the settings, unless they are already set.
+    pub fn apply<E>(&self, input: SystemInput<E>) -> Result<Vec<SystemUnit>, Error>
+    where
+        E: Copy + e::Environment,
+    {
+        let vars = input.vars();
+
+        let SystemInput {
+            root,
+            base_dirs,
+            environment,
+            allocator,
+            ..
+        } = input;
+
+        // NB: dconf only loads into directories, which start and end with a slash.
+        if !self.path.starts_with('/') || !self.path.ends_with('/') {
+            bail!(
+                "Bad dconf `path` `{}`, expected a directory like `/org/gnome/terminal/`",
+                self.path
+            );
+        }
+
+        let from = match self.from.as_path(root, base_dirs, vars, environment)? {
+            Some(from) => from,
+            None => return Ok(Vec::new()),
+        };
+
+        Ok(vec![allocator.unit(LoadDconf {
+            path: self.path.clone(),
+            from,
+        })])
+    }
+}
+
+impl fmt::Display for DconfLoad {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(fmt, "load dconf `{}` from `{}`", self.path, self.from)
+    }
+}
@@ -2,7 +2,8 @@ use crate::{
     environment as e,
     system::SystemInput,
     template::Template,
-    unit::{self, Dependency},
+    unit::{self, Dependency, FileMode},
+    verify::Verify,
 };
 use anyhow::{anyhow, bail, Context as _, Error};
 use std::fmt;
@@ -14,6 +15,12 @@ system_struct! {
         pub url: String,
         #[doc="Where to download the file to."]
         pub path: Template,
+        #[doc="How to verify the download."]
+        #[serde(default)]
+        pub verify: Verify,
+        #[doc="Permissions to set on the downloaded file."]
+        #[serde(default)]
+        pub mode: Option<FileMode>,
     }
 }
 
@@ -25,12 +32,13 @@ impl Download {
     where
         E: Copy + e::Environment,
     {
+        let vars = input.vars();
+
         let SystemInput {
             root,
             base_dirs,
             allocator,
             state,
-            facts,
             environment,
             file_system,
             ..
@@ -51,16 +59,21 @@ impl Download {
             generated_id.as_str()
         };
 
-        if state.has_run_once(id) {
-            return Ok(vec![]);
-        }
-
-        let path = match self.path.as_path(root, base_dirs, facts, environment)? {
+        let path = match self.path.as_path(root, base_dirs, vars, environment)? {
             Some(path) => path,
             None => bail!("target path is not supported"),
         };
 
         let mut units = Vec::new();
+
+        if state.has_run_once(id) {
+            // NB: permissions are kept up to date, even if the file was already downloaded.
+            if let (Some(mode), true) = (self.mode, path.is_file()) {
+                units.push(allocator.unit(unit::SetMode { path, mode }));
+            }
+
+            return Ok(units);
+        }
         let mut create_dirs = Vec::new();
 
         if let Some(parent) = path.parent() {
@@ -71,6 +84,7 @@ impl Download {
             url,
             path: path.to_owned(),
             id: None,
+            verify: self.verify.clone(),
         });
 
         download
@@ -78,6 +92,16 @@ impl Download {
             .extend(create_dirs.iter().map(|u| Dependency::Dir(u.id)));
 
         units.extend(create_dirs);
+
+        if let Some(mode) = self.mode {
+            let mut set_mode = allocator.unit(unit::SetMode {
+                path: path.to_owned(),
+                mode,
+            });
+            set_mode.dependencies.push(Dependency::Unit(download.id));
+            units.push(set_mode);
+        }
+
         units.push(download);
 
         Ok(units)
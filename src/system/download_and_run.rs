@@ -3,6 +3,7 @@ use crate::{
     system::SystemInput,
     template::Template,
     unit::{AddMode, Dependency, Download, Mode, RunOnce, SystemUnit},
+    verify::Verify,
 };
 use anyhow::{anyhow, Context as _, Error};
 use std::fmt;
@@ -21,12 +22,15 @@ system_struct! {
         #[doc="Arguments to add when running command."]
         #[serde(default)]
         pub args: Vec<Template>,
-        #[doc="Rename the binary to this before running it."]
+        #[doc="Rename the binary to this before running it. Defaults to the name of the system."]
         #[serde(default)]
-        pub name: Option<String>,
+        pub binary: Option<String>,
         /// Run the downloaded command as root.
         #[serde(default)]
         pub root: bool,
+        #[doc="How to verify the download before it's run."]
+        #[serde(default)]
+        pub verify: Verify,
     }
 }
 
@@ -38,11 +42,12 @@ impl DownloadAndRun {
     where
         E: Copy + e::Environment,
     {
+        let vars = input.vars();
+
         let SystemInput {
             allocator,
             file_system,
             state,
-            facts,
             environment,
             ..
         } = input;
@@ -52,7 +57,10 @@ impl DownloadAndRun {
 
         let generated_id;
 
-        let id = match self.id.as_deref().or_else(|| self.name.as_deref()) {
+        // NB: the name of the system used to be the name of the binary.
+        let binary = self.binary.as_deref().or(self.name.as_deref());
+
+        let id = match self.id.as_deref().or(binary) {
             Some(id) => id,
             None => {
                 if let Some(base) = base {
@@ -69,29 +77,33 @@ impl DownloadAndRun {
             return Ok(vec![]);
         }
 
-        let name = if let Some(name) = self.name.as_deref() {
-            name
-        } else {
-            id
-        };
+        let name = binary.unwrap_or(id);
 
         let path = os::exe_path(file_system.state_path(name));
 
         let mut units = Vec::new();
 
         let download = if !path.is_file() {
+            if self.verify.is_empty() {
+                log::warn!(
+                    "{}: downloaded without `verify`, so it runs unverified",
+                    self
+                );
+            }
+
             // Download the file.
             Some(allocator.unit(Download {
                 url,
                 path: path.to_owned(),
                 id: None,
+                verify: self.verify.clone(),
             }))
         } else {
             None
         };
 
         // Make the downloaded file executable.
-        let mode = AddMode::new(path.to_owned()).user(Mode::Execute);
+        let mode = AddMode::new(&path).user(Mode::Execute);
         let mut add_mode = allocator.unit(mode);
         add_mode
             .dependencies
@@ -104,7 +116,7 @@ impl DownloadAndRun {
 
         for (i, arg) in self.args.iter().enumerate() {
             let arg = arg
-                .as_string(facts, environment)?
+                .as_string(vars, environment)?
                 .ok_or_else(|| anyhow!("Cannot render argument #{}", i))?;
 
             run_once.args.push(arg);
@@ -0,0 +1,268 @@
+use crate::{
+    environment as e,
+    system::SystemInput,
+    template::Template,
+    unit::{SystemUnit, WriteFile},
+    FileSystem,
+};
+use anyhow::{anyhow, bail, Error};
+use serde::Deserialize;
+use serde_yaml::Value;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::path::Path;
+
+/// Header of every generated file.
+const HEADER: &str = "Generated by quickcfg from the hierarchy, changes will be overwritten.";
+
+system_struct! {
+    #[doc = "Generates a file for each shell which exports variables from the hierarchy."]
+    Env {
+        #[doc="Hierarchy key with the variables to export."]
+        #[serde(default = "default_key")]
+        pub key: String,
+        #[doc="Directory to write the files to."]
+        #[serde(default = "default_dir")]
+        pub dir: Template,
+        #[doc="Shells to generate files for."]
+        #[serde(default = "default_shells")]
+        pub shells: Vec<Shell>,
+    }
+}
+
+/// A shell to generate a file for.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Shell {
+    /// POSIX shells, like bash and zsh.
+    Sh,
+    /// The fish shell.
+    Fish,
+    /// PowerShell.
+    Powershell,
+}
+
+impl Shell {
+    /// Name of the file with the given base name generated for the shell.
+    pub(super) fn file_name(self, base: &str) -> String {
+        let extension = match self {
+            Shell::Sh => "sh",
+            Shell::Fish => "fish",
+            Shell::Powershell => "ps1",
+        };
+
+        format!("{}.{}", base, extension)
+    }
+
+    /// Quote the given value as a literal string.
+    pub(super) fn quote(self, value: &str) -> String {
+        match self {
+            Shell::Sh => format!("'{}'", value.replace('\'', "'\\''")),
+            Shell::Fish => format!("'{}'", value.replace('\\', "\\\\").replace('\'', "\\'")),
+            Shell::Powershell => format!("'{}'", value.replace('\'', "''")),
+        }
+    }
+
+    /// Generate the content of the file which exports the given variables.
+    fn render(self, vars: &BTreeMap<String, String>) -> String {
+        let mut out = self.header();
+
+        for (name, value) in vars {
+            let value = self.quote(value);
+
+            let line = match self {
+                Shell::Sh => format!("export {}={}", name, value),
+                Shell::Fish => format!("set -gx {} {}", name, value),
+                Shell::Powershell => format!("$env:{} = {}", name, value),
+            };
+
+            out.push_str(&line);
+            out.push('\n');
+        }
+
+        out
+    }
+
+    /// The header of every generated file, which is a comment in every shell.
+    pub(super) fn header(self) -> String {
+        format!("# {}\n", HEADER)
+    }
+}
+
+/// Write a file with the given base name for each shell to `dir`, unless its content is unchanged.
+pub(super) fn write_files<E>(
+    input: SystemInput<E>,
+    dir: &Path,
+    base: &str,
+    shells: &[Shell],
+    render: impl Fn(Shell) -> String,
+) -> Result<Vec<SystemUnit>, Error>
+where
+    E: Copy + e::Environment,
+{
+    let SystemInput {
+        allocator,
+        file_system,
+        ..
+    } = input;
+
+    let mut units = Vec::new();
+
+    for &shell in shells {
+        let path = dir.join(shell.file_name(base));
+        let content = render(shell);
+
+        if FileSystem::try_open_meta(&path)?.is_some_and(|m| m.is_file())
+            && fs::read(&path)? == content.as_bytes()
+        {
+            continue;
+        }
+
+        let mut unit = allocator.unit(WriteFile {
+            path: path.clone(),
+            content: content.into_bytes(),
+        });
+
+        if !dir.is_dir() {
+            units.extend(file_system.create_dir_all(dir)?);
+            unit.dependencies.push(file_system.dir_dependency(dir)?);
+        }
+
+        unit.provides.push(file_system.file_dependency(&path)?);
+        units.push(unit);
+    }
+
+    Ok(units)
+}
+
+fn default_key() -> String {
+    String::from("env")
+}
+
+pub(super) fn default_dir() -> Template {
+    Template::parse("home://.config/quickcfg").expect("valid template")
+}
+
+pub(super) fn default_shells() -> Vec<Shell> {
+    vec![Shell::Sh, Shell::Fish, Shell::Powershell]
+}
+
+impl Env {
+    system_defaults!(translate);
+
+    /// Write a file for each shell, if its content has changed.
+    pub fn apply<E>(&self, input: SystemInput<E>) -> Result<Vec<SystemUnit>, Error>
+    where
+        E: Copy + e::Environment,
+    {
+        let vars = input.vars();
+
+        let SystemInput {
+            root,
+            base_dirs,
+            data,
+            environment,
+            ..
+        } = input;
+
+        let dir = self
+            .dir
+            .as_path(root, base_dirs, vars, environment)?
+            .ok_or_else(|| anyhow!("Cannot render `dir`: {}", self.dir))?;
+
+        let mut exports = BTreeMap::new();
+
+        for (name, value) in data
+            .load::<BTreeMap<String, Value>>(&self.key)?
+            .unwrap_or_default()
+        {
+            if !is_valid_name(&name) {
+                bail!("`{}` in `{}` is not a valid variable name", name, self.key);
+            }
+
+            let value = match value {
+                Value::String(value) => value,
+                Value::Number(value) => value.to_string(),
+                Value::Bool(value) => value.to_string(),
+                _ => bail!(
+                    "`{}` in `{}` must be a string, number or bool",
+                    name,
+                    self.key
+                ),
+            };
+
+            let value = Template::parse(&value)?
+                .as_string(vars, environment)?
+                .ok_or_else(|| anyhow!("Cannot render `{}` in `{}`", name, self.key))?;
+
+            exports.insert(name, value);
+        }
+
+        write_files(input, &dir, "env", &self.shells, |shell| {
+            shell.render(&exports)
+        })
+    }
+}
+
+impl fmt::Display for Env {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(fmt, "export `{}` to {}", self.key, self.dir)
+    }
+}
+
+/// Test if the given name is a valid name of an environment variable in every shell.
+fn is_valid_name(name: &str) -> bool {
+    let mut chars = name.chars();
+
+    chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{is_valid_name, Shell};
+    use std::collections::BTreeMap;
+
+    #[test]
+    fn test_render() {
+        let mut vars = BTreeMap::new();
+        vars.insert(String::from("EDITOR"), String::from("vim"));
+        vars.insert(String::from("GREETING"), String::from("it's \\o/"));
+
+        let lines = |shell: Shell| {
+            shell
+                .render(&vars)
+                .lines()
+                .skip(1)
+                .map(String::from)
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            lines(Shell::Sh),
+            vec!["export EDITOR='vim'", "export GREETING='it'\\''s \\o/'"]
+        );
+
+        assert_eq!(
+            lines(Shell::Fish),
+            vec!["set -gx EDITOR 'vim'", "set -gx GREETING 'it\\'s \\\\o/'"]
+        );
+
+        assert_eq!(
+            lines(Shell::Powershell),
+            vec!["$env:EDITOR = 'vim'", "$env:GREETING = 'it''s \\o/'"]
+        );
+    }
+
+    #[test]
+    fn test_is_valid_name() {
+        assert!(is_valid_name("GOPATH"));
+        assert!(is_valid_name("_private1"));
+        assert!(!is_valid_name("1st"));
+        assert!(!is_valid_name("NO-DASH"));
+        assert!(!is_valid_name(""));
+    }
+}
@@ -0,0 +1,135 @@
+use crate::{
+    environment as e,
+    system::SystemInput,
+    template::{self, Template, Vars},
+    unit::{Dependency, FileMode, SetMode, SystemUnit, WriteFile},
+    FileSystem,
+};
+use anyhow::{anyhow, Error};
+use std::fmt;
+use std::fs;
+
+system_struct! {
+    #[doc = "Writes a small file with content from the configuration."]
+    File {
+        #[doc="Where to write the file."]
+        pub path: Template,
+        #[doc="Content of the file, which is a template."]
+        pub content: String,
+        #[serde(default)]
+        #[doc="Permissions to set on the file."]
+        pub mode: Option<FileMode>,
+    }
+}
+
+impl File {
+    system_defaults!(translate);
+
+    /// Write the file, if its content has changed.
+    pub fn apply<E>(&self, input: SystemInput<E>) -> Result<Vec<SystemUnit>, Error>
+    where
+        E: Copy + e::Environment,
+    {
+        let vars = input.vars();
+
+        let SystemInput {
+            root,
+            base_dirs,
+            environment,
+            file_system,
+            allocator,
+            ..
+        } = input;
+
+        let path = match self.path.as_path(root, base_dirs, vars, environment)? {
+            Some(path) => path,
+            None => return Ok(Vec::new()),
+        };
+
+        let content = render_content(&self.content, vars)?;
+
+        let mut units = Vec::new();
+        let mut write = None;
+
+        if !(FileSystem::try_open_meta(&path)?.is_some_and(|m| m.is_file())
+            && fs::read(&path)? == content.as_bytes())
+        {
+            let mut unit = allocator.unit(WriteFile {
+                path: path.clone(),
+                content: content.into_bytes(),
+            });
+
+            if let Some(parent) = path.parent() {
+                if !parent.is_dir() {
+                    units.extend(file_system.create_dir_all(parent)?);
+                    unit.dependencies.push(file_system.dir_dependency(parent)?);
+                }
+            }
+
+            unit.provides.push(file_system.file_dependency(&path)?);
+            write = Some(unit.id);
+            units.push(unit);
+        }
+
+        if let Some(mode) = self.mode {
+            let mut set_mode = allocator.unit(SetMode {
+                path: path.clone(),
+                mode,
+            });
+
+            set_mode.dependencies.extend(write.map(Dependency::Unit));
+            units.push(set_mode);
+        }
+
+        Ok(units)
+    }
+}
+
+impl fmt::Display for File {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(fmt, "write file `{}`", self.path)
+    }
+}
+
+/// Render the content of the file with the given variables.
+fn render_content(content: &str, vars: impl Vars) -> Result<String, Error> {
+    template::render_cached(content, vars.context_key(), || {
+        template::registry()
+            .render_template(content, &vars.to_json())
+            .map_err(|e| anyhow!("failed to render `content`: {}", e))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::render_content;
+    use crate::{
+        template::{Context, Scope},
+        Data, Facts,
+    };
+    use std::collections::BTreeMap;
+
+    #[test]
+    fn test_render_content() {
+        let facts = Facts::new(vec![("os".to_string(), "linux".to_string())]);
+        let mut vars = BTreeMap::new();
+        vars.insert(
+            "registry".to_string(),
+            "https://npm.example.com".to_string(),
+        );
+        let data = Data::new(None, vec![]);
+
+        let scope = Scope {
+            facts: &facts,
+            vars: &vars,
+            data: &data,
+            item: None,
+            context: &Context::default(),
+        };
+
+        assert_eq!(
+            render_content("registry={{registry}}\n# {{facts.os}}\n", scope).unwrap(),
+            "registry=https://npm.example.com\n# linux\n"
+        );
+    }
+}
@@ -22,7 +22,7 @@ impl FromDb {
     /// Copy one directory to another.
     pub fn apply<E>(&self, input: SystemInput<E>) -> Result<Vec<SystemUnit>>
     where
-        E: Copy + e::Environment,
+        E: Copy + e::Environment + Sync,
     {
         use serde_yaml::Value;
 
@@ -42,7 +42,7 @@ impl FromDb {
             system.insert("type".into(), self.system.clone().into());
             let system = serde_yaml::from_value::<System>(Value::Mapping(system))?;
 
-            match system.translate() {
+            match system.translate(input.facts) {
                 Translation::Discard => continue,
                 Translation::Keep => {
                     for s in system.apply(input)? {
@@ -0,0 +1,210 @@
+use crate::{
+    environment as e,
+    system::SystemInput,
+    template::Template,
+    unit::{AddMode, Dependency, Mode, SetGitConfig, SystemUnit},
+    FileSystem,
+};
+use anyhow::{anyhow, Context as _, Error};
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+system_struct! {
+    #[doc = "Installs hook scripts into git repositories."]
+    GitHooks {
+        #[doc="Directory with the hook scripts, named like the hooks they are."]
+        pub from: Template,
+        #[doc="Repositories to install the hooks into."]
+        pub repos: Vec<Template>,
+        #[serde(default)]
+        #[doc="If we should treat hook scripts as templates."]
+        pub templates: bool,
+        #[serde(default)]
+        #[doc="Directory to install the hooks to once, which every repository uses through `core.hooksPath`."]
+        pub hooks_path: Option<Template>,
+    }
+}
+
+impl GitHooks {
+    system_defaults!(translate);
+
+    /// Copy the hooks into every repository, or into `hooks_path` and point every repository at it.
+    pub fn apply<E>(&self, input: SystemInput<E>) -> Result<Vec<SystemUnit>, Error>
+    where
+        E: Copy + e::Environment,
+    {
+        let vars = input.vars();
+
+        let SystemInput {
+            root,
+            base_dirs,
+            environment,
+            file_system,
+            allocator,
+            state,
+            ..
+        } = input;
+
+        let from = match self.from.as_path(root, base_dirs, vars, environment)? {
+            Some(from) => from,
+            None => return Ok(Vec::new()),
+        };
+
+        let mut hooks = Vec::new();
+
+        for entry in fs::read_dir(&from)
+            .with_context(|| anyhow!("failed to read hooks: {}", from.display()))?
+        {
+            let entry = entry?;
+            let meta = entry.metadata()?;
+
+            if meta.is_file() {
+                hooks.push((entry.path(), meta));
+            }
+        }
+
+        hooks.sort_by(|a, b| a.0.cmp(&b.0));
+
+        let mut units = Vec::new();
+
+        let install = |dir: &Path, units: &mut Vec<SystemUnit>| -> Result<(), Error> {
+            if !dir.is_dir() {
+                units.extend(file_system.create_dir_all(dir)?);
+            }
+
+            for (path, meta) in &hooks {
+                let name = path.file_name().ok_or_else(|| anyhow!("no file name"))?;
+                let to = dir.join(name);
+                let to_meta = FileSystem::try_open_meta(&to)?;
+
+                let copy = file_system.copy_file(
+                    path,
+                    meta.clone(),
+                    &to,
+                    to_meta.as_ref(),
+                    self.templates,
+                    state,
+                )?;
+
+                // NB: git silently ignores hooks which aren't executable.
+                if let Some(copy) = copy {
+                    let mut add_mode = allocator.unit(AddMode::new(&to).user(Mode::Execute));
+                    add_mode.dependencies.push(Dependency::Unit(copy.id));
+                    units.push(copy);
+                    units.push(add_mode);
+                }
+            }
+
+            Ok(())
+        };
+
+        let hooks_path = match &self.hooks_path {
+            Some(hooks_path) => {
+                let hooks_path = hooks_path
+                    .as_path(root, base_dirs, vars, environment)?
+                    .ok_or_else(|| anyhow!("Cannot render `hooks_path`: {}", hooks_path))?;
+
+                install(&hooks_path, &mut units)?;
+                Some(hooks_path)
+            }
+            None => None,
+        };
+
+        for repo in &self.repos {
+            let repo = match repo.as_path(root, base_dirs, vars, environment)? {
+                Some(repo) => repo,
+                None => continue,
+            };
+
+            // NB: repositories which aren't cloned yet get their hooks on a later run.
+            let hooks_dir = match hooks_dir(&repo)? {
+                Some(hooks_dir) => hooks_dir,
+                None => {
+                    log::trace!("Skipping hooks for missing repository: {}", repo.display());
+                    continue;
+                }
+            };
+
+            match &hooks_path {
+                Some(hooks_path) => units.push(allocator.unit(SetGitConfig {
+                    repo,
+                    key: String::from("core.hooksPath"),
+                    value: hooks_path.display().to_string(),
+                })),
+                None => install(&hooks_dir, &mut units)?,
+            }
+        }
+
+        Ok(units)
+    }
+}
+
+impl fmt::Display for GitHooks {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(fmt, "install git hooks from `{}`", self.from)
+    }
+}
+
+/// The directory with the hooks of the given repository, if it is one.
+///
+/// In worktrees and submodules `.git` is a file pointing to the git directory, and worktrees
+/// share the hooks of the repository they belong to.
+fn hooks_dir(repo: &Path) -> Result<Option<PathBuf>, Error> {
+    let dot_git = repo.join(".git");
+
+    let meta = match FileSystem::try_open_meta(&dot_git)? {
+        Some(meta) => meta,
+        None => return Ok(None),
+    };
+
+    if meta.is_dir() {
+        return Ok(Some(dot_git.join("hooks")));
+    }
+
+    let content = fs::read_to_string(&dot_git)?;
+
+    let git_dir = match content.trim().strip_prefix("gitdir:") {
+        Some(git_dir) => repo.join(git_dir.trim()),
+        None => return Ok(None),
+    };
+
+    let common_dir = match fs::read_to_string(git_dir.join("commondir")) {
+        Ok(common_dir) => git_dir.join(common_dir.trim()),
+        Err(_) => git_dir,
+    };
+
+    Ok(Some(common_dir.join("hooks")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::hooks_dir;
+    use std::fs;
+
+    #[test]
+    fn test_hooks_dir() {
+        let root = std::env::temp_dir().join(format!("quickcfg-hooks-{}", std::process::id()));
+        let repo = root.join("repo");
+        let worktree = root.join("worktree");
+        let git_dir = repo.join(".git/worktrees/worktree");
+
+        fs::create_dir_all(&git_dir).unwrap();
+        fs::create_dir_all(&worktree).unwrap();
+        fs::write(
+            worktree.join(".git"),
+            format!("gitdir: {}\n", git_dir.display()),
+        )
+        .unwrap();
+        fs::write(git_dir.join("commondir"), "../..\n").unwrap();
+
+        assert_eq!(hooks_dir(&repo).unwrap(), Some(repo.join(".git/hooks")));
+        assert_eq!(
+            hooks_dir(&worktree).unwrap(),
+            Some(git_dir.join("../..").join("hooks"))
+        );
+        assert_eq!(hooks_dir(&root).unwrap(), None);
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -22,6 +22,9 @@ system_struct! {
             deserialize_with = "config::human_duration"
         )]
         pub refresh: Duration,
+        #[doc="Maximum random delay added to the refresh interval."]
+        #[serde(default, deserialize_with = "config::human_duration")]
+        pub refresh_jitter: Duration,
     }
 }
 
@@ -38,13 +41,14 @@ impl GitSync {
     where
         E: Copy + e::Environment,
     {
+        let vars = input.vars();
+
         let SystemInput {
             root,
             base_dirs,
             allocator,
             file_system,
             state,
-            facts,
             environment,
             now,
             opts,
@@ -58,15 +62,15 @@ impl GitSync {
 
         let mut units = Vec::new();
 
-        let path = match self.path.as_path(root, base_dirs, facts, environment)? {
+        let path = match self.path.as_path(root, base_dirs, vars, environment)? {
             Some(path) => path,
             None => return Ok(units),
         };
 
         if let Some(last_update) = state.last_update(&id) {
-            let duration = now.duration_since(last_update.clone())?;
+            let duration = now.duration_since(*last_update)?;
 
-            if duration < self.refresh {
+            if duration < config::jitter(self.refresh, self.refresh_jitter) {
                 return Ok(units);
             }
         };
@@ -0,0 +1,262 @@
+use crate::{
+    environment as e,
+    system::SystemInput,
+    template::Template,
+    unit::{SystemUnit, WriteSystemFile},
+};
+use anyhow::{anyhow, bail, Context as _, Error};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+
+/// Line which starts the block of entries managed by quickcfg.
+const BEGIN: &str = "# BEGIN quickcfg";
+/// Line which ends the block of entries managed by quickcfg.
+const END: &str = "# END quickcfg";
+
+system_struct! {
+    #[doc = "Maintains a block of entries in the hosts file from the hierarchy."]
+    Hosts {
+        #[doc="Hierarchy key with the address of each hostname."]
+        #[serde(default = "default_key")]
+        pub key: String,
+        #[doc="The hosts file, if it isn't where the operating system keeps it."]
+        #[serde(default)]
+        pub path: Option<Template>,
+    }
+}
+
+fn default_key() -> String {
+    String::from("hosts")
+}
+
+impl Hosts {
+    system_defaults!(translate);
+
+    /// Update the block of entries in the hosts file, if it has changed.
+    pub fn apply<E>(&self, input: SystemInput<E>) -> Result<Vec<SystemUnit>, Error>
+    where
+        E: Copy + e::Environment,
+    {
+        let vars = input.vars();
+
+        let SystemInput {
+            root,
+            base_dirs,
+            data,
+            environment,
+            allocator,
+            ..
+        } = input;
+
+        let path = match &self.path {
+            Some(path) => path
+                .as_path(root, base_dirs, vars, environment)?
+                .ok_or_else(|| anyhow!("Cannot render `path`: {}", path))?,
+            None => default_path(environment)?,
+        };
+
+        let entries = data
+            .load::<BTreeMap<String, String>>(&self.key)?
+            .unwrap_or_default();
+
+        for (name, address) in &entries {
+            if !is_valid_hostname(name) {
+                bail!("`{}` in `{}` is not a valid hostname", name, self.key);
+            }
+
+            if address.parse::<IpAddr>().is_err() {
+                bail!(
+                    "`{}` for `{}` in `{}` is not an address",
+                    address,
+                    name,
+                    self.key
+                );
+            }
+        }
+
+        let current = fs::read_to_string(&path)
+            .with_context(|| anyhow!("failed to read hosts file: {}", path.display()))?;
+
+        let content = update_block(&path, &current, &entries)?;
+
+        if content == current {
+            return Ok(Vec::new());
+        }
+
+        // NB: written in place, since the hosts file is bind mounted into containers and can't be
+        // replaced.
+        Ok(vec![allocator.unit(WriteSystemFile {
+            path,
+            content: content.into_bytes(),
+            in_place: true,
+        })])
+    }
+}
+
+impl fmt::Display for Hosts {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(fmt, "hosts from `{}`", self.key)
+    }
+}
+
+/// Where the operating system keeps the hosts file.
+fn default_path(environment: impl e::Environment) -> Result<PathBuf, Error> {
+    if !cfg!(windows) {
+        return Ok(PathBuf::from("/etc/hosts"));
+    }
+
+    let system_root = environment
+        .var("SystemRoot")?
+        .unwrap_or_else(|| String::from(r"C:\Windows"));
+
+    Ok(PathBuf::from(system_root).join(r"System32\drivers\etc\hosts"))
+}
+
+/// Replace the block of entries managed by quickcfg, adding it at the end if there isn't one.
+///
+/// Everything outside of the block is left alone, and the block is removed if there are no
+/// entries. A block which is never ended is an error, since we can't tell where it was meant to
+/// stop.
+fn update_block(
+    path: &Path,
+    current: &str,
+    entries: &BTreeMap<String, String>,
+) -> Result<String, Error> {
+    // NB: the hosts file on Windows usually has CRLF line endings.
+    let newline = if current.contains("\r\n") {
+        "\r\n"
+    } else {
+        "\n"
+    };
+
+    let mut block = String::new();
+
+    if !entries.is_empty() {
+        block.push_str(BEGIN);
+        block.push_str(newline);
+
+        for (name, address) in entries {
+            block.push_str(&format!("{} {}{}", address, name, newline));
+        }
+
+        block.push_str(END);
+        block.push_str(newline);
+    }
+
+    let mut before = String::new();
+    let mut after = String::new();
+    let mut state = 0;
+    let mut begin = 0;
+
+    for (n, line) in current.split_inclusive('\n').enumerate() {
+        match (state, line.trim_end()) {
+            (0, BEGIN) => {
+                state = 1;
+                begin = n + 1;
+            }
+            (1, END) => state = 2,
+            (0, _) => before.push_str(line),
+            (1, _) => (),
+            _ => after.push_str(line),
+        }
+    }
+
+    if state == 1 {
+        bail!(
+            "{}:{}: `{}` is never ended by `{}`",
+            path.display(),
+            begin,
+            BEGIN,
+            END
+        );
+    }
+
+    // NB: the file might not end with a newline.
+    if !before.is_empty() && !before.ends_with('\n') && !block.is_empty() {
+        before.push_str(newline);
+    }
+
+    Ok(before + &block + &after)
+}
+
+/// Test if the given name can be used as a hostname.
+fn is_valid_hostname(name: &str) -> bool {
+    !name.is_empty()
+        && !name.starts_with(['-', '.'])
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '.' | '_'))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{is_valid_hostname, update_block};
+    use std::collections::BTreeMap;
+    use std::path::Path;
+
+    #[test]
+    fn test_update_block() -> anyhow::Result<()> {
+        let path = Path::new("hosts");
+
+        let mut entries = BTreeMap::new();
+        entries.insert(String::from("app.test"), String::from("127.0.0.1"));
+        entries.insert(String::from("api.test"), String::from("::1"));
+
+        let current = "127.0.0.1 localhost\n";
+        let updated = update_block(path, current, &entries)?;
+
+        assert_eq!(
+            updated,
+            "127.0.0.1 localhost\n# BEGIN quickcfg\n::1 api.test\n127.0.0.1 app.test\n# END quickcfg\n"
+        );
+
+        entries.remove("api.test");
+        let edited = format!("{}10.0.0.1 nas\n", updated);
+
+        assert_eq!(
+            update_block(path, &edited, &entries)?,
+            "127.0.0.1 localhost\n# BEGIN quickcfg\n127.0.0.1 app.test\n# END quickcfg\n10.0.0.1 nas\n"
+        );
+
+        assert_eq!(
+            update_block(path, &edited, &BTreeMap::new())?,
+            "127.0.0.1 localhost\n10.0.0.1 nas\n"
+        );
+
+        assert_eq!(
+            update_block(path, "127.0.0.1 localhost", &entries)?,
+            "127.0.0.1 localhost\n# BEGIN quickcfg\n127.0.0.1 app.test\n# END quickcfg\n"
+        );
+
+        assert_eq!(
+            update_block(path, "127.0.0.1 localhost\r\n", &entries)?,
+            "127.0.0.1 localhost\r\n# BEGIN quickcfg\r\n127.0.0.1 app.test\r\n# END quickcfg\r\n"
+        );
+
+        let e = update_block(
+            path,
+            "127.0.0.1 localhost\n# BEGIN quickcfg\n10.0.0.1 nas\n",
+            &entries,
+        )
+        .unwrap_err();
+
+        assert_eq!(
+            e.to_string(),
+            "hosts:2: `# BEGIN quickcfg` is never ended by `# END quickcfg`"
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_is_valid_hostname() {
+        assert!(is_valid_hostname("app.test"));
+        assert!(is_valid_hostname("my-service.local"));
+        assert!(!is_valid_hostname("-x"));
+        assert!(!is_valid_hostname("two names"));
+        assert!(!is_valid_hostname(""));
+    }
+}
@@ -1,11 +1,15 @@
 use crate::{
     environment as e,
+    hierarchy::Data,
+    packages::{self, PackageManager},
     system::SystemInput,
     unit::{self, SystemUnit},
+    State,
 };
 use anyhow::{anyhow, Error};
-use std::collections::{BTreeSet, HashSet};
+use std::collections::BTreeSet;
 use std::fmt;
+use std::sync::Arc;
 
 system_struct! {
     #[doc = "Builds one unit for every batch of packages to install."]
@@ -26,7 +30,7 @@ fn default_key() -> String {
 impl Install {
     system_defaults!(translate);
 
-    /// Copy one directory to another.
+    /// Install the packages which aren't installed yet.
     pub fn apply<E>(&self, input: SystemInput<E>) -> Result<Vec<SystemUnit>, Error>
     where
         E: Copy + e::Environment,
@@ -36,37 +40,17 @@ impl Install {
             data,
             allocator,
             state,
+            now,
             ..
         } = input;
 
         let mut units = Vec::new();
 
-        let provider = self.provider.as_deref();
-
-        let package_manager = match provider {
-            Some(provider) => packages.get(provider)?,
-            None => packages.default(),
-        };
-
-        let id = self
-            .id
-            .as_deref()
-            .map(|id| id.to_string())
-            .or_else(|| provider.map(|id| id.to_string()))
-            .or_else(|| packages.default().map(|p| p.name().to_string()))
-            .ok_or_else(|| anyhow!("no usable install provider id"))?;
-
-        let mut all_packages = BTreeSet::new();
-
-        let key = match package_manager.as_deref().and_then(|p| p.key()) {
-            Some(key) => key.to_string(),
-            None => match provider {
-                Some(provider) => format!("{}::{}", provider, self.key),
-                None => self.key.to_string(),
-            },
-        };
-
-        all_packages.extend(data.load_or_default::<Vec<String>>(&key)?);
+        let Resolved {
+            package_manager,
+            id,
+            all_packages,
+        } = self.resolve(packages, data)?;
 
         // test if stored hash is stale.
         if state.is_hash_fresh(&id, &all_packages)? {
@@ -83,7 +67,7 @@ impl Install {
 
                 // warn, because we have packages that we want to install but can't since there is
                 // no package manager.
-                match provider {
+                match self.provider.as_deref() {
                     Some(provider) => {
                         log::warn!("No package manager for provider `{}` found", provider)
                     }
@@ -94,13 +78,13 @@ impl Install {
             }
         };
 
-        let mut to_install = all_packages.iter().cloned().collect::<HashSet<_>>();
-
-        for package in package_manager.list_packages()? {
-            to_install.remove(&package.name);
-        }
+        let installed = packages.list_packages(&*package_manager, now)?;
 
-        let to_install = to_install.into_iter().collect();
+        let to_install = all_packages
+            .iter()
+            .filter(|package| !installed.contains(*package))
+            .cloned()
+            .collect();
 
         // thread-local if package manager requires user interaction.
         let thread_local = package_manager.needs_interaction();
@@ -117,6 +101,71 @@ impl Install {
         units.push(unit);
         Ok(units)
     }
+
+    /// The package manager which needs to list its packages for this system to apply, if any.
+    ///
+    /// This is the case if the packages to install have changed or haven't been checked recently.
+    pub fn stale_package_manager(
+        &self,
+        packages: &packages::Provider,
+        data: &Data,
+        state: &State,
+    ) -> Result<Option<Arc<dyn PackageManager>>, Error> {
+        let Resolved {
+            package_manager,
+            id,
+            all_packages,
+        } = self.resolve(packages, data)?;
+
+        if state.is_hash_fresh(&id, &all_packages)? {
+            return Ok(None);
+        }
+
+        Ok(package_manager)
+    }
+
+    /// Resolve the package manager, the id, and the packages to install.
+    fn resolve(&self, packages: &packages::Provider, data: &Data) -> Result<Resolved, Error> {
+        let provider = self.provider.as_deref();
+
+        let package_manager = match provider {
+            Some(provider) => packages.get(provider)?,
+            None => packages.default(),
+        };
+
+        let id = self
+            .id
+            .as_deref()
+            .map(|id| id.to_string())
+            .or_else(|| provider.map(|id| id.to_string()))
+            .or_else(|| packages.default().map(|p| p.name().to_string()))
+            .ok_or_else(|| anyhow!("no usable install provider id"))?;
+
+        let mut all_packages = BTreeSet::new();
+
+        let key = match package_manager.as_deref().and_then(|p| p.key()) {
+            Some(key) => key.to_string(),
+            None => match provider {
+                Some(provider) => format!("{}::{}", provider, self.key),
+                None => self.key.to_string(),
+            },
+        };
+
+        all_packages.extend(data.load_or_default::<Vec<String>>(&key)?);
+
+        Ok(Resolved {
+            package_manager,
+            id,
+            all_packages,
+        })
+    }
+}
+
+/// What an install system resolves to.
+struct Resolved {
+    package_manager: Option<Arc<dyn PackageManager>>,
+    id: String,
+    all_packages: BTreeSet<String>,
 }
 
 impl fmt::Display for Install {
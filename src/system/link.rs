@@ -22,23 +22,25 @@ impl Link {
     where
         E: Copy + e::Environment,
     {
+        let vars = input.vars();
+
         let SystemInput {
             root,
             base_dirs,
-            facts,
             environment,
             file_system,
+            state,
             ..
         } = input;
 
         let mut units = Vec::new();
 
-        let path = match self.path.as_path(root, base_dirs, facts, environment)? {
+        let path = match self.path.as_path(root, base_dirs, vars, environment)? {
             Some(path) => path,
             None => return Ok(units),
         };
 
-        let link = match self.link.as_path(root, base_dirs, facts, environment)? {
+        let link = match self.link.as_path(root, base_dirs, vars, environment)? {
             Some(link) => link,
             None => return Ok(units),
         };
@@ -49,12 +51,12 @@ impl Link {
         let link = if link.is_absolute() {
             path.parent()
                 .and_then(|p| FileSystem::path_relative_from(&link, p))
-                .unwrap_or_else(|| link)
+                .unwrap_or(link)
         } else {
             link
         };
 
-        units.extend(file_system.symlink(&path, link, m.as_ref())?);
+        units.extend(file_system.symlink(&path, link, m.as_ref(), state)?);
         Ok(units)
     }
 }
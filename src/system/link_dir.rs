@@ -21,30 +21,29 @@ impl LinkDir {
     where
         E: Copy + e::Environment,
     {
+        let vars = input.vars();
+
         let SystemInput {
             root,
             base_dirs,
-            facts,
             environment,
             file_system,
+            state,
             ..
         } = input;
 
-        let mut units = Vec::new();
-
-        let from = match self.from.as_path(root, base_dirs, facts, environment)? {
+        let from = match self.from.as_path(root, base_dirs, vars, environment)? {
             Some(from) => from,
-            None => return Ok(units),
+            None => return Ok(Vec::new()),
         };
 
         // resolve destination, if unspecified defaults to relative current directory.
-        let to = match self.to.as_path(root, base_dirs, facts, environment)? {
+        let to = match self.to.as_path(root, base_dirs, vars, environment)? {
             Some(to) => to,
-            None => return Ok(units),
+            None => return Ok(Vec::new()),
         };
 
-        for e in ignore::WalkBuilder::new(&from).hidden(false).build() {
-            let e = e?;
+        FileSystem::walk_dir(&from, |e, units| {
             let from_path = e.path();
             let to_path = to.join(from_path.strip_prefix(&from)?);
 
@@ -58,19 +57,18 @@ impl LinkDir {
                     units.extend(file_system.create_dir_all(&to_path)?);
                 }
 
-                continue;
+                return Ok(());
             }
 
             let link = to_path
                 .parent()
-                .and_then(|p| FileSystem::path_relative_from(&from_path, p))
+                .and_then(|p| FileSystem::path_relative_from(from_path, p))
                 .unwrap_or_else(|| from_path.to_owned());
 
             // Maybe create a symlink!
-            units.extend(file_system.symlink(&to_path, link, to.as_ref())?);
-        }
-
-        Ok(units)
+            units.extend(file_system.symlink(&to_path, link, to.as_ref(), state)?);
+            Ok(())
+        })
     }
 }
 
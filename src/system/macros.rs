@@ -15,10 +15,71 @@ macro_rules! system_struct {
             /// Id of this system.
             pub id: Option<String>,
 
+            /// Human-readable name of this system, used in logs and errors.
+            pub name: Option<String>,
+
             #[serde(default)]
             /// Things that this system requires.
             pub requires: Vec<String>,
 
+            #[serde(default)]
+            /// How many times to retry units of this system which fail.
+            pub retries: u32,
+
+            #[serde(
+                default = "crate::system::default_retry_delay",
+                deserialize_with = "crate::config::human_duration"
+            )]
+            /// How long to wait before retrying a unit which failed.
+            pub retry_delay: ::std::time::Duration,
+
+            #[serde(default, deserialize_with = "crate::config::human_duration_opt")]
+            /// How long each unit of this system may run before it fails.
+            pub timeout: Option<::std::time::Duration>,
+
+            #[serde(default)]
+            /// Whether units of this system must run on their own, without any other units
+            /// running at the same time.
+            pub serial: bool,
+
+            #[serde(default)]
+            /// Units of systems with a lower order are started first when they are ready at the
+            /// same time.
+            pub order: i32,
+
+            #[serde(default)]
+            /// Environment variables to set for every command that this system spawns.
+            pub env: ::std::collections::BTreeMap<String, crate::template::Template>,
+
+            #[serde(default, alias = "platform")]
+            /// Operating systems that this system applies to, it's skipped on all others.
+            pub os: crate::system::Platforms,
+
+            #[serde(default)]
+            /// Conditions, like `nixos` or `wsl`, under which this system is skipped.
+            pub skip_on: Vec<String>,
+
+            #[serde(default)]
+            /// Whether this system should never run again after it has applied successfully once.
+            pub once: bool,
+
+            #[serde(default)]
+            /// Files or directories in the configuration, which this system only applies again
+            /// after they have changed.
+            pub depends: Vec<::relative_path::RelativePathBuf>,
+
+            #[serde(default)]
+            /// Hierarchy key with a list, where the system is applied once for each item in it.
+            pub for_each: Option<String>,
+
+            #[serde(default)]
+            /// Commands to run before the units of this system.
+            pub pre: Vec<String>,
+
+            #[serde(default)]
+            /// Commands to run after all units of this system succeeded.
+            pub post: Vec<String>,
+
             $($(#[$attr])* pub $field: $field_ty,)*
         }
 
@@ -27,9 +88,65 @@ macro_rules! system_struct {
                 self.id.as_ref().map(|s| s.as_str())
             }
 
+            pub fn name(&self) -> Option<&str> {
+                self.name.as_deref()
+            }
+
             pub fn requires(&self) -> &[String] {
                 &self.requires
             }
+
+            pub fn retries(&self) -> u32 {
+                self.retries
+            }
+
+            pub fn retry_delay(&self) -> ::std::time::Duration {
+                self.retry_delay
+            }
+
+            pub fn timeout(&self) -> Option<::std::time::Duration> {
+                self.timeout
+            }
+
+            pub fn serial(&self) -> bool {
+                self.serial
+            }
+
+            pub fn order(&self) -> i32 {
+                self.order
+            }
+
+            pub fn env(&self) -> &::std::collections::BTreeMap<String, crate::template::Template> {
+                &self.env
+            }
+
+            pub fn os(&self) -> &crate::system::Platforms {
+                &self.os
+            }
+
+            pub fn skip_on(&self) -> &[String] {
+                &self.skip_on
+            }
+
+            pub fn once(&self) -> bool {
+                self.once
+            }
+
+            pub fn depends(&self) -> &[::relative_path::RelativePathBuf] {
+                &self.depends
+            }
+
+            pub fn for_each(&self) -> Option<&str> {
+                self.for_each.as_deref()
+            }
+
+            pub fn pre(&self) -> &[String] {
+                &self.pre
+            }
+
+            pub fn post(&self) -> &[String] {
+                &self.post
+            }
         }
     }
 }
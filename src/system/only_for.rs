@@ -8,23 +8,14 @@ use std::fmt;
 system_struct! {
     #[doc = "Conditionally run only for the given operating system."]
     OnlyFor {
-        #[doc="Which OS to run the given systems for."]
-        pub os: Option<String>,
+        #[doc="Systems to run, if `os` matches."]
         pub systems: Vec<System>,
     }
 }
 
 impl OnlyFor {
+    /// Systems are expanded if `os` matches, which is tested for every system.
     pub fn translate(&self) -> Translation<'_> {
-        if let Some(os) = self.os.as_ref() {
-            match (os.as_str(), std::env::consts::OS) {
-                (current, actual) if current == actual => (),
-                ("unix", "linux") => (),
-                ("unix", "macos") => (),
-                _ => return Translation::Discard,
-            }
-        }
-
         Translation::Expand(&self.systems)
     }
 
@@ -39,6 +30,6 @@ impl OnlyFor {
 
 impl fmt::Display for OnlyFor {
     fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
-        write!(fmt, "conditionally run for (os: {:?})", self.os)
+        write!(fmt, "conditionally run for (os: {})", self.os)
     }
 }
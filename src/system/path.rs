@@ -0,0 +1,170 @@
+use crate::{
+    environment as e, os,
+    system::{
+        env::{default_dir, default_shells, write_files, Shell},
+        SystemInput,
+    },
+    template::Template,
+    unit::{self, SystemUnit},
+};
+use anyhow::{anyhow, Error};
+use std::fmt;
+
+system_struct! {
+    #[doc = "Adds directories to PATH through a generated file for each shell, and the user PATH on Windows."]
+    AddPath {
+        #[doc="Directories to add to the start of PATH, in order."]
+        #[serde(default)]
+        pub prepend: Vec<Template>,
+        #[doc="Directories to add to the end of PATH, in order."]
+        #[serde(default)]
+        pub append: Vec<Template>,
+        #[doc="Directory to write the files to."]
+        #[serde(default = "default_dir")]
+        pub dir: Template,
+        #[doc="Shells to generate files for."]
+        #[serde(default = "default_shells")]
+        pub shells: Vec<Shell>,
+    }
+}
+
+impl AddPath {
+    system_defaults!(translate);
+
+    /// Write a file for each shell, and update the user PATH on Windows.
+    pub fn apply<E>(&self, input: SystemInput<E>) -> Result<Vec<SystemUnit>, Error>
+    where
+        E: Copy + e::Environment,
+    {
+        let vars = input.vars();
+
+        let SystemInput {
+            root,
+            base_dirs,
+            allocator,
+            environment,
+            ..
+        } = input;
+
+        let dir = self
+            .dir
+            .as_path(root, base_dirs, vars, environment)?
+            .ok_or_else(|| anyhow!("Cannot render `dir`: {}", self.dir))?;
+
+        let render = |templates: &[Template]| {
+            let mut dirs = Vec::<String>::new();
+
+            for template in templates {
+                let dir = template
+                    .as_string(vars, environment)?
+                    .ok_or_else(|| anyhow!("Cannot render directory: {}", template))?;
+
+                if !dirs.contains(&dir) {
+                    dirs.push(dir);
+                }
+            }
+
+            Ok::<_, Error>(dirs)
+        };
+
+        let prepend = render(&self.prepend)?;
+        let append = render(&self.append)?;
+
+        let mut units = write_files(input, &dir, "path", &self.shells, |shell| {
+            render_file(shell, &prepend, &append)
+        })?;
+
+        if cfg!(windows) {
+            let current = os::user_path()?;
+
+            if unit::merge_path(&current, &prepend, &append) != current {
+                units.push(allocator.unit(unit::SetUserPath { prepend, append }));
+            }
+        }
+
+        Ok(units)
+    }
+}
+
+impl fmt::Display for AddPath {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(fmt, "add directories to PATH")
+    }
+}
+
+/// Generate the content of the file which adds the given directories to PATH, unless they're
+/// already in it.
+fn render_file(shell: Shell, prepend: &[String], append: &[String]) -> String {
+    let mut out = shell.header();
+
+    // NB: directories are prepended one at a time, so the first one has to be prepended last.
+    let dirs = prepend
+        .iter()
+        .rev()
+        .map(|dir| (dir, true))
+        .chain(append.iter().map(|dir| (dir, false)));
+
+    for (dir, at_start) in dirs {
+        let dir = shell.quote(dir);
+
+        let line = match (shell, at_start) {
+            (Shell::Sh, true) => format!(
+                "case \":${{PATH}}:\" in *:{dir}:*) ;; *) PATH={dir}\"${{PATH:+:$PATH}}\" ;; esac",
+                dir = dir
+            ),
+            (Shell::Sh, false) => format!(
+                "case \":${{PATH}}:\" in *:{dir}:*) ;; *) PATH=\"${{PATH:+$PATH:}}\"{dir} ;; esac",
+                dir = dir
+            ),
+            (Shell::Fish, true) => format!("contains -- {dir} $PATH; or set -gx PATH {dir} $PATH", dir = dir),
+            (Shell::Fish, false) => format!("contains -- {dir} $PATH; or set -gx PATH $PATH {dir}", dir = dir),
+            (Shell::Powershell, true) => format!(
+                "if (($env:PATH -split [IO.Path]::PathSeparator) -notcontains {dir}) {{ $env:PATH = {dir} + [IO.Path]::PathSeparator + $env:PATH }}",
+                dir = dir
+            ),
+            (Shell::Powershell, false) => format!(
+                "if (($env:PATH -split [IO.Path]::PathSeparator) -notcontains {dir}) {{ $env:PATH = $env:PATH + [IO.Path]::PathSeparator + {dir} }}",
+                dir = dir
+            ),
+        };
+
+        out.push_str(&line);
+        out.push('\n');
+    }
+
+    if shell == Shell::Sh {
+        out.push_str("export PATH\n");
+    }
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{render_file, Shell};
+
+    #[test]
+    fn test_render_file() {
+        let prepend = vec![String::from("/a"), String::from("/b")];
+        let append = vec![String::from("/c")];
+
+        let sh = render_file(Shell::Sh, &prepend, &append);
+        let lines = sh.lines().skip(1).collect::<Vec<_>>();
+
+        assert_eq!(
+            lines,
+            vec![
+                "case \":${PATH}:\" in *:'/b':*) ;; *) PATH='/b'\"${PATH:+:$PATH}\" ;; esac",
+                "case \":${PATH}:\" in *:'/a':*) ;; *) PATH='/a'\"${PATH:+:$PATH}\" ;; esac",
+                "case \":${PATH}:\" in *:'/c':*) ;; *) PATH=\"${PATH:+$PATH:}\"'/c' ;; esac",
+                "export PATH",
+            ]
+        );
+
+        let fish = render_file(Shell::Fish, &prepend, &append);
+        assert_eq!(
+            fish.lines().nth(3),
+            Some("contains -- '/c' $PATH; or set -gx PATH $PATH '/c'")
+        );
+    }
+}
@@ -0,0 +1,277 @@
+use crate::{
+    command::Command,
+    environment as e, os,
+    system::SystemInput,
+    template::{Template, Vars as _},
+    unit::{Dependency, RunCommand, SystemUnit, WriteFile},
+    FileSystem,
+};
+use anyhow::{anyhow, bail, Context as _, Error};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::fs;
+use std::path::Path;
+
+/// Version of the protocol spoken with plugins.
+const PROTOCOL_VERSION: u32 = 1;
+
+/// Directory in the configuration repo where plugins are looked up.
+const PLUGINS_DIR: &str = "plugins";
+
+system_struct! {
+    #[doc = "Runs an external plugin which decides which files to write and commands to run."]
+    Plugin {
+        #[doc="Name of the executable in the `plugins` directory of the repo."]
+        pub plugin: String,
+        #[doc="Configuration passed as-is to the plugin."]
+        #[serde(default)]
+        pub config: serde_yaml::Value,
+    }
+}
+
+/// The request written as JSON to the stdin of a plugin.
+#[derive(Serialize)]
+struct Request<'a> {
+    version: u32,
+    root: &'a Path,
+    facts: serde_json::Value,
+    vars: serde_json::Value,
+    data: serde_json::Value,
+    config: &'a serde_yaml::Value,
+    state: RequestState<'a>,
+}
+
+/// The part of the state which is visible to a plugin.
+#[derive(Serialize)]
+struct RequestState<'a> {
+    /// Ids of commands which have already been run once.
+    once: Vec<&'a str>,
+}
+
+/// The response read as JSON from the stdout of a plugin.
+#[derive(Deserialize, Debug, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+struct Response {
+    #[serde(default)]
+    units: Vec<UnitRequest>,
+}
+
+/// A single unit that a plugin asks for.
+#[derive(Deserialize, Debug, PartialEq, Eq)]
+#[serde(tag = "type", rename_all = "kebab-case", deny_unknown_fields)]
+pub(crate) enum UnitRequest {
+    /// Write the given content to a file.
+    WriteFile { path: String, content: String },
+    /// Run a command, optionally only once.
+    Command {
+        command: Vec<String>,
+        #[serde(default)]
+        once: Option<String>,
+    },
+}
+
+impl Plugin {
+    system_defaults!(translate);
+
+    /// Run the plugin and translate its response into units.
+    pub fn apply<E>(&self, input: SystemInput<E>) -> Result<Vec<SystemUnit>, Error>
+    where
+        E: Copy + e::Environment,
+    {
+        let path = os::exe_path(input.root.join(PLUGINS_DIR).join(&self.plugin));
+
+        if !path.is_file() {
+            bail!("Missing plugin: {}", path.display());
+        }
+
+        // NB: the plugin runs in the root directory, which the path might be relative to.
+        let path = fs::canonicalize(&path)?;
+
+        let mut command = Command::new(&path);
+        command.working_directory(input.root);
+        command.stdin(request(&input, &self.plugin, &self.config)?);
+
+        let output = command
+            .run_stdout()
+            .with_context(|| anyhow!("Failed to run plugin: {}", path.display()))?;
+
+        let response = parse_response(&output)
+            .with_context(|| anyhow!("Bad response from plugin: {}", path.display()))?;
+
+        translate(input, &self.plugin, response.units)
+    }
+}
+
+/// Build the JSON request passed to the plugin with the given name.
+pub(crate) fn request<E>(
+    input: &SystemInput<E>,
+    name: &str,
+    config: &serde_yaml::Value,
+) -> Result<Vec<u8>, Error>
+where
+    E: e::Environment,
+{
+    let prefix = once_prefix(name);
+
+    let request = Request {
+        version: PROTOCOL_VERSION,
+        root: &fs::canonicalize(input.root)?,
+        facts: (&input.facts).to_json(),
+        vars: serde_json::to_value(input.vars)?,
+        data: input.data.to_json(),
+        config,
+        state: RequestState {
+            once: input
+                .state
+                .once
+                .keys()
+                .flat_map(|id| id.strip_prefix(prefix.as_str()))
+                .collect(),
+        },
+    };
+
+    Ok(serde_json::to_vec(&request)?)
+}
+
+/// Translate the units requested by the plugin with the given name into units to apply.
+pub(crate) fn translate<E>(
+    input: SystemInput<E>,
+    name: &str,
+    requests: Vec<UnitRequest>,
+) -> Result<Vec<SystemUnit>, Error>
+where
+    E: Copy + e::Environment,
+{
+    let vars = input.vars();
+
+    let SystemInput {
+        root,
+        base_dirs,
+        environment,
+        allocator,
+        file_system,
+        state,
+        ..
+    } = input;
+
+    let prefix = once_prefix(name);
+
+    let mut units = Vec::new();
+    // NB: commands run in the order they are requested, after all files have been written.
+    let mut writes = Vec::new();
+    let mut commands = Vec::new();
+
+    for request in requests {
+        match request {
+            UnitRequest::WriteFile { path, content } => {
+                let template = Template::parse(&path)?;
+
+                let path = template
+                    .as_path(root, base_dirs, vars, environment)?
+                    .ok_or_else(|| anyhow!("Cannot render path `{}`", template))?;
+
+                if FileSystem::try_open_meta(&path)?.is_some_and(|m| m.is_file())
+                    && fs::read(&path)? == content.as_bytes()
+                {
+                    continue;
+                }
+
+                let mut unit = allocator.unit(WriteFile {
+                    path: path.clone(),
+                    content: content.into_bytes(),
+                });
+
+                if let Some(parent) = path.parent() {
+                    if !parent.is_dir() {
+                        units.extend(file_system.create_dir_all(parent)?);
+                        unit.dependencies.push(file_system.dir_dependency(parent)?);
+                    }
+                }
+
+                unit.provides.push(file_system.file_dependency(&path)?);
+                writes.push(unit);
+            }
+            UnitRequest::Command { command, once } => {
+                if command.is_empty() {
+                    bail!("Plugin requested an empty command");
+                }
+
+                let once = once.map(|id| format!("{}{}", prefix, id));
+
+                if let Some(id) = once.as_deref() {
+                    if state.has_run_once(id) {
+                        continue;
+                    }
+                }
+
+                commands.push(RunCommand {
+                    command,
+                    working_directory: root.to_owned(),
+                    once,
+                });
+            }
+        }
+    }
+
+    let mut previous = writes.iter().map(|u| u.id).collect::<Vec<_>>();
+    units.extend(writes);
+
+    for command in commands {
+        let mut unit = allocator.unit(command);
+        unit.dependencies
+            .extend(previous.drain(..).map(Dependency::Unit));
+        previous.push(unit.id);
+        units.push(unit);
+    }
+
+    Ok(units)
+}
+
+/// Prefix of the ids of commands which only run once, so that plugins don't clash.
+fn once_prefix(name: &str) -> String {
+    format!("{}/", name)
+}
+
+impl fmt::Display for Plugin {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(fmt, "plugin `{}`", self.plugin)
+    }
+}
+
+/// Parse the response printed by a plugin.
+fn parse_response(output: &str) -> Result<Response, Error> {
+    Ok(serde_json::from_str(output)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_response, UnitRequest};
+
+    #[test]
+    fn test_parse_response() {
+        let response = parse_response(
+            r#"{"units": [
+                {"type": "write-file", "path": "home://.foorc", "content": "foo = 1\n"},
+                {"type": "command", "command": ["foo", "--init"], "once": "init"}
+            ]}"#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            response.units,
+            vec![
+                UnitRequest::WriteFile {
+                    path: String::from("home://.foorc"),
+                    content: String::from("foo = 1\n"),
+                },
+                UnitRequest::Command {
+                    command: vec![String::from("foo"), String::from("--init")],
+                    once: Some(String::from("init")),
+                },
+            ]
+        );
+
+        assert!(parse_response(r#"{"units": [{"type": "delete"}]}"#).is_err());
+        assert!(parse_response("{}").unwrap().units.is_empty());
+    }
+}
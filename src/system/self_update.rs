@@ -0,0 +1,73 @@
+use crate::{
+    config, environment as e,
+    system::SystemInput,
+    unit::{self, SystemUnit},
+};
+use anyhow::Error;
+use std::fmt;
+use std::time::Duration;
+
+/// State id of the last check for a newer release.
+const ID: &str = "self-update";
+
+system_struct! {
+    #[doc = "Keep the quickcfg binary updated to the latest release."]
+    SelfUpdate {
+        #[doc="GitHub repository to take releases from."]
+        #[serde(default = "config::default_self_update_repo")]
+        pub repo: String,
+        #[doc="The base64-encoded minisign public key which releases are signed with."]
+        pub public_key: String,
+        #[doc="How often to check for a newer release."]
+        #[serde(
+            default = "default_refresh",
+            deserialize_with = "config::human_duration"
+        )]
+        pub refresh: Duration,
+        #[doc="Maximum random delay added to the refresh interval."]
+        #[serde(default, deserialize_with = "config::human_duration")]
+        pub refresh_jitter: Duration,
+    }
+}
+
+/// Get default refresh.
+fn default_refresh() -> Duration {
+    Duration::from_secs(3600 * 24)
+}
+
+impl SelfUpdate {
+    system_defaults!(translate);
+
+    /// Check for a newer release, unless it was checked for recently.
+    pub fn apply<E>(&self, input: SystemInput<E>) -> Result<Vec<SystemUnit>, Error>
+    where
+        E: Copy + e::Environment,
+    {
+        let SystemInput {
+            allocator,
+            state,
+            now,
+            ..
+        } = input;
+
+        if let Some(last_update) = state.last_update(ID) {
+            let duration = now.duration_since(*last_update)?;
+
+            if duration < config::jitter(self.refresh, self.refresh_jitter) {
+                return Ok(Vec::new());
+            }
+        }
+
+        Ok(vec![allocator.unit(unit::SelfUpdate {
+            id: ID.to_string(),
+            repo: self.repo.clone(),
+            public_key: self.public_key.clone(),
+        })])
+    }
+}
+
+impl fmt::Display for SelfUpdate {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(fmt, "update quickcfg from `{}`", self.repo)
+    }
+}
@@ -0,0 +1,209 @@
+use crate::{
+    environment as e,
+    system::SystemInput,
+    template::Template,
+    unit::{self, Dependency, SystemUnit},
+    FileSystem,
+};
+use anyhow::{anyhow, bail, Context as _, Error};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::path::PathBuf;
+
+system_struct! {
+    #[doc = "Installs versions of tools from their releases, with the version to use in the hierarchy."]
+    Toolchain {
+        #[doc="Hierarchy key with the version of each tool to use."]
+        #[serde(default = "default_key")]
+        pub key: String,
+        #[doc="Directory to install tools to."]
+        #[serde(default = "default_dir")]
+        pub dir: Template,
+        #[doc="Where to download each tool from."]
+        pub tools: BTreeMap<String, Tool>,
+    }
+}
+
+/// Where to download a tool from.
+#[derive(Deserialize, Debug, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct Tool {
+    /// URL to download, where `{version}` is the version to install.
+    pub url: String,
+    /// URL of a file with the SHA-256 checksum of the download, like a `SHA256SUMS` file.
+    #[serde(default)]
+    pub checksums: Option<String>,
+    /// Expected SHA-256 of the download, by version.
+    #[serde(default)]
+    pub sha256: BTreeMap<String, String>,
+}
+
+fn default_key() -> String {
+    String::from("toolchains")
+}
+
+fn default_dir() -> Template {
+    Template::parse("home://.local/share/quickcfg/toolchains").expect("valid template")
+}
+
+impl Toolchain {
+    system_defaults!(translate);
+
+    /// Install the version of every tool in the hierarchy, and point `current` at it.
+    pub fn apply<E>(&self, input: SystemInput<E>) -> Result<Vec<SystemUnit>, Error>
+    where
+        E: Copy + e::Environment,
+    {
+        let vars = input.vars();
+
+        let SystemInput {
+            root,
+            base_dirs,
+            data,
+            allocator,
+            file_system,
+            state,
+            environment,
+            ..
+        } = input;
+
+        let dir = self
+            .dir
+            .as_path(root, base_dirs, vars, environment)?
+            .ok_or_else(|| anyhow!("Cannot render `dir`: {}", self.dir))?;
+
+        let versions = data
+            .load::<BTreeMap<String, String>>(&self.key)?
+            .unwrap_or_default();
+
+        let mut units = Vec::new();
+
+        for (name, tool) in &self.tools {
+            // NB: tools might only be used on some machines.
+            let version = match versions.get(name) {
+                Some(version) => version,
+                None => continue,
+            };
+
+            if !is_valid_component(name) || !is_valid_component(version) {
+                bail!("Bad version `{}` of tool `{}`", version, name);
+            }
+
+            let tool_dir = dir.join(name);
+            let version_dir = tool_dir.join(version);
+            let id = format!("toolchain/{}/{}", name, version);
+
+            let mut install = None;
+
+            if !state.has_run_once(&id) || !version_dir.is_dir() {
+                let url = with_version(&tool.url, version)
+                    .with_context(|| anyhow!("illegal `url` for tool `{}`", name))?;
+                let sha256 = tool.sha256.get(version).cloned();
+                let checksums = tool
+                    .checksums
+                    .as_ref()
+                    .map(|checksums| with_version(checksums, version))
+                    .transpose()
+                    .with_context(|| anyhow!("illegal `checksums` for tool `{}`", name))?;
+
+                if sha256.is_none() && checksums.is_none() {
+                    bail!(
+                        "Tool `{}` needs `checksums` or `sha256` for version {} to verify it",
+                        name,
+                        version
+                    );
+                }
+
+                let mut unit = allocator.unit(unit::InstallToolchain {
+                    id,
+                    name: name.clone(),
+                    version: version.clone(),
+                    url,
+                    sha256,
+                    checksums,
+                    dir: version_dir.clone(),
+                });
+
+                if !tool_dir.is_dir() {
+                    units.extend(file_system.create_dir_all(&tool_dir)?);
+                    unit.dependencies
+                        .push(file_system.dir_dependency(&tool_dir)?);
+                }
+
+                install = Some(unit.id);
+                units.push(unit);
+            }
+
+            let current = tool_dir.join("current");
+            // NB: relative, so that the directory can be moved.
+            let link = PathBuf::from(version);
+
+            let symlink = match FileSystem::try_open_meta(&current)? {
+                // NB: switching versions is expected, so an outdated link is replaced without
+                // `--force`.
+                Some(meta) if meta.file_type().is_symlink() => {
+                    if fs::read_link(&current)? == link {
+                        None
+                    } else {
+                        Some(allocator.unit(unit::Symlink {
+                            remove: true,
+                            path: current,
+                            link,
+                        }))
+                    }
+                }
+                meta => file_system.symlink(&current, link, meta.as_ref(), state)?,
+            };
+
+            if let Some(mut symlink) = symlink {
+                symlink.dependencies.extend(install.map(Dependency::Unit));
+                units.push(symlink);
+            }
+        }
+
+        Ok(units)
+    }
+}
+
+impl fmt::Display for Toolchain {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(fmt, "toolchains from `{}` to {}", self.key, self.dir)
+    }
+}
+
+/// Substitute `{version}` in the given URL, and make sure that it's valid.
+fn with_version(url: &str, version: &str) -> Result<String, Error> {
+    let url = url.replace("{version}", version);
+    reqwest::Url::parse(&url)?;
+    Ok(url)
+}
+
+/// Test if the given name can be used as a directory name, without going anywhere else.
+fn is_valid_component(name: &str) -> bool {
+    !matches!(name, "" | "." | ".." | "current") && !name.contains(['/', '\\'])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{is_valid_component, with_version};
+
+    #[test]
+    fn test_is_valid_component() {
+        assert!(is_valid_component("1.29.0"));
+        assert!(is_valid_component("v20.11.0"));
+        assert!(!is_valid_component("../etc"));
+        assert!(!is_valid_component("current"));
+        assert!(!is_valid_component(""));
+    }
+
+    #[test]
+    fn test_with_version() {
+        assert_eq!(
+            with_version("https://example.com/v{version}/tool-{version}.zip", "1.2.0").unwrap(),
+            "https://example.com/v1.2.0/tool-1.2.0.zip"
+        );
+        assert!(with_version("example.com/{version}", "1.2.0").is_err());
+    }
+}
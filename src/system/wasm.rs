@@ -0,0 +1,216 @@
+use crate::{
+    environment as e,
+    system::{plugin, SystemInput},
+    unit::SystemUnit,
+};
+use anyhow::{anyhow, bail, Context as _, Error};
+use std::fmt;
+use std::fs;
+
+/// Directory in the configuration repo where modules are looked up.
+const PLUGINS_DIR: &str = "plugins";
+
+/// How much fuel a module gets, so that one which loops forever fails instead of hanging.
+const FUEL: u64 = 10_000_000_000;
+
+system_struct! {
+    #[doc = "Runs a WASM module which decides which files to write and commands to run."]
+    Wasm {
+        #[doc="Name of the module in the `plugins` directory of the repo."]
+        pub module: String,
+        #[doc="Configuration passed as-is to the module."]
+        #[serde(default)]
+        pub config: serde_yaml::Value,
+    }
+}
+
+impl Wasm {
+    system_defaults!(translate);
+
+    /// Run the module and translate the units it emitted.
+    pub fn apply<E>(&self, input: SystemInput<E>) -> Result<Vec<SystemUnit>, Error>
+    where
+        E: Copy + e::Environment,
+    {
+        let path = input.root.join(PLUGINS_DIR).join(&self.module);
+
+        if !path.is_file() {
+            bail!("Missing module: {}", path.display());
+        }
+
+        let module = fs::read(&path)?;
+        let request = plugin::request(&input, &self.module, &self.config)?;
+
+        let units = run(&self.module, &module, request, FUEL)
+            .with_context(|| anyhow!("Failed to run module: {}", path.display()))?;
+
+        plugin::translate(input, &self.module, units)
+    }
+}
+
+impl fmt::Display for Wasm {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(fmt, "wasm module `{}`", self.module)
+    }
+}
+
+#[cfg(not(feature = "wasmtime"))]
+fn run(_: &str, _: &[u8], _: Vec<u8>, _: u64) -> Result<Vec<plugin::UnitRequest>, Error> {
+    bail!("quickcfg was built without support for WASM modules")
+}
+
+/// Run the `apply` function of a module with the given amount of fuel, with the request available
+/// to it, and collect the units it emitted.
+///
+/// Modules can only use the functions in the `quickcfg` namespace, so they can't access the file
+/// system or the network on their own:
+///
+/// * `input_len() -> i32` - the length of the JSON request.
+/// * `input(ptr: i32)` - copy the JSON request to the given location in memory.
+/// * `emit(ptr: i32, len: i32)` - emit a unit, encoded as JSON.
+/// * `log(ptr: i32, len: i32)` - log a message.
+#[cfg(feature = "wasmtime")]
+fn run(
+    name: &str,
+    module: &[u8],
+    input: Vec<u8>,
+    fuel: u64,
+) -> Result<Vec<plugin::UnitRequest>, Error> {
+    use std::convert::TryFrom as _;
+    use wasmtime::{format_err, Caller, Config, Engine, Linker, Module, Store};
+
+    struct Host {
+        name: String,
+        input: Vec<u8>,
+        units: Vec<plugin::UnitRequest>,
+    }
+
+    /// Access the range of the exported memory of the calling module.
+    fn memory<'a>(
+        caller: &'a mut Caller<'_, Host>,
+        ptr: i32,
+        len: usize,
+    ) -> wasmtime::Result<(&'a mut [u8], &'a mut Host)> {
+        let memory = caller
+            .get_export("memory")
+            .and_then(|e| e.into_memory())
+            .ok_or_else(|| format_err!("Module doesn't export `memory`"))?;
+
+        let (data, host) = memory.data_and_store_mut(caller);
+        let start = usize::try_from(ptr)?;
+
+        let data = start
+            .checked_add(len)
+            .and_then(move |end| data.get_mut(start..end))
+            .ok_or_else(|| format_err!("Out of bounds memory access"))?;
+
+        Ok((data, host))
+    }
+
+    let mut config = Config::new();
+    config.consume_fuel(true);
+
+    let engine = Engine::new(&config)?;
+    let module = Module::new(&engine, module)?;
+
+    let host = Host {
+        name: name.to_string(),
+        input,
+        units: Vec::new(),
+    };
+
+    let mut store = Store::new(&engine, host);
+    store.set_fuel(fuel)?;
+
+    let mut linker = Linker::new(&engine);
+
+    linker.func_wrap(
+        "quickcfg",
+        "input_len",
+        |caller: Caller<'_, Host>| -> wasmtime::Result<i32> {
+            Ok(i32::try_from(caller.data().input.len())?)
+        },
+    )?;
+
+    linker.func_wrap(
+        "quickcfg",
+        "input",
+        |mut caller: Caller<'_, Host>, ptr: i32| -> wasmtime::Result<()> {
+            let len = caller.data().input.len();
+            let (data, host) = memory(&mut caller, ptr, len)?;
+            data.copy_from_slice(&host.input);
+            Ok(())
+        },
+    )?;
+
+    linker.func_wrap(
+        "quickcfg",
+        "emit",
+        |mut caller: Caller<'_, Host>, ptr: i32, len: i32| -> wasmtime::Result<()> {
+            let (data, host) = memory(&mut caller, ptr, usize::try_from(len)?)?;
+            let unit =
+                serde_json::from_slice(data).map_err(|e| format_err!("Bad unit emitted: {}", e))?;
+            host.units.push(unit);
+            Ok(())
+        },
+    )?;
+
+    linker.func_wrap(
+        "quickcfg",
+        "log",
+        |mut caller: Caller<'_, Host>, ptr: i32, len: i32| -> wasmtime::Result<()> {
+            let (data, host) = memory(&mut caller, ptr, usize::try_from(len)?)?;
+            log::info!("{}: {}", host.name, String::from_utf8_lossy(data));
+            Ok(())
+        },
+    )?;
+
+    let instance = linker.instantiate(&mut store, &module)?;
+    let apply = instance.get_typed_func::<(), ()>(&mut store, "apply")?;
+    apply.call(&mut store, ())?;
+
+    Ok(store.into_data().units)
+}
+
+#[cfg(all(test, feature = "wasmtime"))]
+mod tests {
+    use super::run;
+    use crate::system::plugin::UnitRequest;
+
+    #[test]
+    fn test_run() {
+        let module = r#"
+            (module
+                (import "quickcfg" "input_len" (func $input_len (result i32)))
+                (import "quickcfg" "input" (func $input (param i32)))
+                (import "quickcfg" "emit" (func $emit (param i32 i32)))
+                (memory (export "memory") 1)
+                (data (i32.const 0) "{\"type\": \"command\", \"command\": [\"true\"]}")
+                (func (export "apply")
+                    ;; echo the input back as the command of a second unit.
+                    (call $emit (i32.const 0) (i32.const 40))
+                    (call $input (i32.const 1024))
+                    (call $emit (i32.const 1024) (call $input_len))))
+        "#;
+
+        let input = br#"{"type": "command", "command": ["echo"], "once": "hi"}"#.to_vec();
+        let units = run("test", module.as_bytes(), input, 1_000_000).unwrap();
+
+        assert_eq!(
+            units,
+            vec![
+                UnitRequest::Command {
+                    command: vec![String::from("true")],
+                    once: None,
+                },
+                UnitRequest::Command {
+                    command: vec![String::from("echo")],
+                    once: Some(String::from("hi")),
+                },
+            ]
+        );
+
+        let looping = r#"(module (func (export "apply") (loop (br 0))))"#;
+        assert!(run("test", looping.as_bytes(), Vec::new(), 1_000_000).is_err());
+    }
+}
@@ -1,11 +1,84 @@
 //! Model for template variables.
 use crate::environment::Environment;
+use crate::{Data, Facts};
 use anyhow::{anyhow, bail, Error};
 use directories::BaseDirs;
+use fxhash::FxHashMap;
+use handlebars::{handlebars_helper, Handlebars, JsonValue};
 use relative_path::{RelativePath, RelativePathBuf};
 use serde::de;
+use std::collections::BTreeMap;
 use std::fmt;
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Mutex, OnceLock};
+
+/// Rendered templates, keyed by a hash of the template and the variables it was rendered with.
+static RENDERED: OnceLock<Mutex<FxHashMap<blake3::Hash, String>>> = OnceLock::new();
+
+/// Number of templates which were rendered.
+static RENDERED_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+/// Number of templates which were taken from the cache instead of being rendered.
+static CACHED_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+/// How many templates were rendered, and how many were taken from the cache.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct RenderStats {
+    pub rendered: usize,
+    pub cached: usize,
+}
+
+impl RenderStats {
+    /// Templates rendered since the given stats were taken.
+    pub fn since(self, earlier: RenderStats) -> RenderStats {
+        RenderStats {
+            rendered: self.rendered - earlier.rendered,
+            cached: self.cached - earlier.cached,
+        }
+    }
+}
+
+/// How many templates have been rendered by this process.
+pub fn render_stats() -> RenderStats {
+    RenderStats {
+        rendered: RENDERED_COUNT.load(Ordering::Relaxed),
+        cached: CACHED_COUNT.load(Ordering::Relaxed),
+    }
+}
+
+/// Render the given template source with the variables identified by `context`, unless it has
+/// already been rendered with the same variables.
+///
+/// See [Vars::context_key] for how variables are identified.
+pub fn render_cached<F>(source: &str, context: blake3::Hash, render: F) -> Result<String, Error>
+where
+    F: FnOnce() -> Result<String, Error>,
+{
+    let mut hasher = blake3::Hasher::new();
+    hasher.update(source.as_bytes());
+    hasher.update(context.as_bytes());
+    let key = hasher.finalize();
+
+    let rendered = RENDERED.get_or_init(Default::default);
+
+    if let Some(value) = rendered.lock().unwrap_or_else(|e| e.into_inner()).get(&key) {
+        CACHED_COUNT.fetch_add(1, Ordering::Relaxed);
+        return Ok(value.clone());
+    }
+
+    // NB: rendered without holding the lock, so the same template might be rendered more than
+    // once when it's first used from many threads.
+    let value = render()?;
+    RENDERED_COUNT.fetch_add(1, Ordering::Relaxed);
+
+    rendered
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .insert(key, value.clone());
+
+    Ok(value)
+}
 
 /// A loaded template string.
 #[derive(Debug, PartialEq, Eq)]
@@ -23,6 +96,7 @@ impl fmt::Display for Template {
                 Static(ref string) => string.fmt(fmt)?,
                 Variable(ref var) => write!(fmt, "{{{}}}", var)?,
                 Environ(ref env) => write!(fmt, "${}", env)?,
+                Expression(ref expr) => expr.fmt(fmt)?,
             }
         }
 
@@ -41,16 +115,171 @@ enum Part {
     Variable(String),
     /// An environment variable.
     Environ(String),
+    /// A handlebars expression, like `{{default foo "bar"}}` or a `{{#if}}` block.
+    Expression(String),
 }
 
 /// Trait to access variables.
 pub trait Vars {
     /// Access a variable used for expansion.
     fn get(&self, k: &str) -> Option<&str>;
+
+    /// Build the context used when rendering expressions.
+    fn to_json(&self) -> JsonValue;
+
+    /// Key which identifies the context built by [Vars::to_json], so that rendered templates can
+    /// be cached without building it.
+    fn context_key(&self) -> blake3::Hash {
+        blake3::hash(self.to_json().to_string().as_bytes())
+    }
+}
+
+/// Facts, vars and data serialized for rendering, which are the same for every template in a run
+/// and are only serialized once.
+///
+/// Must only be shared by scopes with the same facts, vars and data.
+#[derive(Default)]
+pub struct Context {
+    base: OnceLock<(JsonValue, blake3::Hash)>,
+}
+
+/// Variables available when rendering templates.
+///
+/// Facts and user-defined vars are available both at the top level and under `facts` and `vars`
+/// respectively, and the merged hierarchy is available under `data`. Vars take precedence over
+/// facts with the same name.
+///
+/// Systems with `for_each` have the current item available under `item`.
+#[derive(Clone, Copy)]
+pub struct Scope<'a> {
+    /// Facts about the current system.
+    pub facts: &'a Facts,
+    /// User-defined vars from the configuration.
+    pub vars: &'a BTreeMap<String, String>,
+    /// Data loaded from the hierarchy.
+    pub data: &'a Data,
+    /// The current item of a system with `for_each`.
+    pub item: Option<&'a JsonValue>,
+    /// The serialized facts, vars and data.
+    pub context: &'a Context,
+}
+
+impl Scope<'_> {
+    /// Access the serialized facts, vars and data, serializing them if this is the first use.
+    fn base(&self) -> &(JsonValue, blake3::Hash) {
+        self.context.base.get_or_init(|| {
+            let facts = (&self.facts).to_json();
+            let vars = handlebars::to_json(self.vars);
+
+            let mut object = match facts.clone() {
+                JsonValue::Object(object) => object,
+                _ => Default::default(),
+            };
+
+            if let JsonValue::Object(vars) = &vars {
+                object.extend(vars.clone());
+            }
+
+            object.insert("facts".to_string(), facts);
+            object.insert("vars".to_string(), vars);
+            object.insert("data".to_string(), self.data.to_json());
+
+            let json = JsonValue::Object(object);
+            let key = blake3::hash(json.to_string().as_bytes());
+            (json, key)
+        })
+    }
+}
+
+impl Vars for Scope<'_> {
+    fn get(&self, k: &str) -> Option<&str> {
+        if let Some(item) = self.item {
+            // NB: only strings can be used as variables, anything else needs an expression.
+            let value = match k.strip_prefix("item") {
+                Some("") => Some(item),
+                Some(field) => field.strip_prefix('.').and_then(|field| item.get(field)),
+                None => None,
+            };
+
+            if let Some(value) = value {
+                return value.as_str();
+            }
+        }
+
+        match self.vars.get(k) {
+            Some(value) => Some(value.as_str()),
+            None => self.facts.get(k),
+        }
+    }
+
+    fn to_json(&self) -> JsonValue {
+        let mut json = self.base().0.clone();
+
+        if let (JsonValue::Object(object), Some(item)) = (&mut json, self.item) {
+            object.insert("item".to_string(), item.clone());
+        }
+
+        json
+    }
+
+    fn context_key(&self) -> blake3::Hash {
+        let (_, key) = self.base();
+
+        let item = match self.item {
+            Some(item) => item,
+            None => return *key,
+        };
+
+        let mut hasher = blake3::Hasher::new();
+        hasher.update(key.as_bytes());
+        hasher.update(item.to_string().as_bytes());
+        hasher.finalize()
+    }
+}
+
+handlebars_helper!(default: |value: Json, fallback: Json| {
+    if value.is_null() {
+        fallback.clone()
+    } else {
+        value.clone()
+    }
+});
+handlebars_helper!(lower: |value: str| value.to_lowercase());
+handlebars_helper!(upper: |value: str| value.to_uppercase());
+handlebars_helper!(trim: |value: str| value.trim());
+handlebars_helper!(join: |values: array, separator: str| {
+    values
+        .iter()
+        .map(|v| match v {
+            JsonValue::String(s) => s.to_string(),
+            other => other.to_string(),
+        })
+        .collect::<Vec<_>>()
+        .join(separator)
+});
+
+/// Access the handlebars registry used for all templates.
+///
+/// Output is never HTML-escaped, since we are rendering paths and configuration files.
+pub fn registry() -> &'static Handlebars<'static> {
+    static REGISTRY: OnceLock<Handlebars<'static>> = OnceLock::new();
+
+    REGISTRY.get_or_init(|| {
+        let mut reg = Handlebars::new();
+        reg.register_escape_fn(handlebars::no_escape);
+        reg.register_helper("default", Box::new(default));
+        reg.register_helper("lower", Box::new(lower));
+        reg.register_helper("upper", Box::new(upper));
+        reg.register_helper("trim", Box::new(trim));
+        reg.register_helper("join", Box::new(join));
+        reg
+    })
 }
 
 impl Template {
     /// Parse a template string, with variables delimited with `{var}`.
+    ///
+    /// Anything delimited with `{{` and `}}` is treated as a handlebars expression.
     pub fn parse(mut input: &str) -> Result<Template, Error> {
         let mut parts = Vec::new();
 
@@ -65,6 +294,24 @@ impl Template {
 
         while let Some((index, c)) = it.next() {
             match c {
+                '{' if input[index..].starts_with("{{") => {
+                    if index != start {
+                        parts.push(Part::Static(input[start..index].to_string()));
+                    }
+
+                    let end = expression(input, index)?;
+                    let expr = &input[index..end];
+
+                    handlebars::Template::compile(expr)
+                        .map_err(|e| anyhow!("bad expression `{}`: {}", expr, e))?;
+
+                    while it.clone().next().map(|(i, _)| i < end).unwrap_or(false) {
+                        it.next();
+                    }
+
+                    start = end;
+                    parts.push(Part::Expression(expr.to_string()));
+                }
                 '{' => {
                     if index != start {
                         parts.push(Part::Static(input[start..index].to_string()));
@@ -99,28 +346,70 @@ impl Template {
         ) -> Result<(usize, &str), Error> {
             let (start, _) = it.next().ok_or_else(|| anyhow!("missing char"))?;
 
-            while let Some((index, c)) = it.next() {
+            for (index, c) in it {
                 if c == '}' {
-                    let (end, _) = it.next().ok_or_else(|| anyhow!("missing char"))?;
-                    return Ok((end, &input[start..index]));
+                    return Ok((index + 1, &input[start..index]));
                 }
             }
 
-            bail!("missing closing '}'")
+            bail!("missing closing '}}'")
         }
 
-        fn environ(
-            input: &str,
-            mut it: impl Iterator<Item = (usize, char)>,
-        ) -> Result<(usize, &str), Error> {
+        /// Find the end of the expression starting at `start`, including any nested blocks.
+        fn expression(input: &str, start: usize) -> Result<usize, Error> {
+            let mut depth = 0usize;
+            let mut cursor = start;
+
+            loop {
+                let open = match input[cursor..].find("{{") {
+                    Some(open) => cursor + open,
+                    None => bail!("missing closing block in `{}`", &input[start..]),
+                };
+
+                let close = if input[open..].starts_with("{{{") {
+                    "}}}"
+                } else {
+                    "}}"
+                };
+
+                let end = match input[open + close.len()..].find(close) {
+                    Some(end) => open + close.len() + end,
+                    None => bail!("missing closing `{}` in `{}`", close, &input[start..]),
+                };
+
+                let tag = input[open + close.len()..end].trim_start_matches('~');
+
+                if tag.starts_with('#') {
+                    depth += 1;
+                } else if tag.starts_with('/') {
+                    depth = depth
+                        .checked_sub(1)
+                        .ok_or_else(|| anyhow!("unexpected closing block `{}`", tag))?;
+                }
+
+                cursor = end + close.len();
+
+                if depth == 0 {
+                    return Ok(cursor);
+                }
+            }
+        }
+
+        fn environ<'a>(
+            input: &'a str,
+            it: &mut std::str::CharIndices<'_>,
+        ) -> Result<(usize, &'a str), Error> {
             let (start, _) = it.next().ok_or_else(|| anyhow!("missing char"))?;
 
-            for (index, c) in it {
+            // NB: peek, so that the character ending the variable is processed.
+            while let Some((index, c)) = it.clone().next() {
                 match c {
-                    _ if c.is_uppercase() => continue,
-                    '_' => continue,
+                    _ if c.is_uppercase() => (),
+                    '_' => (),
                     _ => return Ok((index, &input[start..index])),
                 }
+
+                it.next();
             }
 
             Ok((input.len(), &input[start..]))
@@ -154,12 +443,16 @@ impl Template {
         environment: impl Environment,
     ) -> Result<Option<PathBuf>, Error> {
         let mut base = Some(root);
+        let home_dir = environment.home_dir();
 
         let protocol = |proto: &str| {
             let b = match proto {
-                "home" => base_dirs
-                    .ok_or_else(|| anyhow!("Base dirs are required for home directory"))?
-                    .home_dir(),
+                "home" => match home_dir {
+                    Some(home_dir) => home_dir,
+                    None => base_dirs
+                        .ok_or_else(|| anyhow!("Base dirs are required for home directory"))?
+                        .home_dir(),
+                },
                 proto => {
                     bail!("Unsupported protocol `{}`", proto);
                 }
@@ -219,6 +512,15 @@ impl Template {
                     Some(value) => out.write_str(value.as_str())?,
                     None => return Ok(None),
                 },
+                Expression(ref expr) => {
+                    let value = render_cached(expr, vars.context_key(), || {
+                        registry()
+                            .render_template(expr, &vars.to_json())
+                            .map_err(|e| anyhow!("failed to render `{}`: {}", expr, e))
+                    })?;
+
+                    out.write_str(&value)?;
+                }
             }
         }
 
@@ -239,9 +541,9 @@ impl<'de> de::Deserialize<'de> for Template {
 #[cfg(test)]
 mod tests {
     use self::Part::*;
-    use super::{Part, Template};
-    use crate::facts::Facts;
-    use std::collections::HashMap;
+    use super::{render_cached, Context, Part, Scope, Template};
+    use crate::{facts::Facts, hierarchy::Data};
+    use std::collections::{BTreeMap, HashMap};
 
     #[test]
     fn test_parse_template() {
@@ -271,4 +573,152 @@ mod tests {
             Some("root/baz/home/bar.yaml".to_string())
         );
     }
+
+    #[test]
+    fn test_variable_boundaries() {
+        let t = Template::parse("{foo}$HOME{foo}").unwrap();
+
+        assert_eq!(
+            t.parts,
+            vec![
+                Variable("foo".to_string()),
+                Environ("HOME".to_string()),
+                Variable("foo".to_string()),
+            ]
+        );
+
+        let facts = Facts::new(vec![("foo".to_string(), "baz".to_string())]);
+
+        let mut environment = HashMap::new();
+        environment.insert("HOME".to_string(), "home".to_string());
+
+        assert_eq!(
+            t.render(&facts, &environment, |_| Ok(())).unwrap(),
+            Some("bazhomebaz".to_string())
+        );
+    }
+
+    #[test]
+    fn test_parse_expression() {
+        let t = Template::parse(
+            "home://{{#if foo}}{{upper foo}}{{else}}none{{/if}}/{{default bar \"x\"}}/$HOME",
+        )
+        .unwrap();
+
+        assert_eq!(
+            t.parts,
+            vec![
+                Protocol("home".to_string()),
+                Expression("{{#if foo}}{{upper foo}}{{else}}none{{/if}}".to_string()),
+                Static("/".to_string()),
+                Expression("{{default bar \"x\"}}".to_string()),
+                Static("/".to_string()),
+                Environ("HOME".to_string()),
+            ]
+        );
+
+        let facts = Facts::new(vec![("foo".to_string(), "baz".to_string())]);
+
+        let mut environment = HashMap::new();
+        environment.insert("HOME".to_string(), "home".to_string());
+
+        assert_eq!(
+            t.render(&facts, &environment, |_| Ok(())).unwrap(),
+            Some("BAZ/x/home".to_string())
+        );
+
+        assert!(Template::parse("{{#if foo}}bar").is_err());
+    }
+
+    #[test]
+    fn test_render_cached() {
+        let renders = std::cell::Cell::new(0);
+
+        let render = |source: &str, context: &str| {
+            render_cached(source, blake3::hash(context.as_bytes()), || {
+                renders.set(renders.get() + 1);
+                Ok(format!("{}:{}", source, context))
+            })
+            .unwrap()
+        };
+
+        assert_eq!("test-render-cached:a", render("test-render-cached", "a"));
+        assert_eq!("test-render-cached:a", render("test-render-cached", "a"));
+        assert_eq!(1, renders.get());
+
+        assert_eq!("test-render-cache:a", render("test-render-cache", "a"));
+        assert_eq!("test-render-cached:b", render("test-render-cached", "b"));
+        assert_eq!(3, renders.get());
+    }
+
+    #[test]
+    fn test_scope_vars() {
+        let facts = Facts::new(vec![
+            ("foo".to_string(), "fact".to_string()),
+            ("bar".to_string(), "fact".to_string()),
+        ]);
+
+        let mut vars = BTreeMap::new();
+        vars.insert("foo".to_string(), "var".to_string());
+
+        let data = Data::new(None, vec![]);
+
+        let scope = Scope {
+            facts: &facts,
+            vars: &vars,
+            data: &data,
+            item: None,
+            context: &Context::default(),
+        };
+
+        let environment = HashMap::new();
+        let t = Template::parse("{foo}/{bar}/{{vars.foo}}/{{facts.foo}}").unwrap();
+
+        assert_eq!(
+            t.render(scope, &environment, |_| Ok(())).unwrap(),
+            Some("var/fact/var/fact".to_string())
+        );
+    }
+
+    #[test]
+    fn test_scope_item() {
+        let facts = Facts::new(vec![("os".to_string(), "linux".to_string())]);
+        let vars = BTreeMap::new();
+        let data = Data::new(None, vec![]);
+        let item = serde_json::json!({"name": "photos", "keep": 7});
+
+        let scope = Scope {
+            facts: &facts,
+            vars: &vars,
+            data: &data,
+            item: Some(&item),
+            context: &Context::default(),
+        };
+
+        let environment = HashMap::new();
+        let t = Template::parse("{item.name}-{os}/{{item.keep}}/{item.keep}").unwrap();
+
+        // NB: the number is missing as a variable, so nothing is rendered.
+        assert_eq!(t.render(scope, &environment, |_| Ok(())).unwrap(), None);
+
+        let t = Template::parse("backup-{item.name}-{os}/{{item.keep}}").unwrap();
+
+        assert_eq!(
+            t.render(scope, &environment, |_| Ok(())).unwrap(),
+            Some("backup-photos-linux/7".to_string())
+        );
+
+        // NB: scopes share the serialized context, but not the item.
+        let other = serde_json::json!({"name": "music", "keep": 3});
+
+        let scope = Scope {
+            item: Some(&other),
+            ..scope
+        };
+
+        assert_eq!(
+            t.render(scope, &environment, |_| Ok(())).unwrap(),
+            Some("backup-music-linux/3".to_string())
+        );
+    }
 }
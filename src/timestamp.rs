@@ -1,4 +1,6 @@
 use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::ops::Sub;
 use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 /// A timestamp.
@@ -11,9 +13,35 @@ impl Timestamp {
         Self(SystemTime::now())
     }
 
+    /// Test if two timestamps are the same, with the precision that they are serialized with.
+    pub fn same_millis(self, other: Self) -> bool {
+        let millis = |t: Self| t.0.duration_since(UNIX_EPOCH).ok().map(|d| d.as_millis());
+        millis(self) == millis(other)
+    }
+
     /// Get the duration since another duration.
     pub fn duration_since(self, other: Self) -> Result<Duration, std::time::SystemTimeError> {
-        Ok(self.0.duration_since(other.0)?)
+        self.0.duration_since(other.0)
+    }
+}
+
+impl From<SystemTime> for Timestamp {
+    fn from(time: SystemTime) -> Self {
+        Self(time)
+    }
+}
+
+impl Sub<Duration> for Timestamp {
+    type Output = Timestamp;
+
+    fn sub(self, duration: Duration) -> Self::Output {
+        Self(self.0.checked_sub(duration).unwrap_or(UNIX_EPOCH))
+    }
+}
+
+impl fmt::Display for Timestamp {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        humantime::format_rfc3339_seconds(self.0).fmt(fmt)
     }
 }
 
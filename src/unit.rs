@@ -1,16 +1,23 @@
 //! A unit of work. Does a single thing and DOES IT WELL.
 
 use crate::{
-    git::GitSystem, hierarchy::Data, os, packages, packages::PackageManager, state::State,
+    facts::Facts,
+    git::{GitSystem, Revision},
+    hierarchy::Data,
+    os, packages,
+    packages::PackageManager,
+    state::{Deployed, DeployedKind, State},
+    state_dir::StateFile,
+    verify::Verify,
     FileSystem, Timestamp,
 };
-use anyhow::{anyhow, Context as _, Error};
-use std::collections::BTreeSet;
+use anyhow::{anyhow, bail, Context as _, Error};
+use std::collections::{BTreeMap, BTreeSet};
 use std::fmt;
 use std::path::{Path, PathBuf};
 use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
-use std::time::SystemTime;
+use std::time::{Duration, SystemTime};
 use thiserror::Error;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
@@ -34,6 +41,23 @@ impl fmt::Display for RenderError {
     }
 }
 
+/// Errors raised when applying units.
+#[derive(Debug, Error)]
+pub enum UnitError {
+    /// The unit took longer than its timeout.
+    #[error("Unit timed out after {timeout:?}: {unit}")]
+    TimedOut { timeout: Duration, unit: String },
+    /// The unit panicked.
+    #[error("Unit panicked: {0}")]
+    Panicked(String),
+    /// A command run by the unit exited with a non-zero status.
+    #[error("failed to run `{}`: status={status}", .path.display())]
+    Status { path: PathBuf, status: i32 },
+    /// The unit has an empty command to run.
+    #[error("empty command")]
+    EmptyCommand,
+}
+
 pub type UnitId = usize;
 
 #[derive(Debug, Default)]
@@ -58,6 +82,10 @@ impl UnitAllocator {
 pub struct UnitInput<'a, 's> {
     /// Primary package manager.
     pub packages: &'a packages::Provider,
+    /// Facts about the system.
+    pub facts: &'a Facts,
+    /// User-defined vars from the configuration.
+    pub vars: &'a BTreeMap<String, String>,
     /// Data loaded from the hierarchy.
     pub data: &'a Data,
     /// Read-only state.
@@ -69,6 +97,70 @@ pub struct UnitInput<'a, 's> {
     pub now: Timestamp,
     /// Current git system.
     pub git_system: &'a dyn GitSystem,
+    /// The id of the system that the unit belongs to.
+    pub system: Option<&'a str>,
+    /// Directory where state is stored.
+    pub state_dir: &'a Path,
+    /// The item of the system with `for_each` that the unit was created for.
+    pub item: Option<&'a serde_json::Value>,
+}
+
+impl<'s> UnitInput<'_, 's> {
+    /// Reborrow the input, so that it can be used more than once.
+    fn reborrow(&mut self) -> UnitInput<'_, 's> {
+        UnitInput {
+            packages: self.packages,
+            facts: self.facts,
+            vars: self.vars,
+            data: self.data,
+            read_state: self.read_state,
+            state: &mut *self.state,
+            now: self.now,
+            git_system: self.git_system,
+            system: self.system,
+            state_dir: self.state_dir,
+            item: self.item,
+        }
+    }
+}
+
+/// Move a path which isn't managed by quickcfg out of the way before replacing it, so that it can
+/// be restored when uninstalling.
+///
+/// Returns the path of the backup, if one was made.
+fn backup(read_state: &State, state_dir: &Path, path: &Path) -> Result<Option<PathBuf>, Error> {
+    use std::fs;
+    use std::hash::{Hash, Hasher};
+
+    if read_state.is_deployed(path) || FileSystem::try_open_meta(path)?.is_none() {
+        return Ok(None);
+    }
+
+    let backups = StateFile::Backups.path(state_dir);
+    fs::create_dir_all(&backups)?;
+
+    let mut state = fxhash::FxHasher64::default();
+    path.hash(&mut state);
+    let name = format!("{:x}", state.finish());
+
+    let mut backup = backups.join(&name);
+    let mut n = 0;
+
+    while FileSystem::try_open_meta(&backup)?.is_some() {
+        n += 1;
+        backup = backups.join(format!("{}.{}", name, n));
+    }
+
+    log::info!("backing up {} to {}", path.display(), backup.display());
+
+    // NB: the state directory might be on a different file system.
+    if fs::rename(path, &backup).is_err() {
+        fs::copy(path, &backup)
+            .with_context(|| anyhow!("failed to back up: {}", path.display()))?;
+        fs::remove_file(path)?;
+    }
+
+    Ok(Some(backup))
 }
 
 /// Declare unit enum.
@@ -82,18 +174,19 @@ macro_rules! unit {
         }
 
         impl Unit {
-            pub fn apply(&self, input: UnitInput) -> Result<(), Error> {
+            pub fn apply(&self, input: UnitInput) -> Result<Outcome, Error> {
                 use self::Unit::*;
 
                 let res = match *self {
                     // do nothing.
-                    System => Ok(()),
+                    System => Ok(Outcome::Unchanged),
                     // do something.
                     $($name(ref unit) => unit.apply(input),)*
                 };
 
-                Ok(res.with_context(|| anyhow!("Failed to run unit: {:?}", self))?)
+                res.with_context(|| anyhow!("Failed to run unit: {:?}", self))
             }
+
         }
 
         impl fmt::Display for Unit {
@@ -118,11 +211,75 @@ unit![
     Install,
     Download,
     AddMode,
+    SetMode,
     RunOnce,
+    RunHook,
+    WriteFile,
+    RunCommand,
     GitClone,
     GitUpdate,
+    SetUserPath,
+    MarkOnce,
+    MarkHash,
+    SelfUpdate,
+    SetGitConfig,
+    InstallToolchain,
+    LoadDconf,
+    WriteSystemFile,
+    StoreCredential,
+    RestoreContexts,
 ];
 
+/// Key which is the same for units that do exactly the same thing, for the kinds of units which
+/// can be merged when several systems emit them.
+#[derive(Debug, PartialEq, Eq, Hash)]
+pub enum DedupKey<'a> {
+    CreateDir(&'a CreateDir),
+    Symlink(&'a Symlink),
+    CopyFile(&'a CopyFile),
+    AddMode(&'a AddMode),
+    SetMode(&'a SetMode),
+    Download(&'a Download),
+}
+
+impl Unit {
+    /// The path that the unit creates or replaces, which needs its SELinux context restored.
+    pub fn written_path(&self) -> Option<&Path> {
+        match self {
+            Unit::CreateDir(CreateDir(path)) => Some(path),
+            Unit::CopyFile(unit) => Some(&unit.to),
+            Unit::CopyTemplate(unit) => Some(&unit.to),
+            Unit::Download(unit) => Some(&unit.path),
+            Unit::WriteFile(unit) => Some(&unit.path),
+            _ => None,
+        }
+    }
+
+    /// The key to merge the unit by, if it's a kind of unit which can be merged.
+    pub fn dedup_key(&self) -> Option<DedupKey<'_>> {
+        match self {
+            Unit::CreateDir(unit) => Some(DedupKey::CreateDir(unit)),
+            Unit::Symlink(unit) => Some(DedupKey::Symlink(unit)),
+            Unit::CopyFile(unit) => Some(DedupKey::CopyFile(unit)),
+            Unit::AddMode(unit) => Some(DedupKey::AddMode(unit)),
+            Unit::SetMode(unit) => Some(DedupKey::SetMode(unit)),
+            Unit::Download(unit) => Some(DedupKey::Download(unit)),
+            _ => None,
+        }
+    }
+}
+
+/// The outcome of running a unit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Outcome {
+    /// The unit changed something.
+    Changed,
+    /// The unit ran, but everything was already up to date.
+    Unchanged,
+    /// The unit didn't run.
+    Skipped,
+}
+
 /// A system unit, which is a unit coupled with a set of dependencies.
 #[derive(Debug)]
 pub struct SystemUnit {
@@ -134,6 +291,24 @@ pub struct SystemUnit {
     pub provides: Vec<Dependency>,
     /// Whether the unit needs access to the main thread. For example, for user input.
     pub thread_local: bool,
+    /// Whether the unit must run on its own, without any other units running at the same time.
+    pub serial: bool,
+    /// Environment variables to set for every command that the unit spawns.
+    pub env: Vec<(String, String)>,
+    /// The item of the system with `for_each` that the unit was created for.
+    pub item: Option<Arc<serde_json::Value>>,
+    /// Units with a lower order are started first when they are ready at the same time.
+    pub order: i32,
+    /// The id of the system that the unit belongs to.
+    pub system: Option<String>,
+    /// The human-readable name of the system that the unit belongs to.
+    pub system_name: Option<String>,
+    /// How many times to retry the unit if it fails.
+    pub retries: u32,
+    /// How long to wait before retrying the unit.
+    pub retry_delay: Duration,
+    /// How long each attempt to run the unit may take before it fails.
+    pub timeout: Option<Duration>,
     /// The unit of work.
     /// Note: box to make it cheaper to move.
     unit: Box<Unit>,
@@ -141,11 +316,13 @@ pub struct SystemUnit {
 
 impl fmt::Display for SystemUnit {
     fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
-        write!(
-            fmt,
-            "unit({:03}): {} (depends: {:?})",
-            self.id, self.unit, self.dependencies
-        )
+        write!(fmt, "unit({:03})", self.id)?;
+
+        if let Some(name) = &self.system_name {
+            write!(fmt, " in `{}`", name)?;
+        }
+
+        write!(fmt, ": {} (depends: {:?})", self.unit, self.dependencies)
     }
 }
 
@@ -157,13 +334,110 @@ impl SystemUnit {
             dependencies: Vec::new(),
             provides: Vec::new(),
             thread_local: false,
+            serial: false,
+            env: Vec::new(),
+            item: None,
+            order: 0,
+            system: None,
+            system_name: None,
+            retries: 0,
+            retry_delay: Duration::default(),
+            timeout: None,
             unit: Box::new(unit.into()),
         }
     }
 
-    /// Apply the unit of work.
-    pub fn apply(&self, input: UnitInput) -> Result<(), Error> {
-        self.unit.apply(input)
+    /// Apply the unit of work, retrying it if it fails.
+    pub fn apply(&self, mut input: UnitInput) -> Result<Outcome, Error> {
+        let mut attempt = 0;
+
+        loop {
+            let result = crate::deadline::with_timeout(self.timeout, || {
+                let result =
+                    crate::command::with_env(&self.env, || self.unit.apply(input.reborrow()));
+
+                match (result, self.timeout) {
+                    (Err(e), Some(timeout)) if crate::deadline::has_passed() => {
+                        Err(e.context(UnitError::TimedOut {
+                            timeout,
+                            unit: self.unit.to_string(),
+                        }))
+                    }
+                    (result, _) => result,
+                }
+            });
+
+            match result {
+                Err(e) if attempt < self.retries => {
+                    attempt += 1;
+
+                    log::warn!(
+                        "{}: failed, retrying in {:?} ({}/{}): {}",
+                        self.unit,
+                        self.retry_delay,
+                        attempt,
+                        self.retries,
+                        e.root_cause()
+                    );
+
+                    std::thread::sleep(self.retry_delay);
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// The path that the unit creates or replaces, if any.
+    pub fn written_path(&self) -> Option<&Path> {
+        self.unit.written_path()
+    }
+
+    /// Key which is the same for units that do exactly the same thing, if they can be merged.
+    pub fn dedup_key(&self) -> Option<DedupKey<'_>> {
+        self.unit.dedup_key()
+    }
+
+    /// Test if this unit marks that a system has applied, either once or for the files it
+    /// depends on.
+    pub fn is_mark(&self) -> bool {
+        matches!(*self.unit, Unit::MarkOnce(..) | Unit::MarkHash(..))
+    }
+
+    /// Access the packages to install, if this is a unit which installs packages.
+    pub fn install_mut(&mut self) -> Option<&mut Install> {
+        match &mut *self.unit {
+            Unit::Install(install) => Some(install),
+            _ => None,
+        }
+    }
+
+    /// The path which the unit writes to, replacing whatever is there.
+    ///
+    /// Anything there which isn't managed by quickcfg is backed up first.
+    pub fn replaces(&self) -> Option<&Path> {
+        match &*self.unit {
+            Unit::CopyFile(unit) if unit.to_exists => Some(&unit.to),
+            Unit::CopyTemplate(unit) if unit.to_exists => Some(&unit.to),
+            Unit::Symlink(unit) if unit.remove => Some(&unit.path),
+            Unit::WriteFile(unit) => Some(&unit.path),
+            _ => None,
+        }
+    }
+
+    /// Test if this is a unit which only exists to wire up dependencies between systems.
+    pub fn is_system(&self) -> bool {
+        matches!(*self.unit, Unit::System)
+    }
+
+    /// The name of the system that the unit belongs to to show to users, or its id if it doesn't
+    /// have a name.
+    pub fn system_label(&self) -> Option<&str> {
+        self.system_name.as_deref().or(self.system.as_deref())
+    }
+
+    /// Describe the unit of work, without its id and dependencies.
+    pub fn describe(&self) -> String {
+        self.unit.to_string()
     }
 }
 
@@ -185,8 +459,8 @@ impl fmt::Display for FromDb {
 }
 
 impl FromDb {
-    fn apply(&self, _: UnitInput) -> Result<(), Error> {
-        Ok(())
+    fn apply(&self, _: UnitInput) -> Result<Outcome, Error> {
+        Ok(Outcome::Unchanged)
     }
 }
 
@@ -197,7 +471,7 @@ impl From<FromDb> for Unit {
 }
 
 /// The configuration to create a single directory.
-#[derive(Debug)]
+#[derive(Debug, PartialEq, Eq, Hash)]
 pub struct CreateDir(pub PathBuf);
 
 impl fmt::Display for CreateDir {
@@ -207,12 +481,25 @@ impl fmt::Display for CreateDir {
 }
 
 impl CreateDir {
-    fn apply(&self, _: UnitInput) -> Result<(), Error> {
+    fn apply(&self, input: UnitInput) -> Result<Outcome, Error> {
         use std::fs;
         let CreateDir(ref dir) = self;
         log::info!("creating dir: {}", dir.display());
         fs::create_dir(dir)?;
-        Ok(())
+
+        input.state.deploy(
+            dir,
+            Deployed {
+                kind: DeployedKind::Dir,
+                system: input.system.map(String::from),
+                modified: None,
+                size: None,
+                link: None,
+                backup: None,
+            },
+        );
+
+        Ok(Outcome::Changed)
     }
 }
 
@@ -223,7 +510,7 @@ impl From<CreateDir> for Unit {
 }
 
 /// The configuration for a unit to copy a single file.
-#[derive(Debug, Hash)]
+#[derive(Debug, PartialEq, Eq, Hash)]
 pub struct CopyFile {
     /// The source file.
     pub from: PathBuf,
@@ -231,6 +518,8 @@ pub struct CopyFile {
     pub from_modified: SystemTime,
     /// The destination file.
     pub to: PathBuf,
+    /// If the destination file exists, its content is compared before it's overwritten.
+    pub to_exists: bool,
 }
 
 impl fmt::Display for CopyFile {
@@ -245,7 +534,7 @@ impl fmt::Display for CopyFile {
 }
 
 impl CopyFile {
-    fn apply(&self, _: UnitInput) -> Result<(), Error> {
+    fn apply(&self, input: UnitInput) -> Result<Outcome, Error> {
         use std::fs::File;
         use std::io;
 
@@ -253,12 +542,37 @@ impl CopyFile {
             ref from,
             ref from_modified,
             ref to,
+            to_exists,
         } = *self;
 
+        let mut deployed = Deployed {
+            kind: DeployedKind::File,
+            system: input.system.map(String::from),
+            modified: Some(Timestamp::from(*from_modified)),
+            size: Some(from.metadata()?.len()),
+            link: None,
+            backup: None,
+        };
+
+        if to_exists && FileSystem::same_contents(from, to)? {
+            // Only the modified time of the file differs, like after a fresh clone.
+            log::info!("touching {}", to.display());
+            FileSystem::touch(to, from_modified)?;
+            input.state.deploy(to, deployed);
+            return Ok(Outcome::Unchanged);
+        }
+
+        deployed.backup = backup(input.read_state, input.state_dir, to)?;
+
         log::info!("{} -> {}", from.display(), to.display());
-        io::copy(&mut File::open(from)?, &mut File::create(to)?)?;
+        FileSystem::write_atomic(to, false, |out| {
+            io::copy(&mut File::open(from)?, out)?;
+            Ok(())
+        })?;
         // make sure timestamp is in sync.
-        FileSystem::touch(&to, from_modified)
+        FileSystem::touch(to, from_modified)?;
+        input.state.deploy(to, deployed);
+        Ok(Outcome::Changed)
     }
 }
 
@@ -293,8 +607,8 @@ impl fmt::Display for CopyTemplate {
 }
 
 impl CopyTemplate {
-    /// Construct the ID for this unit.
-    fn id(&self) -> String {
+    /// Construct the ID that the hash of the rendered template is stored under.
+    pub fn id(&self) -> String {
         use std::hash::{Hash, Hasher};
 
         let mut state = fxhash::FxHasher64::default();
@@ -303,9 +617,10 @@ impl CopyTemplate {
         format!("copy-template/{:x}", state.finish())
     }
 
-    fn apply(&self, input: UnitInput) -> Result<(), Error> {
-        use handlebars::{Context, Handlebars, Output, RenderContext, Renderable, Template};
-        use std::fs::{self, File};
+    fn apply(&self, input: UnitInput) -> Result<Outcome, Error> {
+        use crate::template::{Scope, Vars as _};
+        use handlebars::{Context, JsonValue, Output, RenderContext, Renderable, Template};
+        use std::fs;
         use std::io::{self, Cursor, Write};
 
         let CopyTemplate {
@@ -316,12 +631,26 @@ impl CopyTemplate {
         } = *self;
 
         let UnitInput {
+            facts,
+            vars,
             data,
             read_state,
             state,
+            system,
+            state_dir,
+            item,
             ..
         } = input;
 
+        let mut deployed = Deployed {
+            kind: DeployedKind::File,
+            system: system.map(String::from),
+            modified: Some(Timestamp::from(*from_modified)),
+            size: None,
+            link: None,
+            backup: None,
+        };
+
         // We do some extra work in here that we would usually split up into more units.
         // The reason is that we can't efficiently determine if that work should be done without
         // doing a lot of it up front.
@@ -329,10 +658,10 @@ impl CopyTemplate {
         // This includes:
         // * Reading the template file to determine which database variables to use.
 
-        let content = fs::read_to_string(&from)
+        let content = fs::read_to_string(from)
             .map_err(|e| anyhow!("failed to read path: {}: {}", from.display(), e))?;
 
-        let data = data.load_from_spec(&content).map_err(|e| {
+        let spec = data.load_from_spec(&content).map_err(|e| {
             anyhow!(
                 "failed to load hierarchy for path: {}: {}",
                 from.display(),
@@ -340,34 +669,66 @@ impl CopyTemplate {
             )
         })?;
 
+        // Variables from the spec are available at the top level, next to `facts` and `data`.
+        let mut context = Scope {
+            facts,
+            vars,
+            data,
+            item,
+            context: &Default::default(),
+        }
+        .to_json();
+
+        if let (JsonValue::Object(context), JsonValue::Object(spec)) =
+            (&mut context, handlebars::to_json(&spec))
+        {
+            context.extend(spec);
+        }
+
         let id = self.id();
-        let hash = (&data, &content);
+        let context_string = context.to_string();
+        let hash = (&context_string, &content);
 
-        if to_exists && read_state.is_hash_fresh(&id, &hash)? {
+        if to_exists && read_state.is_hash_fresh(&id, hash)? {
             // Nothing about the template would change, only update the modified time of the file.
             log::info!("touching {}", to.display());
             // only need to update timestamp.
-            return FileSystem::touch(&to, from_modified);
+            FileSystem::touch(to, from_modified)?;
+            deployed.size = Some(to.metadata()?.len());
+            state.deploy(to, deployed);
+            return Ok(Outcome::Unchanged);
         }
 
-        let reg = Handlebars::new();
+        deployed.backup = backup(read_state, state_dir, to)?;
+
+        // NB: the same template might be copied to many places with the same variables.
+        let key = blake3::hash(context_string.as_bytes());
+
+        let out = crate::template::render_cached(&content, key, || {
+            let reg = crate::template::registry();
+
+            let mut out = Vec::<u8>::new();
 
-        let mut out = Vec::<u8>::new();
+            let mut tpl = Template::compile2(&content, true)?;
+            tpl.name = Some(from.display().to_string());
 
-        let mut tpl = Template::compile2(&content, true)?;
-        tpl.name = Some(from.display().to_string());
+            tpl.render(
+                reg,
+                &Context::wraps(&context)?,
+                &mut RenderContext::new(None),
+                &mut WriteOutput::new(Cursor::new(&mut out)),
+            )?;
 
-        tpl.render(
-            &reg,
-            &Context::wraps(&data)?,
-            &mut RenderContext::new(None),
-            &mut WriteOutput::new(Cursor::new(&mut out)),
-        )?;
+            Ok(String::from_utf8(out)?)
+        })?;
 
         log::info!("{} -> {} (template)", from.display(), to.display());
-        File::create(&to)?.write_all(&out)?;
-        state.touch_hash(&id, &hash)?;
-        return FileSystem::touch(&to, from_modified);
+        FileSystem::write_atomic(to, false, |f| Ok(f.write_all(out.as_bytes())?))?;
+        state.touch_hash(&id, hash)?;
+        FileSystem::touch(to, from_modified)?;
+        deployed.size = Some(out.len() as u64);
+        state.deploy(to, deployed);
+        return Ok(Outcome::Changed);
 
         pub struct WriteOutput<W: Write> {
             write: W,
@@ -394,7 +755,7 @@ impl From<CopyTemplate> for Unit {
 }
 
 /// The configuration for a unit to create a symlink.
-#[derive(Debug)]
+#[derive(Debug, PartialEq, Eq, Hash)]
 pub struct Symlink {
     /// `true` if the destination file needs to be removed.
     pub remove: bool,
@@ -415,9 +776,68 @@ impl fmt::Display for Symlink {
     }
 }
 
+/// How a symlink was created.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Linked {
+    /// As a symlink.
+    Symlink,
+    /// As a directory junction, since symlinks aren't available.
+    Junction,
+    /// As a copy of the file it links to, since symlinks aren't available.
+    Copy,
+}
+
 impl Symlink {
-    fn apply(&self, _: UnitInput) -> Result<(), Error> {
-        os::create_symlink(self)
+    /// The path that the link points to, where a relative link is resolved from the directory
+    /// that the symlink is in.
+    pub fn target(&self) -> PathBuf {
+        FileSystem::resolve_link(&self.path, &self.link)
+    }
+
+    fn apply(&self, input: UnitInput) -> Result<Outcome, Error> {
+        let backup = if self.remove {
+            backup(input.read_state, input.state_dir, &self.path)?
+        } else {
+            None
+        };
+
+        let fallback = input.state.config.symlink_fallback;
+
+        let linked = if backup.is_some() {
+            // NB: the existing link has been moved out of the way.
+            let symlink = Symlink {
+                remove: false,
+                path: self.path.clone(),
+                link: self.link.clone(),
+            };
+
+            os::create_symlink(&symlink, fallback)?
+        } else {
+            os::create_symlink(self, fallback)?
+        };
+
+        // NB: a copy is tracked like a file, so that we can tell if it has been modified.
+        let (kind, modified) = match linked {
+            Linked::Copy => (
+                DeployedKind::File,
+                Some(Timestamp::from(self.path.metadata()?.modified()?)),
+            ),
+            Linked::Symlink | Linked::Junction => (DeployedKind::Symlink, None),
+        };
+
+        input.state.deploy(
+            &self.path,
+            Deployed {
+                kind,
+                system: input.system.map(String::from),
+                modified,
+                size: None,
+                link: Some(self.link.clone()),
+                backup,
+            },
+        );
+
+        Ok(Outcome::Changed)
     }
 }
 
@@ -448,8 +868,10 @@ impl fmt::Display for Install {
 }
 
 impl Install {
-    fn apply(&self, input: UnitInput) -> Result<(), Error> {
-        let UnitInput { state, .. } = input;
+    fn apply(&self, input: UnitInput) -> Result<Outcome, Error> {
+        let UnitInput {
+            packages, state, ..
+        } = input;
 
         let Install {
             ref package_manager,
@@ -458,14 +880,31 @@ impl Install {
             ref id,
         } = *self;
 
+        let mut outcome = Outcome::Unchanged;
+
         if !to_install.is_empty() {
             let names = to_install.join(", ");
             log::info!("Installing packages for `{}`: {}", id, names);
-            package_manager.install_packages(to_install)?;
+            // NB: the cached listing is stale even if installing fails part of the way through.
+            packages.invalidate(package_manager.name());
+            let result = package_manager.install_packages(to_install);
+
+            if package_manager.needs_interaction() && crate::command::is_non_interactive() {
+                result.with_context(|| {
+                    anyhow!(
+                        "`{}` might need a password for sudo, which can't be asked for in non-interactive mode",
+                        package_manager.name()
+                    )
+                })?;
+            } else {
+                result?;
+            }
+
+            outcome = Outcome::Changed;
         }
 
-        state.touch_hash(id, &all_packages)?;
-        Ok(())
+        state.touch_hash(id, all_packages)?;
+        Ok(outcome)
     }
 }
 
@@ -476,11 +915,13 @@ impl From<Install> for Unit {
 }
 
 /// Download the given URL as an executable and write to the given path.
-#[derive(Debug)]
+#[derive(Debug, PartialEq, Eq, Hash)]
 pub struct Download {
     pub url: reqwest::Url,
     pub path: PathBuf,
     pub id: Option<Box<str>>,
+    /// How to verify the download before it's written to the path.
+    pub verify: Verify,
 }
 
 impl fmt::Display for Download {
@@ -490,26 +931,35 @@ impl fmt::Display for Download {
 }
 
 impl Download {
-    fn apply(&self, input: UnitInput) -> Result<(), Error> {
-        use std::fs::File;
+    fn apply(&self, input: UnitInput) -> Result<Outcome, Error> {
         let UnitInput { state, .. } = input;
-        let Download { url, path, id } = self;
+        let Download {
+            url,
+            path,
+            id,
+            verify,
+        } = self;
+        let mut outcome = Outcome::Unchanged;
 
         if !path.is_file() {
-            let mut out =
-                File::create(&path).with_context(|| anyhow!("open file: {}", path.display()))?;
+            let sha256 = verify.sha256.as_deref();
 
-            let mut response = reqwest::blocking::get(url.clone())
-                .with_context(|| anyhow!("download url: {}", url))?;
+            // NB: a partial or unverified download would otherwise be mistaken for a complete one.
+            // It's verified as it's written, so that large files aren't held in memory.
+            FileSystem::write_atomic(path, false, |out| {
+                verify.check_stream(url.as_str(), out, |out| {
+                    crate::cache::download(url.as_str(), sha256, out)
+                })
+            })?;
 
-            response.copy_to(&mut out)?;
+            outcome = Outcome::Changed;
         }
 
         if let Some(id) = id {
-            state.touch_once(&id);
+            state.touch_once(id);
         }
 
-        Ok(())
+        Ok(outcome)
     }
 }
 
@@ -528,7 +978,7 @@ pub enum Mode {
 }
 
 /// Change the permissions of the given file.
-#[derive(Debug)]
+#[derive(Debug, PartialEq, Eq, Hash)]
 pub struct AddMode {
     pub path: PathBuf,
     user: u32,
@@ -564,6 +1014,11 @@ impl AddMode {
         false
     }
 
+    /// If the added mode makes the file writable by its owner.
+    pub fn is_user_writable(&self) -> bool {
+        self.user & (Mode::Write as u32) != 0
+    }
+
     /// Modify user mode.
     pub fn user(mut self, mode: Mode) -> Self {
         self.user |= mode as u32;
@@ -606,8 +1061,9 @@ impl fmt::Display for AddMode {
 }
 
 impl AddMode {
-    fn apply(&self, _: UnitInput) -> Result<(), Error> {
-        os::add_mode(self)
+    fn apply(&self, _: UnitInput) -> Result<Outcome, Error> {
+        os::add_mode(self)?;
+        Ok(Outcome::Changed)
     }
 }
 
@@ -617,6 +1073,91 @@ impl From<AddMode> for Unit {
     }
 }
 
+/// Error raised when parsing a file mode.
+#[derive(Debug, Error)]
+#[error("bad mode `{0}`, expected octal permissions like `0600`")]
+pub struct FileModeError(String);
+
+/// Permissions of a file, written in octal like the modes used by `chmod`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct FileMode(u32);
+
+impl FileMode {
+    /// The permission bits of the mode.
+    pub fn bits(self) -> u32 {
+        self.0
+    }
+
+    /// If the owner of the file may write to it.
+    pub fn is_user_writable(self) -> bool {
+        self.0 & 0o200 != 0
+    }
+
+    /// If nobody but the owner of the file may access it.
+    pub fn is_private(self) -> bool {
+        self.0 & 0o077 == 0
+    }
+}
+
+impl std::str::FromStr for FileMode {
+    type Err = FileModeError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let digits = s.trim();
+        let digits = digits.strip_prefix("0o").unwrap_or(digits);
+
+        match u32::from_str_radix(digits, 8) {
+            Ok(bits) if !digits.is_empty() && bits <= 0o7777 => Ok(FileMode(bits)),
+            _ => Err(FileModeError(s.to_string())),
+        }
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for FileMode {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+impl fmt::Display for FileMode {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(fmt, "{:04o}", self.0)
+    }
+}
+
+/// Set the permissions of the given file, replacing the existing ones.
+#[derive(Debug, PartialEq, Eq, Hash)]
+pub struct SetMode {
+    pub path: PathBuf,
+    pub mode: FileMode,
+}
+
+impl fmt::Display for SetMode {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(fmt, "set mode {} on {}", self.mode, self.path.display())
+    }
+}
+
+impl SetMode {
+    fn apply(&self, _: UnitInput) -> Result<Outcome, Error> {
+        if os::set_mode(&self.path, self.mode)? {
+            Ok(Outcome::Changed)
+        } else {
+            Ok(Outcome::Unchanged)
+        }
+    }
+}
+
+impl From<SetMode> for Unit {
+    fn from(value: SetMode) -> Unit {
+        Unit::SetMode(value)
+    }
+}
+
 /// Run the given executable once.
 #[derive(Debug)]
 pub struct RunOnce {
@@ -651,7 +1192,7 @@ impl RunOnce {
     }
 
     /// Apply the unit.
-    fn apply(&self, input: UnitInput) -> Result<(), Error> {
+    fn apply(&self, input: UnitInput) -> Result<Outcome, Error> {
         use crate::command::Command;
         use std::io;
 
@@ -675,15 +1216,15 @@ impl RunOnce {
             .with_context(|| anyhow!("failed to run: {}", path.display()))?;
 
         if status != 0 {
-            return Err(anyhow!(
-                "failed to run `{}`: status={}",
-                path.display(),
-                status
-            ));
+            return Err(UnitError::Status {
+                path: path.to_owned(),
+                status,
+            }
+            .into());
         }
 
-        state.touch_once(&id);
-        return Ok(());
+        state.touch_once(id);
+        return Ok(Outcome::Changed);
 
         #[cfg(windows)]
         fn run_command(
@@ -737,7 +1278,7 @@ impl RunOnce {
             let status = cmd.status()?;
             let code = status
                 .code()
-                .ok_or_else(|| io::Error::new(io::ErrorKind::Other, "no status code"))?;
+                .ok_or_else(|| io::Error::other("no status code"))?;
             Ok(code)
         }
     }
@@ -749,6 +1290,151 @@ impl From<RunOnce> for Unit {
     }
 }
 
+/// Run a hook command of a system using the shell.
+#[derive(Debug)]
+pub struct RunHook {
+    /// The command to run.
+    pub command: String,
+    /// Directory to run the command in.
+    pub working_directory: PathBuf,
+}
+
+impl fmt::Display for RunHook {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(fmt, "run hook `{}`", self.command)
+    }
+}
+
+impl RunHook {
+    /// Apply the unit.
+    fn apply(&self, _: UnitInput) -> Result<Outcome, Error> {
+        use crate::command::Command;
+
+        log::info!("running hook: {}", self.command);
+
+        let mut cmd = if cfg!(windows) {
+            let mut cmd = Command::new("cmd");
+            cmd.arg("/C");
+            cmd
+        } else {
+            let mut cmd = Command::new("/bin/sh");
+            cmd.arg("-c");
+            cmd
+        };
+
+        cmd.arg(&self.command);
+        cmd.working_directory(&self.working_directory);
+        cmd.run_checked()?;
+        Ok(Outcome::Changed)
+    }
+}
+
+impl From<RunHook> for Unit {
+    fn from(value: RunHook) -> Unit {
+        Unit::RunHook(value)
+    }
+}
+
+/// Write the given content to a file.
+#[derive(Debug)]
+pub struct WriteFile {
+    /// The file to write.
+    pub path: PathBuf,
+    /// The content of the file.
+    pub content: Vec<u8>,
+}
+
+impl fmt::Display for WriteFile {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(fmt, "write file {}", self.path.display())
+    }
+}
+
+impl WriteFile {
+    /// Apply the unit.
+    fn apply(&self, input: UnitInput) -> Result<Outcome, Error> {
+        use std::io::Write as _;
+
+        let WriteFile {
+            ref path,
+            ref content,
+        } = *self;
+
+        let backup = backup(input.read_state, input.state_dir, path)?;
+
+        log::info!("writing {}", path.display());
+        FileSystem::write_atomic(path, false, |f| Ok(f.write_all(content)?))?;
+
+        input.state.deploy(
+            path,
+            Deployed {
+                kind: DeployedKind::File,
+                system: input.system.map(String::from),
+                modified: None,
+                size: None,
+                link: None,
+                backup,
+            },
+        );
+
+        Ok(Outcome::Changed)
+    }
+}
+
+impl From<WriteFile> for Unit {
+    fn from(value: WriteFile) -> Unit {
+        Unit::WriteFile(value)
+    }
+}
+
+/// Run a command, optionally only once.
+#[derive(Debug)]
+pub struct RunCommand {
+    /// The program to run, followed by its arguments.
+    pub command: Vec<String>,
+    /// Directory to run the command in.
+    pub working_directory: PathBuf,
+    /// ID to mark once run, if the command should only run once.
+    pub once: Option<String>,
+}
+
+impl fmt::Display for RunCommand {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(fmt, "run `{}`", self.command.join(" "))
+    }
+}
+
+impl RunCommand {
+    /// Apply the unit.
+    fn apply(&self, input: UnitInput) -> Result<Outcome, Error> {
+        use crate::command::Command;
+
+        let (name, args) = match self.command.split_first() {
+            Some(split) => split,
+            None => return Err(UnitError::EmptyCommand.into()),
+        };
+
+        log::info!("running: {}", self.command.join(" "));
+
+        let mut cmd = Command::new(name);
+        cmd.args(args);
+        cmd.working_directory(&self.working_directory);
+        cmd.run_checked()?;
+
+        if let Some(id) = self.once.as_deref() {
+            input.state.touch_once(id);
+        }
+
+        Ok(Outcome::Changed)
+    }
+}
+
+impl From<RunCommand> for Unit {
+    fn from(value: RunCommand) -> Unit {
+        Unit::RunCommand(value)
+    }
+}
+
 /// Run the given executable once.
 #[derive(Debug)]
 pub struct GitClone {
@@ -773,7 +1459,7 @@ impl fmt::Display for GitClone {
 
 impl GitClone {
     /// Apply the unit.
-    fn apply(&self, input: UnitInput) -> Result<(), Error> {
+    fn apply(&self, input: UnitInput) -> Result<Outcome, Error> {
         let UnitInput {
             state, git_system, ..
         } = input;
@@ -786,8 +1472,8 @@ impl GitClone {
 
         log::info!("Cloning `{}` into `{}`", remote, path.display());
         GitSystem::clone(git_system, remote, path)?;
-        state.touch(&id);
-        Ok(())
+        state.touch(id);
+        Ok(Outcome::Changed)
     }
 }
 
@@ -816,7 +1502,7 @@ impl fmt::Display for GitUpdate {
 
 impl GitUpdate {
     /// Apply the unit.
-    fn apply(&self, input: UnitInput) -> Result<(), Error> {
+    fn apply(&self, input: UnitInput) -> Result<Outcome, Error> {
         let UnitInput {
             state, git_system, ..
         } = input;
@@ -828,19 +1514,22 @@ impl GitUpdate {
         } = *self;
 
         let git = git_system.open(path)?;
+        let mut outcome = Outcome::Unchanged;
 
-        if git.needs_update()? {
+        if git.needs_update(Revision::Head)? {
             if force {
                 log::info!("Force updating `{}`", git.path().display());
-                git.force_update()?;
+                git.force_update(Revision::Head)?;
             } else {
                 log::info!("Updating `{}`", git.path().display());
-                git.update()?;
+                git.update(Revision::Head)?;
             }
+
+            outcome = Outcome::Changed;
         }
 
-        state.touch(&id);
-        Ok(())
+        state.touch(id);
+        Ok(outcome)
     }
 }
 
@@ -849,3 +1538,737 @@ impl From<GitUpdate> for Unit {
         Unit::GitUpdate(value)
     }
 }
+
+/// Add directories to the PATH of the user, which is stored in the registry on Windows.
+#[derive(Debug)]
+pub struct SetUserPath {
+    /// Directories to add to the start, in order.
+    pub prepend: Vec<String>,
+    /// Directories to add to the end, in order.
+    pub append: Vec<String>,
+}
+
+impl fmt::Display for SetUserPath {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(fmt, "add directories to user PATH")
+    }
+}
+
+impl SetUserPath {
+    fn apply(&self, _: UnitInput) -> Result<Outcome, Error> {
+        let current = os::user_path()?;
+        let path = merge_path(&current, &self.prepend, &self.append);
+
+        if path == current {
+            return Ok(Outcome::Unchanged);
+        }
+
+        log::info!("setting user PATH to {}", path.join(";"));
+        os::set_user_path(&path)?;
+        Ok(Outcome::Changed)
+    }
+}
+
+impl From<SetUserPath> for Unit {
+    fn from(value: SetUserPath) -> Unit {
+        Unit::SetUserPath(value)
+    }
+}
+
+/// Mark that a system with `once` has applied, which runs after all of its other units.
+#[derive(Debug)]
+pub struct MarkOnce {
+    /// ID to mark once run.
+    pub id: String,
+}
+
+impl fmt::Display for MarkOnce {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(fmt, "mark `{}` as applied once", self.id)
+    }
+}
+
+impl MarkOnce {
+    fn apply(&self, input: UnitInput) -> Result<Outcome, Error> {
+        input.state.touch_once(&self.id);
+        Ok(Outcome::Unchanged)
+    }
+}
+
+impl From<MarkOnce> for Unit {
+    fn from(value: MarkOnce) -> Unit {
+        Unit::MarkOnce(value)
+    }
+}
+
+/// Mark that a system with `depends` has applied for the current hash of the files it depends on,
+/// which runs after all of its other units.
+#[derive(Debug)]
+pub struct MarkHash {
+    /// ID to store the hash under.
+    pub id: String,
+    /// Hash of the files the system depends on.
+    pub hash: String,
+}
+
+impl fmt::Display for MarkHash {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(fmt, "mark `{}` as applied", self.id)
+    }
+}
+
+impl MarkHash {
+    fn apply(&self, input: UnitInput) -> Result<Outcome, Error> {
+        input.state.touch_hash(&self.id, &self.hash)?;
+        Ok(Outcome::Unchanged)
+    }
+}
+
+impl From<MarkHash> for Unit {
+    fn from(value: MarkHash) -> Unit {
+        Unit::MarkHash(value)
+    }
+}
+
+/// Replace the quickcfg binary with the latest release, if it's newer.
+#[derive(Debug)]
+pub struct SelfUpdate {
+    /// ID to mark as updated.
+    pub id: String,
+    /// GitHub repository to take releases from.
+    pub repo: String,
+    /// The minisign public key which releases are signed with.
+    pub public_key: String,
+}
+
+impl fmt::Display for SelfUpdate {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(fmt, "update quickcfg from `{}`", self.repo)
+    }
+}
+
+impl SelfUpdate {
+    fn apply(&self, input: UnitInput) -> Result<Outcome, Error> {
+        let release = crate::self_update::latest(&self.repo)?;
+        let mut outcome = Outcome::Unchanged;
+
+        if release.is_newer() {
+            let exe = crate::self_update::install(&release, &self.public_key)?;
+            log::info!("updated {} to {}", exe.display(), release.version);
+            outcome = Outcome::Changed;
+        }
+
+        input.state.touch(&self.id);
+        Ok(outcome)
+    }
+}
+
+impl From<SelfUpdate> for Unit {
+    fn from(value: SelfUpdate) -> Unit {
+        Unit::SelfUpdate(value)
+    }
+}
+
+/// Set a configuration option in the local configuration of a git repository.
+#[derive(Debug)]
+pub struct SetGitConfig {
+    /// The repository to configure.
+    pub repo: PathBuf,
+    /// The option to set, like `core.hooksPath`.
+    pub key: String,
+    /// The value to set it to.
+    pub value: String,
+}
+
+impl fmt::Display for SetGitConfig {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            fmt,
+            "set git config `{}` in {}",
+            self.key,
+            self.repo.display()
+        )
+    }
+}
+
+impl SetGitConfig {
+    fn apply(&self, _: UnitInput) -> Result<Outcome, Error> {
+        use crate::command::Command;
+
+        let mut get = Command::new(os::command("git"));
+        get.args(&["config", "--local", "--get", &self.key]);
+        get.working_directory(&self.repo);
+        let output = get.run()?;
+
+        // NB: git exits with status 1 if the option isn't set.
+        if output.status.success() && output.stdout.trim() == self.value {
+            return Ok(Outcome::Unchanged);
+        }
+
+        log::info!(
+            "setting git config `{}` in {}",
+            self.key,
+            self.repo.display()
+        );
+
+        let mut set = Command::new(os::command("git"));
+        set.args(&["config", "--local", &self.key, &self.value]);
+        set.working_directory(&self.repo);
+        set.run_checked()?;
+        Ok(Outcome::Changed)
+    }
+}
+
+impl From<SetGitConfig> for Unit {
+    fn from(value: SetGitConfig) -> Unit {
+        Unit::SetGitConfig(value)
+    }
+}
+
+/// Load a dump of dconf settings into a directory, unless they are already set.
+#[derive(Debug)]
+pub struct LoadDconf {
+    /// The directory to load the settings into, like `/org/gnome/terminal/`.
+    pub path: String,
+    /// The file with the dump to load.
+    pub from: PathBuf,
+}
+
+impl fmt::Display for LoadDconf {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            fmt,
+            "load dconf `{}` from {}",
+            self.path,
+            self.from.display()
+        )
+    }
+}
+
+impl LoadDconf {
+    fn apply(&self, _: UnitInput) -> Result<Outcome, Error> {
+        use crate::command::Command;
+        use std::fs;
+
+        let content = fs::read_to_string(&self.from)
+            .map_err(|e| anyhow!("failed to read path: {}: {}", self.from.display(), e))?;
+
+        let mut dump = Command::new(os::command("dconf"));
+        dump.args(&["dump", &self.path]);
+        let current = dump.run_stdout()?;
+
+        // NB: `dconf load` leaves settings which aren't in the dump alone.
+        if is_dconf_subset(&content, &current) {
+            return Ok(Outcome::Unchanged);
+        }
+
+        log::info!("loading dconf `{}` from {}", self.path, self.from.display());
+
+        let mut load = Command::new(os::command("dconf"));
+        load.args(&["load", &self.path]);
+        load.stdin(content);
+        load.run_checked()?;
+        Ok(Outcome::Changed)
+    }
+}
+
+impl From<LoadDconf> for Unit {
+    fn from(value: LoadDconf) -> Unit {
+        Unit::LoadDconf(value)
+    }
+}
+
+/// Write a file which usually lives in a directory only root can write to, like browser policies
+/// or `/etc/hosts`.
+#[derive(Debug)]
+pub struct WriteSystemFile {
+    /// The file to write.
+    pub path: PathBuf,
+    /// The content of the file.
+    pub content: Vec<u8>,
+    /// Write the file in place instead of replacing it, for files like `/etc/hosts` which are bind
+    /// mounted into containers and can't be replaced.
+    pub in_place: bool,
+}
+
+impl fmt::Display for WriteSystemFile {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(fmt, "write system file {}", self.path.display())
+    }
+}
+
+impl WriteSystemFile {
+    fn apply(&self, _: UnitInput) -> Result<Outcome, Error> {
+        use std::fs;
+        use std::io::{self, Write as _};
+
+        let WriteSystemFile {
+            ref path,
+            ref content,
+            in_place,
+        } = *self;
+
+        log::info!("writing {}", path.display());
+
+        let result = (|| -> Result<(), Error> {
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+
+            if in_place {
+                fs::write(path, content)
+                    .with_context(|| anyhow!("Failed to write: {}", path.display()))?;
+                return Ok(());
+            }
+
+            FileSystem::write_atomic(path, false, |f| Ok(f.write_all(content)?))
+        })();
+
+        let e = match result {
+            Ok(()) => return Ok(Outcome::Changed),
+            Err(e) => e,
+        };
+
+        let denied = e
+            .chain()
+            .filter_map(|e| e.downcast_ref::<io::Error>())
+            .any(|e| e.kind() == io::ErrorKind::PermissionDenied);
+
+        if !denied || !cfg!(unix) {
+            return Err(e);
+        }
+
+        sudo_write(path, content)?;
+        return Ok(Outcome::Changed);
+
+        fn sudo_write(path: &Path, content: &[u8]) -> Result<(), Error> {
+            use crate::command::Command;
+
+            const PROMPT: &str = "[sudo] password for %u to write system file: ";
+
+            if let Some(parent) = path.parent() {
+                let mut mkdir = Command::new("sudo");
+                mkdir.args(&["-p", PROMPT, "--", "mkdir", "-p", "--"]);
+                mkdir.arg(parent);
+                mkdir.run_checked()?;
+            }
+
+            let mut tee = Command::new("sudo");
+            tee.args(&["-p", PROMPT, "--", "tee", "--"]);
+            tee.arg(path);
+            tee.stdin(content.to_vec());
+            tee.run_checked()?;
+            Ok(())
+        }
+    }
+}
+
+impl From<WriteSystemFile> for Unit {
+    fn from(value: WriteSystemFile) -> Unit {
+        Unit::WriteSystemFile(value)
+    }
+}
+
+/// Store a secret in the keyring of the operating system, unless it already has one.
+pub struct StoreCredential {
+    /// The service the secret is for.
+    pub service: String,
+    /// The account the secret is for.
+    pub account: String,
+    /// The secret to store.
+    pub secret: String,
+}
+
+// NB: units are printed when they fail, which mustn't include the secret.
+impl fmt::Debug for StoreCredential {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_struct("StoreCredential")
+            .field("service", &self.service)
+            .field("account", &self.account)
+            .finish_non_exhaustive()
+    }
+}
+
+impl fmt::Display for StoreCredential {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            fmt,
+            "store credential for `{}` in `{}`",
+            self.account, self.service
+        )
+    }
+}
+
+impl StoreCredential {
+    fn apply(&self, _: UnitInput) -> Result<Outcome, Error> {
+        let StoreCredential {
+            ref service,
+            ref account,
+            ref secret,
+        } = *self;
+
+        // NB: secrets which already exist are left alone, since they might have been rotated.
+        if os::has_credential(service, account)? {
+            return Ok(Outcome::Unchanged);
+        }
+
+        log::info!("storing credential for `{}` in `{}`", account, service);
+        os::store_credential(service, account, secret)?;
+        Ok(Outcome::Changed)
+    }
+}
+
+impl From<StoreCredential> for Unit {
+    fn from(value: StoreCredential) -> Unit {
+        Unit::StoreCredential(value)
+    }
+}
+
+/// Restore the SELinux contexts of the paths written by a system.
+///
+/// Files which are written through a temporary file or copied from elsewhere otherwise keep a
+/// label which doesn't match where they are, like `~/.ssh` or systemd unit directories, and
+/// whatever reads them is silently denied access.
+#[derive(Debug)]
+pub struct RestoreContexts {
+    /// The paths to restore the contexts of.
+    pub paths: Vec<PathBuf>,
+}
+
+impl fmt::Display for RestoreContexts {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            fmt,
+            "restore SELinux contexts of {} paths",
+            self.paths.len()
+        )
+    }
+}
+
+impl RestoreContexts {
+    fn apply(&self, _: UnitInput) -> Result<Outcome, Error> {
+        use crate::command::Command;
+
+        let mut restorecon = Command::new("restorecon");
+        restorecon.arg("--");
+        restorecon.args(&self.paths);
+
+        if let Err(e) = restorecon.run_checked() {
+            log::warn!("Failed to restore SELinux contexts: {}", e);
+        }
+
+        Ok(Outcome::Unchanged)
+    }
+}
+
+impl From<RestoreContexts> for Unit {
+    fn from(value: RestoreContexts) -> Unit {
+        Unit::RestoreContexts(value)
+    }
+}
+
+/// Test if every setting in the dconf dump `content` has the same value in `current`.
+fn is_dconf_subset(content: &str, current: &str) -> bool {
+    let current = parse_dconf(current);
+
+    parse_dconf(content)
+        .iter()
+        .all(|(section, settings)| match current.get(section) {
+            Some(current) => settings
+                .iter()
+                .all(|(key, value)| current.get(key) == Some(value)),
+            None => settings.is_empty(),
+        })
+}
+
+/// Parse a dconf dump, which is in the INI-like format of `dconf dump`.
+fn parse_dconf(content: &str) -> BTreeMap<&str, BTreeMap<&str, &str>> {
+    let mut sections = BTreeMap::new();
+    let mut section = sections.entry("/").or_insert_with(BTreeMap::new);
+
+    for line in content.lines() {
+        let line = line.trim();
+
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        if let Some(name) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+            section = sections.entry(name.trim()).or_insert_with(BTreeMap::new);
+            continue;
+        }
+
+        if let Some((key, value)) = line.split_once('=') {
+            section.insert(key.trim(), value.trim());
+        }
+    }
+
+    sections
+}
+
+/// Add directories to the start and end of a PATH, removing duplicates and keeping the order of
+/// everything else.
+///
+/// Directories are compared without regard to case, since PATH is only managed like this on
+/// Windows.
+pub fn merge_path(current: &[String], prepend: &[String], append: &[String]) -> Vec<String> {
+    let mut out = Vec::<String>::new();
+
+    let rest = current
+        .iter()
+        .filter(|dir| !append.iter().any(|a| a.eq_ignore_ascii_case(dir)));
+
+    for dir in prepend.iter().chain(rest).chain(append) {
+        if !dir.is_empty() && !out.iter().any(|d| d.eq_ignore_ascii_case(dir)) {
+            out.push(dir.clone());
+        }
+    }
+
+    out
+}
+
+/// Install a version of a tool into its own directory.
+#[derive(Debug)]
+pub struct InstallToolchain {
+    /// ID to mark as installed.
+    pub id: String,
+    /// Name of the tool.
+    pub name: String,
+    /// The version being installed.
+    pub version: String,
+    /// URL to download the tool from, either an archive or the binary itself.
+    pub url: String,
+    /// Expected SHA-256 of the download.
+    pub sha256: Option<String>,
+    /// URL of a file with the SHA-256 of the download, used if `sha256` isn't set.
+    pub checksums: Option<String>,
+    /// Directory to install the version to.
+    pub dir: PathBuf,
+}
+
+impl fmt::Display for InstallToolchain {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            fmt,
+            "install {} {} to {}",
+            self.name,
+            self.version,
+            self.dir.display()
+        )
+    }
+}
+
+impl InstallToolchain {
+    fn apply(&self, input: UnitInput) -> Result<Outcome, Error> {
+        let expected = match (&self.sha256, &self.checksums) {
+            (Some(sha256), _) => sha256.to_lowercase(),
+            (None, Some(checksums)) => {
+                let content = crate::net::client()?.get(checksums)?.text()?;
+
+                find_checksum(&content, &self.url)
+                    .ok_or_else(|| anyhow!("no checksum for {} in {}", self.url, checksums))?
+            }
+            (None, None) => bail!("no checksum to verify {} with", self.url),
+        };
+
+        if crate::archive::is_archive_url(&self.url) {
+            // NB: archives are downloaded next to where they're extracted, so that they're never
+            // held in memory.
+            if let Some(parent) = self.dir.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+
+            let archive = crate::archive::sibling(&self.dir, "download")?;
+
+            let result = crate::archive::download(&self.url, &expected, &archive)
+                .and_then(|()| crate::archive::extract(&self.url, &archive, &self.dir));
+
+            let _ = std::fs::remove_file(&archive);
+            result?;
+        } else {
+            std::fs::create_dir_all(&self.dir)?;
+            let path = self
+                .dir
+                .join(format!("{}{}", self.name, std::env::consts::EXE_SUFFIX));
+
+            let verify = Verify {
+                sha256: Some(expected.clone()),
+                ..Verify::default()
+            };
+
+            FileSystem::write_atomic(&path, false, |out| {
+                verify.check_stream(&self.url, out, |out| {
+                    crate::cache::download(&self.url, Some(&expected), out)
+                })
+            })?;
+
+            os::add_mode(&AddMode::new(&path).user(Mode::Execute))?;
+        }
+
+        log::info!("installed {} {}", self.name, self.version);
+        input.state.touch_once(&self.id);
+        Ok(Outcome::Changed)
+    }
+}
+
+impl From<InstallToolchain> for Unit {
+    fn from(value: InstallToolchain) -> Unit {
+        Unit::InstallToolchain(value)
+    }
+}
+
+/// Find the checksum of the file downloaded from the given URL in a checksum file.
+///
+/// This is either just the checksum, or a list of checksums and file names in the format used by
+/// `sha256sum`.
+fn find_checksum(content: &str, url: &str) -> Option<String> {
+    let path = url.split(['?', '#']).next().unwrap_or(url);
+    let file_name = path.rsplit('/').next().unwrap_or(path);
+
+    let mut lines = content.lines().filter(|line| !line.trim().is_empty());
+
+    for line in lines.clone() {
+        let mut parts = line.split_whitespace();
+
+        let (checksum, name) = match (parts.next(), parts.next()) {
+            (Some(checksum), Some(name)) => (checksum, name),
+            _ => continue,
+        };
+
+        // NB: `sha256sum` marks files that were read in binary mode with a `*`.
+        if name.trim_start_matches('*') == file_name {
+            return crate::archive::parse_checksum(checksum);
+        }
+    }
+
+    match (lines.next(), lines.next()) {
+        (Some(line), None) if line.split_whitespace().count() == 1 => {
+            crate::archive::parse_checksum(line)
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        find_checksum, is_dconf_subset, merge_path, FileMode, Linked, StoreCredential, Symlink,
+    };
+    use crate::{environment as e, facts::Facts, os, template::Template, SymlinkFallback};
+    use std::fs;
+    use std::path::PathBuf;
+
+    #[test]
+    fn test_merge_path() {
+        let strings = |s: &[&str]| s.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+
+        let current = strings(&[r"C:\Windows", r"C:\Tools", "", r"c:\windows", r"C:\Go\bin"]);
+
+        assert_eq!(
+            merge_path(
+                &current,
+                &strings(&[r"C:\Tools"]),
+                &strings(&[r"c:\go\bin"])
+            ),
+            strings(&[r"C:\Tools", r"C:\Windows", r"c:\go\bin"])
+        );
+    }
+
+    #[test]
+    fn test_find_checksum() {
+        let a = "a".repeat(64);
+        let b = "b".repeat(64);
+        let url = "https://example.com/v1.0.0/tool_linux_amd64.zip";
+
+        let sums = format!(
+            "{}  tool_darwin_amd64.zip\n{} *tool_linux_amd64.zip\n",
+            a, b
+        );
+        assert_eq!(find_checksum(&sums, url), Some(b.clone()));
+        assert_eq!(find_checksum(&format!("{}\n", a), url), Some(a.clone()));
+        assert_eq!(find_checksum(&format!("{}  other.zip\n", a), url), None);
+    }
+
+    #[test]
+    fn test_store_credential_debug() {
+        let unit = StoreCredential {
+            service: String::from("github"),
+            account: String::from("udoprog"),
+            secret: String::from("hunter2"),
+        };
+
+        assert!(!format!("{:?}", unit).contains("hunter2"));
+    }
+
+    #[test]
+    fn test_is_dconf_subset() {
+        let content =
+            "[/]\nfont='Monospace 12'\n\n[profiles:/:b1dcc9dd]\nscrollbar-policy='never'\n";
+        let current = "[legacy]\ntheme-variant='dark'\n\n[/]\nfont = 'Monospace 12'\nschema-version=uint32 3\n\n[profiles:/:b1dcc9dd]\nscrollbar-policy='never'\n";
+
+        assert!(is_dconf_subset(content, current));
+        assert!(!is_dconf_subset(content, "[/]\nfont='Monospace 11'\n"));
+        assert!(!is_dconf_subset(content, ""));
+        assert!(is_dconf_subset("", current));
+    }
+
+    #[test]
+    fn test_symlink() -> anyhow::Result<()> {
+        let overlay = std::env::temp_dir().join(format!("quickcfg-symlink-{}", std::process::id()));
+        let fake = e::Fake::new(&overlay);
+        let facts = Facts::new(Vec::new());
+
+        let vim = fake.home().join(".vim");
+        fs::create_dir_all(&vim)?;
+        fs::write(vim.join("vimrc"), "set nocompatible")?;
+
+        let path = Template::parse("home://.vimrc")?
+            .as_path(&overlay, None, &facts, &fake)?
+            .expect("home path");
+
+        let file = Symlink {
+            remove: false,
+            path: path.clone(),
+            link: PathBuf::from(".vim/vimrc"),
+        };
+
+        assert_eq!(vim.join("vimrc"), file.target());
+
+        // NB: copying is allowed, since symlinks need a privilege on Windows.
+        if os::create_symlink(&file, SymlinkFallback::Copy)? == Linked::Symlink {
+            assert_eq!(file.link, fs::read_link(&path)?);
+        }
+
+        assert_eq!("set nocompatible", fs::read_to_string(&path)?);
+
+        let dir = Symlink {
+            remove: false,
+            path: fake.home().join(".config"),
+            link: PathBuf::from(".vim"),
+        };
+
+        assert_eq!(vim, dir.target());
+        os::create_symlink(&dir, SymlinkFallback::Junction)?;
+        assert!(dir.path.join("vimrc").is_file());
+
+        fs::remove_dir_all(&overlay)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_file_mode() {
+        let mode = "0600".parse::<FileMode>().expect("mode");
+        assert_eq!(0o600, mode.bits());
+        assert!(mode.is_user_writable());
+        assert!(mode.is_private());
+        assert_eq!("0600", mode.to_string());
+
+        let mode = "0o444".parse::<FileMode>().expect("mode");
+        assert!(!mode.is_user_writable());
+        assert!(!mode.is_private());
+
+        assert!("".parse::<FileMode>().is_err());
+        assert!("0800".parse::<FileMode>().is_err());
+        assert!("17777".parse::<FileMode>().is_err());
+    }
+}
@@ -0,0 +1,427 @@
+//! Verification of content fetched from the network, against checksums and signatures.
+
+use crate::{command::Command, net};
+use anyhow::{anyhow, Context as _, Error};
+use serde::Deserialize;
+use sha2::{Digest, Sha256, Sha512};
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use thiserror::Error;
+
+/// Counter to give temporary files unique names.
+static TEMP: AtomicUsize = AtomicUsize::new(0);
+
+/// Error raised when fetched content can't be verified.
+#[derive(Debug, Error)]
+pub enum VerifyError {
+    /// The content doesn't have the expected checksum.
+    #[error("{algorithm} mismatch for {url}: expected {expected}, but was {actual}")]
+    Checksum {
+        url: String,
+        algorithm: &'static str,
+        expected: String,
+        actual: String,
+    },
+    /// The minisign signature of the content is invalid.
+    #[error("bad minisign signature for {url}: {reason}")]
+    Minisign { url: String, reason: String },
+    /// The GPG signature of the content is invalid.
+    #[error("bad gpg signature for {url}: {reason}")]
+    Gpg { url: String, reason: String },
+}
+
+/// How to verify fetched content.
+///
+/// Every configured checksum and signature must match.
+#[derive(Deserialize, Debug, Clone, Default, PartialEq, Eq, Hash)]
+#[serde(deny_unknown_fields)]
+pub struct Verify {
+    /// Expected hex-encoded SHA-256 of the content.
+    #[serde(default)]
+    pub sha256: Option<String>,
+    /// Expected hex-encoded SHA-512 of the content.
+    #[serde(default)]
+    pub sha512: Option<String>,
+    /// Verify a minisign signature of the content.
+    #[serde(default)]
+    pub minisign: Option<Minisign>,
+    /// Verify a GPG signature of the content.
+    #[serde(default)]
+    pub gpg: Option<Gpg>,
+}
+
+/// A minisign signature.
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+#[serde(deny_unknown_fields)]
+pub struct Minisign {
+    /// The base64-encoded public key, as printed by `minisign -G`.
+    pub public_key: String,
+    /// URL of the signature. (default: the URL of the content with `.minisig` appended)
+    #[serde(default)]
+    pub signature: Option<String>,
+}
+
+/// A GPG signature.
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+#[serde(deny_unknown_fields)]
+pub struct Gpg {
+    /// URL of the detached signature. (default: the URL of the content with `.asc` appended)
+    #[serde(default)]
+    pub signature: Option<String>,
+    /// Keyring with the keys to trust, instead of the default keyring of the user.
+    #[serde(default)]
+    pub keyring: Option<PathBuf>,
+}
+
+impl Verify {
+    /// Test if nothing is verified.
+    pub fn is_empty(&self) -> bool {
+        self.sha256.is_none()
+            && self.sha512.is_none()
+            && self.minisign.is_none()
+            && self.gpg.is_none()
+    }
+
+    /// Verify the given content, downloaded from the given URL.
+    pub fn check(&self, url: &str, content: &[u8]) -> Result<(), Error> {
+        self.check_stream(url, &mut io::sink(), |out| Ok(out.write_all(content)?))
+    }
+
+    /// Verify content downloaded from the given URL while `write` writes it to `out`.
+    ///
+    /// The content is hashed as it's written, so that large downloads are never held in memory.
+    /// If verification fails, what has been written to `out` must be discarded.
+    pub fn check_stream<F>(&self, url: &str, out: &mut dyn Write, write: F) -> Result<(), Error>
+    where
+        F: FnOnce(&mut dyn Write) -> Result<(), Error>,
+    {
+        let minisign = match &self.minisign {
+            Some(minisign) => Some(minisign.prepare(url)?),
+            None => None,
+        };
+
+        // NB: legacy signatures aren't prehashed, so they can only be verified all at once.
+        let (mut stream, mut legacy) = match &minisign {
+            Some((public_key, signature)) => match public_key.verify_stream(signature) {
+                Ok(stream) => (Some(stream), None),
+                Err(minisign_verify::Error::UnsupportedLegacyMode) => (None, Some(Vec::new())),
+                Err(e) => return Err(minisign_error(url, e.to_string())),
+            },
+            None => (None, None),
+        };
+
+        let spool = match &self.gpg {
+            Some(..) => Some(Spool::create()?),
+            None => None,
+        };
+
+        let mut tee = Tee {
+            out,
+            sha256: self.sha256.as_ref().map(|_| Sha256::new()),
+            sha512: self.sha512.as_ref().map(|_| Sha512::new()),
+            minisign: stream.as_mut(),
+            legacy: legacy.as_mut(),
+            spool: spool.as_ref().map(|spool| &spool.file),
+        };
+
+        write(&mut tee)?;
+        tee.flush()?;
+
+        let Tee { sha256, sha512, .. } = tee;
+
+        if let (Some(expected), Some(digest)) = (&self.sha256, sha256) {
+            check_checksum(url, "sha256", expected, &digest.finalize())?;
+        }
+
+        if let (Some(expected), Some(digest)) = (&self.sha512, sha512) {
+            check_checksum(url, "sha512", expected, &digest.finalize())?;
+        }
+
+        if let Some(stream) = &mut stream {
+            stream
+                .finalize()
+                .map_err(|e| minisign_error(url, e.to_string()))?;
+        }
+
+        if let (Some((public_key, signature)), Some(content)) = (&minisign, &legacy) {
+            public_key
+                .verify(content, signature, true)
+                .map_err(|e| minisign_error(url, e.to_string()))?;
+        }
+
+        if let (Some(gpg), Some(spool)) = (&self.gpg, &spool) {
+            gpg.check(url, &spool.path)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Minisign {
+    /// Decode the public key, and download and decode the signature of the given URL.
+    fn prepare(
+        &self,
+        url: &str,
+    ) -> Result<(minisign_verify::PublicKey, minisign_verify::Signature), Error> {
+        let error = |reason: String| VerifyError::Minisign {
+            url: url.to_string(),
+            reason,
+        };
+
+        let public_key = minisign_verify::PublicKey::from_base64(self.public_key.trim())
+            .map_err(|e| error(format!("bad `public_key`: {}", e)))?;
+
+        let signature_url = signature_url(url, self.signature.as_deref(), ".minisig");
+        let signature = fetch_signature(&signature_url)?;
+        let signature = minisign_verify::Signature::decode(&signature)
+            .map_err(|e| error(format!("bad signature in {}: {}", signature_url, e)))?;
+
+        Ok((public_key, signature))
+    }
+}
+
+impl Gpg {
+    /// Verify the content at the given path, downloaded from the given URL.
+    fn check(&self, url: &str, content: &Path) -> Result<(), Error> {
+        let signature_url = signature_url(url, self.signature.as_deref(), ".asc");
+        let signature = fetch_signature(&signature_url)?;
+
+        let path = temp_path("asc");
+        fs::write(&path, signature)
+            .with_context(|| anyhow!("failed to write signature: {}", path.display()))?;
+
+        let result = self.run_gpg(&path, content);
+        let _ = fs::remove_file(&path);
+
+        let output = result?;
+
+        if !output.status.success() {
+            let reason = output.stderr.trim().to_string();
+
+            return Err(VerifyError::Gpg {
+                url: url.to_string(),
+                reason,
+            }
+            .into());
+        }
+
+        Ok(())
+    }
+
+    /// Run gpg to verify the content at the given path against the signature at the given path.
+    fn run_gpg(&self, signature: &Path, content: &Path) -> Result<crate::command::Output, Error> {
+        let mut gpg = Command::new("gpg");
+        gpg.args(&["--batch", "--no-tty"]);
+
+        if let Some(keyring) = &self.keyring {
+            gpg.arg("--no-default-keyring");
+            gpg.arg("--keyring");
+            gpg.arg(keyring);
+        }
+
+        gpg.arg("--verify");
+        gpg.arg(signature);
+        gpg.arg(content);
+
+        gpg.run().with_context(|| "failed to run gpg")
+    }
+}
+
+/// Writer which passes content through while hashing it.
+struct Tee<'a, 'k> {
+    out: &'a mut dyn Write,
+    sha256: Option<Sha256>,
+    sha512: Option<Sha512>,
+    minisign: Option<&'a mut minisign_verify::StreamVerifier<'k>>,
+    /// Content of a legacy minisign signature, which has to be verified all at once.
+    legacy: Option<&'a mut Vec<u8>>,
+    /// File which the content is copied to for gpg, which verifies it by path.
+    spool: Option<&'a fs::File>,
+}
+
+impl Write for Tee<'_, '_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.out.write(buf)?;
+        let buf = &buf[..n];
+
+        if let Some(sha256) = &mut self.sha256 {
+            sha256.update(buf);
+        }
+
+        if let Some(sha512) = &mut self.sha512 {
+            sha512.update(buf);
+        }
+
+        if let Some(minisign) = &mut self.minisign {
+            minisign.update(buf);
+        }
+
+        if let Some(legacy) = &mut self.legacy {
+            legacy.extend_from_slice(buf);
+        }
+
+        if let Some(mut spool) = self.spool {
+            spool.write_all(buf)?;
+        }
+
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if let Some(mut spool) = self.spool {
+            spool.flush()?;
+        }
+
+        self.out.flush()
+    }
+}
+
+/// A temporary file which is removed when dropped.
+struct Spool {
+    path: PathBuf,
+    file: fs::File,
+}
+
+impl Spool {
+    fn create() -> Result<Self, Error> {
+        let path = temp_path("content");
+        let file = fs::File::create(&path)
+            .with_context(|| anyhow!("failed to create: {}", path.display()))?;
+        Ok(Spool { path, file })
+    }
+}
+
+impl Drop for Spool {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// A unique path for a temporary file with the given extension.
+fn temp_path(extension: &str) -> PathBuf {
+    // NB: units download in parallel, so every file needs a unique name.
+    let n = TEMP.fetch_add(1, Ordering::SeqCst);
+    std::env::temp_dir().join(format!(
+        "quickcfg-{}-{}.{}",
+        std::process::id(),
+        n,
+        extension
+    ))
+}
+
+/// Error raised when content doesn't match its minisign signature.
+fn minisign_error(url: &str, reason: String) -> Error {
+    VerifyError::Minisign {
+        url: url.to_string(),
+        reason,
+    }
+    .into()
+}
+
+/// Compare the given digest against the expected hex-encoded checksum.
+fn check_checksum(
+    url: &str,
+    algorithm: &'static str,
+    expected: &str,
+    digest: &[u8],
+) -> Result<(), VerifyError> {
+    let actual = digest
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect::<String>();
+
+    if !expected.trim().eq_ignore_ascii_case(&actual) {
+        return Err(VerifyError::Checksum {
+            url: url.to_string(),
+            algorithm,
+            expected: expected.trim().to_string(),
+            actual,
+        });
+    }
+
+    Ok(())
+}
+
+/// The URL of a signature, defaulting to the URL of the content with the given extension.
+fn signature_url(url: &str, signature: Option<&str>, extension: &str) -> String {
+    match signature {
+        Some(signature) => signature.to_string(),
+        None => format!("{}{}", url, extension),
+    }
+}
+
+/// Download the signature at the given URL.
+fn fetch_signature(url: &str) -> Result<String, Error> {
+    let response = net::client()?.get(url)?;
+    response
+        .text()
+        .with_context(|| anyhow!("failed to download signature: {}", url))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Verify, VerifyError};
+
+    const HELLO_SHA256: &str = "b94d27b9934d3e08a52e52d7da7dabfac484efe37a5380ee9088f7ace2efcde9";
+
+    #[test]
+    fn test_checksums() {
+        let verify = Verify {
+            sha256: Some(HELLO_SHA256.to_uppercase()),
+            ..Verify::default()
+        };
+
+        assert!(verify.check("https://example.com", b"hello world").is_ok());
+
+        let e = verify.check("https://example.com", b"goodbye").unwrap_err();
+
+        match e.downcast_ref::<VerifyError>() {
+            Some(VerifyError::Checksum { algorithm, .. }) => assert_eq!("sha256", *algorithm),
+            _ => panic!("unexpected error: {}", e),
+        }
+
+        let verify = Verify {
+            sha512: Some(String::from("00")),
+            ..Verify::default()
+        };
+
+        assert!(verify.check("https://example.com", b"hello world").is_err());
+    }
+
+    #[test]
+    fn test_check_stream() {
+        let verify = Verify {
+            sha256: Some(HELLO_SHA256.to_string()),
+            ..Verify::default()
+        };
+
+        let mut out = Vec::new();
+
+        let result = verify.check_stream("https://example.com", &mut out, |w| {
+            w.write_all(b"hello")?;
+            w.write_all(b" world")?;
+            Ok(())
+        });
+
+        assert!(result.is_ok());
+        assert_eq!(b"hello world", &out[..]);
+
+        let mut out = Vec::new();
+        let result = verify.check_stream("https://example.com", &mut out, |w| {
+            Ok(w.write_all(b"hello")?)
+        });
+
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_is_empty() {
+        assert!(Verify::default().is_empty());
+
+        let verify: Verify = serde_yaml::from_str("gpg: {}").unwrap();
+        assert!(!verify.is_empty());
+        assert_eq!(None, verify.gpg.and_then(|g| g.keyring));
+    }
+}